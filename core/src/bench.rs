@@ -0,0 +1,433 @@
+//! Commands that only need a power stage, shared by the firmware CLI and the simulator.
+//!
+//! `Commands` holds a table line for each, generic over the stage and the reply output, so both
+//! tables are built from the same names, arguments, help and handlers.
+use crate::board::Board;
+use crate::calibration::{self, CalibrationError, Point};
+use crate::cli::{args_fit, parse_phase, parse_switch, Arg, Command, Unit, Values};
+use crate::config::{self, Config, ConfigError};
+use crate::diagnose::{self, LegReadings, Verdict};
+use crate::response::{ErrorKind, Failure, Mode, Outcome, Response};
+use crate::shorts::{self, Short};
+use crate::stage::{Phase, PowerStage, SwitchState};
+use crate::store::{self, Flash, StoreError};
+use crate::units::{MilliAmperes, MilliVolts};
+use crate::bootstrap;
+use core::fmt::{self, Write};
+use core::marker::PhantomData;
+
+pub type Handler<B, W> = fn(&mut B, &Values, &mut Response<W>) -> Outcome;
+
+pub const MANUAL_DISABLED: Failure = Failure::new(ErrorKind::WrongMode, "Manual control disabled, swmode manual first");
+pub const NOT_OPENLOOP: Failure = Failure::new(ErrorKind::WrongMode, "Not in openloop mode, swmode openloop first");
+pub const DRV_DISABLED: Failure = Failure::new(ErrorKind::WrongMode, "DRV disabled, drv on first");
+pub const SHORT_FOUND: Failure = Failure::new(ErrorKind::WrongMode, "Short found, test shorts has to pass first");
+pub const NOT_IDLE: Failure = Failure::new(ErrorKind::WrongMode, "Saving stalls the CPU, swmode manual with all switches off first");
+
+pub const PHASES: &[&str] = &["a", "b", "c"];
+pub const DUTY: Arg = Arg::int("duty", 0, 100, Unit::Percent);
+pub const TIME: Arg = Arg::int("time", 1, 10_000, Unit::Milliseconds);
+
+const CAL_POINTS: &[&str] = &["low", "high"];
+
+const SWITCH_ARGS: &[Arg] = &[Arg::choice("switch", &["ah", "al", "az", "bh", "bl", "bz", "ch", "cl", "cz"])];
+const MODE_ARGS: &[Arg] = &[Arg::choice("mode", &["manual", "openloop"])];
+const OL_MANUAL_ARGS: &[Arg] = &[Arg::choice("phase", PHASES), DUTY];
+const OL_ALIGN_ARGS: &[Arg] = &[Arg::int("angle", -360, 360, Unit::Degrees), DUTY, TIME];
+const OL_BRAKE_ARGS: &[Arg] = &[Arg::choice("phase", PHASES), DUTY, TIME];
+const CFG_SET_ARGS: &[Arg] = &[Arg::word("name"), Arg::int("value", i32::MIN, i32::MAX, Unit::None)];
+const CAL_VOLTAGE_ARGS: &[Arg] = &[Arg::choice("point", CAL_POINTS), Arg::int("reference", 0, 100_000, Unit::MilliVolts)];
+const CAL_CURRENT_ARGS: &[Arg] = &[Arg::choice("phase", PHASES), Arg::int("current", 100, 50_000, Unit::MilliAmperes)];
+const BOOTSTRAP_ARGS: &[Arg] = &[Arg::int("min", 1, 1000, Unit::Milliseconds).optional()];
+const _: () = assert!(
+    args_fit(SWITCH_ARGS)
+        && args_fit(MODE_ARGS)
+        && args_fit(OL_MANUAL_ARGS)
+        && args_fit(OL_ALIGN_ARGS)
+        && args_fit(OL_BRAKE_ARGS)
+        && args_fit(CFG_SET_ARGS)
+        && args_fit(CAL_VOLTAGE_ARGS)
+        && args_fit(CAL_CURRENT_ARGS)
+        && args_fit(BOOTSTRAP_ARGS)
+);
+
+/// Power stage together with the state the commands keep between calls.
+pub trait Bench: PowerStage {
+    type Flash: Flash;
+
+    fn board(&self) -> Board;
+    /// Runtime adjustable parameters, `cfg save` stores them in flash.
+    fn config(&mut self) -> &mut Config;
+    /// Found by the last `test shorts`, openloop and the other tests refuse to run until it passes.
+    fn short(&mut self) -> &mut Option<Short>;
+    /// Low point of a `cal voltage` in progress, the high point completes it.
+    fn cal_low(&mut self) -> &mut Option<Point>;
+    fn flash(&mut self) -> &mut Self::Flash;
+    /// Gate driver enabled, a fault turns it off.
+    fn driver_enabled(&self) -> bool;
+    /// Measures the ADC reference again before a calibration, returns VDDA.
+    fn calibrate_adc(&mut self) -> MilliVolts;
+    /// Static voltage vector with the full PWM resolution, see `vector::vector_duties`.
+    /// Returns false if not in openloop mode.
+    fn apply_vector(&mut self, angle_deg: i32, duty: u8) -> bool;
+    /// `phase` at `duty` with the low sides of the other phases on, false if not in openloop mode.
+    fn dc_injection(&mut self, phase: Phase, duty: u8) -> bool;
+    /// All phases at 50%, false if not in openloop mode.
+    fn zero_vector(&mut self) -> bool;
+}
+
+/// Table lines of the shared commands for stage `B` replying to `W`.
+pub struct Commands<B, W>(PhantomData<(B, W)>);
+
+impl<B: Bench, W: Write> Commands<B, W> {
+    pub const SWITCH: Command<Handler<B, W>> = Command {
+        name: "sw",
+        help: "Set a switch in manual mode, h - high side on, l - low side on, z - both off",
+        args: SWITCH_ARGS,
+        handler: switch_command::<B, W>,
+    };
+
+    pub const SWITCH_MODE: Command<Handler<B, W>> = Command { name: "swmode", help: "Switch between manual switches and PWM", args: MODE_ARGS, handler: switch_mode_command::<B, W> };
+
+    pub const OL_MANUAL: Command<Handler<B, W>> = Command { name: "ol manual", help: "Set duty of one phase", args: OL_MANUAL_ARGS, handler: ol_manual_command::<B, W> };
+
+    pub const OL_ALIGN: Command<Handler<B, W>> = Command { name: "ol align", help: "Apply a voltage vector for a while", args: OL_ALIGN_ARGS, handler: ol_align_command::<B, W> };
+
+    pub const OL_BRAKE: Command<Handler<B, W>> = Command { name: "ol brake", help: "Inject DC through one phase for a while", args: OL_BRAKE_ARGS, handler: ol_brake_command::<B, W> };
+
+    pub const CFG_SHOW: Command<Handler<B, W>> = Command { name: "cfg show", help: "Current settings and their ranges", args: &[], handler: cfg_show_command::<B, W> };
+
+    pub const CFG_SET: Command<Handler<B, W>> = Command { name: "cfg set", help: "Change a setting, see cfg show", args: CFG_SET_ARGS, handler: cfg_set_command::<B, W> };
+
+    pub const CFG_SAVE: Command<Handler<B, W>> = Command { name: "cfg save", help: "Store settings in flash", args: &[], handler: cfg_save_command::<B, W> };
+
+    pub const CFG_LOAD: Command<Handler<B, W>> = Command { name: "cfg load", help: "Load settings from flash", args: &[], handler: cfg_load_command::<B, W> };
+
+    pub const CFG_RESET: Command<Handler<B, W>> = Command { name: "cfg reset", help: "Restore default settings", args: &[], handler: cfg_reset_command::<B, W> };
+
+    pub const CAL_VOLTAGE: Command<Handler<B, W>> = Command {
+        name: "cal voltage",
+        help: "Two point calibration of V_IN and the phase voltages at a known bus voltage, low point first, high point stores, nothing connected",
+        args: CAL_VOLTAGE_ARGS,
+        handler: cal_voltage_command::<B, W>,
+    };
+
+    pub const CAL_CURRENT: Command<Handler<B, W>> = Command {
+        name: "cal current",
+        help: "Gain of a phase current returning from the other legs through the ref_load resistor and an inductor, stores it",
+        args: CAL_CURRENT_ARGS,
+        handler: cal_current_command::<B, W>,
+    };
+
+    pub const TEST_BOOTSTRAP: Command<Handler<B, W>> = Command {
+        name: "test bootstrap",
+        help: "Longest high side on time of each leg after charging the bootstrap, leaves manual mode with all off",
+        args: BOOTSTRAP_ARGS,
+        handler: test_bootstrap_command::<B, W>,
+    };
+
+    pub const TEST_SHORTS: Command<Handler<B, W>> = Command {
+        name: "test shorts",
+        help: "Pulses each leg at low duty against the other low sides, stops at the first phase to phase or ground short, abort tells if nFAULT also stops the PWM in hardware. Disconnect the motor first, its windings read as a short",
+        args: &[],
+        handler: test_shorts_command::<B, W>,
+    };
+
+    pub const TEST_LEGS: Command<Handler<B, W>> = Command {
+        name: "test legs",
+        help: "Phase voltages of each leg floating, high and low, finds open and shorted switches, nothing connected, leaves manual mode with all off",
+        args: &[],
+        handler: test_legs_command::<B, W>,
+    };
+}
+
+/// Tests drive the switches, they need the gate driver and no known short.
+fn ready<B: Bench>(bench: &mut B) -> Outcome {
+    if !bench.driver_enabled() {
+        return Err(DRV_DISABLED);
+    }
+    if bench.short().is_some() {
+        return Err(SHORT_FOUND);
+    }
+    Ok(())
+}
+
+fn phase(args: &Values, i: usize) -> Result<Phase, Failure> {
+    parse_phase(args.text(i)).ok_or(Failure::new(ErrorKind::BadArgument, "Unknown phase"))
+}
+
+fn switch_command<B: Bench, W: Write>(bench: &mut B, args: &Values, _response: &mut Response<W>) -> Outcome {
+    if let Some((phase, state)) = parse_switch(args.text(0)) {
+        if !bench.set_switch(phase, state) {
+            return Err(MANUAL_DISABLED);
+        }
+    }
+    Ok(())
+}
+
+fn switch_mode_command<B: Bench, W: Write>(bench: &mut B, args: &Values, response: &mut Response<W>) -> Outcome {
+    let (switched, mode) = if args.choice(0) == 0 {
+        (bench.switch_to_manual(), "manual")
+    } else if bench.short().is_some() {
+        return Err(SHORT_FOUND);
+    } else {
+        (bench.switch_to_openloop(), "openloop")
+    };
+    response.text_field("mode", mode);
+    response.field("switched", switched, "");
+    Ok(())
+}
+
+fn ol_manual_command<B: Bench, W: Write>(bench: &mut B, args: &Values, _response: &mut Response<W>) -> Outcome {
+    match bench.set_duty(phase(args, 0)?, args.int(1) as u8) {
+        true => Ok(()),
+        false => Err(NOT_OPENLOOP),
+    }
+}
+
+fn ol_align_command<B: Bench, W: Write>(bench: &mut B, args: &Values, _response: &mut Response<W>) -> Outcome {
+    if !bench.apply_vector(args.int(0), args.int(1) as u8) {
+        return Err(NOT_OPENLOOP);
+    }
+    bench.delay_ms(args.int(2) as u32);
+    bench.zero_vector();
+    Ok(())
+}
+
+fn ol_brake_command<B: Bench, W: Write>(bench: &mut B, args: &Values, _response: &mut Response<W>) -> Outcome {
+    if !bench.dc_injection(phase(args, 0)?, args.int(1) as u8) {
+        return Err(NOT_OPENLOOP);
+    }
+    bench.delay_ms(args.int(2) as u32);
+    bench.zero_vector();
+    Ok(())
+}
+
+fn cfg_show_command<B: Bench, W: Write>(bench: &mut B, _args: &Values, response: &mut Response<W>) -> Outcome {
+    let settings = *bench.config();
+    for (name, unit, _, _) in config::FIELDS {
+        response.field(name, settings.get(name).unwrap_or(0), unit);
+    }
+    Ok(())
+}
+
+fn cfg_set_command<B: Bench, W: Write>(bench: &mut B, args: &Values, response: &mut Response<W>) -> Outcome {
+    let name = args.text(0);
+    let board = bench.board();
+    match bench.config().set(&board, name, args.int(1)) {
+        Ok(()) => {}
+        Err(ConfigError::UnknownField) => return Err(Failure::new(ErrorKind::BadArgument, "Unknown setting, see cfg show")),
+        Err(ConfigError::OutOfRange) => return Err(Failure::new(ErrorKind::BadArgument, "Out of range, see help cfg")),
+    }
+    if name == "dead_time" || name == "pwm_freq" {
+        response.message("Applied on next swmode openloop");
+    }
+    Ok(())
+}
+
+fn cfg_save_command<B: Bench, W: Write>(bench: &mut B, _args: &Values, response: &mut Response<W>) -> Outcome {
+    if !idle(bench) {
+        return Err(NOT_IDLE);
+    }
+    let settings = *bench.config();
+    match store::save(bench.flash(), &settings) {
+        Ok(sequence) => response.field("sequence", sequence, ""),
+        Err(StoreError::Flash) => return Err(Failure::new(ErrorKind::Failed, "Save failed: flash error")),
+        Err(StoreError::Verify) => return Err(Failure::new(ErrorKind::Failed, "Save failed: read back differs")),
+    }
+    Ok(())
+}
+
+fn cfg_load_command<B: Bench, W: Write>(bench: &mut B, _args: &Values, response: &mut Response<W>) -> Outcome {
+    let board = bench.board();
+    let (settings, sequence) = store::load(bench.flash(), &board)
+        .ok_or(Failure::new(ErrorKind::NotFound, "Nothing stored for this board"))?;
+    *bench.config() = settings;
+    response.field("sequence", sequence, "");
+    Ok(())
+}
+
+fn cfg_reset_command<B: Bench, W: Write>(bench: &mut B, _args: &Values, response: &mut Response<W>) -> Outcome {
+    *bench.config() = Config::defaults(&bench.board());
+    response.message("Defaults restored, cfg save to keep them");
+    Ok(())
+}
+
+/// Erasing a flash sector stalls code fetch for 1-2s, the PWM and protection interrupts included,
+/// so flash is only written while nothing switches.
+fn idle<B: Bench>(bench: &B) -> bool {
+    Phase::ALL.iter().all(|&phase| bench.switch(phase) == Some(SwitchState::Off))
+}
+
+/// Stores the settings a calibration just changed, with all switches turned off first.
+fn save_calibration<B: Bench, W: Write>(bench: &mut B, response: &mut Response<W>) -> Outcome {
+    bench.switch_to_manual();
+    for &phase in Phase::ALL.iter() {
+        bench.set_switch(phase, SwitchState::Off);
+    }
+    let settings = *bench.config();
+    match store::save(bench.flash(), &settings) {
+        Ok(sequence) => response.field("sequence", sequence, ""),
+        Err(_) => return Err(Failure::new(ErrorKind::Failed, "Applied, save failed, cfg save to retry")),
+    }
+    Ok(())
+}
+
+fn cal_voltage_command<B: Bench, W: Write>(bench: &mut B, args: &Values, response: &mut Response<W>) -> Outcome {
+    ready(bench)?;
+    let vdda = bench.calibrate_adc();
+    let board = bench.board();
+    let settings = *bench.config();
+    let point = calibration::measure_point(bench, &board, &settings, MilliVolts(args.int(1)));
+    response.field("vdda", vdda.0, "mV");
+    for (name, v) in calibration::VOLTAGE_NAMES.iter().zip(point.measured.iter()) {
+        response.field(name, v.0, "mV");
+    }
+    if args.choice(0) == 0 {
+        *bench.cal_low() = Some(point);
+        response.message("Low point taken, cal voltage high next");
+        return Ok(());
+    }
+    let low = bench.cal_low().ok_or(Failure::new(ErrorKind::WrongMode, "No low point, cal voltage low first"))?;
+    let (gains, offsets) = match calibration::two_point(&low, &point) {
+        Ok(result) => result,
+        Err(CalibrationError::Span) => return Err(Failure::new(ErrorKind::BadArgument, "References too close together")),
+        Err(_) => return Err(Failure::new(ErrorKind::Failed, "Correction out of range, check the references")),
+    };
+    *bench.cal_low() = None;
+    bench.config().voltage_gains = gains;
+    bench.config().voltage_offsets = offsets;
+    for (name, (gain, offset)) in calibration::VOLTAGE_NAMES.iter().zip(gains.iter().zip(offsets.iter())) {
+        response.field(format_args!("{}_gain", name), gain, "");
+        response.field(format_args!("{}_offset", name), offset.0, "mV");
+    }
+    save_calibration(bench, response)
+}
+
+fn cal_current_command<B: Bench, W: Write>(bench: &mut B, args: &Values, response: &mut Response<W>) -> Outcome {
+    ready(bench)?;
+    let phase = phase(args, 0)?;
+    bench.calibrate_adc();
+    let board = bench.board();
+    let settings = *bench.config();
+    let result = calibration::measure_current(bench, &board, &settings, phase, MilliAmperes(args.int(1)))
+        .and_then(|points| Ok((points, calibration::fit_gain(phase, &points)?)));
+    let (points, gain) = match result {
+        Ok(result) => result,
+        Err(CalibrationError::Unreachable) => return Err(Failure::new(ErrorKind::BadArgument, "Current out of reach with this ref_load and V_IN")),
+        Err(CalibrationError::Ripple(spread)) => {
+            response.field("spread", spread.0, "mA");
+            return Err(Failure::new(ErrorKind::Failed, "Current is pulsed, put an inductor in series with the resistor"));
+        }
+        Err(_) => return Err(Failure::new(ErrorKind::Failed, "Correction out of range, check the resistor and ref_load")),
+    };
+    let last = points[calibration::CURRENT_STEPS - 1];
+    response.field("duty", last.duty, "%");
+    response.field("v_in", last.v_in.0, "mV");
+    response.field("expected", last.expected.0, "mA");
+    response.field("measured", last.measured.0, "mA");
+    response.field("gain", gain, "");
+    let k = match phase {
+        Phase::A => 0,
+        Phase::B => 1,
+        Phase::C => 2,
+    };
+    bench.config().current_gains[k] = gain;
+    save_calibration(bench, response)
+}
+
+fn test_bootstrap_command<B: Bench, W: Write>(bench: &mut B, args: &Values, response: &mut Response<W>) -> Outcome {
+    ready(bench)?;
+    let min_hold_ms = if args.is_present(0) { args.int(0) as u32 } else { bootstrap::MIN_HOLD_MS };
+    let board = bench.board();
+    let settings = *bench.config();
+    let report = bootstrap::run(bench, &board, &settings);
+    response.field("v_in", report.v_in.0, "mV");
+    for leg in report.legs.iter() {
+        let name = leg.phase.name();
+        response.field(format_args!("{}_low", name), leg.v_low.0, "mV");
+        response.field(format_args!("{}_sustained", name), leg.sustained_ms, "ms");
+        match (leg.sag_at_ms, leg.v_sag) {
+            (Some(t), Some(v)) => {
+                response.field(format_args!("{}_sag_at", name), t, "ms");
+                response.field(format_args!("{}_sag_v", name), v.0, "mV");
+            }
+            _ => response.field(format_args!("{}_sag_at", name), "null", ""),
+        }
+        response.field(format_args!("{}_passed", name), leg.passed(report.v_in, min_hold_ms), "");
+    }
+    match report.passed(min_hold_ms) {
+        true => Ok(()),
+        false => Err(Failure::new(ErrorKind::Failed, "A leg didn't pull low or sagged early")),
+    }
+}
+
+fn test_shorts_command<B: Bench, W: Write>(bench: &mut B, _args: &Values, response: &mut Response<W>) -> Outcome {
+    if !bench.driver_enabled() {
+        return Err(DRV_DISABLED);
+    }
+    let board = bench.board();
+    let settings = *bench.config();
+    let report = shorts::run(bench, &board, &settings);
+    response.text_field("abort", shorts::abort_path(&board));
+    response.field("v_in", report.v_in.0, "mV");
+    response.field("v_in_min", report.v_in_min.0, "mV");
+    response.field("i_peak", report.i_peak.0, "mA");
+    // The DRV's overcurrent protection is faster than the sampled periods, whatever the samples saw
+    if !bench.driver_enabled() {
+        *bench.short() = Some(Short::Driver);
+        response.text_field("short", Short::Driver);
+        return Err(Failure::new(ErrorKind::Failed, "DRV shut down during the test, likely a short"));
+    }
+    if !report.complete {
+        return Err(Failure::new(ErrorKind::Failed, "PWM sample trigger missing, no verdict"));
+    }
+    *bench.short() = report.short;
+    match report.short {
+        Some(short) => {
+            response.text_field("short", short);
+            Err(Failure::new(ErrorKind::Failed, "Short found, openloop and tests refused"))
+        }
+        None => {
+            response.field("short", "null", "");
+            Ok(())
+        }
+    }
+}
+
+/// Row of the `test legs` table for humans.
+struct LegRow(LegReadings, Verdict);
+
+impl fmt::Display for LegRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let leg = &self.0;
+        write!(f, "{:>7} {:>7} {:>7} {:>7}  {}", leg.v_float.0, leg.v_high.0, leg.v_low.0, leg.v_released.0, self.1.name())
+    }
+}
+
+fn test_legs_command<B: Bench, W: Write>(bench: &mut B, _args: &Values, response: &mut Response<W>) -> Outcome {
+    ready(bench)?;
+    let board = bench.board();
+    let settings = *bench.config();
+    let report = diagnose::run(bench, &board, &settings);
+    response.field("v_in", report.v_in.0, "mV");
+    if response.mode() == Mode::Human {
+        response.message("mV   float    high     low release  verdict");
+    }
+    for (leg, verdict) in report.legs.iter().zip(report.verdicts().iter()) {
+        let name = leg.phase.name();
+        if response.mode() == Mode::Human {
+            response.text_field(name, LegRow(*leg, *verdict));
+        } else {
+            response.field(format_args!("{}_float", name), leg.v_float.0, "mV");
+            response.field(format_args!("{}_high", name), leg.v_high.0, "mV");
+            response.field(format_args!("{}_low", name), leg.v_low.0, "mV");
+            response.field(format_args!("{}_released", name), leg.v_released.0, "mV");
+            response.text_field(format_args!("{}_verdict", name), verdict.name());
+        }
+    }
+    match report.passed() {
+        true => Ok(()),
+        false => Err(Failure::new(ErrorKind::Failed, "A leg has an open or shorted switch")),
+    }
+}
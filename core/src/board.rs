@@ -0,0 +1,238 @@
+//! Descriptions of the power stage hardware revisions.
+//!
+//! Everything that differs between revisions is kept here as plain data, the firmware picks one
+//! description with a cargo feature and the conversion code only ever looks at it.
+//! Only revisions checked against their schematic are described, a new one is added with its
+//! schematic at hand.
+
+use crate::stage::{Channel, Phase};
+use crate::units::{resistor_divider_inverse, voltage_to_current, MicroOhms, MilliVolts, Ohms};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Pin {
+    pub port: Port,
+    pub pin: u8,
+}
+
+impl Pin {
+    pub const fn new(port: Port, pin: u8) -> Self {
+        Pin { port, pin }
+    }
+
+    /// `==` is not usable in constants, used to check a description at compile time.
+    pub const fn same_as(&self, other: &Pin) -> bool {
+        self.port as u8 == other.port as u8 && self.pin == other.pin
+    }
+}
+
+/// High and low side gate outputs of one half-bridge.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Leg {
+    pub high: Pin,
+    pub low: Pin,
+}
+
+/// ADC1 input number for each measurement channel.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct AnalogInputs {
+    pub v_in: u8,
+    pub v_a: u8,
+    pub v_b: u8,
+    pub v_c: u8,
+    pub i_a: u8,
+    pub i_b: u8,
+    pub i_c: u8,
+    pub v_can: u8,
+}
+
+impl AnalogInputs {
+    pub fn get(&self, channel: Channel) -> u8 {
+        match channel {
+            Channel::VIn => self.v_in,
+            Channel::VA => self.v_a,
+            Channel::VB => self.v_b,
+            Channel::VC => self.v_c,
+            Channel::IA => self.i_a,
+            Channel::IB => self.i_b,
+            Channel::IC => self.i_c,
+            Channel::VCan => self.v_can,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Divider {
+    pub rt: Ohms,
+    pub rb: Ohms,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DrvVariant {
+    /// SPI, 4 registers, shunt amplifiers with 10/20/40/80 gain.
+    Drv8301,
+    /// No SPI, gain 10 or 40 selected with the GAIN pin.
+    Drv8302,
+    /// SPI, 7 registers, shunt amplifiers with 5/10/20/40 gain.
+    Drv8323S,
+}
+
+impl DrvVariant {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DrvVariant::Drv8301 => "DRV8301",
+            DrvVariant::Drv8302 => "DRV8302",
+            DrvVariant::Drv8323S => "DRV8323S",
+        }
+    }
+
+    /// Number of SPI registers starting from address 0, zero if there is no SPI.
+    pub fn register_count(&self) -> u8 {
+        match self {
+            DrvVariant::Drv8301 => 4,
+            DrvVariant::Drv8302 => 0,
+            DrvVariant::Drv8323S => 7,
+        }
+    }
+
+    pub fn current_gains(&self) -> &'static [u8] {
+        match self {
+            DrvVariant::Drv8301 => &[10, 20, 40, 80],
+            DrvVariant::Drv8302 => &[10, 40],
+            DrvVariant::Drv8323S => &[5, 10, 20, 40],
+        }
+    }
+
+    pub fn supports_gain(&self, gain: u8) -> bool {
+        self.current_gains().contains(&gain)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Board {
+    pub name: &'static str,
+    /// Gate outputs of phase A, B and C.
+    pub legs: [Leg; 3],
+    /// TIM1 break input wired to the DRV's nFAULT, it stops the gate outputs in hardware. None
+    /// when nFAULT only reaches a GPIO and faults are handled in software.
+    pub fault_break: Option<Pin>,
+    pub analog: AnalogInputs,
+    pub phase_divider: Divider,
+    pub v_in_divider: Divider,
+    pub v_can_divider: Divider,
+    pub shunt: MicroOhms,
+    /// Current amplifier output at zero current.
+    pub current_midpoint: MilliVolts,
+    pub drv: DrvVariant,
+    pub default_current_gain: u8,
+}
+
+impl Board {
+    pub fn leg(&self, phase: Phase) -> Leg {
+        match phase {
+            Phase::A => self.legs[0],
+            Phase::B => self.legs[1],
+            Phase::C => self.legs[2],
+        }
+    }
+
+    /// Converts the voltage seen by the ADC into mV or mA depending on the channel.
+    pub fn convert(&self, channel: Channel, v_adc: MilliVolts, current_gain: u8) -> i32 {
+        let divider = match channel {
+            Channel::VIn => self.v_in_divider,
+            Channel::VA | Channel::VB | Channel::VC => self.phase_divider,
+            Channel::VCan => self.v_can_divider,
+            Channel::IA | Channel::IB | Channel::IC => {
+                return voltage_to_current(v_adc, self.current_midpoint, self.shunt, current_gain).0;
+            }
+        };
+        resistor_divider_inverse(divider.rt, divider.rb, v_adc).0
+    }
+}
+
+const DIVIDER_60V: Divider = Divider { rt: Ohms(34900), rb: Ohms(4990) };
+
+/// First revision, DRV8301 with 10mΩ shunts.
+pub const REV1: Board = Board {
+    name: "rev1",
+    legs: [
+        Leg { high: Pin::new(Port::A, 8), low: Pin::new(Port::B, 13) },
+        Leg { high: Pin::new(Port::A, 9), low: Pin::new(Port::B, 14) },
+        Leg { high: Pin::new(Port::A, 10), low: Pin::new(Port::B, 15) },
+    ],
+    // nFAULT goes to PB4, which has no TIM1_BKIN function
+    fault_break: None,
+    analog: AnalogInputs { v_in: 13, v_a: 0, v_b: 1, v_c: 2, i_a: 12, i_b: 11, i_c: 10, v_can: 15 },
+    phase_divider: DIVIDER_60V,
+    v_in_divider: DIVIDER_60V,
+    v_can_divider: DIVIDER_60V,
+    shunt: MicroOhms(10_000),
+    current_midpoint: MilliVolts(1650),
+    drv: DrvVariant::Drv8301,
+    default_current_gain: 20,
+};
+
+pub const BOARDS: [Board; 1] = [REV1];
+
+/// REV1 with a hardware gain DRV8302, for the gain checks of the settings.
+#[cfg(test)]
+pub(crate) const DRV8302_STAGE: Board = Board { name: "drv8302", drv: DrvVariant::Drv8302, default_current_gain: 10, ..REV1 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Nominal VDDA, the ADC can't see anything above it.
+    const ADC_FULL_SCALE: MilliVolts = MilliVolts(3300);
+
+    const CHANNELS: [Channel; 8] = [
+        Channel::VIn, Channel::VA, Channel::VB, Channel::VC,
+        Channel::IA, Channel::IB, Channel::IC, Channel::VCan
+    ];
+
+    fn check_consistency(board: &Board) {
+        assert!(board.drv.supports_gain(board.default_current_gain), "{}", board.name);
+        for (i, a) in CHANNELS.iter().enumerate() {
+            let input = board.analog.get(*a);
+            assert!(input <= 15, "{}: {:?} is not an external ADC input", board.name, a);
+            for b in &CHANNELS[i + 1..] {
+                assert_ne!(input, board.analog.get(*b), "{}: {:?} and {:?} share an input", board.name, a, b);
+            }
+        }
+        let pins: Vec<Pin> = board.legs.iter().flat_map(|l| [l.high, l.low]).collect();
+        for (i, a) in pins.iter().enumerate() {
+            assert!(!pins[i + 1..].contains(a), "{}: gate pin {:?} used twice", board.name, a);
+        }
+        assert!(board.current_midpoint > MilliVolts(0), "{}", board.name);
+        assert!(board.current_midpoint < ADC_FULL_SCALE, "{}: midpoint outside the ADC range", board.name);
+        assert_eq!(board.convert(Channel::IA, board.current_midpoint, board.default_current_gain), 0);
+    }
+
+    #[test]
+    fn rev1() {
+        check_consistency(&REV1);
+        assert_eq!(REV1.convert(Channel::VIn, MilliVolts(1000), 20), 7993);
+        assert_eq!(REV1.convert(Channel::VCan, MilliVolts(1000), 20), 7993);
+        assert_eq!(REV1.convert(Channel::IB, MilliVolts(1850), 20), 1000);
+        assert_eq!(REV1.analog.get(Channel::IA), 12);
+        assert_eq!(REV1.drv.register_count(), 4);
+        assert_eq!(REV1.leg(Phase::C).low, Pin::new(Port::B, 15));
+        assert!(REV1.leg(Phase::B).high.same_as(&Pin::new(Port::A, 9)));
+    }
+
+    #[test]
+    fn drv_variants() {
+        assert_eq!(DrvVariant::Drv8323S.register_count(), 7);
+        assert!(DrvVariant::Drv8323S.supports_gain(5));
+        assert!(!DrvVariant::Drv8323S.supports_gain(80));
+        assert_eq!(DrvVariant::Drv8302.register_count(), 0);
+        assert!(!DrvVariant::Drv8302.supports_gain(20));
+    }
+}
@@ -0,0 +1,114 @@
+//! Bootstrap test: the low side of a leg charges the bootstrap capacitor, then the high side is
+//! held on for growing times while the phase voltage is watched. A bootstrap supplied high side
+//! driver loses its gate voltage after a while and the phase voltage sags, a charge pump keeps
+//! it up for every hold time.
+//!
+//! Runs in manual mode with nothing connected that would draw current from a single leg.
+
+use crate::board::Board;
+use crate::config::Config;
+use crate::stage::{measure, Channel, Phase, PowerStage, SwitchState};
+use crate::units::MilliVolts;
+
+/// Low side on time before every hold.
+pub const CHARGE_MS: u32 = 5;
+/// High side hold times, the test stops at the first one that sags.
+pub const HOLD_MS: [u32; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+/// Shortest hold a leg has to sustain unless the caller asks for another.
+pub const MIN_HOLD_MS: u32 = 100;
+/// Phase voltage below this share of V_IN counts as a sag.
+pub const SAG_PERCENT: i32 = 90;
+/// Phase voltage above this share of V_IN with the low side on means it didn't switch.
+pub const LOW_PERCENT: i32 = 10;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LegResult {
+    pub phase: Phase,
+    /// Phase voltage at the end of the first charge.
+    pub v_low: MilliVolts,
+    /// Longest hold that kept the phase voltage up, 0 if even the shortest sagged.
+    pub sustained_ms: u32,
+    /// Time into the hold when the phase voltage sagged, None if no hold did.
+    pub sag_at_ms: Option<u32>,
+    /// Phase voltage when the sag was seen.
+    pub v_sag: Option<MilliVolts>,
+}
+
+impl LegResult {
+    /// The leg pulled the phase low and kept it high for at least `min_hold_ms`.
+    pub fn passed(&self, v_in: MilliVolts, min_hold_ms: u32) -> bool {
+        self.v_low.0 <= v_in.0 * LOW_PERCENT / 100 && self.sustained_ms >= min_hold_ms
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Report {
+    pub v_in: MilliVolts,
+    pub legs: [LegResult; 3],
+}
+
+impl Report {
+    pub fn passed(&self, min_hold_ms: u32) -> bool {
+        self.legs.iter().all(|leg| leg.passed(self.v_in, min_hold_ms))
+    }
+}
+
+/// Tests the legs one after another, the stage is left in manual mode with all switches off.
+pub fn run<S: PowerStage + ?Sized>(stage: &mut S, board: &Board, config: &Config) -> Report {
+    stage.switch_to_manual();
+    for &phase in Phase::ALL.iter() {
+        stage.set_switch(phase, SwitchState::Off);
+    }
+    let v_in = MilliVolts(measure(stage, board, config, Channel::VIn));
+    let legs = Phase::ALL.map(|phase| test_leg(stage, board, config, phase, v_in));
+    Report { v_in, legs }
+}
+
+fn test_leg<S: PowerStage + ?Sized>(stage: &mut S, board: &Board, config: &Config, phase: Phase, v_in: MilliVolts) -> LegResult {
+    let threshold = v_in.0 * SAG_PERCENT / 100;
+    let mut result = LegResult { phase, v_low: MilliVolts(0), sustained_ms: 0, sag_at_ms: None, v_sag: None };
+    for (n, &hold_ms) in HOLD_MS.iter().enumerate() {
+        stage.set_switch(phase, SwitchState::Low);
+        stage.delay_ms(CHARGE_MS);
+        if n == 0 {
+            result.v_low = MilliVolts(measure(stage, board, config, phase.voltage_channel()));
+        }
+        stage.set_switch(phase, SwitchState::High);
+        let mut sag = None;
+        for t in 1..=hold_ms {
+            stage.delay_ms(1);
+            let v = measure(stage, board, config, phase.voltage_channel());
+            if v < threshold {
+                sag = Some((t, MilliVolts(v)));
+                break;
+            }
+        }
+        stage.set_switch(phase, SwitchState::Off);
+        match sag {
+            Some((t, v)) => {
+                result.sag_at_ms = Some(t);
+                result.v_sag = Some(v);
+                break;
+            }
+            None => result.sustained_ms = hold_ms,
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_criteria() {
+        let v_in = MilliVolts(24_000);
+        let leg = LegResult { phase: Phase::A, v_low: MilliVolts(100), sustained_ms: 200, sag_at_ms: Some(350), v_sag: Some(MilliVolts(9000)) };
+        assert!(leg.passed(v_in, 100));
+        assert!(!leg.passed(v_in, 500));
+        assert!(!LegResult { v_low: MilliVolts(12_000), ..leg }.passed(v_in, 100));
+        let report = Report { v_in, legs: [leg, leg, LegResult { sustained_ms: 50, ..leg }] };
+        assert!(report.passed(50));
+        assert!(!report.passed(100));
+    }
+}
@@ -0,0 +1,312 @@
+//! Calibration of the analog channels.
+//!
+//! Voltages take two points: the operator applies two known bus voltages, the phases follow the
+//! bus through the high sides, and a gain and offset per channel map the divider conversion onto
+//! the references. Runs with nothing connected to the phases.
+//!
+//! Currents take a gain per phase: a reference resistor of known value connects the calibrated
+//! phase to another one, openloop duty of the other two legs steps up to the requested current
+//! and the calibrated leg stays at 0%. The shunts sit in the low sides, so the calibrated leg is
+//! the one the current returns through: its low side is on for the whole PWM period and its
+//! shunt carries the load current wherever in the period a conversion lands, as long as the load
+//! keeps the current flowing while the driving leg is low. A bare resistor doesn't, the readings
+//! then jump between zero and the peak and the calibration is refused, an inductor or a motor
+//! winding in series smooths it. The expected current follows from the duty, the dead time and
+//! the measured bus voltage. A line through the origin fitted to the readings gives the gain, the
+//! offsets come from `current_offsets` before.
+//!
+//! The conversions use VDDA from VREFINT, the caller refreshes it before measuring.
+
+use crate::board::Board;
+use crate::bootstrap::CHARGE_MS;
+use crate::config::Config;
+use crate::stage::{measure, Channel, Phase, PowerStage, SwitchState};
+use crate::units::{Fixed, MilliAmperes, MilliVolts};
+
+/// Channels corrected with `Config::voltage_gains` and `voltage_offsets`, in that order.
+pub const VOLTAGE_CHANNELS: [Channel; 4] = [Channel::VIn, Channel::VA, Channel::VB, Channel::VC];
+pub const VOLTAGE_NAMES: [&str; 4] = ["v_in", "v_a", "v_b", "v_c"];
+/// Gain of 1.0.
+pub const GAIN_ONE: i32 = 10_000;
+/// Gains and offsets further from 1.0 and 0 than this point at a wrong reference.
+pub const MAX_GAIN_ERROR: i32 = 1_000;
+pub const MAX_OFFSET_MV: i32 = 2_000;
+/// The two references have to be at least this far apart.
+pub const MIN_SPAN_MV: i32 = 1_000;
+/// Time for the phase voltages to follow the high sides.
+pub const SETTLE_MS: u32 = 2;
+/// Readings averaged per channel.
+pub const SAMPLES: i32 = 16;
+/// Duty steps of a current calibration, the last one gives the requested current.
+pub const CURRENT_STEPS: usize = 4;
+/// Duty range of the last step, below it the dead time dominates.
+pub const MIN_DUTY: u8 = 10;
+pub const MAX_DUTY: u8 = 90;
+/// Time for the current to settle in the reference resistor and its leads at each step.
+pub const CURRENT_SETTLE_MS: u32 = 20;
+/// Spread of the readings at the last step, in percent of their average, above which the load
+/// current doesn't flow through the whole period.
+pub const MAX_RIPPLE_PERCENT: i32 = 20;
+
+/// Index into `VOLTAGE_CHANNELS`, None for channels that aren't corrected.
+pub fn voltage_index(channel: Channel) -> Option<usize> {
+    VOLTAGE_CHANNELS.iter().position(|c| *c == channel)
+}
+
+/// Stored gain in units of `GAIN_ONE` as a factor, the settings keep the decimal form.
+pub fn gain_factor(gain: u16) -> Fixed {
+    // Every u16 over GAIN_ONE is in range
+    Fixed::from_ratio(gain as i32, GAIN_ONE).unwrap_or(Fixed::ONE)
+}
+
+/// `value` with the gain in units of `GAIN_ONE` and the offset applied, rounded and saturated.
+pub fn correct(value: i32, gain: u16, offset: MilliVolts) -> i32 {
+    (MilliVolts(gain_factor(gain).scale(value)) + offset).0
+}
+
+/// Uncorrected readings of all voltage channels at one reference.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Point {
+    pub reference: MilliVolts,
+    pub measured: [MilliVolts; 4],
+}
+
+/// Measures all voltage channels with the high sides on so the phases follow the bus, without
+/// the current calibration. The stage is left in manual mode with all switches off.
+pub fn measure_point<S: PowerStage + ?Sized>(stage: &mut S, board: &Board, config: &Config, reference: MilliVolts) -> Point {
+    let config = Config { voltage_gains: [GAIN_ONE as u16; 4], voltage_offsets: [MilliVolts(0); 4], ..*config };
+    stage.switch_to_manual();
+    // Low sides first so bootstrap supplied high sides have their charge
+    for &phase in Phase::ALL.iter() {
+        stage.set_switch(phase, SwitchState::Low);
+    }
+    stage.delay_ms(CHARGE_MS);
+    for &phase in Phase::ALL.iter() {
+        stage.set_switch(phase, SwitchState::High);
+    }
+    stage.delay_ms(SETTLE_MS);
+    let mut sums = [0; 4];
+    for _ in 0..SAMPLES {
+        for (sum, &channel) in sums.iter_mut().zip(VOLTAGE_CHANNELS.iter()) {
+            *sum += measure(stage, board, &config, channel);
+        }
+    }
+    for &phase in Phase::ALL.iter() {
+        stage.set_switch(phase, SwitchState::Off);
+    }
+    Point { reference, measured: sums.map(|sum| MilliVolts(sum / SAMPLES)) }
+}
+
+/// Average current of a leg at `duty` into `load_mohm`, the dead time delays each turn-on and
+/// the low side diode conducts meanwhile.
+pub fn expected_current(v_in: MilliVolts, duty: u8, dead_time_ppm: i32, load_mohm: u32) -> MilliAmperes {
+    let duty_ppm = (duty as i64 * 10_000 - dead_time_ppm as i64).max(0);
+    MilliAmperes((v_in.0 as i64 * duty_ppm / 1_000 / load_mohm as i64) as i32)
+}
+
+/// Duty the leg needs for `target` into `load_mohm`, None outside `MIN_DUTY..=MAX_DUTY`.
+pub fn duty_for(target: MilliAmperes, v_in: MilliVolts, dead_time_ppm: i32, load_mohm: u32) -> Option<u8> {
+    if v_in.0 <= 0 {
+        return None;
+    }
+    let duty_ppm = target.0 as i64 * load_mohm as i64 * 1_000 / v_in.0 as i64 + dead_time_ppm as i64;
+    let duty = (duty_ppm + 5_000) / 10_000;
+    (MIN_DUTY as i64..=MAX_DUTY as i64).contains(&duty).then_some(duty as u8)
+}
+
+/// Share of each PWM period lost to the dead time, in ppm.
+pub fn dead_time_ppm(config: &Config) -> i32 {
+    (config.dead_time_ns as i64 * config.pwm_frequency_hz as i64 / 1_000) as i32
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct CurrentPoint {
+    pub duty: u8,
+    pub v_in: MilliVolts,
+    pub expected: MilliAmperes,
+    /// Phase current without the current calibration.
+    pub measured: MilliAmperes,
+}
+
+/// Gain in units of `GAIN_ONE` of the line through the origin closest to the points, least
+/// squares. `phase` is only reported.
+pub fn fit_gain(phase: Phase, points: &[CurrentPoint]) -> Result<u16, CalibrationError> {
+    let (num, den) = points.iter().fold((0i64, 0i64), |(num, den), p| {
+        (num + p.expected.0 as i64 * p.measured.0 as i64, den + p.measured.0 as i64 * p.measured.0 as i64)
+    });
+    let out_of_range = CalibrationError::OutOfRange(phase.current_channel());
+    if den == 0 {
+        return Err(out_of_range);
+    }
+    let gain = num * GAIN_ONE as i64 / den;
+    if (gain - GAIN_ONE as i64).abs() > MAX_GAIN_ERROR as i64 {
+        return Err(out_of_range);
+    }
+    Ok(gain as u16)
+}
+
+/// Steps the duty of the other legs up to the one giving `target` into
+/// `config.reference_load_mohm`, `phase` holds its low side on and measures the returning
+/// current. The stage is left in manual mode with all switches off.
+pub fn measure_current<S: PowerStage + ?Sized>(
+    stage: &mut S,
+    board: &Board,
+    config: &Config,
+    phase: Phase,
+    target: MilliAmperes,
+) -> Result<[CurrentPoint; CURRENT_STEPS], CalibrationError> {
+    let config = Config { current_gains: [GAIN_ONE as u16; 3], ..*config };
+    let dead_time = dead_time_ppm(&config);
+    stop(stage);
+    let v_in = MilliVolts(measure(stage, board, &config, Channel::VIn));
+    let duty = duty_for(target, v_in, dead_time, config.reference_load_mohm).ok_or(CalibrationError::Unreachable)?;
+
+    stage.switch_to_openloop();
+    for &leg in Phase::ALL.iter() {
+        stage.set_duty(leg, 0);
+    }
+    let mut points = [CurrentPoint { duty: 0, v_in, expected: MilliAmperes(0), measured: MilliAmperes(0) }; CURRENT_STEPS];
+    let mut spread = 0;
+    for (k, point) in points.iter_mut().enumerate() {
+        let step_duty = (duty as usize * (k + 1) / CURRENT_STEPS) as u8;
+        // The resistor goes to one of them, the other one drives nothing
+        for &leg in Phase::ALL.iter().filter(|&&leg| leg != phase) {
+            stage.set_duty(leg, step_duty);
+        }
+        stage.delay_ms(CURRENT_SETTLE_MS);
+        let (mut v_sum, mut i_sum, mut i_min, mut i_max) = (0, 0, i32::MAX, i32::MIN);
+        for _ in 0..SAMPLES {
+            v_sum += measure(stage, board, &config, Channel::VIn);
+            // Flows into the leg
+            let i = -measure(stage, board, &config, phase.current_channel());
+            i_sum += i;
+            i_min = i_min.min(i);
+            i_max = i_max.max(i);
+        }
+        spread = i_max - i_min;
+        let v_in = MilliVolts(v_sum / SAMPLES);
+        *point = CurrentPoint {
+            duty: step_duty,
+            v_in,
+            expected: expected_current(v_in, step_duty, dead_time, config.reference_load_mohm),
+            measured: MilliAmperes(i_sum / SAMPLES),
+        };
+    }
+    stop(stage);
+    let last = points[CURRENT_STEPS - 1].measured.0;
+    if spread as i64 * 100 > MAX_RIPPLE_PERCENT as i64 * last.abs() as i64 {
+        return Err(CalibrationError::Ripple(MilliAmperes(spread)));
+    }
+    Ok(points)
+}
+
+fn stop<S: PowerStage + ?Sized>(stage: &mut S) {
+    stage.switch_to_manual();
+    for &phase in Phase::ALL.iter() {
+        stage.set_switch(phase, SwitchState::Off);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CalibrationError {
+    /// References too close together.
+    Span,
+    /// The requested current needs a duty out of `MIN_DUTY..=MAX_DUTY` with this load and bus.
+    Unreachable,
+    /// A channel needs a gain or offset out of range, it's given.
+    OutOfRange(Channel),
+    /// The current readings spread this much at the last step, the load current is pulsed.
+    Ripple(MilliAmperes),
+}
+
+/// Gain and offset of every channel mapping the measurements of `low` and `high` onto their
+/// references.
+pub fn two_point(low: &Point, high: &Point) -> Result<([u16; 4], [MilliVolts; 4]), CalibrationError> {
+    let span = high.reference.0 - low.reference.0;
+    if span.abs() < MIN_SPAN_MV {
+        return Err(CalibrationError::Span);
+    }
+    let mut gains = [GAIN_ONE as u16; 4];
+    let mut offsets = [MilliVolts(0); 4];
+    for (k, &channel) in VOLTAGE_CHANNELS.iter().enumerate() {
+        let measured_span = high.measured[k].0 - low.measured[k].0;
+        if measured_span == 0 {
+            return Err(CalibrationError::OutOfRange(channel));
+        }
+        let gain = span as i64 * GAIN_ONE as i64 / measured_span as i64;
+        if (gain - GAIN_ONE as i64).abs() > MAX_GAIN_ERROR as i64 {
+            return Err(CalibrationError::OutOfRange(channel));
+        }
+        let offset = low.reference - MilliVolts(correct(low.measured[k].0, gain as u16, MilliVolts(0)));
+        if offset.abs().0 > MAX_OFFSET_MV {
+            return Err(CalibrationError::OutOfRange(channel));
+        }
+        gains[k] = gain as u16;
+        offsets[k] = offset;
+    }
+    Ok((gains, offsets))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_points() {
+        // V_IN reads 2.5% low, phase A 100 mV high, B and C right
+        let low = Point { reference: MilliVolts(12_000), measured: [MilliVolts(11_700), MilliVolts(12_100), MilliVolts(12_000), MilliVolts(12_000)] };
+        let high = Point { reference: MilliVolts(36_000), measured: [MilliVolts(35_100), MilliVolts(36_100), MilliVolts(36_000), MilliVolts(36_000)] };
+        let (gains, offsets) = two_point(&low, &high).unwrap();
+        assert_eq!(gains, [10_256, 10_000, 10_000, 10_000]);
+        assert_eq!(offsets, [MilliVolts(1), MilliVolts(-100), MilliVolts(0), MilliVolts(0)]);
+        assert_eq!(correct(35_100, gains[0], offsets[0]), 35_999);
+        assert_eq!(correct(24_100, gains[1], offsets[1]), 24_000);
+
+        assert_eq!(two_point(&low, &Point { reference: MilliVolts(12_500), ..high }), Err(CalibrationError::Span));
+        let wrong_reference = Point { reference: MilliVolts(48_000), ..high };
+        assert_eq!(two_point(&low, &wrong_reference), Err(CalibrationError::OutOfRange(Channel::VIn)));
+        let dead = Point { measured: [MilliVolts(35_100), MilliVolts(12_100), MilliVolts(36_000), MilliVolts(36_000)], ..high };
+        assert_eq!(two_point(&low, &dead), Err(CalibrationError::OutOfRange(Channel::VA)));
+        assert_eq!(voltage_index(Channel::VC), Some(3));
+    }
+
+    #[test]
+    fn correction_rounds_and_saturates() {
+        assert_eq!(gain_factor(GAIN_ONE as u16), Fixed::ONE);
+        assert_eq!(correct(1_000, 10_006, MilliVolts(0)), 1_001);
+        assert_eq!(correct(-1_000, 10_006, MilliVolts(0)), -1_001);
+        assert_eq!(correct(i32::MAX, 11_000, MilliVolts(0)), i32::MAX);
+        assert_eq!(correct(i32::MAX - 5, GAIN_ONE as u16, MilliVolts(10)), i32::MAX);
+        assert_eq!(correct(i32::MIN, GAIN_ONE as u16, MilliVolts(-10)), i32::MIN);
+        assert_eq!(voltage_index(Channel::IA), None);
+    }
+
+    #[test]
+    fn current_gain() {
+        let config = Config { dead_time_ns: 1000, pwm_frequency_hz: 20_000, ..Config::defaults(&crate::board::REV1) };
+        assert_eq!(dead_time_ppm(&config), 20_000);
+        // 2 Ohm at 24 V: 50% less the 2% dead time
+        assert_eq!(expected_current(MilliVolts(24_000), 50, 20_000, 2_000), MilliAmperes(5_760));
+        assert_eq!(expected_current(MilliVolts(24_000), 1, 20_000, 2_000), MilliAmperes(0));
+        assert_eq!(duty_for(MilliAmperes(5_760), MilliVolts(24_000), 20_000, 2_000), Some(50));
+        assert_eq!(duty_for(MilliAmperes(500), MilliVolts(24_000), 20_000, 2_000), None);
+        assert_eq!(duty_for(MilliAmperes(20_000), MilliVolts(24_000), 20_000, 2_000), None);
+        assert_eq!(duty_for(MilliAmperes(5_000), MilliVolts(0), 20_000, 2_000), None);
+
+        // Reads 4% high with a little noise
+        let point = |expected: i32, measured: i32| CurrentPoint {
+            duty: 0,
+            v_in: MilliVolts(24_000),
+            expected: MilliAmperes(expected),
+            measured: MilliAmperes(measured),
+        };
+        let points = [point(1_000, 1_045), point(2_000, 2_075), point(3_000, 3_120), point(4_000, 4_160)];
+        assert_eq!(fit_gain(Phase::B, &points), Ok(9_616));
+        assert_eq!(correct(4_160, 9_616, MilliVolts(0)), 4_000);
+        let zero = [point(1_000, 0); 4];
+        assert_eq!(fit_gain(Phase::B, &zero), Err(CalibrationError::OutOfRange(Channel::IB)));
+        // Reversed current, the resistor is on the wrong phase or the shunt is mounted backwards
+        let reversed = points.map(|p| CurrentPoint { measured: MilliAmperes(-p.measured.0), ..p });
+        assert_eq!(fit_gain(Phase::B, &reversed), Err(CalibrationError::OutOfRange(Channel::IB)));
+    }
+}
@@ -0,0 +1,196 @@
+//! bxCAN bit timing and error state interpretation.
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Bitrate {
+    K125,
+    K250,
+    K500,
+    M1,
+}
+impl Bitrate {
+    pub fn from_kbps(kbps: u32) -> Option<Self> {
+        match kbps {
+            125 => Some(Bitrate::K125),
+            250 => Some(Bitrate::K250),
+            500 => Some(Bitrate::K500),
+            1000 => Some(Bitrate::M1),
+            _ => None
+        }
+    }
+
+    pub fn bps(self) -> u32 {
+        match self {
+            Bitrate::K125 => 125_000,
+            Bitrate::K250 => 250_000,
+            Bitrate::K500 => 500_000,
+            Bitrate::M1 => 1_000_000,
+        }
+    }
+}
+
+/// CAN_BTR value for the given APB1 clock in Hz, sample point at 87.5% and SJW = 1.
+/// Returns None if the bitrate can't be derived from the clock exactly.
+pub fn bit_timing(pclk_hz: u32, bitrate: Bitrate) -> Option<u32> {
+    // Time quanta per bit, more is better for resynchronisation
+    for tq in (8..=25).rev() {
+        let tq_freq = bitrate.bps() * tq;
+        if pclk_hz % tq_freq != 0 {
+            continue;
+        }
+        let brp = pclk_hz / tq_freq;
+        if brp == 0 || brp > 1024 {
+            continue;
+        }
+        let ts1 = tq * 7 / 8 - 1;
+        let ts2 = tq - 1 - ts1;
+        if ts1 > 16 || ts2 > 8 {
+            continue;
+        }
+        return Some(((ts2 - 1) << 20) | ((ts1 - 1) << 16) | (brp - 1));
+    }
+    None
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum LastErrorCode {
+    NoError,
+    Stuff,
+    Form,
+    Acknowledgment,
+    BitRecessive,
+    BitDominant,
+    Crc,
+    SetBySoftware,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ErrorStatus {
+    pub tec: u8,
+    pub rec: u8,
+    pub warning: bool,
+    pub passive: bool,
+    pub bus_off: bool,
+    pub last_error: LastErrorCode,
+}
+
+/// Decode CAN_ESR register.
+pub fn decode_esr(esr: u32) -> ErrorStatus {
+    let last_error = match (esr >> 4) & 0b111 {
+        0 => LastErrorCode::NoError,
+        1 => LastErrorCode::Stuff,
+        2 => LastErrorCode::Form,
+        3 => LastErrorCode::Acknowledgment,
+        4 => LastErrorCode::BitRecessive,
+        5 => LastErrorCode::BitDominant,
+        6 => LastErrorCode::Crc,
+        _ => LastErrorCode::SetBySoftware,
+    };
+    ErrorStatus {
+        tec: (esr >> 16) as u8,
+        rec: (esr >> 24) as u8,
+        warning: esr & 0b001 != 0,
+        passive: esr & 0b010 != 0,
+        bus_off: esr & 0b100 != 0,
+        last_error,
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BusDiagnosis {
+    /// Frame was acknowledged by another node
+    Ok,
+    /// Own frame was read back correctly, but nobody acknowledged it: transceiver works, no other node
+    NoOtherNode,
+    /// Bits read back differ from the transmitted ones: transceiver, termination or wiring fault
+    TransceiverFault,
+    BusOff,
+    /// Frame was not transmitted and no error recorded, e.g. bus stuck dominant
+    NoResponse,
+}
+
+/// Interpret the result of a single frame sent in normal mode.
+pub fn diagnose_bus(transmitted: bool, status: &ErrorStatus) -> BusDiagnosis {
+    if status.bus_off {
+        return BusDiagnosis::BusOff;
+    }
+    if transmitted {
+        return BusDiagnosis::Ok;
+    }
+    match status.last_error {
+        LastErrorCode::Acknowledgment => BusDiagnosis::NoOtherNode,
+        LastErrorCode::NoError | LastErrorCode::SetBySoftware => BusDiagnosis::NoResponse,
+        _ => BusDiagnosis::TransceiverFault,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(btr: u32) -> (u32, u32, u32) {
+        let brp = (btr & 0x3FF) + 1;
+        let ts1 = ((btr >> 16) & 0xF) + 1;
+        let ts2 = ((btr >> 20) & 0x7) + 1;
+        (brp, ts1, ts2)
+    }
+
+    #[test]
+    fn bit_timing_matches_bitrate() {
+        for &pclk in &[8_000_000, 16_000_000, 42_000_000] {
+            for &bitrate in &[Bitrate::K125, Bitrate::K250, Bitrate::K500, Bitrate::M1] {
+                let btr = match bit_timing(pclk, bitrate) {
+                    Some(btr) => btr,
+                    None => continue
+                };
+                let (brp, ts1, ts2) = fields(btr);
+                assert_eq!(pclk / (brp * (1 + ts1 + ts2)), bitrate.bps());
+                assert_eq!(btr >> 24, 0, "SJW = 1");
+            }
+        }
+    }
+
+    #[test]
+    fn bit_timing_at_8mhz() {
+        assert_eq!(fields(bit_timing(8_000_000, Bitrate::K500).unwrap()), (1, 13, 2));
+        assert_eq!(fields(bit_timing(8_000_000, Bitrate::K125).unwrap()), (4, 13, 2));
+        assert_eq!(fields(bit_timing(8_000_000, Bitrate::M1).unwrap()), (1, 6, 1));
+    }
+
+    #[test]
+    fn bit_timing_unreachable() {
+        assert!(bit_timing(7_000_000, Bitrate::M1).is_none());
+    }
+
+    #[test]
+    fn bitrate_from_kbps() {
+        assert_eq!(Bitrate::from_kbps(250), Some(Bitrate::K250));
+        assert_eq!(Bitrate::from_kbps(100), None);
+    }
+
+    #[test]
+    fn esr_fields() {
+        let status = decode_esr(0x7F80_0037);
+        assert_eq!(status.rec, 0x7F);
+        assert_eq!(status.tec, 0x80);
+        assert!(status.warning);
+        assert!(status.passive);
+        assert!(status.bus_off);
+        assert_eq!(status.last_error, LastErrorCode::Acknowledgment);
+
+        let status = decode_esr(0);
+        assert_eq!((status.tec, status.rec), (0, 0));
+        assert!(!status.warning && !status.passive && !status.bus_off);
+        assert_eq!(status.last_error, LastErrorCode::NoError);
+    }
+
+    #[test]
+    fn bus_diagnosis() {
+        let ack_error = decode_esr(0x0008_0031);
+        assert_eq!(diagnose_bus(false, &ack_error), BusDiagnosis::NoOtherNode);
+        let bit_error = decode_esr(0x0008_0051);
+        assert_eq!(diagnose_bus(false, &bit_error), BusDiagnosis::TransceiverFault);
+        assert_eq!(diagnose_bus(false, &decode_esr(0)), BusDiagnosis::NoResponse);
+        assert_eq!(diagnose_bus(true, &decode_esr(0)), BusDiagnosis::Ok);
+        assert_eq!(diagnose_bus(true, &decode_esr(0x00FF_0004)), BusDiagnosis::BusOff);
+    }
+}
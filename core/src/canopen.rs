@@ -0,0 +1,619 @@
+//! CANopen-like node: SDO server, TPDOs and NMT state over an application object dictionary.
+//!
+//! Doesn't touch the CAN peripheral, frames are passed in and out as (COB-ID, data).
+//! Only expedited download is supported, upload is expedited or segmented.
+
+pub const NMT_ID: u16 = 0x000;
+pub const SDO_TX_BASE: u16 = 0x580;
+pub const SDO_RX_BASE: u16 = 0x600;
+pub const BOOT_UP_BASE: u16 = 0x700;
+pub const TPDO_COUNT: usize = 2;
+const TPDO_BASE: [u16; TPDO_COUNT] = [0x180, 0x280];
+const TPDO_MAX_MAPPED: usize = 4;
+/// COB-ID bit 31: PDO does not exist / is not valid
+const COB_ID_INVALID: u32 = 1 << 31;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum Abort {
+    Toggle = 0x0503_0000,
+    CommandSpecifier = 0x0504_0001,
+    ReadOnly = 0x0601_0002,
+    ObjectDoesNotExist = 0x0602_0000,
+    NotMappable = 0x0604_0041,
+    MappingLength = 0x0604_0042,
+    LengthMismatch = 0x0607_0010,
+    SubindexDoesNotExist = 0x0609_0011,
+    ValueRange = 0x0609_0030,
+    DeviceState = 0x0800_0022,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DataType {
+    U8,
+    U16,
+    U32,
+    I32,
+    VisibleString,
+}
+impl DataType {
+    /// Size in bytes, None for variable length types.
+    pub fn size(self) -> Option<usize> {
+        match self {
+            DataType::U8 => Some(1),
+            DataType::U16 => Some(2),
+            DataType::U32 | DataType::I32 => Some(4),
+            DataType::VisibleString => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Access {
+    Ro,
+    Rw,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Entry {
+    pub index: u16,
+    pub sub: u8,
+    pub data_type: DataType,
+    pub access: Access,
+    pub name: &'static str,
+}
+
+/// Application objects, numeric values are passed as raw u32 (i32 reinterpreted).
+/// Access rights and lengths are checked by the node before calling read/write.
+pub trait ObjectDictionary {
+    fn entries(&self) -> &'static [Entry];
+    fn read(&mut self, index: u16, sub: u8) -> Result<u32, Abort>;
+    fn write(&mut self, index: u16, sub: u8, value: u32) -> Result<(), Abort>;
+    fn read_string(&mut self, _index: u16, _sub: u8) -> Result<&'static [u8], Abort> {
+        Err(Abort::ObjectDoesNotExist)
+    }
+}
+
+pub fn find_entry(entries: &[Entry], index: u16, sub: u8) -> Result<&Entry, Abort> {
+    if !entries.iter().any(|e| e.index == index) {
+        return Err(Abort::ObjectDoesNotExist);
+    }
+    entries.iter().find(|e| e.index == index && e.sub == sub).ok_or(Abort::SubindexDoesNotExist)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum NmtState {
+    PreOperational,
+    Operational,
+    Stopped,
+}
+
+#[derive(Copy, Clone)]
+pub struct Tpdo {
+    pub cob_id: u32,
+    pub event_timer_ms: u16,
+    elapsed_ms: u16,
+    /// index << 16 | sub << 8 | length in bits
+    mapping: [u32; TPDO_MAX_MAPPED],
+    mapped: u8,
+}
+impl Tpdo {
+    fn new(node_id: u8, n: usize) -> Self {
+        Tpdo {
+            cob_id: (TPDO_BASE[n] + node_id as u16) as u32 | COB_ID_INVALID,
+            event_timer_ms: 0,
+            elapsed_ms: 0,
+            mapping: [0; TPDO_MAX_MAPPED],
+            mapped: 0,
+        }
+    }
+
+    pub fn mapping(&self) -> &[u32] {
+        &self.mapping[..self.mapped as usize]
+    }
+}
+
+enum SdoState {
+    Idle,
+    Upload {
+        index: u16,
+        sub: u8,
+        data: &'static [u8],
+        offset: usize,
+        toggle: bool,
+    },
+}
+
+pub struct Node {
+    pub node_id: u8,
+    pub nmt: NmtState,
+    pub tpdo: [Tpdo; TPDO_COUNT],
+    sdo: SdoState,
+}
+
+/// Entries handled by the node itself: TPDO communication and mapping parameters.
+const COMM_ENTRIES: &[Entry] = &[
+    Entry { index: 0x1800, sub: 1, data_type: DataType::U32, access: Access::Rw, name: "TPDO1 COB-ID" },
+    Entry { index: 0x1800, sub: 5, data_type: DataType::U16, access: Access::Rw, name: "TPDO1 event timer" },
+    Entry { index: 0x1801, sub: 1, data_type: DataType::U32, access: Access::Rw, name: "TPDO2 COB-ID" },
+    Entry { index: 0x1801, sub: 5, data_type: DataType::U16, access: Access::Rw, name: "TPDO2 event timer" },
+    Entry { index: 0x1A00, sub: 0, data_type: DataType::U8, access: Access::Rw, name: "TPDO1 mapped count" },
+    Entry { index: 0x1A00, sub: 1, data_type: DataType::U32, access: Access::Rw, name: "TPDO1 mapping 1" },
+    Entry { index: 0x1A00, sub: 2, data_type: DataType::U32, access: Access::Rw, name: "TPDO1 mapping 2" },
+    Entry { index: 0x1A00, sub: 3, data_type: DataType::U32, access: Access::Rw, name: "TPDO1 mapping 3" },
+    Entry { index: 0x1A00, sub: 4, data_type: DataType::U32, access: Access::Rw, name: "TPDO1 mapping 4" },
+    Entry { index: 0x1A01, sub: 0, data_type: DataType::U8, access: Access::Rw, name: "TPDO2 mapped count" },
+    Entry { index: 0x1A01, sub: 1, data_type: DataType::U32, access: Access::Rw, name: "TPDO2 mapping 1" },
+    Entry { index: 0x1A01, sub: 2, data_type: DataType::U32, access: Access::Rw, name: "TPDO2 mapping 2" },
+    Entry { index: 0x1A01, sub: 3, data_type: DataType::U32, access: Access::Rw, name: "TPDO2 mapping 3" },
+    Entry { index: 0x1A01, sub: 4, data_type: DataType::U32, access: Access::Rw, name: "TPDO2 mapping 4" },
+];
+
+fn sdo_abort(index: u16, sub: u8, abort: Abort) -> [u8; 8] {
+    let mut response = [0x80, 0, 0, sub, 0, 0, 0, 0];
+    response[1..3].copy_from_slice(&index.to_le_bytes());
+    response[4..8].copy_from_slice(&(abort as u32).to_le_bytes());
+    response
+}
+
+fn sdo_header(cs: u8, index: u16, sub: u8) -> [u8; 8] {
+    let mut response = [cs, 0, 0, sub, 0, 0, 0, 0];
+    response[1..3].copy_from_slice(&index.to_le_bytes());
+    response
+}
+
+impl Node {
+    pub fn new(node_id: u8) -> Self {
+        Node {
+            node_id,
+            nmt: NmtState::PreOperational,
+            tpdo: [Tpdo::new(node_id, 0), Tpdo::new(node_id, 1)],
+            sdo: SdoState::Idle,
+        }
+    }
+
+    pub fn sdo_rx_id(&self) -> u16 {
+        SDO_RX_BASE + self.node_id as u16
+    }
+
+    pub fn sdo_tx_id(&self) -> u16 {
+        SDO_TX_BASE + self.node_id as u16
+    }
+
+    /// Sent once after (re)initialisation.
+    pub fn boot_up_id(&self) -> u16 {
+        BOOT_UP_BASE + self.node_id as u16
+    }
+
+    /// Returns true if the command resets the communication parameters.
+    pub fn handle_nmt(&mut self, data: &[u8]) -> bool {
+        if data.len() < 2 || (data[1] != 0 && data[1] != self.node_id) {
+            return false;
+        }
+        match data[0] {
+            0x01 => self.nmt = NmtState::Operational,
+            0x02 => self.nmt = NmtState::Stopped,
+            0x80 => self.nmt = NmtState::PreOperational,
+            0x81 | 0x82 => {
+                *self = Node::new(self.node_id);
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+
+    /// Process SDO request addressed to this node, returns response to be sent on `sdo_tx_id`.
+    pub fn handle_sdo<OD: ObjectDictionary>(&mut self, od: &mut OD, request: &[u8]) -> Option<[u8; 8]> {
+        if request.len() != 8 || self.nmt == NmtState::Stopped {
+            return None;
+        }
+        let index = u16::from_le_bytes([request[1], request[2]]);
+        let sub = request[3];
+        let ccs = request[0] >> 5;
+        match ccs {
+            // Initiate download
+            1 => {
+                self.sdo = SdoState::Idle;
+                let expedited = request[0] & 0b10 != 0;
+                let size_indicated = request[0] & 0b01 != 0;
+                if !expedited {
+                    return Some(sdo_abort(index, sub, Abort::CommandSpecifier));
+                }
+                let size = if size_indicated {
+                    Some(4 - ((request[0] >> 2) & 0b11) as usize)
+                } else {
+                    None
+                };
+                let value = u32::from_le_bytes([request[4], request[5], request[6], request[7]]);
+                match self.download(od, index, sub, value, size) {
+                    Ok(()) => Some(sdo_header(0x60, index, sub)),
+                    Err(abort) => Some(sdo_abort(index, sub, abort)),
+                }
+            }
+            // Initiate upload
+            2 => {
+                self.sdo = SdoState::Idle;
+                match self.upload(od, index, sub) {
+                    Ok(response) => Some(response),
+                    Err(abort) => Some(sdo_abort(index, sub, abort)),
+                }
+            }
+            // Upload segment
+            3 => {
+                let toggle = request[0] & 0x10 != 0;
+                match &mut self.sdo {
+                    SdoState::Upload { index, sub, data, offset, toggle: expected } => {
+                        if toggle != *expected {
+                            let abort = sdo_abort(*index, *sub, Abort::Toggle);
+                            self.sdo = SdoState::Idle;
+                            return Some(abort);
+                        }
+                        let chunk = &data[*offset..(*offset + 7).min(data.len())];
+                        let mut response = [0u8; 8];
+                        response[1..1 + chunk.len()].copy_from_slice(chunk);
+                        *offset += chunk.len();
+                        *expected = !*expected;
+                        let last = *offset >= data.len();
+                        response[0] = ((toggle as u8) << 4) | (((7 - chunk.len()) as u8) << 1) | last as u8;
+                        if last {
+                            self.sdo = SdoState::Idle;
+                        }
+                        Some(response)
+                    }
+                    SdoState::Idle => Some(sdo_abort(0, 0, Abort::CommandSpecifier)),
+                }
+            }
+            // Abort transfer
+            4 => {
+                self.sdo = SdoState::Idle;
+                None
+            }
+            _ => Some(sdo_abort(index, sub, Abort::CommandSpecifier)),
+        }
+    }
+
+    fn download<OD: ObjectDictionary>(&mut self, od: &mut OD, index: u16, sub: u8, value: u32, size: Option<usize>) -> Result<(), Abort> {
+        let entry = match find_entry(COMM_ENTRIES, index, sub) {
+            Ok(entry) => entry,
+            Err(_) => find_entry(od.entries(), index, sub)?,
+        };
+        if entry.access == Access::Ro {
+            return Err(Abort::ReadOnly);
+        }
+        let type_size = entry.data_type.size().ok_or(Abort::LengthMismatch)?;
+        if let Some(size) = size {
+            if size != type_size {
+                return Err(Abort::LengthMismatch);
+            }
+        }
+        let value = match type_size {
+            1 => value & 0xFF,
+            2 => value & 0xFFFF,
+            _ => value,
+        };
+        match index {
+            0x1800..=0x1801 => {
+                let tpdo = &mut self.tpdo[(index - 0x1800) as usize];
+                match sub {
+                    1 => tpdo.cob_id = value,
+                    _ => tpdo.event_timer_ms = value as u16,
+                }
+                tpdo.elapsed_ms = 0;
+                Ok(())
+            }
+            0x1A00..=0x1A01 => {
+                let n = (index - 0x1A00) as usize;
+                if sub == 0 {
+                    self.set_mapped_count(od, n, value as usize)
+                } else {
+                    // Mapping can only be changed while disabled
+                    if self.tpdo[n].mapped != 0 {
+                        return Err(Abort::DeviceState);
+                    }
+                    self.tpdo[n].mapping[sub as usize - 1] = value;
+                    Ok(())
+                }
+            }
+            _ => od.write(index, sub, value),
+        }
+    }
+
+    fn set_mapped_count<OD: ObjectDictionary>(&mut self, od: &mut OD, n: usize, count: usize) -> Result<(), Abort> {
+        if count > TPDO_MAX_MAPPED {
+            return Err(Abort::ValueRange);
+        }
+        let mut bits = 0;
+        for &mapping in &self.tpdo[n].mapping[..count] {
+            let entry = find_entry(od.entries(), (mapping >> 16) as u16, (mapping >> 8) as u8)
+                .map_err(|_| Abort::NotMappable)?;
+            let size = entry.data_type.size().ok_or(Abort::NotMappable)?;
+            if (mapping & 0xFF) as usize != size * 8 {
+                return Err(Abort::NotMappable);
+            }
+            bits += size * 8;
+        }
+        if bits > 64 {
+            return Err(Abort::MappingLength);
+        }
+        self.tpdo[n].mapped = count as u8;
+        Ok(())
+    }
+
+    fn read_comm(&self, index: u16, sub: u8) -> u32 {
+        match index {
+            0x1800..=0x1801 => {
+                let tpdo = &self.tpdo[(index - 0x1800) as usize];
+                match sub {
+                    1 => tpdo.cob_id,
+                    _ => tpdo.event_timer_ms as u32,
+                }
+            }
+            _ => {
+                let tpdo = &self.tpdo[(index - 0x1A00) as usize];
+                match sub {
+                    0 => tpdo.mapped as u32,
+                    _ => tpdo.mapping[sub as usize - 1],
+                }
+            }
+        }
+    }
+
+    fn upload<OD: ObjectDictionary>(&mut self, od: &mut OD, index: u16, sub: u8) -> Result<[u8; 8], Abort> {
+        if find_entry(COMM_ENTRIES, index, sub).is_ok() {
+            let mut response = sdo_header(0x43, index, sub);
+            response[4..8].copy_from_slice(&self.read_comm(index, sub).to_le_bytes());
+            return Ok(response);
+        }
+        let entry = find_entry(od.entries(), index, sub)?;
+        match entry.data_type.size() {
+            Some(size) => {
+                let value = od.read(index, sub)?;
+                let mut response = sdo_header(0x43 | (((4 - size) as u8) << 2), index, sub);
+                response[4..4 + size].copy_from_slice(&value.to_le_bytes()[..size]);
+                Ok(response)
+            }
+            None => {
+                let data = od.read_string(index, sub)?;
+                if data.len() <= 4 {
+                    let mut response = sdo_header(0x43 | (((4 - data.len()) as u8) << 2), index, sub);
+                    response[4..4 + data.len()].copy_from_slice(data);
+                    return Ok(response);
+                }
+                let mut response = sdo_header(0x41, index, sub);
+                response[4..8].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                self.sdo = SdoState::Upload { index, sub, data, offset: 0, toggle: false };
+                Ok(response)
+            }
+        }
+    }
+
+    /// Advance TPDO event timers by `elapsed_ms` and pass due PDOs to `send` as (COB-ID, data).
+    pub fn poll_tpdos<OD: ObjectDictionary, F: FnMut(u16, &[u8])>(&mut self, od: &mut OD, elapsed_ms: u16, mut send: F) {
+        if self.nmt != NmtState::Operational {
+            return;
+        }
+        for tpdo in self.tpdo.iter_mut() {
+            if tpdo.cob_id & COB_ID_INVALID != 0 || tpdo.event_timer_ms == 0 || tpdo.mapped == 0 {
+                continue;
+            }
+            tpdo.elapsed_ms = tpdo.elapsed_ms.saturating_add(elapsed_ms);
+            if tpdo.elapsed_ms < tpdo.event_timer_ms {
+                continue;
+            }
+            tpdo.elapsed_ms = 0;
+            let mut data = [0u8; 8];
+            let mut len = 0;
+            for &mapping in tpdo.mapping() {
+                let size = (mapping & 0xFF) as usize / 8;
+                let value = od.read((mapping >> 16) as u16, (mapping >> 8) as u8).unwrap_or(0);
+                data[len..len + size].copy_from_slice(&value.to_le_bytes()[..size]);
+                len += size;
+            }
+            send((tpdo.cob_id & 0x7FF) as u16, &data[..len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRIES: &[Entry] = &[
+        Entry { index: 0x1008, sub: 0, data_type: DataType::VisibleString, access: Access::Ro, name: "name" },
+        Entry { index: 0x2000, sub: 1, data_type: DataType::U8, access: Access::Rw, name: "u8" },
+        Entry { index: 0x2000, sub: 2, data_type: DataType::U16, access: Access::Ro, name: "u16" },
+        Entry { index: 0x2001, sub: 0, data_type: DataType::I32, access: Access::Rw, name: "i32" },
+    ];
+
+    struct Od {
+        u8: u8,
+        i32: i32,
+    }
+    impl ObjectDictionary for Od {
+        fn entries(&self) -> &'static [Entry] {
+            ENTRIES
+        }
+
+        fn read(&mut self, index: u16, sub: u8) -> Result<u32, Abort> {
+            match (index, sub) {
+                (0x2000, 1) => Ok(self.u8 as u32),
+                (0x2000, 2) => Ok(0xBEEF),
+                (0x2001, 0) => Ok(self.i32 as u32),
+                _ => Err(Abort::ObjectDoesNotExist),
+            }
+        }
+
+        fn write(&mut self, index: u16, sub: u8, value: u32) -> Result<(), Abort> {
+            match (index, sub) {
+                (0x2000, 1) if value > 100 => return Err(Abort::ValueRange),
+                (0x2000, 1) => self.u8 = value as u8,
+                (0x2001, 0) => self.i32 = value as i32,
+                _ => return Err(Abort::ObjectDoesNotExist),
+            }
+            Ok(())
+        }
+
+        fn read_string(&mut self, _index: u16, _sub: u8) -> Result<&'static [u8], Abort> {
+            Ok(b"power-stage-tester")
+        }
+    }
+
+    fn od() -> Od {
+        Od { u8: 7, i32: -5 }
+    }
+
+    fn upload(index: u16, sub: u8) -> [u8; 8] {
+        let i = index.to_le_bytes();
+        [0x40, i[0], i[1], sub, 0, 0, 0, 0]
+    }
+
+    fn download(index: u16, sub: u8, size: usize, value: u32) -> [u8; 8] {
+        let i = index.to_le_bytes();
+        let v = value.to_le_bytes();
+        [0x23 | (((4 - size) as u8) << 2), i[0], i[1], sub, v[0], v[1], v[2], v[3]]
+    }
+
+    fn abort_code(response: &[u8; 8]) -> Option<u32> {
+        if response[0] == 0x80 {
+            Some(u32::from_le_bytes([response[4], response[5], response[6], response[7]]))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn expedited_upload() {
+        let mut node = Node::new(3);
+        let mut od = od();
+        let response = node.handle_sdo(&mut od, &upload(0x2000, 1)).unwrap();
+        assert_eq!(response, [0x4F, 0x00, 0x20, 1, 7, 0, 0, 0]);
+        let response = node.handle_sdo(&mut od, &upload(0x2000, 2)).unwrap();
+        assert_eq!(response, [0x4B, 0x00, 0x20, 2, 0xEF, 0xBE, 0, 0]);
+        let response = node.handle_sdo(&mut od, &upload(0x2001, 0)).unwrap();
+        assert_eq!(response, [0x43, 0x01, 0x20, 0, 0xFB, 0xFF, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn expedited_download() {
+        let mut node = Node::new(3);
+        let mut od = od();
+        let response = node.handle_sdo(&mut od, &download(0x2000, 1, 1, 42)).unwrap();
+        assert_eq!(response, [0x60, 0x00, 0x20, 1, 0, 0, 0, 0]);
+        assert_eq!(od.u8, 42);
+        node.handle_sdo(&mut od, &download(0x2001, 0, 4, -100i32 as u32)).unwrap();
+        assert_eq!(od.i32, -100);
+    }
+
+    #[test]
+    fn download_aborts() {
+        let mut node = Node::new(3);
+        let mut od = od();
+        let response = node.handle_sdo(&mut od, &download(0x2000, 2, 2, 1)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::ReadOnly as u32));
+        let response = node.handle_sdo(&mut od, &download(0x2000, 1, 2, 1)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::LengthMismatch as u32));
+        let response = node.handle_sdo(&mut od, &download(0x2000, 1, 1, 101)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::ValueRange as u32));
+        let response = node.handle_sdo(&mut od, &download(0x3000, 0, 1, 1)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::ObjectDoesNotExist as u32));
+        let response = node.handle_sdo(&mut od, &download(0x2000, 9, 1, 1)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::SubindexDoesNotExist as u32));
+        assert_eq!(od.u8, 7);
+    }
+
+    #[test]
+    fn segmented_upload() {
+        let mut node = Node::new(3);
+        let mut od = od();
+        let response = node.handle_sdo(&mut od, &upload(0x1008, 0)).unwrap();
+        assert_eq!(response[0], 0x41);
+        assert_eq!(u32::from_le_bytes([response[4], response[5], response[6], response[7]]), 18);
+
+        let mut received = Vec::new();
+        let mut toggle = 0;
+        loop {
+            let response = node.handle_sdo(&mut od, &[0x60 | toggle, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+            assert_eq!(response[0] & 0x10, toggle);
+            let unused = ((response[0] >> 1) & 0b111) as usize;
+            received.extend_from_slice(&response[1..8 - unused]);
+            if response[0] & 1 != 0 {
+                break;
+            }
+            toggle ^= 0x10;
+        }
+        assert_eq!(received, b"power-stage-tester");
+    }
+
+    #[test]
+    fn segmented_upload_toggle_error() {
+        let mut node = Node::new(3);
+        let mut od = od();
+        node.handle_sdo(&mut od, &upload(0x1008, 0)).unwrap();
+        let response = node.handle_sdo(&mut od, &[0x70, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::Toggle as u32));
+    }
+
+    #[test]
+    fn nmt() {
+        let mut node = Node::new(3);
+        assert_eq!(node.nmt, NmtState::PreOperational);
+        node.handle_nmt(&[0x01, 4]);
+        assert_eq!(node.nmt, NmtState::PreOperational);
+        node.handle_nmt(&[0x01, 3]);
+        assert_eq!(node.nmt, NmtState::Operational);
+        node.handle_nmt(&[0x02, 0]);
+        assert_eq!(node.nmt, NmtState::Stopped);
+        assert!(node.handle_sdo(&mut od(), &upload(0x2000, 1)).is_none());
+        assert!(node.handle_nmt(&[0x81, 0]));
+        assert_eq!(node.nmt, NmtState::PreOperational);
+    }
+
+    fn map_tpdo1(node: &mut Node, od: &mut Od) {
+        node.handle_sdo(od, &download(0x1A00, 1, 4, 0x2000_0108)).unwrap();
+        node.handle_sdo(od, &download(0x1A00, 2, 4, 0x2001_0020)).unwrap();
+        let response = node.handle_sdo(od, &download(0x1A00, 0, 1, 2)).unwrap();
+        assert_eq!(response[0], 0x60);
+        node.handle_sdo(od, &download(0x1800, 5, 2, 100)).unwrap();
+        node.handle_sdo(od, &download(0x1800, 1, 4, 0x183)).unwrap();
+    }
+
+    #[test]
+    fn tpdo_mapping_and_timer() {
+        let mut node = Node::new(3);
+        let mut od = od();
+        map_tpdo1(&mut node, &mut od);
+
+        let mut sent = Vec::new();
+        node.poll_tpdos(&mut od, 100, |id, data| sent.push((id, data.to_vec())));
+        assert!(sent.is_empty(), "not operational");
+
+        node.handle_nmt(&[0x01, 0]);
+        node.poll_tpdos(&mut od, 50, |id, data| sent.push((id, data.to_vec())));
+        assert!(sent.is_empty());
+        node.poll_tpdos(&mut od, 50, |id, data| sent.push((id, data.to_vec())));
+        assert_eq!(sent, vec![(0x183, vec![7, 0xFB, 0xFF, 0xFF, 0xFF])]);
+    }
+
+    #[test]
+    fn tpdo_mapping_validation() {
+        let mut node = Node::new(3);
+        let mut od = od();
+        // wrong length
+        node.handle_sdo(&mut od, &download(0x1A00, 1, 4, 0x2000_0110)).unwrap();
+        let response = node.handle_sdo(&mut od, &download(0x1A00, 0, 1, 1)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::NotMappable as u32));
+        // strings can't be mapped
+        node.handle_sdo(&mut od, &download(0x1A00, 1, 4, 0x1008_0008)).unwrap();
+        let response = node.handle_sdo(&mut od, &download(0x1A00, 0, 1, 1)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::NotMappable as u32));
+        let response = node.handle_sdo(&mut od, &download(0x1A00, 0, 1, 5)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::ValueRange as u32));
+
+        map_tpdo1(&mut node, &mut od);
+        let response = node.handle_sdo(&mut od, &download(0x1A00, 1, 4, 0x2000_0108)).unwrap();
+        assert_eq!(abort_code(&response), Some(Abort::DeviceState as u32));
+        let response = node.handle_sdo(&mut od, &upload(0x1A00, 2)).unwrap();
+        assert_eq!(response[4..8], 0x2001_0020u32.to_le_bytes());
+    }
+}
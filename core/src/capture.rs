@@ -0,0 +1,495 @@
+//! Triggered waveform capture into a ring buffer, fed one sample at a time from the ADC interrupt.
+//!
+//! While armed every recorded sample goes into the ring. Once it holds `pretrigger` samples the
+//! trigger is checked, after it fires the ring is filled up with the rest of the record and the
+//! capture stops until it's armed again.
+
+use crate::stage::{Channel, Phase};
+use core::fmt;
+use core::ops::Range;
+
+/// Inputs converted per sample, ADC injected sequences are up to 4 long.
+pub const MAX_SOURCES: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Source {
+    /// mV or mA.
+    Channel(Channel),
+    /// Percent.
+    Duty(Phase),
+    HallIndex,
+}
+
+/// Names used by the CLI and in the CSV header.
+const SOURCES: &[(&str, Source)] = &[
+    ("ia", Source::Channel(Channel::IA)),
+    ("ib", Source::Channel(Channel::IB)),
+    ("ic", Source::Channel(Channel::IC)),
+    ("va", Source::Channel(Channel::VA)),
+    ("vb", Source::Channel(Channel::VB)),
+    ("vc", Source::Channel(Channel::VC)),
+    ("vin", Source::Channel(Channel::VIn)),
+    ("vcan", Source::Channel(Channel::VCan)),
+    ("duty_a", Source::Duty(Phase::A)),
+    ("duty_b", Source::Duty(Phase::B)),
+    ("duty_c", Source::Duty(Phase::C)),
+    ("hall", Source::HallIndex),
+];
+
+/// Source names in the order of `SOURCES`, for CLI choices.
+pub const SOURCE_NAMES: &[&str] = &["ia", "ib", "ic", "va", "vb", "vc", "vin", "vcan", "duty_a", "duty_b", "duty_c", "hall"];
+
+impl Source {
+    pub fn from_name(name: &str) -> Option<Source> {
+        SOURCES.iter().find(|(n, _)| *n == name).map(|(_, s)| *s)
+    }
+
+    pub fn name(self) -> &'static str {
+        SOURCES.iter().find(|(_, s)| *s == self).map(|(n, _)| *n).unwrap_or("?")
+    }
+
+    /// ADC channel the source needs converted.
+    pub fn channel(self) -> Option<Channel> {
+        match self {
+            Source::Channel(channel) => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Edge {
+    Rising,
+    Falling,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    /// As soon as the pre-trigger part is recorded.
+    Now,
+    /// `source` crosses `level` between two recorded samples.
+    Level { source: Source, edge: Edge, level: i32 },
+    /// Gate driver fault input.
+    Fault,
+    /// Next command that isn't a capture one, to record a step response.
+    Command,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Settings {
+    pub sources: [Source; MAX_SOURCES],
+    pub source_count: usize,
+    /// Record every n-th sample, 1 - all.
+    pub decimation: u16,
+    pub trigger: Trigger,
+    pub pretrigger: usize,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SettingsError {
+    NoSources,
+    /// Sources and the trigger need more ADC inputs than a sample converts.
+    TooManyInputs,
+    /// Pre-trigger part doesn't leave room for the trigger sample.
+    Pretrigger,
+}
+
+impl Settings {
+    pub fn sources(&self) -> &[Source] {
+        &self.sources[..self.source_count]
+    }
+
+    /// Distinct ADC channels to convert per sample, the trigger source included.
+    pub fn adc_channels(&self) -> Result<([Channel; MAX_SOURCES], usize), SettingsError> {
+        let mut channels = [Channel::VIn; MAX_SOURCES];
+        let mut count = 0;
+        let trigger = match self.trigger {
+            Trigger::Level { source, .. } => source.channel(),
+            _ => None,
+        };
+        for channel in self.sources().iter().filter_map(|s| s.channel()).chain(trigger) {
+            if channels[..count].contains(&channel) {
+                continue;
+            }
+            *channels.get_mut(count).ok_or(SettingsError::TooManyInputs)? = channel;
+            count += 1;
+        }
+        Ok((channels, count))
+    }
+
+    /// CSV header of a record taken with these settings.
+    pub fn write_csv_header<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        out.write_str("sample,t_ns")?;
+        for source in self.sources() {
+            write!(out, ",{}", source.name())?;
+        }
+        out.write_str("\r\n")
+    }
+
+    /// One CSV row, `n` and `values` as `Capture::samples` yields them, `period_ns` is the sample
+    /// period before decimation.
+    pub fn write_csv_row<W: fmt::Write>(&self, out: &mut W, period_ns: u32, n: i32, values: &[i32]) -> fmt::Result {
+        let period = period_ns as i64 * self.decimation.max(1) as i64;
+        write!(out, "{},{}", n, n as i64 * period)?;
+        for value in values {
+            write!(out, ",{}", value)?;
+        }
+        out.write_str("\r\n")
+    }
+
+    /// Whether a record of `depth` samples can be taken.
+    pub fn check(&self, depth: usize) -> Result<(), SettingsError> {
+        if self.source_count == 0 || self.source_count > MAX_SOURCES {
+            return Err(SettingsError::NoSources);
+        }
+        if self.pretrigger >= depth {
+            return Err(SettingsError::Pretrigger);
+        }
+        self.adc_channels().map(|_| ())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum State {
+    Idle,
+    /// Recording, waiting for the trigger.
+    Armed,
+    /// Recording the part after the trigger.
+    Triggered,
+    Done,
+}
+
+pub struct Capture<const DEPTH: usize> {
+    settings: Settings,
+    state: State,
+    buf: [[i32; MAX_SOURCES]; DEPTH],
+    /// Next slot to write, the oldest sample once the ring is full.
+    write: usize,
+    recorded: usize,
+    /// Samples still to record after the trigger.
+    remaining: usize,
+    skip: u16,
+    previous: Option<i32>,
+    command: bool,
+}
+
+impl<const DEPTH: usize> Capture<DEPTH> {
+    pub const fn new() -> Self {
+        Capture {
+            settings: Settings {
+                sources: [Source::HallIndex; MAX_SOURCES],
+                source_count: 0,
+                decimation: 1,
+                trigger: Trigger::Now,
+                pretrigger: 0,
+            },
+            state: State::Idle,
+            buf: [[0; MAX_SOURCES]; DEPTH],
+            write: 0,
+            recorded: 0,
+            remaining: 0,
+            skip: 0,
+            previous: None,
+            command: false,
+        }
+    }
+
+    /// Drops the previous record and starts recording.
+    pub fn arm(&mut self, settings: Settings) -> Result<(), SettingsError> {
+        settings.check(DEPTH)?;
+        self.settings = settings;
+        self.state = State::Armed;
+        self.write = 0;
+        self.recorded = 0;
+        self.remaining = 0;
+        self.skip = 0;
+        self.previous = None;
+        self.command = false;
+        Ok(())
+    }
+
+    /// Stops recording, what was recorded is dropped.
+    pub fn stop(&mut self) {
+        self.state = State::Idle;
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Samples recorded so far, up to `DEPTH`.
+    pub fn recorded(&self) -> usize {
+        self.recorded
+    }
+
+    /// A command started, fires the `Command` trigger.
+    pub fn command_started(&mut self) {
+        if self.state == State::Armed && self.settings.trigger == Trigger::Command {
+            self.command = true;
+        }
+    }
+
+    /// Called at the sample rate, `read` returns the value of a source in this sample.
+    pub fn push(&mut self, mut read: impl FnMut(Source) -> i32, fault: bool) {
+        if self.state != State::Armed && self.state != State::Triggered {
+            return;
+        }
+        if self.skip > 0 {
+            self.skip -= 1;
+            return;
+        }
+        self.skip = self.settings.decimation.max(1) - 1;
+
+        let slot = &mut self.buf[self.write];
+        for (value, source) in slot.iter_mut().zip(self.settings.sources()) {
+            *value = read(*source);
+        }
+        self.write = (self.write + 1) % DEPTH;
+        self.recorded = (self.recorded + 1).min(DEPTH);
+
+        match self.state {
+            State::Armed => {
+                let fired = match self.settings.trigger {
+                    Trigger::Now => true,
+                    Trigger::Level { source, edge, level } => {
+                        let value = read(source);
+                        let previous = self.previous.replace(value);
+                        match (previous, edge) {
+                            (Some(previous), Edge::Rising) => previous < level && value >= level,
+                            (Some(previous), Edge::Falling) => previous > level && value <= level,
+                            (None, _) => false,
+                        }
+                    }
+                    Trigger::Fault => fault,
+                    Trigger::Command => self.command,
+                };
+                // The pre-trigger part has to be there before the trigger sample
+                if fired && self.recorded > self.settings.pretrigger {
+                    self.remaining = DEPTH - self.settings.pretrigger - 1;
+                    self.state = if self.remaining == 0 { State::Done } else { State::Triggered };
+                }
+            }
+            _ => {
+                self.remaining -= 1;
+                if self.remaining == 0 {
+                    self.state = State::Done;
+                }
+            }
+        }
+    }
+
+    /// Finished record oldest first, with the sample number relative to the trigger.
+    pub fn samples(&self) -> impl Iterator<Item = (i32, &[i32])> + '_ {
+        let count = if self.state == State::Done { DEPTH } else { 0 };
+        let sources = self.settings.source_count;
+        let pretrigger = self.settings.pretrigger as i32;
+        (0..count).map(move |i| (i as i32 - pretrigger, &self.buf[(self.write + i) % DEPTH][..sources]))
+    }
+
+    /// Finished record as CSV with a header, `period_ns` is the sample period before decimation.
+    pub fn write_csv<W: fmt::Write>(&self, out: &mut W, period_ns: u32) -> fmt::Result {
+        self.write_csv_header(out)?;
+        self.write_csv_lines(out, period_ns, 0..DEPTH)
+    }
+
+    pub fn write_csv_header<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        self.settings.write_csv_header(out)
+    }
+
+    /// Part of the record, for writing it out in pieces.
+    pub fn write_csv_lines<W: fmt::Write>(&self, out: &mut W, period_ns: u32, lines: Range<usize>) -> fmt::Result {
+        for (n, values) in self.samples().skip(lines.start).take(lines.len()) {
+            self.settings.write_csv_row(out, period_ns, n, values)?;
+        }
+        Ok(())
+    }
+}
+
+/// Longest CSV line a capture writes, the header or a row with `MAX_SOURCES` values.
+pub const CSV_LINE_LEN: usize = 96;
+
+/// One CSV line collected for a single write, a channel that drops writes it has no room for
+/// then loses whole lines. Writing more than `CSV_LINE_LEN` bytes fails.
+pub struct CsvLine {
+    buf: [u8; CSV_LINE_LEN],
+    len: usize,
+}
+
+impl CsvLine {
+    pub const fn new() -> Self {
+        CsvLine { buf: [0; CSV_LINE_LEN], len: 0 }
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Default for CsvLine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Write for CsvLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        self.buf.get_mut(self.len..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+impl<const DEPTH: usize> Default for Capture<DEPTH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(trigger: Trigger, pretrigger: usize) -> Settings {
+        Settings {
+            sources: [Source::Channel(Channel::IA), Source::Duty(Phase::A), Source::HallIndex, Source::HallIndex],
+            source_count: 2,
+            decimation: 1,
+            trigger,
+            pretrigger,
+        }
+    }
+
+    /// Ramp on IA, the sample number as duty.
+    fn run(capture: &mut Capture<8>, samples: core::ops::Range<i32>, fault_at: i32) {
+        for n in samples {
+            capture.push(
+                |source| match source {
+                    Source::Channel(Channel::IA) => n * 100,
+                    Source::Duty(_) => n,
+                    _ => -1,
+                },
+                n == fault_at,
+            );
+        }
+    }
+
+    #[test]
+    fn level_trigger_with_pretrigger() {
+        let mut capture = Capture::<8>::new();
+        let trigger = Trigger::Level { source: Source::Channel(Channel::IA), edge: Edge::Rising, level: 950 };
+        capture.arm(settings(trigger, 3)).unwrap();
+        run(&mut capture, 0..10, -1);
+        assert_eq!(capture.state(), State::Armed);
+        run(&mut capture, 10..11, -1);
+        assert_eq!(capture.state(), State::Triggered);
+        assert_eq!(capture.samples().count(), 0);
+        run(&mut capture, 11..15, -1);
+        assert_eq!(capture.state(), State::Done);
+        let samples: Vec<_> = capture.samples().map(|(n, v)| (n, v.to_vec())).collect();
+        assert_eq!(samples.len(), 8);
+        assert_eq!(samples[0], (-3, vec![700, 7]));
+        assert_eq!(samples[3], (0, vec![1000, 10]));
+        assert_eq!(samples[7], (4, vec![1400, 14]));
+        // Stays done
+        run(&mut capture, 15..20, -1);
+        assert_eq!(capture.samples().last().unwrap().1, &[1400, 14]);
+
+        let falling = Trigger::Level { source: Source::Channel(Channel::IA), edge: Edge::Falling, level: 950 };
+        capture.arm(settings(falling, 3)).unwrap();
+        run(&mut capture, 0..100, -1);
+        assert_eq!(capture.state(), State::Armed);
+    }
+
+    #[test]
+    fn waits_for_the_pretrigger_part() {
+        let mut capture = Capture::<8>::new();
+        capture.arm(settings(Trigger::Fault, 4)).unwrap();
+        run(&mut capture, 0..20, 2);
+        assert_eq!(capture.state(), State::Armed);
+        capture.arm(settings(Trigger::Fault, 4)).unwrap();
+        run(&mut capture, 0..20, 6);
+        assert_eq!(capture.samples().nth(4), Some((0, &[600, 6][..])));
+        assert_eq!(capture.samples().next(), Some((-4, &[200, 2][..])));
+    }
+
+    #[test]
+    fn decimation_and_command() {
+        let mut capture = Capture::<8>::new();
+        let mut s = settings(Trigger::Command, 2);
+        s.decimation = 3;
+        capture.arm(s).unwrap();
+        run(&mut capture, 0..30, -1);
+        capture.command_started();
+        run(&mut capture, 30..100, -1);
+        assert_eq!(capture.state(), State::Done);
+        let duties: Vec<_> = capture.samples().map(|(_, v)| v[1]).collect();
+        assert_eq!(duties, [24, 27, 30, 33, 36, 39, 42, 45]);
+
+        let mut csv = String::new();
+        capture.write_csv(&mut csv, 100_000).unwrap();
+        let lines: Vec<_> = csv.lines().collect();
+        assert_eq!(lines.len(), 9);
+        assert_eq!(lines[0], "sample,t_ns,ia,duty_a");
+        assert_eq!(lines[1], "-2,-600000,2400,24");
+        assert_eq!(lines[3], "0,0,3000,30");
+        let mut part = String::new();
+        capture.write_csv_lines(&mut part, 100_000, 6..20).unwrap();
+        assert_eq!(part, "4,1200000,4200,42\r\n5,1500000,4500,45\r\n");
+
+        capture.command_started();
+        capture.stop();
+        assert_eq!(capture.state(), State::Idle);
+        assert_eq!(capture.samples().count(), 0);
+    }
+
+    #[test]
+    fn longest_csv_line_fits() {
+        let mut s = settings(Trigger::Now, 0);
+        s.sources = [Source::from_name("duty_a").unwrap(); MAX_SOURCES];
+        s.source_count = MAX_SOURCES;
+        s.decimation = u16::MAX;
+        let mut capture = Capture::<1024>::new();
+        capture.arm(s).unwrap();
+        while capture.state() != State::Done {
+            capture.push(|_| i32::MIN, false);
+        }
+        let mut line = CsvLine::new();
+        capture.write_csv_header(&mut line).unwrap();
+        line.clear();
+        capture.write_csv_lines(&mut line, u32::MAX, 1023..1024).unwrap();
+        assert!(line.as_bytes().ends_with(b"\r\n"));
+    }
+
+    #[test]
+    fn settings_checks() {
+        let mut capture = Capture::<8>::new();
+        assert_eq!(capture.arm(settings(Trigger::Now, 8)), Err(SettingsError::Pretrigger));
+        let mut s = settings(Trigger::Now, 0);
+        s.source_count = 0;
+        assert_eq!(capture.arm(s), Err(SettingsError::NoSources));
+
+        let channels = ["ia", "ib", "va", "duty_b"].map(|n| Source::from_name(n).unwrap());
+        let mut s = Settings { sources: channels, source_count: 4, ..settings(Trigger::Now, 0) };
+        assert_eq!(s.adc_channels().unwrap().1, 3);
+        s.trigger = Trigger::Level { source: Source::Channel(Channel::IA), edge: Edge::Rising, level: 0 };
+        assert_eq!(s.adc_channels().unwrap().1, 3);
+        s.trigger = Trigger::Level { source: Source::Channel(Channel::VIn), edge: Edge::Rising, level: 0 };
+        assert_eq!(s.adc_channels().unwrap(), ([Channel::IA, Channel::IB, Channel::VA, Channel::VIn], 4));
+        s.sources[3] = Source::Channel(Channel::VC);
+        assert_eq!(capture.arm(s), Err(SettingsError::TooManyInputs));
+
+        for name in SOURCE_NAMES {
+            assert_eq!(Source::from_name(name).unwrap().name(), *name);
+        }
+        assert_eq!(SOURCE_NAMES.len(), SOURCES.len());
+    }
+}
@@ -0,0 +1,663 @@
+//! Command table, typed arguments and the parsers shared by the CLI command handlers.
+//!
+//! Commands are declared as data: a name of one or more words and the arguments following it.
+//! Parsing, range checks, usage strings, help and tab completion are all derived from the table,
+//! so handlers only ever see values that are already validated.
+use crate::stage::{Phase, SwitchState};
+use core::fmt;
+
+/// Most arguments a command can take. Every table checks this at build time with
+/// `const _: () = assert!(fits(TABLE));`, dispatch has no room for more.
+pub const MAX_ARGS: usize = 5;
+
+/// Whether `args` fit into `MAX_ARGS`.
+pub const fn args_fit(args: &[Arg]) -> bool {
+    args.len() <= MAX_ARGS
+}
+
+/// Whether no command of `table` takes more than `MAX_ARGS` arguments.
+pub const fn fits<H>(table: &[Command<H>]) -> bool {
+    let mut i = 0;
+    while i < table.len() {
+        if !args_fit(table[i].args) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Unit {
+    None,
+    MilliVolts,
+    MilliAmperes,
+    Hertz,
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Degrees,
+    Percent,
+}
+
+impl Unit {
+    /// Suffixes accepted after a number and what they multiply it by, the first one is the base unit.
+    pub fn suffixes(self) -> &'static [(&'static str, i64)] {
+        match self {
+            Unit::None => &[("", 1)],
+            Unit::MilliVolts => &[("mV", 1), ("V", 1000)],
+            Unit::MilliAmperes => &[("mA", 1), ("A", 1000)],
+            Unit::Hertz => &[("Hz", 1), ("kHz", 1000)],
+            Unit::Nanoseconds => &[("ns", 1), ("us", 1000)],
+            Unit::Microseconds => &[("us", 1), ("ms", 1000)],
+            Unit::Milliseconds => &[("ms", 1), ("s", 1000)],
+            Unit::Degrees => &[("deg", 1)],
+            Unit::Percent => &[("%", 1)],
+        }
+    }
+
+    pub fn suffix(self) -> &'static str {
+        self.suffixes()[0].0
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// One of the words, parsed into its index.
+    Choice(&'static [&'static str]),
+    /// Number in the base unit or with one of the unit suffixes, decimals allowed if the result is whole.
+    Int { min: i32, max: i32, unit: Unit },
+    /// Anything, checked by the handler.
+    Word,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: Kind,
+    /// Only trailing arguments can be optional.
+    pub optional: bool,
+}
+
+impl Arg {
+    pub const fn choice(name: &'static str, choices: &'static [&'static str]) -> Self {
+        Arg { name, kind: Kind::Choice(choices), optional: false }
+    }
+
+    pub const fn int(name: &'static str, min: i32, max: i32, unit: Unit) -> Self {
+        Arg { name, kind: Kind::Int { min, max, unit }, optional: false }
+    }
+
+    pub const fn word(name: &'static str) -> Self {
+        Arg { name, kind: Kind::Word, optional: false }
+    }
+
+    pub const fn optional(self) -> Self {
+        Arg { optional: true, ..self }
+    }
+
+    fn parse(&self, token: &str) -> Result<Value, ArgError> {
+        match self.kind {
+            Kind::Choice(choices) => {
+                choices.iter().position(|c| *c == token).map(Value::Choice).ok_or(ArgError::Invalid)
+            }
+            Kind::Int { min, max, unit } => {
+                let value = parse_quantity(token, unit).ok_or(ArgError::Invalid)?;
+                if value < min as i64 || value > max as i64 {
+                    return Err(ArgError::OutOfRange);
+                }
+                Ok(Value::Int(value as i32))
+            }
+            Kind::Word => Ok(Value::Word),
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    /// `a/b/c`, `<duty 0..100%>` or `<name>`, in brackets if optional.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.optional {
+            f.write_str("[")?;
+        }
+        match self.kind {
+            Kind::Choice(choices) => {
+                for (i, choice) in choices.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str("/")?;
+                    }
+                    f.write_str(choice)?;
+                }
+            }
+            Kind::Int { min, max, unit } => write!(f, "<{} {}..{}{}>", self.name, min, max, unit.suffix())?,
+            Kind::Word => write!(f, "<{}>", self.name)?,
+        }
+        if self.optional {
+            f.write_str("]")?;
+        }
+        Ok(())
+    }
+}
+
+enum ArgError {
+    Invalid,
+    OutOfRange,
+}
+
+/// `12`, `-5`, `1.5V` or `20kHz`, in the base unit of `unit`.
+fn parse_quantity(token: &str, unit: Unit) -> Option<i64> {
+    let number_len = token
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(token.len());
+    let (number, suffix) = token.split_at(number_len);
+    let scale = if suffix.is_empty() {
+        1
+    } else {
+        unit.suffixes().iter().find(|(s, _)| *s == suffix)?.1
+    };
+    let (negative, number) = match number.as_bytes().first()? {
+        b'-' => (true, &number[1..]),
+        b'+' => (false, &number[1..]),
+        _ => (false, number),
+    };
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    let mut value: i64 = 0;
+    let mut divider: i64 = 1;
+    for c in whole.bytes().chain(fraction.bytes()) {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        value = value.checked_mul(10)?.checked_add((c - b'0') as i64)?;
+    }
+    for _ in 0..fraction.len() {
+        divider = divider.checked_mul(10)?;
+    }
+    let value = value.checked_mul(scale)?;
+    if value % divider != 0 {
+        return None;
+    }
+    let value = value / divider;
+    Some(if negative { -value } else { value })
+}
+
+/// One line of the command table, `handler` is whatever the caller dispatches to.
+pub struct Command<H> {
+    /// One or more words, `can bitrate`.
+    pub name: &'static str,
+    pub help: &'static str,
+    pub args: &'static [Arg],
+    pub handler: H,
+}
+
+impl<H> Command<H> {
+    /// Name followed by the arguments.
+    pub fn write_usage<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        out.write_str(self.name)?;
+        for arg in self.args {
+            write!(out, " {}", arg)?;
+        }
+        Ok(())
+    }
+
+    fn words(&self) -> usize {
+        self.name.split(' ').count()
+    }
+
+    /// How many leading words of `line` match the name.
+    fn matching_words(&self, line: &str) -> usize {
+        self.name.split(' ').zip(line.split_ascii_whitespace()).take_while(|(a, b)| a == b).count()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Value {
+    /// Optional argument that wasn't given.
+    Absent,
+    Choice(usize),
+    Int(i32),
+    Word,
+}
+
+/// Parsed arguments of a command, indexed in declaration order.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Values<'a> {
+    values: [Value; MAX_ARGS],
+    tokens: [&'a str; MAX_ARGS],
+}
+
+impl<'a> Values<'a> {
+    pub fn is_present(&self, i: usize) -> bool {
+        self.values[i] != Value::Absent
+    }
+
+    /// Index into the choices of a `Choice` argument.
+    pub fn choice(&self, i: usize) -> usize {
+        match self.values[i] {
+            Value::Choice(index) => index,
+            _ => 0,
+        }
+    }
+
+    /// Value of an `Int` argument, already range checked.
+    pub fn int(&self, i: usize) -> i32 {
+        match self.values[i] {
+            Value::Int(value) => value,
+            _ => 0,
+        }
+    }
+
+    /// Argument as typed, empty if absent.
+    pub fn text(&self, i: usize) -> &'a str {
+        self.tokens[i]
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum DispatchError<'a> {
+    Empty,
+    Unknown(&'a str),
+    /// Words of a command group without the subcommand, `can`.
+    Incomplete(&'a str),
+    Missing(&'static Arg),
+    Invalid(&'a str, &'static Arg),
+    OutOfRange(&'a str, &'static Arg),
+    Extra(&'a str),
+}
+
+impl DispatchError<'_> {
+    /// Where in `line` the error is, one past the end if something is missing.
+    pub fn column(&self, line: &str) -> usize {
+        match *self {
+            DispatchError::Unknown(token)
+            | DispatchError::Invalid(token, _)
+            | DispatchError::OutOfRange(token, _)
+            | DispatchError::Extra(token) => token.as_ptr() as usize - line.as_ptr() as usize,
+            DispatchError::Empty | DispatchError::Incomplete(_) | DispatchError::Missing(_) => line.trim_end().len() + 1,
+        }
+    }
+}
+
+/// Finds the command `line` starts with and parses its arguments.
+pub fn dispatch<'t, 'l, H>(table: &'t [Command<H>], line: &'l str) -> Result<(&'t Command<H>, Values<'l>), DispatchError<'l>> {
+    if line.trim().is_empty() {
+        return Err(DispatchError::Empty);
+    }
+    let command = table.iter()
+        .filter(|c| c.matching_words(line) == c.words())
+        .max_by_key(|c| c.words());
+    let command = match command {
+        Some(command) => command,
+        None => {
+            let matched = table.iter().map(|c| c.matching_words(line)).max().unwrap_or(0);
+            return match line.split_ascii_whitespace().nth(matched) {
+                Some(word) => Err(DispatchError::Unknown(word)),
+                None => Err(DispatchError::Incomplete(line.trim())),
+            };
+        }
+    };
+    let mut words = line.split_ascii_whitespace().skip(command.words());
+    let mut values = Values { values: [Value::Absent; MAX_ARGS], tokens: [""; MAX_ARGS] };
+    for (i, arg) in command.args.iter().enumerate() {
+        let token = match words.next() {
+            Some(token) => token,
+            None if arg.optional => break,
+            None => return Err(DispatchError::Missing(arg)),
+        };
+        values.values[i] = match arg.parse(token) {
+            Ok(value) => value,
+            Err(ArgError::Invalid) => return Err(DispatchError::Invalid(token, arg)),
+            Err(ArgError::OutOfRange) => return Err(DispatchError::OutOfRange(token, arg)),
+        };
+        values.tokens[i] = token;
+    }
+    match words.next() {
+        Some(extra) => Err(DispatchError::Extra(extra)),
+        None => Ok((command, values)),
+    }
+}
+
+/// Repeats `line` and points at the word that failed to parse.
+pub fn write_error<H, W: fmt::Write>(table: &[Command<H>], line: &str, error: &DispatchError, out: &mut W) -> fmt::Result {
+    let width = match *error {
+        DispatchError::Empty => return Ok(()),
+        DispatchError::Unknown(token)
+        | DispatchError::Invalid(token, _)
+        | DispatchError::OutOfRange(token, _)
+        | DispatchError::Extra(token) => token.len(),
+        DispatchError::Incomplete(_) | DispatchError::Missing(_) => 1,
+    };
+    let column = error.column(line);
+    writeln!(out, "{}", line.trim_end())?;
+    write!(out, "{:column$}", "", column = column)?;
+    for _ in 0..width {
+        out.write_char('^')?;
+    }
+    match *error {
+        DispatchError::Empty => {}
+        DispatchError::Unknown(_) => out.write_str(" unknown command, try help")?,
+        DispatchError::Incomplete(words) => {
+            out.write_str(" expected ")?;
+            for (i, word) in next_words(table, words).enumerate() {
+                if i != 0 {
+                    out.write_str("/")?;
+                }
+                out.write_str(word)?;
+            }
+        }
+        DispatchError::Missing(arg) => write!(out, " missing {}", arg)?,
+        DispatchError::Invalid(_, arg) => write!(out, " expected {}", arg)?,
+        DispatchError::OutOfRange(_, arg) => write!(out, " out of range {}", arg)?,
+        DispatchError::Extra(_) => out.write_str(" unexpected argument")?,
+    }
+    writeln!(out)
+}
+
+/// Next name word of the commands whose names start with `words`, each once.
+pub(crate) fn next_words<'a, H>(table: &'a [Command<H>], words: &'a str) -> impl Iterator<Item = &'static str> + Clone + 'a {
+    let next = move |c: &Command<H>| -> Option<&'static str> {
+        let mut name = c.name.split(' ');
+        for word in words.split_ascii_whitespace() {
+            if name.next() != Some(word) {
+                return None;
+            }
+        }
+        name.next()
+    };
+    table.iter().enumerate().filter_map(move |(i, c)| {
+        let word = next(c)?;
+        // Groups are listed once, by their first command
+        table[..i].iter().all(|other| next(other) != Some(word)).then_some(word)
+    })
+}
+
+/// Choices of the argument that follows `words`, if it's a `Choice`.
+fn next_choices<H>(command: &Command<H>, words: &str) -> &'static [&'static str] {
+    let mut given = words.split_ascii_whitespace();
+    for part in command.name.split(' ') {
+        if given.next() != Some(part) {
+            return &[];
+        }
+    }
+    match command.args.get(given.count()).map(|a| a.kind) {
+        Some(Kind::Choice(choices)) => choices,
+        _ => &[],
+    }
+}
+
+/// Words that can replace the last, possibly empty, word of `line`.
+pub fn candidates<'a, H>(table: &'a [Command<H>], line: &'a str) -> impl Iterator<Item = &'static str> + 'a {
+    let partial = if line.ends_with(|c: char| c.is_ascii_whitespace()) {
+        ""
+    } else {
+        line.split_ascii_whitespace().last().unwrap_or("")
+    };
+    let words = &line[..line.len() - partial.len()];
+    let choices = table.iter().flat_map(move |c| next_choices(c, words).iter().copied());
+    next_words(table, words).chain(choices).filter(move |w| w.starts_with(partial))
+}
+
+/// What tab inserts: the common part of all candidates after what is already typed, and whether
+/// the candidate was unique so the word is finished.
+pub fn completion<H>(table: &[Command<H>], line: &str) -> (&'static str, bool) {
+    let typed = if line.ends_with(|c: char| c.is_ascii_whitespace()) {
+        0
+    } else {
+        line.split_ascii_whitespace().last().map_or(0, |w| w.len())
+    };
+    let mut found = candidates(table, line);
+    let first = match found.next() {
+        Some(first) => first,
+        None => return ("", false),
+    };
+    let mut common = first.len();
+    let mut unique = true;
+    for other in found {
+        unique = false;
+        common = first.bytes().zip(other.bytes()).take(common).take_while(|(a, b)| a == b).count();
+    }
+    (&first[typed..common.max(typed)], unique)
+}
+
+/// `help` lists the commands, `help can` the usage of every command in the group, or of a single one.
+pub fn write_help<H, W: fmt::Write>(table: &[Command<H>], topic: &str, out: &mut W) -> fmt::Result {
+    let topic = topic.trim();
+    if topic.is_empty() {
+        for command in table {
+            writeln!(out, "{:16}{}", command.name, command.help)?;
+        }
+        return Ok(());
+    }
+    let mut found = false;
+    for command in table.iter().filter(|c| c.matching_words(topic) == topic.split_ascii_whitespace().count()) {
+        found = true;
+        command.write_usage(out)?;
+        writeln!(out)?;
+        writeln!(out, "    {}", command.help)?;
+    }
+    if !found {
+        writeln!(out, "Unknown command: {}", topic)?;
+    }
+    Ok(())
+}
+
+/// `write_help` output as a value, to embed it in a reply.
+pub struct Help<'a, H>(pub &'a [Command<H>], pub &'a str);
+
+impl<H> fmt::Display for Help<'_, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_help(self.0, self.1, f)
+    }
+}
+
+pub fn parse_phase(s: &str) -> Option<Phase> {
+    match s {
+        "a" => Some(Phase::A),
+        "b" => Some(Phase::B),
+        "c" => Some(Phase::C),
+        _ => None
+    }
+}
+
+pub fn parse_on_off(s: &str) -> Option<bool> {
+    match s {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None
+    }
+}
+
+/// `ah` - phase A high side on, `al` - low side on, `az` - both off, same for b and c.
+pub fn parse_switch(s: &str) -> Option<(Phase, SwitchState)> {
+    let mut chars = s.chars();
+    let phase = match chars.next()? {
+        'a' => Phase::A,
+        'b' => Phase::B,
+        'c' => Phase::C,
+        _ => return None
+    };
+    let state = match chars.next()? {
+        'h' => SwitchState::High,
+        'l' => SwitchState::Low,
+        'z' => SwitchState::Off,
+        _ => return None
+    };
+    if chars.next().is_some() {
+        return None;
+    }
+    Some((phase, state))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TABLE: &[Command<u8>] = &[
+        Command { name: "drv on", help: "Enable gate driver", args: &[], handler: 1 },
+        Command { name: "drv off", help: "Disable gate driver", args: &[], handler: 2 },
+        Command { name: "sw", help: "Switches", args: &[Arg::choice("switch", &["ah", "al", "az"])], handler: 3 },
+        Command { name: "swmode", help: "Mode", args: &[Arg::choice("mode", &["manual", "openloop"])], handler: 4 },
+        Command {
+            name: "ol align",
+            help: "Apply a voltage vector",
+            args: &[
+                Arg::int("angle", -360, 360, Unit::Degrees),
+                Arg::int("duty", 0, 100, Unit::Percent),
+                Arg::int("time", 1, 10_000, Unit::Milliseconds),
+            ],
+            handler: 5,
+        },
+        Command { name: "cfg set", help: "Change a setting", args: &[Arg::word("name"), Arg::int("value", -1000, 100_000, Unit::None)], handler: 6 },
+        Command { name: "can pump", help: "Charge pump", args: &[Arg::int("freq", 1, 100_000, Unit::Hertz).optional()], handler: 7 },
+        Command { name: "help", help: "This help", args: &[Arg::word("command").optional()], handler: 8 },
+    ];
+    const _: () = assert!(fits(TABLE));
+
+    fn all(line: &str) -> Vec<&'static str> {
+        candidates(TABLE, line).collect()
+    }
+
+    fn error(line: &str) -> String {
+        let mut out = String::new();
+        write_error(TABLE, line, &dispatch(TABLE, line).err().unwrap(), &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn dispatch_commands() {
+        let (command, values) = dispatch(TABLE, "  ol align -90 50%  2s ").unwrap();
+        assert_eq!(command.handler, 5);
+        assert_eq!((values.int(0), values.int(1), values.int(2)), (-90, 50, 2000));
+        let (command, values) = dispatch(TABLE, "swmode openloop").unwrap();
+        assert_eq!((command.handler, values.choice(0), values.text(0)), (4, 1, "openloop"));
+        let (command, values) = dispatch(TABLE, "cfg set dead_time 500").unwrap();
+        assert_eq!((command.handler, values.text(0), values.int(1)), (6, "dead_time", 500));
+        assert_eq!(dispatch(TABLE, "drv off").map(|(c, _)| c.handler), Ok(2));
+        let (_, values) = dispatch(TABLE, "can pump").unwrap();
+        assert!(!values.is_present(0));
+        let (_, values) = dispatch(TABLE, "can pump 20kHz").unwrap();
+        assert_eq!(values.int(0), 20_000);
+    }
+
+    #[test]
+    fn dispatch_errors() {
+        let align = &TABLE[4].args;
+        assert_eq!(dispatch(TABLE, " ").err(), Some(DispatchError::Empty));
+        assert_eq!(dispatch(TABLE, "s ah").err(), Some(DispatchError::Unknown("s")));
+        assert_eq!(dispatch(TABLE, "drv regs").err(), Some(DispatchError::Unknown("regs")));
+        assert_eq!(dispatch(TABLE, "drv").err(), Some(DispatchError::Incomplete("drv")));
+        assert_eq!(dispatch(TABLE, "sw ax").err(), Some(DispatchError::Invalid("ax", &TABLE[2].args[0])));
+        assert_eq!(dispatch(TABLE, "ol align 0 50").err(), Some(DispatchError::Missing(&align[2])));
+        assert_eq!(dispatch(TABLE, "ol align 0 150 1").err(), Some(DispatchError::OutOfRange("150", &align[1])));
+        assert_eq!(dispatch(TABLE, "ol align 0 50 1V").err(), Some(DispatchError::Invalid("1V", &align[2])));
+        assert_eq!(dispatch(TABLE, "drv on now").err(), Some(DispatchError::Extra("now")));
+    }
+
+    #[test]
+    fn quantities() {
+        assert_eq!(parse_quantity("12", Unit::MilliVolts), Some(12));
+        assert_eq!(parse_quantity("12mV", Unit::MilliVolts), Some(12));
+        assert_eq!(parse_quantity("1.5V", Unit::MilliVolts), Some(1500));
+        assert_eq!(parse_quantity("-0.25A", Unit::MilliAmperes), Some(-250));
+        assert_eq!(parse_quantity("+3", Unit::None), Some(3));
+        assert_eq!(parse_quantity("1.5", Unit::None), None);
+        assert_eq!(parse_quantity("1.0", Unit::None), Some(1));
+        assert_eq!(parse_quantity("1.5mV", Unit::MilliVolts), None);
+        assert_eq!(parse_quantity("2us", Unit::Nanoseconds), Some(2000));
+        assert_eq!(parse_quantity("1.5ms", Unit::Microseconds), Some(1500));
+        assert_eq!(parse_quantity("5kHz", Unit::MilliVolts), None);
+        assert_eq!(parse_quantity("", Unit::None), None);
+        assert_eq!(parse_quantity("-", Unit::None), None);
+        assert_eq!(parse_quantity(".", Unit::None), None);
+        assert_eq!(parse_quantity("1-2", Unit::None), None);
+        assert_eq!(parse_quantity("99999999999999999999", Unit::None), None);
+    }
+
+    #[test]
+    fn errors_point_at_token() {
+        assert_eq!(error("sw ax"), "sw ax\n   ^^ expected ah/al/az\n");
+        assert_eq!(error("ol align 0 150 1"), "ol align 0 150 1\n           ^^^ out of range <duty 0..100%>\n");
+        assert_eq!(error("ol align 0 50"), "ol align 0 50\n              ^ missing <time 1..10000ms>\n");
+        assert_eq!(error("drv"), "drv\n    ^ expected on/off\n");
+        assert_eq!(error("xyz 1"), "xyz 1\n^^^ unknown command, try help\n");
+    }
+
+    #[test]
+    fn usage() {
+        let mut out = String::new();
+        TABLE[4].write_usage(&mut out).unwrap();
+        assert_eq!(out, "ol align <angle -360..360deg> <duty 0..100%> <time 1..10000ms>");
+        out.clear();
+        TABLE[6].write_usage(&mut out).unwrap();
+        assert_eq!(out, "can pump [<freq 1..100000Hz>]");
+    }
+
+    #[test]
+    fn complete_command_names() {
+        assert_eq!(all(""), vec!["drv", "sw", "swmode", "ol", "cfg", "can", "help"]);
+        assert_eq!(all("s"), vec!["sw", "swmode"]);
+        assert_eq!(completion(TABLE, "s"), ("w", false));
+        assert_eq!(completion(TABLE, "sw"), ("", false));
+        assert_eq!(completion(TABLE, "swm"), ("ode", true));
+        assert_eq!(completion(TABLE, "d"), ("rv", true));
+        assert_eq!(completion(TABLE, "x"), ("", false));
+        assert_eq!(all("drv "), vec!["on", "off"]);
+        assert_eq!(completion(TABLE, "ol a"), ("lign", true));
+    }
+
+    #[test]
+    fn complete_choices() {
+        assert_eq!(all("sw "), vec!["ah", "al", "az"]);
+        assert_eq!(all("sw a"), vec!["ah", "al", "az"]);
+        assert_eq!(completion(TABLE, "swmode  m"), ("anual", true));
+        assert!(all("sw ah ").is_empty());
+        assert!(all("ol align ").is_empty());
+        assert!(all("led ").is_empty());
+    }
+
+    #[test]
+    fn help() {
+        let mut out = String::new();
+        write_help(TABLE, "", &mut out).unwrap();
+        assert_eq!(out.lines().count(), TABLE.len());
+        assert!(out.starts_with("drv on          Enable gate driver\n"), "{}", out);
+        out.clear();
+        write_help(TABLE, "drv", &mut out).unwrap();
+        assert_eq!(out, "drv on\n    Enable gate driver\ndrv off\n    Disable gate driver\n");
+        out.clear();
+        write_help(TABLE, "sw", &mut out).unwrap();
+        assert_eq!(out, "sw ah/al/az\n    Switches\n");
+        out.clear();
+        write_help(TABLE, "x", &mut out).unwrap();
+        assert_eq!(out, "Unknown command: x\n");
+    }
+
+    #[test]
+    fn phase() {
+        assert_eq!(parse_phase("a"), Some(Phase::A));
+        assert_eq!(parse_phase("c"), Some(Phase::C));
+        assert_eq!(parse_phase("d"), None);
+        assert_eq!(parse_phase("A"), None);
+        assert_eq!(parse_phase(""), None);
+    }
+
+    #[test]
+    fn on_off() {
+        assert_eq!(parse_on_off("on"), Some(true));
+        assert_eq!(parse_on_off("off"), Some(false));
+        assert_eq!(parse_on_off("1"), None);
+    }
+
+    #[test]
+    fn switch() {
+        assert_eq!(parse_switch("ah"), Some((Phase::A, SwitchState::High)));
+        assert_eq!(parse_switch("bl"), Some((Phase::B, SwitchState::Low)));
+        assert_eq!(parse_switch("cz"), Some((Phase::C, SwitchState::Off)));
+        assert_eq!(parse_switch("dz"), None);
+        assert_eq!(parse_switch("ax"), None);
+        assert_eq!(parse_switch("a"), None);
+        assert_eq!(parse_switch("ahh"), None);
+    }
+}
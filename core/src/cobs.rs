@@ -0,0 +1,104 @@
+//! Consistent overhead byte stuffing: encoded data has no zero bytes, so a zero can delimit frames.
+
+/// Encoded size of `len` bytes, without the delimiter.
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + len / 254 + 1
+}
+
+/// Returns the encoded length, None if `dst` is too small.
+pub fn encode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut code_at = 0;
+    let mut out = 1;
+    let mut code = 1u8;
+    for &byte in src {
+        if byte != 0 {
+            *dst.get_mut(out)? = byte;
+            out += 1;
+            code += 1;
+        }
+        if byte == 0 || code == 0xFF {
+            *dst.get_mut(code_at)? = code;
+            code_at = out;
+            out += 1;
+            code = 1;
+        }
+    }
+    *dst.get_mut(code_at)? = code;
+    Some(out)
+}
+
+/// Decodes one frame without its delimiter, None if it's malformed or `dst` is too small.
+pub fn decode(src: &[u8], dst: &mut [u8]) -> Option<usize> {
+    let mut i = 0;
+    let mut out = 0;
+    while i < src.len() {
+        let code = src[i];
+        if code == 0 {
+            return None;
+        }
+        i += 1;
+        for _ in 1..code {
+            let byte = *src.get(i)?;
+            if byte == 0 {
+                return None;
+            }
+            *dst.get_mut(out)? = byte;
+            out += 1;
+            i += 1;
+        }
+        if code != 0xFF && i < src.len() {
+            *dst.get_mut(out)? = 0;
+            out += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0; max_encoded_len(data.len())];
+        let len = encode(data, &mut encoded).unwrap();
+        encoded.truncate(len);
+        assert!(!encoded.contains(&0), "{:?}", encoded);
+        let mut decoded = vec![0; data.len()];
+        assert_eq!(decode(&encoded, &mut decoded), Some(data.len()));
+        assert_eq!(decoded, data);
+        encoded
+    }
+
+    #[test]
+    fn known_vectors() {
+        assert_eq!(round_trip(&[]), [0x01]);
+        assert_eq!(round_trip(&[0x00]), [0x01, 0x01]);
+        assert_eq!(round_trip(&[0x00, 0x00]), [0x01, 0x01, 0x01]);
+        assert_eq!(round_trip(&[0x11, 0x22, 0x00, 0x33]), [0x03, 0x11, 0x22, 0x02, 0x33]);
+        assert_eq!(round_trip(&[0x11, 0x00, 0x00, 0x00]), [0x02, 0x11, 0x01, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn long_runs() {
+        let data: Vec<u8> = (1..=254).collect();
+        let encoded = round_trip(&data);
+        assert_eq!(encoded[0], 0xFF);
+        assert_eq!(encoded.len(), 256);
+        for len in [253, 255, 508, 600] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 7) as u8 + 1).collect();
+            round_trip(&data);
+        }
+        let data: Vec<u8> = (0..1000).map(|i| (i % 255) as u8).collect();
+        round_trip(&data);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut buf = [0; 8];
+        assert_eq!(decode(&[0x03, 0x11], &mut buf), None);
+        assert_eq!(decode(&[0x03, 0x11, 0x00], &mut buf), None);
+        assert_eq!(decode(&[0x00], &mut buf), None);
+        assert_eq!(decode(&[0x05, 1, 2, 3, 4], &mut buf[..2]), None);
+        assert_eq!(encode(&[1, 2, 3], &mut buf[..3]), None);
+    }
+}
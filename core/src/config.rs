@@ -0,0 +1,456 @@
+//! Runtime settings and their flash record format.
+//!
+//! Record layout, little endian, `RECORD_LEN` bytes with unused space left erased (0xFF):
+//! `magic: u32, version: u8, payload_len: u8, reserved: u16, sequence: u32, payload, crc32: u32`,
+//! CRC covers everything before it. New fields are only ever appended to the payload, so a record
+//! written by another firmware version loads with missing fields set to defaults. `VERSION` only
+//! changes with the layout of the existing fields, records of another version aren't loaded.
+
+use crate::board::Board;
+use crate::calibration::{self, GAIN_ONE};
+use crate::crc::crc32;
+use crate::stage::Channel;
+use crate::units::{Hertz, MilliVolts, Nanoseconds};
+
+pub const MAGIC: u32 = 0x4354_5350; // "PSTC"
+pub const VERSION: u8 = 1;
+pub const RECORD_LEN: usize = 64;
+const HEADER_LEN: usize = 12;
+const CRC_LEN: usize = 4;
+const MAX_PAYLOAD_LEN: usize = RECORD_LEN - HEADER_LEN - CRC_LEN;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    pub current_sense_gain: u8,
+    pub can_undervoltage: MilliVolts,
+    pub dead_time_ns: u16,
+    pub pwm_frequency_hz: u32,
+    /// Current amplifier output at zero current relative to the board midpoint, per phase.
+    pub current_offsets: [MilliVolts; 3],
+    /// Two point calibration of V_IN and the phase voltages, see `calibration`. Gains are in
+    /// units of `GAIN_ONE`, offsets in mV at the input.
+    pub voltage_gains: [u16; 4],
+    pub voltage_offsets: [MilliVolts; 4],
+    /// Current calibration per phase in units of `GAIN_ONE`, applied after `current_offsets`.
+    pub current_gains: [u16; 3],
+    /// Reference resistor `cal current` drives, phase to phase.
+    pub reference_load_mohm: u32,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum RecordError {
+    /// Slot was never written.
+    Erased,
+    BadMagic,
+    BadLength,
+    BadCrc,
+    /// Layout version this firmware doesn't know, it's given.
+    UnknownVersion(u8),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ConfigError {
+    UnknownField,
+    OutOfRange,
+}
+
+/// Name, unit and allowed range of the settings, in `cfg show` order.
+pub const FIELDS: &[(&str, &str, i32, i32)] = &[
+    ("gain", "", 1, 255),
+    ("can_uv", "mV", 0, 60_000),
+    ("dead_time", "ns", 0, 5_000),
+    ("pwm_freq", "Hz", 1_000, 100_000),
+    ("offset_a", "mV", -500, 500),
+    ("offset_b", "mV", -500, 500),
+    ("offset_c", "mV", -500, 500),
+    ("vgain_in", "", 9_000, 11_000),
+    ("vgain_a", "", 9_000, 11_000),
+    ("vgain_b", "", 9_000, 11_000),
+    ("vgain_c", "", 9_000, 11_000),
+    ("voffset_in", "mV", -2_000, 2_000),
+    ("voffset_a", "mV", -2_000, 2_000),
+    ("voffset_b", "mV", -2_000, 2_000),
+    ("voffset_c", "mV", -2_000, 2_000),
+    ("igain_a", "", 9_000, 11_000),
+    ("igain_b", "", 9_000, 11_000),
+    ("igain_c", "", 9_000, 11_000),
+    ("ref_load", "mOhm", 100, 100_000),
+];
+
+/// Names of the gain and offset fields of each of `calibration::VOLTAGE_CHANNELS`.
+const VOLTAGE_FIELDS: [(&str, &str); 4] = [
+    ("vgain_in", "voffset_in"),
+    ("vgain_a", "voffset_a"),
+    ("vgain_b", "voffset_b"),
+    ("vgain_c", "voffset_c"),
+];
+
+impl Config {
+    pub fn defaults(board: &Board) -> Self {
+        Config {
+            current_sense_gain: board.default_current_gain,
+            can_undervoltage: MilliVolts(10_000),
+            // Before it was a setting BDTR.DTG was fixed at 127, 15.9us with the 8MHz timer clock
+            // and 756ns at 168MHz. 1us is long enough for the DRVs' own switching times.
+            dead_time_ns: 1000,
+            pwm_frequency_hz: 20_000,
+            current_offsets: [MilliVolts(0); 3],
+            voltage_gains: [GAIN_ONE as u16; 4],
+            voltage_offsets: [MilliVolts(0); 4],
+            current_gains: [GAIN_ONE as u16; 3],
+            reference_load_mohm: 1_000,
+        }
+    }
+
+    pub fn pwm_frequency(&self) -> Hertz {
+        Hertz(self.pwm_frequency_hz)
+    }
+
+    pub fn dead_time(&self) -> Nanoseconds {
+        Nanoseconds(self.dead_time_ns as u32)
+    }
+
+    /// Applies the voltage and current calibration to a converted reading, other channels pass
+    /// unchanged.
+    pub fn correct(&self, channel: Channel, value: i32) -> i32 {
+        if let Some(k) = calibration::voltage_index(channel) {
+            return calibration::correct(value, self.voltage_gains[k], self.voltage_offsets[k]);
+        }
+        match channel {
+            Channel::IA => calibration::correct(value, self.current_gains[0], MilliVolts(0)),
+            Channel::IB => calibration::correct(value, self.current_gains[1], MilliVolts(0)),
+            Channel::IC => calibration::correct(value, self.current_gains[2], MilliVolts(0)),
+            _ => value,
+        }
+    }
+
+    /// Calibrated offset of the voltage at the ADC pin, zero for voltage channels.
+    pub fn offset(&self, channel: Channel) -> MilliVolts {
+        match channel {
+            Channel::IA => self.current_offsets[0],
+            Channel::IB => self.current_offsets[1],
+            Channel::IC => self.current_offsets[2],
+            _ => MilliVolts(0),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<i32> {
+        let value = match name {
+            "gain" => self.current_sense_gain as i32,
+            "can_uv" => self.can_undervoltage.0,
+            "dead_time" => self.dead_time_ns as i32,
+            "pwm_freq" => self.pwm_frequency_hz as i32,
+            "offset_a" => self.current_offsets[0].0,
+            "offset_b" => self.current_offsets[1].0,
+            "offset_c" => self.current_offsets[2].0,
+            "igain_a" => self.current_gains[0] as i32,
+            "igain_b" => self.current_gains[1] as i32,
+            "igain_c" => self.current_gains[2] as i32,
+            "ref_load" => self.reference_load_mohm as i32,
+            _ => {
+                let (k, gain) = voltage_field(name)?;
+                if gain { self.voltage_gains[k] as i32 } else { self.voltage_offsets[k].0 }
+            }
+        };
+        Some(value)
+    }
+
+    /// Gain has to be one the DRV of the `board` supports.
+    pub fn set(&mut self, board: &Board, name: &str, value: i32) -> Result<(), ConfigError> {
+        let (_, _, min, max) = FIELDS.iter().find(|f| f.0 == name).ok_or(ConfigError::UnknownField)?;
+        if value < *min || value > *max {
+            return Err(ConfigError::OutOfRange);
+        }
+        match name {
+            "gain" => {
+                if !board.drv.supports_gain(value as u8) {
+                    return Err(ConfigError::OutOfRange);
+                }
+                self.current_sense_gain = value as u8;
+            }
+            "can_uv" => self.can_undervoltage = MilliVolts(value),
+            "dead_time" => self.dead_time_ns = value as u16,
+            "pwm_freq" => self.pwm_frequency_hz = value as u32,
+            "offset_a" => self.current_offsets[0] = MilliVolts(value),
+            "offset_b" => self.current_offsets[1] = MilliVolts(value),
+            "offset_c" => self.current_offsets[2] = MilliVolts(value),
+            "igain_a" => self.current_gains[0] = value as u16,
+            "igain_b" => self.current_gains[1] = value as u16,
+            "igain_c" => self.current_gains[2] = value as u16,
+            "ref_load" => self.reference_load_mohm = value as u32,
+            _ => match voltage_field(name) {
+                Some((k, true)) => self.voltage_gains[k] = value as u16,
+                Some((k, false)) => self.voltage_offsets[k] = MilliVolts(value),
+                None => return Err(ConfigError::UnknownField),
+            },
+        }
+        Ok(())
+    }
+
+    /// Runs every field through `set`, for settings that didn't come from the CLI.
+    pub fn check(&self, board: &Board) -> Result<(), ConfigError> {
+        let mut scratch = *self;
+        for (name, _, _, _) in FIELDS.iter() {
+            scratch.set(board, name, self.get(name).ok_or(ConfigError::UnknownField)?)?;
+        }
+        Ok(())
+    }
+
+    /// Serializes into a full flash slot.
+    pub fn to_record(&self, sequence: u32) -> [u8; RECORD_LEN] {
+        let mut record = [0xFF; RECORD_LEN];
+        let mut payload = Writer { buf: &mut record[HEADER_LEN..HEADER_LEN + MAX_PAYLOAD_LEN], len: 0 };
+        payload.put(&[self.current_sense_gain]);
+        payload.put(&self.dead_time_ns.to_le_bytes());
+        payload.put(&self.pwm_frequency_hz.to_le_bytes());
+        payload.put(&self.can_undervoltage.0.to_le_bytes());
+        for offset in self.current_offsets.iter() {
+            payload.put(&(offset.0 as i16).to_le_bytes());
+        }
+        for gain in self.voltage_gains.iter() {
+            payload.put(&gain.to_le_bytes());
+        }
+        for offset in self.voltage_offsets.iter() {
+            payload.put(&(offset.0 as i16).to_le_bytes());
+        }
+        for gain in self.current_gains.iter() {
+            payload.put(&gain.to_le_bytes());
+        }
+        payload.put(&self.reference_load_mohm.to_le_bytes());
+        let payload_len = payload.len;
+
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4] = VERSION;
+        record[5] = payload_len as u8;
+        record[8..12].copy_from_slice(&sequence.to_le_bytes());
+        let crc_at = HEADER_LEN + payload_len;
+        let crc = crc32(&record[..crc_at]);
+        record[crc_at..crc_at + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    /// Returns the settings and the sequence number of the record, fields the record doesn't have
+    /// are taken from `defaults`.
+    pub fn from_record(record: &[u8], defaults: Config) -> Result<(Config, u32), RecordError> {
+        if record.len() < RECORD_LEN {
+            return Err(RecordError::BadLength);
+        }
+        if record[..RECORD_LEN].iter().all(|b| *b == 0xFF) {
+            return Err(RecordError::Erased);
+        }
+        if u32::from_le_bytes([record[0], record[1], record[2], record[3]]) != MAGIC {
+            return Err(RecordError::BadMagic);
+        }
+        let payload_len = record[5] as usize;
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(RecordError::BadLength);
+        }
+        let crc_at = HEADER_LEN + payload_len;
+        let crc = u32::from_le_bytes([record[crc_at], record[crc_at + 1], record[crc_at + 2], record[crc_at + 3]]);
+        if crc32(&record[..crc_at]) != crc {
+            return Err(RecordError::BadCrc);
+        }
+        if record[4] != VERSION {
+            return Err(RecordError::UnknownVersion(record[4]));
+        }
+        let sequence = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+
+        let mut config = defaults;
+        let mut payload = Reader { buf: &record[HEADER_LEN..crc_at] };
+        if let Some(b) = payload.take::<1>() {
+            config.current_sense_gain = b[0];
+        }
+        if let Some(b) = payload.take::<2>() {
+            config.dead_time_ns = u16::from_le_bytes(b);
+        }
+        if let Some(b) = payload.take::<4>() {
+            config.pwm_frequency_hz = u32::from_le_bytes(b);
+        }
+        if let Some(b) = payload.take::<4>() {
+            config.can_undervoltage = MilliVolts(i32::from_le_bytes(b));
+        }
+        for offset in config.current_offsets.iter_mut() {
+            if let Some(b) = payload.take::<2>() {
+                *offset = MilliVolts(i16::from_le_bytes(b) as i32);
+            }
+        }
+        for gain in config.voltage_gains.iter_mut() {
+            if let Some(b) = payload.take::<2>() {
+                *gain = u16::from_le_bytes(b);
+            }
+        }
+        for offset in config.voltage_offsets.iter_mut() {
+            if let Some(b) = payload.take::<2>() {
+                *offset = MilliVolts(i16::from_le_bytes(b) as i32);
+            }
+        }
+        for gain in config.current_gains.iter_mut() {
+            if let Some(b) = payload.take::<2>() {
+                *gain = u16::from_le_bytes(b);
+            }
+        }
+        if let Some(b) = payload.take::<4>() {
+            config.reference_load_mohm = u32::from_le_bytes(b);
+        }
+        Ok((config, sequence))
+    }
+}
+
+/// Index into the voltage calibration and true for a gain, false for an offset.
+fn voltage_field(name: &str) -> Option<(usize, bool)> {
+    VOLTAGE_FIELDS.iter().enumerate().find_map(|(k, (gain, offset))| match name {
+        n if n == *gain => Some((k, true)),
+        n if n == *offset => Some((k, false)),
+        _ => None,
+    })
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, data: &[u8]) {
+        self.buf[self.len..self.len + data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.buf.len() < N {
+            return None;
+        }
+        let mut value = [0; N];
+        value.copy_from_slice(&self.buf[..N]);
+        self.buf = &self.buf[N..];
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{DRV8302_STAGE, REV1};
+
+    fn sample() -> Config {
+        Config {
+            current_sense_gain: 40,
+            can_undervoltage: MilliVolts(11_500),
+            dead_time_ns: 750,
+            pwm_frequency_hz: 16_000,
+            current_offsets: [MilliVolts(-12), MilliVolts(3), MilliVolts(0)],
+            voltage_gains: [10_256, 10_000, 9_990, 10_010],
+            voltage_offsets: [MilliVolts(1), MilliVolts(-100), MilliVolts(0), MilliVolts(20)],
+            current_gains: [9_617, 10_000, 10_150],
+            reference_load_mohm: 2_200,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let record = sample().to_record(7);
+        assert_eq!(Config::from_record(&record, Config::defaults(&REV1)), Ok((sample(), 7)));
+        assert!(record[HEADER_LEN + 43 + CRC_LEN..].iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn corrupted_records() {
+        let defaults = Config::defaults(&REV1);
+        assert_eq!(Config::from_record(&[0xFF; RECORD_LEN], defaults), Err(RecordError::Erased));
+        assert_eq!(Config::from_record(&[0xFF; 8], defaults), Err(RecordError::BadLength));
+
+        let mut record = sample().to_record(1);
+        record[13] ^= 0x01;
+        assert_eq!(Config::from_record(&record, defaults), Err(RecordError::BadCrc));
+
+        let mut record = sample().to_record(1);
+        record[0] = 0;
+        assert_eq!(Config::from_record(&record, defaults), Err(RecordError::BadMagic));
+
+        let mut record = sample().to_record(1);
+        record[5] = 60;
+        assert_eq!(Config::from_record(&record, defaults), Err(RecordError::BadLength));
+
+        // The CRC is intact, the layout is another one
+        let mut record = sample().to_record(1);
+        record[4] = VERSION + 1;
+        let crc = crc32(&record[..HEADER_LEN + 43]);
+        record[HEADER_LEN + 43..HEADER_LEN + 43 + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(Config::from_record(&record, defaults), Err(RecordError::UnknownVersion(VERSION + 1)));
+
+        // Power lost in the middle of programming: tail is still erased
+        let mut record = sample().to_record(1);
+        for b in record[20..].iter_mut() {
+            *b = 0xFF;
+        }
+        assert_eq!(Config::from_record(&record, defaults), Err(RecordError::BadCrc));
+    }
+
+    #[test]
+    fn shorter_payload_takes_defaults() {
+        // Record written by a firmware that only knew about the gain and dead time
+        let mut record = [0xFF; RECORD_LEN];
+        record[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        record[4] = 1;
+        record[5] = 3;
+        record[8..12].copy_from_slice(&3u32.to_le_bytes());
+        record[12] = 10;
+        record[13..15].copy_from_slice(&500u16.to_le_bytes());
+        let crc = crc32(&record[..15]);
+        record[15..19].copy_from_slice(&crc.to_le_bytes());
+
+        let defaults = Config::defaults(&REV1);
+        let (config, sequence) = Config::from_record(&record, defaults).unwrap();
+        assert_eq!(sequence, 3);
+        assert_eq!(config.current_sense_gain, 10);
+        assert_eq!(config.dead_time_ns, 500);
+        assert_eq!(config.pwm_frequency_hz, defaults.pwm_frequency_hz);
+        assert_eq!(config.current_offsets, defaults.current_offsets);
+        assert_eq!(config.voltage_gains, defaults.voltage_gains);
+    }
+
+    #[test]
+    fn set_and_get() {
+        let mut config = Config::defaults(&REV1);
+        assert_eq!(config.set(&REV1, "dead_time", 500), Ok(()));
+        assert_eq!(config.get("dead_time"), Some(500));
+        assert_eq!(config.set(&REV1, "offset_b", -20), Ok(()));
+        assert_eq!(config.current_offsets[1], MilliVolts(-20));
+        assert_eq!(config.set(&REV1, "dead_time", 6000), Err(ConfigError::OutOfRange));
+        assert_eq!(config.set(&REV1, "pwm_freq", 10), Err(ConfigError::OutOfRange));
+        assert_eq!(config.set(&REV1, "speed", 1), Err(ConfigError::UnknownField));
+        assert_eq!(config.get("speed"), None);
+        assert_eq!(config.set(&REV1, "gain", 80), Ok(()));
+        assert_eq!(config.set(&DRV8302_STAGE, "gain", 20), Err(ConfigError::OutOfRange));
+        assert_eq!(config.set(&REV1, "vgain_b", 10_100), Ok(()));
+        assert_eq!(config.set(&REV1, "voffset_in", -50), Ok(()));
+        assert_eq!(config.correct(Channel::VB, 10_000), 10_100);
+        assert_eq!(config.correct(Channel::VIn, 10_000), 9_950);
+        assert_eq!(config.correct(Channel::IA, 10_000), 10_000);
+        assert_eq!(config.set(&REV1, "igain_a", 9_500), Ok(()));
+        assert_eq!(config.correct(Channel::IA, 10_000), 9_500);
+        assert_eq!(config.correct(Channel::VCan, 10_000), 10_000);
+        for (name, _, _, _) in FIELDS.iter() {
+            assert!(config.get(name).is_some(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn check() {
+        assert_eq!(sample().check(&REV1), Ok(()));
+        assert_eq!(Config::defaults(&DRV8302_STAGE).check(&DRV8302_STAGE), Ok(()));
+        // Gain of another DRV
+        let gain_80 = Config { current_sense_gain: 80, ..sample() };
+        assert_eq!(gain_80.check(&REV1), Ok(()));
+        assert_eq!(gain_80.check(&DRV8302_STAGE), Err(ConfigError::OutOfRange));
+        assert_eq!(Config { dead_time_ns: 60_000, ..sample() }.check(&REV1), Err(ConfigError::OutOfRange));
+        assert_eq!(Config { pwm_frequency_hz: u32::MAX, ..sample() }.check(&REV1), Err(ConfigError::OutOfRange));
+        assert_eq!(Config { voltage_gains: [0; 4], ..sample() }.check(&REV1), Err(ConfigError::OutOfRange));
+    }
+}
@@ -0,0 +1,36 @@
+//! Checksums for data leaving the MCU: flash records and host links.
+
+/// CRC-32 (IEEE 802.3, as used by zlib), bitwise to keep the flash footprint small.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0xFFFF_FFFF, data) ^ 0xFFFF_FFFF
+}
+
+/// Continues a CRC-32 over `data`, start with 0xFFFF_FFFF and xor the result with it at the end.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn incremental() {
+        let crc = crc32_update(0xFFFF_FFFF, b"1234");
+        let crc = crc32_update(crc, b"56789");
+        assert_eq!(crc ^ 0xFFFF_FFFF, 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,191 @@
+//! Leg diagnosis from the phase voltages: each leg is left floating, switched high and switched
+//! low with the others off, and the readings are classified into a verdict per leg.
+//!
+//! Runs in manual mode with nothing connected to the phases and from a current limited supply,
+//! a shorted leg conducts from the bus to ground while it's tested.
+//!
+//! An open low side leaves the phase floating after the high side charged it, and the phase
+//! divider discharges it at a rate that depends on the board. So the low side reading is compared
+//! with a reference: the leg is charged through the high side again and released with both
+//! switches off, and the phase is read after the same `SETTLE_MS`. The low side is open if both
+//! readings are still up and match, which holds whatever the sense filter and divider timing.
+
+use crate::board::Board;
+use crate::config::Config;
+use crate::stage::{measure, Channel, Phase, PowerStage, SwitchState};
+use crate::units::MilliVolts;
+
+/// Time for the phase voltage to follow a switch.
+pub const SETTLE_MS: u32 = 2;
+/// Phase voltage above this share of V_IN counts as high.
+pub const HIGH_PERCENT: i32 = 90;
+/// Phase voltage below this share of V_IN counts as low.
+pub const LOW_PERCENT: i32 = 10;
+/// V_IN below this share of its idle value while a switch is on means the bus is shorted.
+pub const SAG_PERCENT: i32 = 90;
+/// Two phase readings closer than this share of V_IN match.
+pub const MATCH_PERCENT: i32 = 10;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Verdict {
+    Ok,
+    /// The phase doesn't go high with the high side on.
+    HighSideOpen,
+    /// The phase stays high with the low side on.
+    LowSideOpen,
+    /// The leg conducts from the bus to ground or the phase is high with both switches off.
+    Shorted,
+    /// The phase of another leg follows this one while both of that leg's switches are off.
+    ShortedTo(Phase),
+}
+
+impl Verdict {
+    pub fn name(self) -> &'static str {
+        match self {
+            Verdict::Ok => "ok",
+            Verdict::HighSideOpen => "high side open",
+            Verdict::LowSideOpen => "low side open",
+            Verdict::Shorted => "shorted",
+            Verdict::ShortedTo(Phase::A) => "shorted to a",
+            Verdict::ShortedTo(Phase::B) => "shorted to b",
+            Verdict::ShortedTo(Phase::C) => "shorted to c",
+        }
+    }
+}
+
+/// Readings of one leg, phase voltages are the leg's own unless noted.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct LegReadings {
+    pub phase: Phase,
+    /// Both switches off.
+    pub v_float: MilliVolts,
+    /// High side on.
+    pub v_high: MilliVolts,
+    /// Low side on, `SETTLE_MS` after the high side.
+    pub v_low: MilliVolts,
+    /// Both switches off, `SETTLE_MS` after the high side. What an open low side reads as.
+    pub v_released: MilliVolts,
+    /// Lowest V_IN while a switch was on.
+    pub v_in_min: MilliVolts,
+    /// The other legs and their phase voltages while the high side was on.
+    pub others: [(Phase, MilliVolts); 2],
+}
+
+impl LegReadings {
+    /// `v_in` is the bus voltage with every switch off.
+    pub fn verdict(&self, v_in: MilliVolts) -> Verdict {
+        let high = v_in.0 * HIGH_PERCENT / 100;
+        let low = v_in.0 * LOW_PERCENT / 100;
+        if self.v_in_min.0 < v_in.0 * SAG_PERCENT / 100 || self.v_float.0 > high {
+            return Verdict::Shorted;
+        }
+        if self.v_high.0 < high {
+            return Verdict::HighSideOpen;
+        }
+        if let Some(&(other, _)) = self.others.iter().find(|(_, v)| v.0 > high) {
+            return Verdict::ShortedTo(other);
+        }
+        if self.v_low.0 > low && (self.v_low.0 - self.v_released.0).abs() < v_in.0 * MATCH_PERCENT / 100 {
+            return Verdict::LowSideOpen;
+        }
+        Verdict::Ok
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Report {
+    pub v_in: MilliVolts,
+    pub legs: [LegReadings; 3],
+}
+
+impl Report {
+    pub fn verdicts(&self) -> [Verdict; 3] {
+        self.legs.map(|leg| leg.verdict(self.v_in))
+    }
+
+    pub fn passed(&self) -> bool {
+        self.verdicts().iter().all(|verdict| *verdict == Verdict::Ok)
+    }
+}
+
+/// Tests the legs one after another, the stage is left in manual mode with all switches off.
+pub fn run<S: PowerStage + ?Sized>(stage: &mut S, board: &Board, config: &Config) -> Report {
+    stage.switch_to_manual();
+    for &phase in Phase::ALL.iter() {
+        stage.set_switch(phase, SwitchState::Off);
+    }
+    stage.delay_ms(SETTLE_MS);
+    let v_in = MilliVolts(measure(stage, board, config, Channel::VIn));
+    let legs = Phase::ALL.map(|phase| test_leg(stage, board, config, phase));
+    Report { v_in, legs }
+}
+
+fn test_leg<S: PowerStage + ?Sized>(stage: &mut S, board: &Board, config: &Config, phase: Phase) -> LegReadings {
+    let voltage = |stage: &mut S, phase: Phase| MilliVolts(measure(stage, board, config, phase.voltage_channel()));
+    let others = match phase {
+        Phase::A => [Phase::B, Phase::C],
+        Phase::B => [Phase::A, Phase::C],
+        Phase::C => [Phase::A, Phase::B],
+    };
+    let v_float = voltage(stage, phase);
+
+    stage.set_switch(phase, SwitchState::High);
+    stage.delay_ms(SETTLE_MS);
+    let v_high = voltage(stage, phase);
+    let v_in_high = measure(stage, board, config, Channel::VIn);
+    let others = others.map(|other| (other, voltage(stage, other)));
+
+    stage.set_switch(phase, SwitchState::Off);
+    stage.delay_ms(SETTLE_MS);
+    let v_released = voltage(stage, phase);
+
+    stage.set_switch(phase, SwitchState::High);
+    stage.delay_ms(SETTLE_MS);
+    stage.set_switch(phase, SwitchState::Low);
+    stage.delay_ms(SETTLE_MS);
+    let v_low = voltage(stage, phase);
+    let v_in_low = measure(stage, board, config, Channel::VIn);
+
+    stage.set_switch(phase, SwitchState::Off);
+    stage.delay_ms(SETTLE_MS);
+    LegReadings { phase, v_float, v_high, v_low, v_released, v_in_min: MilliVolts(v_in_high.min(v_in_low)), others }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verdicts() {
+        let v_in = MilliVolts(24_000);
+        let ok = LegReadings {
+            phase: Phase::A,
+            v_float: MilliVolts(50),
+            v_high: MilliVolts(23_900),
+            v_low: MilliVolts(20),
+            v_released: MilliVolts(30),
+            v_in_min: MilliVolts(23_800),
+            others: [(Phase::B, MilliVolts(40)), (Phase::C, MilliVolts(60))],
+        };
+        assert_eq!(ok.verdict(v_in), Verdict::Ok);
+        assert_eq!(LegReadings { v_high: MilliVolts(50), ..ok }.verdict(v_in), Verdict::HighSideOpen);
+        let open_low = LegReadings { v_low: MilliVolts(18_000), v_released: MilliVolts(18_500), ..ok };
+        assert_eq!(open_low.verdict(v_in), Verdict::LowSideOpen);
+        // Still discharging through a slow sense filter, the working low side pulled it further down
+        assert_eq!(LegReadings { v_low: MilliVolts(6_000), v_released: MilliVolts(18_500), ..ok }.verdict(v_in), Verdict::Ok);
+        // A fast divider empties the floating phase too, nothing to tell an open low side by
+        assert_eq!(LegReadings { v_low: MilliVolts(100), v_released: MilliVolts(120), ..ok }.verdict(v_in), Verdict::Ok);
+        assert_eq!(LegReadings { v_float: MilliVolts(23_900), ..ok }.verdict(v_in), Verdict::Shorted);
+        // Shoot-through pulls the phase and the bus down, it's not an open high side
+        assert_eq!(LegReadings { v_high: MilliVolts(300), v_in_min: MilliVolts(2_000), ..ok }.verdict(v_in), Verdict::Shorted);
+        let others = [(Phase::B, MilliVolts(40)), (Phase::C, MilliVolts(23_900))];
+        assert_eq!(LegReadings { others, ..ok }.verdict(v_in), Verdict::ShortedTo(Phase::C));
+        assert_eq!(Verdict::ShortedTo(Phase::C).name(), "shorted to c");
+
+        let report = Report { v_in, legs: [ok, LegReadings { phase: Phase::B, ..ok }, LegReadings { phase: Phase::C, ..ok }] };
+        assert!(report.passed());
+        let report = Report { legs: [ok, ok, open_low], ..report };
+        assert_eq!(report.verdicts()[2], Verdict::LowSideOpen);
+        assert!(!report.passed());
+    }
+}
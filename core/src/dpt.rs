@@ -0,0 +1,151 @@
+//! Double pulse test: the high side of one leg is switched on for a first pulse that builds up
+//! current in an inductive load, off for a gap while the current freewheels through the low side
+//! diode, and on again for a second pulse. The current just before the first pulse ends is what
+//! the switch turns off, the current just after the second starts is what it turns on.
+//!
+//! The load connects the pulsed leg to a return leg that holds its low side on, the third leg
+//! floats. The shunts sit in the low sides, so the pulsed leg's own shunt carries nothing while
+//! its high side is on, the current is sampled on the return leg's shunt. TIM1 counts
+//! edge aligned in PWM mode 2: the first period is a short delay and the first pulse, the second
+//! period the gap and the second pulse. The second one is preloaded so no software runs between
+//! the pulses, one pulse mode stops the counter after it. Compare channel 4 triggers the current
+//! samples, one per period.
+
+use crate::stage::{Channel, Phase};
+use crate::units::{Hertz, MilliAmperes, MilliVolts, Nanoseconds};
+
+/// Longest pulse or gap the CLI accepts, the timer may allow less.
+pub const MAX_US: u32 = 5000;
+/// The ADC converts the first sample during the gap.
+pub const MIN_GAP_US: u32 = 5;
+/// Counter ticks before the first pulse, keeps the output off while the counter starts.
+pub const START_TICKS: u16 = 1;
+/// The sample before turn-off starts this long before the edge.
+pub const SAMPLE_BEFORE_OFF_NS: u32 = 1000;
+/// The sample after turn-on starts this long after the edge, past the ringing.
+pub const SAMPLE_AFTER_ON_NS: u32 = 1000;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Pulses {
+    pub first_us: u32,
+    pub gap_us: u32,
+    pub second_us: u32,
+}
+
+/// One counter period, in ticks of the timer clock.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Period {
+    /// Auto reload value, the period is one tick longer.
+    pub arr: u16,
+    /// The output turns on when the counter reaches this.
+    pub compare: u16,
+    /// Channel 4 compare value, the ADC trigger.
+    pub sample: u16,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Timing {
+    pub first: Period,
+    pub second: Period,
+}
+
+/// Timer values for `pulses`, None if a pulse is too short to sample or a period doesn't fit
+/// the 16 bit counter.
+pub fn timing(clock: Hertz, pulses: Pulses) -> Option<Timing> {
+    if pulses.gap_us < MIN_GAP_US {
+        return None;
+    }
+    let first = Nanoseconds::from_micros(pulses.first_us).ticks(clock);
+    let gap = Nanoseconds::from_micros(pulses.gap_us).ticks(clock);
+    let second = Nanoseconds::from_micros(pulses.second_us).ticks(clock);
+    let before_off = Nanoseconds(SAMPLE_BEFORE_OFF_NS).ticks(clock);
+    let after_on = Nanoseconds(SAMPLE_AFTER_ON_NS).ticks(clock);
+    if first <= before_off || second <= after_on {
+        return None;
+    }
+    let start = START_TICKS as u64;
+    let first_arr = start + first - 1;
+    let second_arr = gap + second - 1;
+    if first_arr > u16::MAX as u64 || second_arr > u16::MAX as u64 {
+        return None;
+    }
+    Some(Timing {
+        first: Period { arr: first_arr as u16, compare: START_TICKS, sample: (start + first - before_off) as u16 },
+        second: Period { arr: second_arr as u16, compare: gap as u16, sample: (gap + after_on) as u16 },
+    })
+}
+
+/// Channel sampled when `phase` is pulsed and the load current returns through `return_phase`,
+/// None if they're the same leg.
+pub fn sampled_channel(phase: Phase, return_phase: Phase) -> Option<Channel> {
+    if phase == return_phase {
+        return None;
+    }
+    Some(return_phase.current_channel())
+}
+
+/// Current flowing out of the pulsed leg into the load from the return leg's shunt reading,
+/// which counts it flowing into the return leg's winding.
+pub fn pulsed_current(return_reading: MilliAmperes) -> MilliAmperes {
+    -return_reading
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Report {
+    pub v_in: MilliVolts,
+    /// Phase current just before the first pulse turned off.
+    pub i_off: MilliAmperes,
+    /// Phase current just after the second pulse turned on.
+    pub i_on: MilliAmperes,
+}
+
+impl Report {
+    /// Load inductance from the current the first pulse built up, None without current.
+    pub fn inductance_uh(&self, first_us: u32) -> Option<u32> {
+        // mV * us / mA = uH
+        let i_off = self.i_off.0.unsigned_abs();
+        if i_off == 0 || self.v_in.0 <= 0 {
+            return None;
+        }
+        Some((self.v_in.0 as u64 * first_us as u64 / i_off as u64) as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pulse_timing() {
+        let pulses = Pulses { first_us: 10, gap_us: 5, second_us: 5 };
+        let timing = timing(Hertz(8_000_000), pulses).unwrap();
+        assert_eq!(timing.first, Period { arr: 80, compare: 1, sample: 73 });
+        assert_eq!(timing.second, Period { arr: 79, compare: 40, sample: 48 });
+        // Both pulses run from the compare value to the reload value
+        assert_eq!(timing.first.arr - timing.first.compare + 1, 80);
+        assert_eq!(timing.second.arr - timing.second.compare + 1, 40);
+
+        assert_eq!(super::timing(Hertz(8_000_000), Pulses { gap_us: 4, ..pulses }), None);
+        assert_eq!(super::timing(Hertz(8_000_000), Pulses { first_us: 1, ..pulses }), None);
+        assert_eq!(super::timing(Hertz(8_000_000), Pulses { second_us: 1, ..pulses }), None);
+        assert_eq!(super::timing(Hertz(8_000_000), Pulses { gap_us: 5000, second_us: 5000, ..pulses }), None);
+        assert!(super::timing(Hertz(8_000_000), Pulses { first_us: 5000, gap_us: 3000, second_us: 5000 }).is_some());
+        assert_eq!(super::timing(Hertz(168_000_000), Pulses { first_us: 500, ..pulses }), None);
+    }
+
+    #[test]
+    fn samples_the_return_leg() {
+        assert_eq!(sampled_channel(Phase::A, Phase::B), Some(Channel::IB));
+        assert_eq!(sampled_channel(Phase::C, Phase::A), Some(Channel::IA));
+        assert_eq!(sampled_channel(Phase::B, Phase::B), None);
+        assert_eq!(pulsed_current(MilliAmperes(-1200)), MilliAmperes(1200));
+    }
+
+    #[test]
+    fn inductance() {
+        let report = Report { v_in: MilliVolts(24_000), i_off: MilliAmperes(1200), i_on: MilliAmperes(1150) };
+        assert_eq!(report.inductance_uh(10), Some(200));
+        assert_eq!(Report { i_off: MilliAmperes(-1200), ..report }.inductance_uh(10), Some(200));
+        assert_eq!(Report { i_off: MilliAmperes(0), ..report }.inductance_uh(10), None);
+    }
+}
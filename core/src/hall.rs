@@ -0,0 +1,18 @@
+/// Hall sensor state as a 3 bit number, A is the most significant bit.
+pub fn hall_index(a: bool, b: bool, c: bool) -> u8 {
+    ((a as u8) << 2) | ((b as u8) << 1) | (c as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_bit_order() {
+        assert_eq!(hall_index(false, false, false), 0);
+        assert_eq!(hall_index(false, false, true), 1);
+        assert_eq!(hall_index(false, true, false), 2);
+        assert_eq!(hall_index(true, false, false), 4);
+        assert_eq!(hall_index(true, true, true), 7);
+    }
+}
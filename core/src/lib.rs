@@ -0,0 +1,28 @@
+//! Hardware independent part of the power stage tester, builds for the host and for the MCU.
+#![cfg_attr(not(test), no_std)]
+
+pub mod units;
+pub mod stage;
+pub mod board;
+pub mod hall;
+pub mod vector;
+pub mod can;
+pub mod remote;
+pub mod canopen;
+pub mod cli;
+pub mod line;
+pub mod crc;
+pub mod pwm;
+pub mod config;
+pub mod store;
+pub mod vt100;
+pub mod response;
+pub mod cobs;
+pub mod telemetry;
+pub mod capture;
+pub mod bootstrap;
+pub mod dpt;
+pub mod diagnose;
+pub mod shorts;
+pub mod calibration;
+pub mod bench;
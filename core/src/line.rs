@@ -0,0 +1,328 @@
+//! Line editing for a byte stream coming from a terminal: echo, backspace, history and tab completion.
+use crate::cli::{candidates, completion, Command};
+use core::fmt::Write;
+
+pub const LINE_LEN: usize = 64;
+pub const HISTORY_LEN: usize = 4;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const TAB: u8 = 0x09;
+const CTRL_C: u8 = 0x03;
+const ESC: u8 = 0x1B;
+/// Carriage return, erase to the end of line.
+const REDRAW: &str = "\r\x1b[K";
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc,
+    Csi,
+}
+
+pub struct LineEditor {
+    prompt: &'static str,
+    line: [u8; LINE_LEN],
+    len: usize,
+    history: [([u8; LINE_LEN], usize); HISTORY_LEN],
+    history_count: usize,
+    /// Slot the next entry goes into.
+    history_next: usize,
+    /// How far back in the history the shown line is, None when editing a new one.
+    browsing: Option<usize>,
+    escape: Escape,
+    last_cr: bool,
+    /// Line was returned, the next byte starts a new one.
+    done: bool,
+}
+
+impl LineEditor {
+    pub const fn new(prompt: &'static str) -> Self {
+        LineEditor {
+            prompt,
+            line: [0; LINE_LEN],
+            len: 0,
+            history: [([0; LINE_LEN], 0); HISTORY_LEN],
+            history_count: 0,
+            history_next: 0,
+            browsing: None,
+            escape: Escape::None,
+            last_cr: false,
+            done: false,
+        }
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        self.prompt
+    }
+
+    fn as_str(&self) -> &str {
+        // Only printable ASCII gets into the buffer
+        core::str::from_utf8(&self.line[..self.len]).unwrap_or("")
+    }
+
+    /// Processes one byte and echoes it to `out`, returns the whole line on enter.
+    /// Tab completes command names and subcommands from `table`.
+    pub fn feed<H, W: Write>(&mut self, byte: u8, table: &[Command<H>], out: &mut W) -> Option<&str> {
+        if self.done {
+            self.done = false;
+            self.len = 0;
+        }
+        let last_cr = self.last_cr;
+        self.last_cr = byte == b'\r';
+
+        match (self.escape, byte) {
+            (Escape::Esc, b'[') => {
+                self.escape = Escape::Csi;
+                return None;
+            }
+            (Escape::Csi, b'A') => {
+                self.escape = Escape::None;
+                self.history_up(out);
+                return None;
+            }
+            (Escape::Csi, b'B') => {
+                self.escape = Escape::None;
+                self.history_down(out);
+                return None;
+            }
+            // Parameter and intermediate bytes, the sequence goes on until a final byte
+            (Escape::Csi, 0x20..=0x3F) => return None,
+            (Escape::Esc, _) | (Escape::Csi, _) => {
+                self.escape = Escape::None;
+                return None;
+            }
+            (Escape::None, _) => {}
+        }
+
+        match byte {
+            b'\n' if last_cr => {}
+            b'\r' | b'\n' => {
+                out.write_str("\r\n").ok();
+                self.push_history();
+                self.browsing = None;
+                self.done = true;
+                return Some(self.as_str());
+            }
+            BACKSPACE | DELETE if self.len > 0 => {
+                self.len -= 1;
+                out.write_str("\x08 \x08").ok();
+            }
+            TAB => self.complete(table, out),
+            CTRL_C => {
+                self.len = 0;
+                self.browsing = None;
+                write!(out, "^C\r\n{}", self.prompt).ok();
+            }
+            ESC => self.escape = Escape::Esc,
+            0x20..=0x7E if self.insert(&[byte]) => {
+                out.write_char(byte as char).ok();
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn insert(&mut self, bytes: &[u8]) -> bool {
+        if self.len + bytes.len() > LINE_LEN {
+            return false;
+        }
+        self.line[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        true
+    }
+
+    fn complete<H, W: Write>(&mut self, table: &[Command<H>], out: &mut W) {
+        let (suffix, unique) = completion(table, self.as_str());
+        if !suffix.is_empty() || unique {
+            if self.insert(suffix.as_bytes()) {
+                out.write_str(suffix).ok();
+                if unique && self.insert(b" ") {
+                    out.write_char(' ').ok();
+                }
+            }
+            return;
+        }
+        let mut found = candidates(table, self.as_str()).peekable();
+        if found.peek().is_none() {
+            return;
+        }
+        out.write_str("\r\n").ok();
+        for candidate in found {
+            write!(out, "{}  ", candidate).ok();
+        }
+        write!(out, "\r\n{}{}", self.prompt, self.as_str()).ok();
+    }
+
+    fn push_history(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+        if self.history_count > 0 {
+            let newest = (self.history_next + HISTORY_LEN - 1) % HISTORY_LEN;
+            let (line, len) = &self.history[newest];
+            if line[..*len] == self.line[..self.len] {
+                return;
+            }
+        }
+        self.history[self.history_next] = (self.line, self.len);
+        self.history_next = (self.history_next + 1) % HISTORY_LEN;
+        self.history_count = (self.history_count + 1).min(HISTORY_LEN);
+    }
+
+    fn show_history<W: Write>(&mut self, back: Option<usize>, out: &mut W) {
+        self.browsing = back;
+        match back {
+            Some(back) => {
+                let slot = (self.history_next + HISTORY_LEN - 1 - back) % HISTORY_LEN;
+                let (line, len) = self.history[slot];
+                self.line = line;
+                self.len = len;
+            }
+            None => self.len = 0,
+        }
+        write!(out, "{}{}{}", REDRAW, self.prompt, self.as_str()).ok();
+    }
+
+    fn history_up<W: Write>(&mut self, out: &mut W) {
+        if self.history_count == 0 {
+            return;
+        }
+        let back = match self.browsing {
+            Some(back) => (back + 1).min(self.history_count - 1),
+            None => 0,
+        };
+        self.show_history(Some(back), out);
+    }
+
+    fn history_down<W: Write>(&mut self, out: &mut W) {
+        match self.browsing {
+            Some(0) => self.show_history(None, out),
+            Some(back) => self.show_history(Some(back - 1), out),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Arg;
+
+    const TABLE: &[Command<()>] = &[
+        Command { name: "drv on", help: "", args: &[], handler: () },
+        Command { name: "drv off", help: "", args: &[], handler: () },
+        Command { name: "drv regs", help: "", args: &[], handler: () },
+        Command { name: "sw", help: "", args: &[], handler: () },
+        Command { name: "swmode", help: "", args: &[Arg::choice("mode", &["manual", "openloop"])], handler: () },
+    ];
+
+    /// Returns the lines entered and the echo.
+    fn type_in(editor: &mut LineEditor, input: &[u8]) -> (Vec<String>, String) {
+        let mut echo = String::new();
+        let mut lines = Vec::new();
+        for byte in input {
+            if let Some(line) = editor.feed(*byte, TABLE, &mut echo) {
+                lines.push(line.to_string());
+            }
+        }
+        (lines, echo)
+    }
+
+    #[test]
+    fn fragmented_input() {
+        let mut editor = LineEditor::new("> ");
+        assert_eq!(type_in(&mut editor, b"drv ").0, Vec::<String>::new());
+        let (lines, echo) = type_in(&mut editor, b"on\r\n");
+        assert_eq!(lines, vec!["drv on"]);
+        assert_eq!(echo, "on\r\n");
+        assert_eq!(type_in(&mut editor, b"sw ah\nsw al\r").0, vec!["sw ah", "sw al"]);
+        assert_eq!(type_in(&mut editor, b"\r").0, vec![""]);
+    }
+
+    #[test]
+    fn backspace_and_ctrl_c() {
+        let mut editor = LineEditor::new("> ");
+        let (lines, echo) = type_in(&mut editor, b"drx\x08v\x7f\x7f\x7f\x7fsw\r");
+        assert_eq!(lines, vec!["sw"]);
+        assert_eq!(echo, "drx\x08 \x08v\x08 \x08\x08 \x08\x08 \x08sw\r\n");
+        assert_eq!(type_in(&mut editor, b"drv\x03sw\r").0, vec!["sw"]);
+    }
+
+    #[test]
+    fn line_length_is_limited() {
+        let mut editor = LineEditor::new("> ");
+        let long = [b'x'; LINE_LEN + 10];
+        let (_, echo) = type_in(&mut editor, &long);
+        assert_eq!(echo.len(), LINE_LEN);
+        assert_eq!(type_in(&mut editor, b"\r").0[0].len(), LINE_LEN);
+    }
+
+    #[test]
+    fn history() {
+        let mut editor = LineEditor::new("> ");
+        type_in(&mut editor, b"sw ah\rsw al\rsw al\r");
+        let up = b"\x1b[A";
+        let down = b"\x1b[B";
+        let (_, echo) = type_in(&mut editor, up);
+        assert_eq!(echo, "\r\x1b[K> sw al");
+        // Duplicates are stored once
+        type_in(&mut editor, up);
+        let (lines, _) = type_in(&mut editor, b"\r");
+        assert_eq!(lines, vec!["sw ah"]);
+
+        // History is now "sw ah", "sw al", "sw ah"
+        type_in(&mut editor, up);
+        type_in(&mut editor, up);
+        type_in(&mut editor, up);
+        type_in(&mut editor, down);
+        let (lines, _) = type_in(&mut editor, b" x\r");
+        assert_eq!(lines, vec!["sw al x"]);
+
+        type_in(&mut editor, up);
+        let (_, echo) = type_in(&mut editor, down);
+        assert_eq!(echo, "\r\x1b[K> ");
+        assert_eq!(type_in(&mut editor, b"drv\r").0, vec!["drv"]);
+    }
+
+    #[test]
+    fn history_keeps_last_entries() {
+        let mut editor = LineEditor::new("> ");
+        for i in 0..HISTORY_LEN + 2 {
+            type_in(&mut editor, format!("cmd{}\r", i).as_bytes());
+        }
+        for _ in 0..HISTORY_LEN + 2 {
+            type_in(&mut editor, b"\x1b[A");
+        }
+        assert_eq!(type_in(&mut editor, b"\r").0, vec![format!("cmd{}", 2)]);
+    }
+
+    #[test]
+    fn tab_completion() {
+        let mut editor = LineEditor::new("> ");
+        let (_, echo) = type_in(&mut editor, b"d\t");
+        assert_eq!(echo, "drv ");
+        let (_, echo) = type_in(&mut editor, b"r\t");
+        assert_eq!(echo, "regs ");
+        assert_eq!(type_in(&mut editor, b"\r").0, vec!["drv regs "]);
+
+        let (_, echo) = type_in(&mut editor, b"s\t");
+        assert_eq!(echo, "sw");
+        // Ambiguous, candidates are listed and the line is shown again
+        let (_, echo) = type_in(&mut editor, b"\t");
+        assert_eq!(echo, "\r\nsw  swmode  \r\n> sw");
+        let (_, echo) = type_in(&mut editor, b"m\t\to\t");
+        assert_eq!(echo, "mode \r\nmanual  openloop  \r\n> swmode openloop ");
+    }
+
+    #[test]
+    fn unknown_escape_sequences_are_dropped() {
+        let mut editor = LineEditor::new("> ");
+        assert_eq!(type_in(&mut editor, b"s\x1b[Cw\x1bx\r").0, vec!["sw"]);
+        // Delete, ctrl+right
+        assert_eq!(type_in(&mut editor, b"s\x1b[3~w\r").0, vec!["sw"]);
+        let (lines, echo) = type_in(&mut editor, b"s\x1b[1;5Cw\r");
+        assert_eq!(lines, vec!["sw"]);
+        assert_eq!(echo, "sw\r\n");
+    }
+}
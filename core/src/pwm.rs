@@ -0,0 +1,240 @@
+//! Advanced timer (TIM1) PWM parameters.
+
+use crate::stage::Phase;
+use crate::units::{Hertz, Nanoseconds};
+
+/// Output compare mode of a channel, CCMRx.OCxM.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OutputMode {
+    /// PWM mode 1, the reference is active while the counter is below the compare value.
+    Pwm,
+    /// PWM mode 2, the reference is active from the compare value on.
+    Pwm2,
+    /// Reference held active, as 100% duty.
+    ForceActive,
+    /// Reference held inactive, as 0% duty.
+    ForceInactive,
+}
+
+impl OutputMode {
+    pub fn ocm(self) -> u8 {
+        match self {
+            OutputMode::Pwm => 0b110,
+            OutputMode::Pwm2 => 0b111,
+            OutputMode::ForceActive => 0b101,
+            OutputMode::ForceInactive => 0b100,
+        }
+    }
+}
+
+/// What one leg of the bridge does while the PWM runs.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LegMode {
+    /// Complementary PWM with dead time.
+    Pwm,
+    /// Only the high side switches, the low side stays off.
+    PwmHigh,
+    /// Only the low side switches, on while the high side would be off.
+    PwmLow,
+    /// High side held on.
+    High,
+    /// Low side held on.
+    Low,
+    /// Both switches off.
+    Float,
+}
+
+/// CLI names in the order of `LegMode::ALL`.
+pub const LEG_MODE_NAMES: &[&str] = &["pwm", "pwm-high", "pwm-low", "high", "low", "float"];
+
+impl LegMode {
+    pub const ALL: [LegMode; 6] = [LegMode::Pwm, LegMode::PwmHigh, LegMode::PwmLow, LegMode::High, LegMode::Low, LegMode::Float];
+
+    pub fn from_name(name: &str) -> Option<LegMode> {
+        LEG_MODE_NAMES.iter().position(|n| *n == name).map(|i| LegMode::ALL[i])
+    }
+
+    pub fn name(self) -> &'static str {
+        LEG_MODE_NAMES[LegMode::ALL.iter().position(|m| *m == self).unwrap_or(0)]
+    }
+
+    /// Output compare mode and the (high, low) output enables of the channel.
+    pub fn outputs(self) -> (OutputMode, bool, bool) {
+        match self {
+            LegMode::Pwm => (OutputMode::Pwm, true, true),
+            LegMode::PwmHigh => (OutputMode::Pwm, true, false),
+            LegMode::PwmLow => (OutputMode::Pwm, false, true),
+            LegMode::High => (OutputMode::ForceActive, true, true),
+            LegMode::Low => (OutputMode::ForceInactive, true, true),
+            LegMode::Float => (OutputMode::Pwm, false, false),
+        }
+    }
+
+    /// Duty the leg actually applies with `pwm_duty` set, None while floating.
+    pub fn duty(self, pwm_duty: u8) -> Option<u8> {
+        match self {
+            LegMode::Pwm | LegMode::PwmHigh | LegMode::PwmLow => Some(pwm_duty),
+            LegMode::High => Some(100),
+            LegMode::Low => Some(0),
+            LegMode::Float => None,
+        }
+    }
+}
+
+/// Timer channel of a phase, counting from 0.
+pub fn channel(phase: Phase) -> usize {
+    match phase {
+        Phase::A => 0,
+        Phase::B => 1,
+        Phase::C => 2,
+    }
+}
+
+/// CCxP of the three channels, the outputs run with inverted OCx polarity.
+pub const CCER_POLARITY: u16 = 0x0222;
+
+/// CCER with the high side (CCxE) and low side (CCxNE) outputs of each channel enabled as given.
+pub fn ccer(outputs: [(bool, bool); 3]) -> u16 {
+    outputs.iter().enumerate().fold(CCER_POLARITY, |ccer, (channel, &(high, low))| {
+        ccer | ((high as u16) | (low as u16) << 2) << (4 * channel)
+    })
+}
+
+/// Compare value for `duty` percent of the reload value, above 100 is 100.
+pub fn duty_compare(duty: u8, arr: u16) -> u16 {
+    (duty.min(100) as u32 * arr as u32 / 100) as u16
+}
+
+/// Duty in percent of a compare value, rounded down.
+pub fn compare_duty(compare: u16, arr: u16) -> u8 {
+    if arr == 0 {
+        return 0;
+    }
+    (compare.min(arr) as u32 * 100 / arr as u32) as u8
+}
+
+/// Compare value keeping the same duty after the reload value changes.
+pub fn rescale_compare(compare: u16, old_arr: u16, new_arr: u16) -> u16 {
+    if old_arr == 0 {
+        return new_arr / 2;
+    }
+    (compare.min(old_arr) as u32 * new_arr as u32 / old_arr as u32) as u16
+}
+
+/// Auto reload value for center aligned PWM at `pwm`, the counter counts up and down
+/// so the period is two times the reload value.
+pub fn center_aligned_arr(timer: Hertz, pwm: Hertz) -> Option<u16> {
+    let arr = timer.0.checked_div(pwm.0)? / 2;
+    if arr < 2 || arr > u16::MAX as u32 {
+        None
+    } else {
+        Some(arr as u16)
+    }
+}
+
+fn ceil_div(a: u64, b: u64) -> u64 {
+    (a + b - 1) / b
+}
+
+/// BDTR.DTG value for at least `dead_time` with the dead time generator running at `timer` (CKD = 1).
+/// None if the dead time is longer than the generator can produce.
+pub fn dead_time_dtg(timer: Hertz, dead_time: Nanoseconds) -> Option<u8> {
+    let ticks = ceil_div(dead_time.0 as u64 * timer.0 as u64, 1_000_000_000);
+    if ticks <= 127 {
+        Some(ticks as u8)
+    } else if ticks <= (64 + 63) * 2 {
+        // 10xxxxxx: (64 + DTG[5:0]) * 2
+        Some(0x80 | (ceil_div(ticks, 2) - 64) as u8)
+    } else if ticks <= (32 + 31) * 8 {
+        // 110xxxxx: (32 + DTG[4:0]) * 8
+        Some(0xC0 | (ceil_div(ticks, 8) - 32) as u8)
+    } else if ticks <= (32 + 31) * 16 {
+        // 111xxxxx: (32 + DTG[4:0]) * 16
+        Some(0xE0 | (ceil_div(ticks, 16) - 32) as u8)
+    } else {
+        None
+    }
+}
+
+/// Dead time in timer ticks produced by a BDTR.DTG value.
+pub fn dtg_ticks(dtg: u8) -> u32 {
+    let dtg = dtg as u32;
+    if dtg & 0x80 == 0 {
+        dtg
+    } else if dtg & 0xC0 == 0x80 {
+        (64 + (dtg & 0x3F)) * 2
+    } else if dtg & 0xE0 == 0xC0 {
+        (32 + (dtg & 0x1F)) * 8
+    } else {
+        (32 + (dtg & 0x1F)) * 16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arr() {
+        assert_eq!(center_aligned_arr(Hertz(8_000_000), Hertz(20_000)), Some(200));
+        assert_eq!(center_aligned_arr(Hertz(168_000_000), Hertz(20_000)), Some(4200));
+        assert_eq!(center_aligned_arr(Hertz(8_000_000), Hertz(0)), None);
+        assert_eq!(center_aligned_arr(Hertz(8_000_000), Hertz(4_000_000)), None);
+        assert_eq!(center_aligned_arr(Hertz(168_000_000), Hertz(1)), None);
+    }
+
+    #[test]
+    fn dead_time_is_never_shorter() {
+        for &hz in [8_000_000u32, 84_000_000, 168_000_000].iter() {
+            for ns in (0..6000).step_by(7) {
+                let dtg = dead_time_dtg(Hertz(hz), Nanoseconds(ns)).unwrap();
+                let actual_ns = dtg_ticks(dtg) as u64 * 1_000_000_000 / hz as u64;
+                assert!(actual_ns + 1 >= ns as u64, "{}Hz {}ns -> {}ns", hz, ns, actual_ns);
+            }
+        }
+    }
+
+    #[test]
+    fn channels_and_duties() {
+        assert_eq!(ccer([(true, true); 3]), 0x0777);
+        assert_eq!(ccer([(false, false); 3]), CCER_POLARITY);
+        assert_eq!(ccer([(true, false), (false, true), (false, false)]), 0x0263);
+        assert_eq!(channel(Phase::C), 2);
+        assert_eq!(OutputMode::ForceActive.ocm(), 5);
+
+        assert_eq!(duty_compare(50, 200), 100);
+        assert_eq!(duty_compare(150, 200), 200);
+        assert_eq!(duty_compare(33, u16::MAX), 21626);
+        assert_eq!(compare_duty(21626, u16::MAX), 32);
+        assert_eq!(compare_duty(300, 200), 100);
+        assert_eq!(compare_duty(10, 0), 0);
+        assert_eq!(rescale_compare(100, 200, 4200), 2100);
+        assert_eq!(rescale_compare(5, 0, 400), 200);
+    }
+
+    #[test]
+    fn leg_modes() {
+        for (mode, name) in LegMode::ALL.iter().zip(LEG_MODE_NAMES.iter()) {
+            assert_eq!(LegMode::from_name(name), Some(*mode));
+            assert_eq!(mode.name(), *name);
+        }
+        assert_eq!(LegMode::from_name("z"), None);
+        assert_eq!(LegMode::PwmLow.outputs(), (OutputMode::Pwm, false, true));
+        assert_eq!(LegMode::High.outputs().0, OutputMode::ForceActive);
+        assert_eq!(LegMode::PwmHigh.duty(30), Some(30));
+        assert_eq!(LegMode::Low.duty(30), Some(0));
+        assert_eq!(LegMode::Float.duty(30), None);
+    }
+
+    #[test]
+    fn dead_time_ranges() {
+        // 125ns ticks
+        assert_eq!(dead_time_dtg(Hertz(8_000_000), Nanoseconds(1000)), Some(8));
+        assert_eq!(dead_time_dtg(Hertz(8_000_000), Nanoseconds(1001)), Some(9));
+        assert_eq!(dead_time_dtg(Hertz(168_000_000), Nanoseconds(1000)), Some(0x80 | 20));
+        assert_eq!(dtg_ticks(0x80 | 20), 168);
+        assert_eq!(dtg_ticks(0xFF), 1008);
+        assert_eq!(dead_time_dtg(Hertz(8_000_000), Nanoseconds(126_000)), Some(0xFF));
+        assert_eq!(dead_time_dtg(Hertz(8_000_000), Nanoseconds(126_001)), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Remote control over CAN, mirrors `cli` commands for boards without a debug probe attached.
+//!
+//! All frames use extended IDs: `0x1A5 << 16 | kind << 8 | node_id`.
+//! Command frame: `[opcode, args..]`, reply frame: `[opcode, status, data..]`.
+use crate::stage::{Channel, Phase, SwitchState};
+
+const ID_BASE: u32 = 0x1A5 << 16;
+
+#[derive(Copy, Clone)]
+#[repr(u8)]
+pub enum FrameKind {
+    Command = 0x01,
+    Reply = 0x02,
+    /// `[drv_enabled, drv_fault, mode, hall_idx]`
+    Status = 0x10,
+    /// `v_in, v_a, v_b, v_c` as i16 LE in 10mV units
+    Voltages = 0x11,
+    /// `i_a, i_b, i_c` as i16 LE in 10mA units
+    Currents = 0x12,
+    /// Empty, `can test` sends it to see the bus acknowledge. Receivers ignore it.
+    Probe = 0x20,
+}
+
+pub fn frame_id(kind: FrameKind, node_id: u8) -> u32 {
+    ID_BASE | ((kind as u32) << 8) | node_id as u32
+}
+
+pub fn command_id(node_id: u8) -> u32 {
+    frame_id(FrameKind::Command, node_id)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 1,
+    BadArgument = 2,
+    WrongMode = 3,
+}
+
+mod opcode {
+    pub const SWITCH_MODE: u8 = 0x01;
+    pub const SWITCH: u8 = 0x02;
+    pub const DUTY: u8 = 0x03;
+    pub const DRV: u8 = 0x04;
+    pub const MEASURE: u8 = 0x05;
+    pub const TELEMETRY: u8 = 0x06;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// 0 - manual, 1 - openloop
+    SwitchMode(bool),
+    Switch(Phase, SwitchState),
+    Duty(Phase, u8),
+    Drv(bool),
+    Measure(Channel),
+    Telemetry(u8),
+}
+
+fn arg(data: &[u8], idx: usize) -> Result<u8, Status> {
+    data.get(idx).copied().ok_or(Status::BadArgument)
+}
+
+fn phase(data: &[u8], idx: usize) -> Result<Phase, Status> {
+    match arg(data, idx)? {
+        0 => Ok(Phase::A),
+        1 => Ok(Phase::B),
+        2 => Ok(Phase::C),
+        _ => Err(Status::BadArgument)
+    }
+}
+
+fn flag(data: &[u8], idx: usize) -> Result<bool, Status> {
+    match arg(data, idx)? {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(Status::BadArgument)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Command, Status> {
+    let opcode = arg(data, 0).map_err(|_| Status::UnknownCommand)?;
+    match opcode {
+        opcode::SWITCH_MODE => Ok(Command::SwitchMode(flag(data, 1)?)),
+        opcode::SWITCH => {
+            let state = match arg(data, 2)? {
+                0 => SwitchState::Off,
+                1 => SwitchState::High,
+                2 => SwitchState::Low,
+                _ => return Err(Status::BadArgument)
+            };
+            Ok(Command::Switch(phase(data, 1)?, state))
+        }
+        opcode::DUTY => {
+            let duty = arg(data, 2)?;
+            if duty > 100 {
+                return Err(Status::BadArgument);
+            }
+            Ok(Command::Duty(phase(data, 1)?, duty))
+        }
+        opcode::DRV => Ok(Command::Drv(flag(data, 1)?)),
+        opcode::MEASURE => {
+            let channel = match arg(data, 1)? {
+                0 => Channel::VIn,
+                1 => Channel::VA,
+                2 => Channel::VB,
+                3 => Channel::VC,
+                4 => Channel::IA,
+                5 => Channel::IB,
+                6 => Channel::IC,
+                7 => Channel::VCan,
+                _ => return Err(Status::BadArgument)
+            };
+            Ok(Command::Measure(channel))
+        }
+        opcode::TELEMETRY => Ok(Command::Telemetry(arg(data, 1)?)),
+        _ => Err(Status::UnknownCommand)
+    }
+}
+
+/// Reply to a command with opcode `opcode`: `[opcode, status, value as i32 LE if any]`.
+pub fn encode_reply(opcode: u8, result: Result<Option<i32>, Status>, buf: &mut [u8; 6]) -> usize {
+    buf[0] = opcode;
+    buf[1] = Status::Ok as u8;
+    match result {
+        Ok(Some(value)) => {
+            buf[2..6].copy_from_slice(&value.to_le_bytes());
+            6
+        }
+        Ok(None) => 2,
+        Err(status) => {
+            buf[1] = status as u8;
+            2
+        }
+    }
+}
+
+pub fn saturate_i16(value: i32) -> i16 {
+    value.clamp(i16::MIN as i32, i16::MAX as i32) as i16
+}
+
+/// Scale values down by `divider` and pack as saturated i16 LE.
+pub fn pack_i16(values: &[i32], divider: i32, buf: &mut [u8; 8]) -> usize {
+    for (i, v) in values.iter().enumerate() {
+        buf[i * 2..i * 2 + 2].copy_from_slice(&saturate_i16(v / divider).to_le_bytes());
+    }
+    values.len() * 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids() {
+        assert_eq!(command_id(5), 0x1A5_0105);
+        assert_eq!(frame_id(FrameKind::Currents, 127), 0x1A5_127F);
+        assert!(frame_id(FrameKind::Currents, 127) <= 0x1FFF_FFFF);
+    }
+
+    #[test]
+    fn decode_commands() {
+        assert_eq!(decode(&[0x01, 1]), Ok(Command::SwitchMode(true)));
+        assert_eq!(decode(&[0x02, 2, 2]), Ok(Command::Switch(Phase::C, SwitchState::Low)));
+        assert_eq!(decode(&[0x03, 0, 55]), Ok(Command::Duty(Phase::A, 55)));
+        assert_eq!(decode(&[0x04, 0]), Ok(Command::Drv(false)));
+        assert_eq!(decode(&[0x05, 7]), Ok(Command::Measure(Channel::VCan)));
+        assert_eq!(decode(&[0x06, 10]), Ok(Command::Telemetry(10)));
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(decode(&[]), Err(Status::UnknownCommand));
+        assert_eq!(decode(&[0x7F]), Err(Status::UnknownCommand));
+        assert_eq!(decode(&[0x03, 0]), Err(Status::BadArgument));
+        assert_eq!(decode(&[0x03, 3, 50]), Err(Status::BadArgument));
+        assert_eq!(decode(&[0x03, 0, 101]), Err(Status::BadArgument));
+        assert_eq!(decode(&[0x01, 2]), Err(Status::BadArgument));
+    }
+
+    #[test]
+    fn reply() {
+        let mut buf = [0u8; 6];
+        assert_eq!(encode_reply(0x05, Ok(Some(-2)), &mut buf), 6);
+        assert_eq!(buf, [0x05, 0, 0xFE, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(encode_reply(0x03, Err(Status::WrongMode), &mut buf), 2);
+        assert_eq!(buf[..2], [0x03, 3]);
+    }
+
+    #[test]
+    fn pack_saturates() {
+        let mut buf = [0u8; 8];
+        let len = pack_i16(&[12_340, -500_000, 500_000], 10, &mut buf);
+        assert_eq!(len, 6);
+        assert_eq!(i16::from_le_bytes([buf[0], buf[1]]), 1234);
+        assert_eq!(i16::from_le_bytes([buf[2], buf[3]]), i16::MIN);
+        assert_eq!(i16::from_le_bytes([buf[4], buf[5]]), i16::MAX);
+    }
+}
@@ -0,0 +1,349 @@
+//! Replies to CLI commands, as text for a terminal or as one JSON object per line for scripts.
+//!
+//! A JSON reply looks like `{"fields":{"bitrate":500000},"status":"ok","code":0}`, failures add
+//! `"error"` and `"message"`, argument errors also `"detail"`, `"column"`, `"token"` and `"expected"`.
+//! Status comes last because fields are written out as the handler produces them.
+
+use crate::cli::{next_words, Command, DispatchError};
+use crate::vt100;
+use core::fmt::{self, Display, Write};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Human,
+    Json,
+}
+
+/// Codes follow `remote::Status` where they overlap.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ErrorKind {
+    UnknownCommand = 1,
+    BadArgument = 2,
+    WrongMode = 3,
+    NotSupported = 4,
+    NotFound = 5,
+    Failed = 6,
+}
+
+impl ErrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorKind::UnknownCommand => "unknown_command",
+            ErrorKind::BadArgument => "bad_argument",
+            ErrorKind::WrongMode => "wrong_mode",
+            ErrorKind::NotSupported => "not_supported",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Failed => "failed",
+        }
+    }
+}
+
+/// Why a command handler didn't complete.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    pub message: &'static str,
+}
+
+impl Failure {
+    pub const fn new(kind: ErrorKind, message: &'static str) -> Self {
+        Failure { kind, message }
+    }
+}
+
+pub type Outcome = Result<(), Failure>;
+
+/// Escapes whatever is written through it for use inside a JSON string.
+struct Escaped<'a, W: Write>(&'a mut W);
+
+impl<W: Write> Write for Escaped<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// JSON object written out field by field, closed by `end`.
+pub struct JsonObject<'a, W: Write> {
+    out: &'a mut W,
+    empty: bool,
+}
+
+impl<'a, W: Write> JsonObject<'a, W> {
+    pub fn begin(out: &'a mut W) -> Self {
+        out.write_char('{').ok();
+        JsonObject { out, empty: true }
+    }
+
+    fn key(&mut self, name: impl Display) {
+        if !self.empty {
+            self.out.write_char(',').ok();
+        }
+        self.empty = false;
+        self.out.write_char('"').ok();
+        write!(Escaped(self.out), "{}", name).ok();
+        self.out.write_str("\":").ok();
+    }
+
+    /// `value` has to format as a JSON literal: a number, `true` or `false`.
+    pub fn field(&mut self, name: impl Display, value: impl Display) {
+        self.key(name);
+        write!(self.out, "{}", value).ok();
+    }
+
+    pub fn text_field(&mut self, name: impl Display, value: impl Display) {
+        self.key(name);
+        self.out.write_char('"').ok();
+        write!(Escaped(self.out), "{}", value).ok();
+        self.out.write_char('"').ok();
+    }
+
+    pub fn end(self) -> &'a mut W {
+        self.out.write_char('}').ok();
+        self.out
+    }
+}
+
+enum Sink<'a, W: Write> {
+    Human(&'a mut W),
+    Json(JsonObject<'a, W>),
+}
+
+/// Output of one command, `finish` writes the status.
+pub struct Response<'a, W: Write> {
+    sink: Sink<'a, W>,
+}
+
+impl<'a, W: Write> Response<'a, W> {
+    pub fn new(out: &'a mut W, mode: Mode) -> Self {
+        let sink = match mode {
+            Mode::Human => Sink::Human(out),
+            Mode::Json => {
+                out.write_str("{\"fields\":").ok();
+                Sink::Json(JsonObject::begin(out))
+            }
+        };
+        Response { sink }
+    }
+
+    pub fn mode(&self) -> Mode {
+        match self.sink {
+            Sink::Human(_) => Mode::Human,
+            Sink::Json(_) => Mode::Json,
+        }
+    }
+
+    /// Number or bool, shown with `unit` to humans only.
+    pub fn field(&mut self, name: impl Display, value: impl Display, unit: &str) {
+        match &mut self.sink {
+            Sink::Human(out) => {
+                writeln!(out, "{}: {}{}", name, value, unit).ok();
+            }
+            Sink::Json(object) => object.field(name, value),
+        }
+    }
+
+    pub fn text_field(&mut self, name: impl Display, value: impl Display) {
+        match &mut self.sink {
+            Sink::Human(out) => {
+                writeln!(out, "{}: {}", name, value).ok();
+            }
+            Sink::Json(object) => object.text_field(name, value),
+        }
+    }
+
+    /// A line of text for humans, the `message` field in JSON.
+    pub fn message(&mut self, text: &str) {
+        match &mut self.sink {
+            Sink::Human(out) => {
+                writeln!(out, "{}", text).ok();
+            }
+            Sink::Json(object) => object.text_field("message", text),
+        }
+    }
+
+    pub fn finish(self, outcome: Outcome) {
+        match (self.sink, outcome) {
+            (Sink::Human(out), Ok(())) => {
+                writeln!(out, "{}Ok{}", vt100::GREEN, vt100::DEFAULT).ok();
+            }
+            (Sink::Human(out), Err(failure)) => {
+                let color = if failure.kind == ErrorKind::Failed { vt100::RED } else { vt100::YELLOW };
+                writeln!(out, "{}{}{}", color, failure.message, vt100::DEFAULT).ok();
+            }
+            (Sink::Json(object), Ok(())) => {
+                writeln!(object.end(), ",\"status\":\"ok\",\"code\":0}}").ok();
+            }
+            (Sink::Json(object), Err(failure)) => {
+                let out = object.end();
+                out.write_char(',').ok();
+                let mut status = JsonObject { out, empty: true };
+                write_failure(&mut status, failure.kind, failure.message);
+                writeln!(status.end()).ok();
+            }
+        }
+    }
+
+    /// Reply to a line that didn't parse, points at the bad word for humans.
+    pub fn finish_dispatch_error<H>(self, table: &[Command<H>], line: &str, error: &DispatchError) {
+        let (kind, message, detail) = match error {
+            DispatchError::Empty => (ErrorKind::UnknownCommand, "empty line", "empty"),
+            DispatchError::Unknown(_) => (ErrorKind::UnknownCommand, "unknown command", "unknown"),
+            DispatchError::Incomplete(_) => (ErrorKind::UnknownCommand, "incomplete command", "incomplete"),
+            DispatchError::Missing(_) => (ErrorKind::BadArgument, "missing argument", "missing"),
+            DispatchError::Invalid(_, _) => (ErrorKind::BadArgument, "invalid argument", "invalid"),
+            DispatchError::OutOfRange(_, _) => (ErrorKind::BadArgument, "argument out of range", "out_of_range"),
+            DispatchError::Extra(_) => (ErrorKind::BadArgument, "unexpected argument", "extra"),
+        };
+        let object = match self.sink {
+            Sink::Human(out) => {
+                out.write_str(vt100::YELLOW).ok();
+                crate::cli::write_error(table, line, error, out).ok();
+                out.write_str(vt100::DEFAULT).ok();
+                return;
+            }
+            Sink::Json(object) => object,
+        };
+        let out = object.end();
+        out.write_char(',').ok();
+        let mut status = JsonObject { out, empty: true };
+        write_failure(&mut status, kind, message);
+        status.text_field("detail", detail);
+        match *error {
+            DispatchError::Unknown(token) | DispatchError::Extra(token) => {
+                status.field("column", error.column(line));
+                status.text_field("token", token);
+            }
+            DispatchError::Invalid(token, arg) | DispatchError::OutOfRange(token, arg) => {
+                status.field("column", error.column(line));
+                status.text_field("token", token);
+                status.text_field("expected", arg);
+            }
+            DispatchError::Missing(arg) => status.text_field("expected", arg),
+            DispatchError::Incomplete(words) => status.text_field("expected", Choices(next_words(table, words))),
+            DispatchError::Empty => {}
+        }
+        writeln!(status.end()).ok();
+    }
+}
+
+fn write_failure<W: Write>(status: &mut JsonObject<W>, kind: ErrorKind, message: &str) {
+    status.text_field("status", "error");
+    status.field("code", kind as u8);
+    status.text_field("error", kind.name());
+    status.text_field("message", message);
+}
+
+/// Words separated by slashes, like a choice in a usage string.
+struct Choices<I>(I);
+
+impl<I: Iterator<Item = &'static str> + Clone> Display for Choices<I> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, word) in self.0.clone().enumerate() {
+            if i != 0 {
+                f.write_char('/')?;
+            }
+            f.write_str(word)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::{dispatch, Arg, Unit};
+
+    const TABLE: &[Command<()>] = &[
+        Command { name: "can on", help: "", args: &[], handler: () },
+        Command { name: "can off", help: "", args: &[], handler: () },
+        Command { name: "duty", help: "", args: &[Arg::int("duty", 0, 100, Unit::Percent)], handler: () },
+    ];
+
+    fn reply(mode: Mode, f: impl FnOnce(&mut Response<String>) -> Outcome) -> String {
+        let mut out = String::new();
+        let mut response = Response::new(&mut out, mode);
+        let outcome = f(&mut response);
+        response.finish(outcome);
+        out
+    }
+
+    fn error(mode: Mode, line: &str) -> String {
+        let mut out = String::new();
+        let error = dispatch(TABLE, line).err().unwrap();
+        Response::new(&mut out, mode).finish_dispatch_error(TABLE, line, &error);
+        out
+    }
+
+    #[test]
+    fn ok_with_fields() {
+        let fields = |r: &mut Response<String>| {
+            r.field("bitrate", 500_000, "bps");
+            r.field("power", true, "");
+            r.text_field("bus", "BusOff");
+            Ok(())
+        };
+        assert_eq!(
+            reply(Mode::Json, fields),
+            "{\"fields\":{\"bitrate\":500000,\"power\":true,\"bus\":\"BusOff\"},\"status\":\"ok\",\"code\":0}\n"
+        );
+        assert_eq!(
+            reply(Mode::Human, fields),
+            format!("bitrate: 500000bps\npower: true\nbus: BusOff\n{}Ok{}\n", vt100::GREEN, vt100::DEFAULT)
+        );
+        assert_eq!(reply(Mode::Json, |_| Ok(())), "{\"fields\":{},\"status\":\"ok\",\"code\":0}\n");
+    }
+
+    #[test]
+    fn failure() {
+        let failed = |r: &mut Response<String>| {
+            r.message("Trying");
+            Err(Failure::new(ErrorKind::WrongMode, "Not in \"openloop\" mode"))
+        };
+        assert_eq!(
+            reply(Mode::Json, failed),
+            "{\"fields\":{\"message\":\"Trying\"},\"status\":\"error\",\"code\":3,\"error\":\"wrong_mode\",\
+             \"message\":\"Not in \\\"openloop\\\" mode\"}\n"
+        );
+        assert_eq!(
+            reply(Mode::Human, failed),
+            format!("Trying\n{}Not in \"openloop\" mode{}\n", vt100::YELLOW, vt100::DEFAULT)
+        );
+    }
+
+    #[test]
+    fn dispatch_errors() {
+        assert_eq!(
+            error(Mode::Json, "duty 150%"),
+            "{\"fields\":{},\"status\":\"error\",\"code\":2,\"error\":\"bad_argument\",\"message\":\"argument out of range\",\
+             \"detail\":\"out_of_range\",\"column\":5,\"token\":\"150%\",\"expected\":\"<duty 0..100%>\"}\n"
+        );
+        assert_eq!(
+            error(Mode::Json, "can"),
+            "{\"fields\":{},\"status\":\"error\",\"code\":1,\"error\":\"unknown_command\",\"message\":\"incomplete command\",\
+             \"detail\":\"incomplete\",\"expected\":\"on/off\"}\n"
+        );
+        assert_eq!(
+            error(Mode::Human, "duty x"),
+            format!("{}duty x\n     ^ expected <duty 0..100%>\n{}", vt100::YELLOW, vt100::DEFAULT)
+        );
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        let mut out = String::new();
+        let mut object = JsonObject::begin(&mut out);
+        object.text_field("text", "a\\b\n\x1b");
+        object.end();
+        assert_eq!(out, "{\"text\":\"a\\\\b\\n\\u001b\"}");
+    }
+}
@@ -0,0 +1,183 @@
+//! Short check before a motor is connected: each leg in turn is pulsed at a low duty in openloop
+//! mode with the other legs at 0%, so their low sides are on. The phases must be disconnected,
+//! motor windings connect them and read as a phase to phase short. Current in another leg means
+//! the two phases are connected, and a sagging bus means the pulsed phase is shorted to ground.
+//!
+//! The readings are taken by the PWM timer: it triggers one conversion at the bottom of its
+//! center aligned count, the middle of every high side on-time, so no pulse of the tested leg
+//! goes unsampled. One channel is converted per period, the currents of the two other phases and
+//! V_IN take turns, so a short is seen within `CHANNELS` periods of its first pulse and the stage
+//! stops right after, 150us at 20kHz. At `DUTY` the on-time has to outlast the dead time by the
+//! ADC sample time, 2.5us against 1us and 0.75us with the default settings.
+//!
+//! The DRV's VDS overcurrent protection pulls nFAULT within microseconds. Only a board with
+//! `Board::fault_break` routes it to the TIM1 break input, which stops the outputs in hardware
+//! right away. Without it, rev1 among them, the software check above is the only abort and the
+//! fault just turns the gate driver off. The command reports which one is active and a DRV
+//! shutdown as a failed test.
+
+use crate::board::Board;
+use crate::config::Config;
+use crate::stage::{convert, measure, Channel, Phase, PowerStage, SwitchState};
+use crate::units::{MilliAmperes, MilliVolts};
+use core::fmt;
+
+/// Duty of the pulsed leg.
+pub const DUTY: u8 = 5;
+/// Channels converted in turn while a leg is pulsed, see `channels`.
+pub const CHANNELS: usize = 3;
+/// PWM periods each leg is pulsed for, 15ms at 20kHz.
+pub const PERIODS: usize = 300;
+/// Phase current above this is a short.
+pub const SHORT_MA: i32 = 500;
+/// V_IN below this share of its idle value is a short to ground.
+pub const SAG_PERCENT: i32 = 90;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Short {
+    /// The pulsed phase first.
+    Phases(Phase, Phase),
+    Ground(Phase),
+    /// The DRV shut down during the check, its overcurrent protection was faster.
+    Driver,
+}
+
+impl fmt::Display for Short {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Short::Phases(a, b) => write!(f, "{}-{}", a.name(), b.name()),
+            Short::Ground(phase) => write!(f, "{}-ground", phase.name()),
+            Short::Driver => write!(f, "drv-fault"),
+        }
+    }
+}
+
+/// Latest readings while `pulsed` runs at `DUTY`, currents in phase order.
+pub fn check(pulsed: Phase, currents: [MilliAmperes; 3], v_in: MilliVolts, v_in_idle: MilliVolts) -> Option<Short> {
+    if v_in.0 < v_in_idle.0 * SAG_PERCENT / 100 {
+        return Some(Short::Ground(pulsed));
+    }
+    if currents.iter().all(|i| i.0.abs() <= SHORT_MA) {
+        return None;
+    }
+    // The current returns through the low side of the other phase
+    let (&other, _) = Phase::ALL.iter().zip(currents.iter())
+        .filter(|(phase, _)| **phase != pulsed)
+        .max_by_key(|(_, i)| i.0.abs())?;
+    Some(Short::Phases(pulsed, other))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Report {
+    /// Bus voltage with all switches off.
+    pub v_in: MilliVolts,
+    /// The short that stopped the check, None if all legs passed.
+    pub short: Option<Short>,
+    /// Every period was sampled. False if the PWM trigger didn't come, the check stopped there
+    /// without a verdict.
+    pub complete: bool,
+    /// Largest current seen in any phase.
+    pub i_peak: MilliAmperes,
+    pub v_in_min: MilliVolts,
+}
+
+/// Name of the fastest abort `board` has, for the report.
+pub fn abort_path(board: &Board) -> &'static str {
+    match board.fault_break {
+        Some(_) => "nfault-break",
+        None => "software",
+    }
+}
+
+/// The currents of the phases other than `pulsed`, the pulsed leg's own low side shunt carries
+/// nothing in its on-time, then V_IN.
+pub fn channels(pulsed: Phase) -> [Channel; CHANNELS] {
+    let mut channels = [Channel::VIn; CHANNELS];
+    for (channel, phase) in channels.iter_mut().zip(Phase::ALL.iter().filter(|&&phase| phase != pulsed)) {
+        *channel = phase.current_channel();
+    }
+    channels
+}
+
+/// Pulses the legs one after another, the stage is left in manual mode with all switches off.
+pub fn run<S: PowerStage + ?Sized>(stage: &mut S, board: &Board, config: &Config) -> Report {
+    stop(stage);
+    let v_in = MilliVolts(measure(stage, board, config, Channel::VIn));
+    let mut report = Report { v_in, short: None, complete: false, i_peak: MilliAmperes(0), v_in_min: v_in };
+
+    stage.switch_to_openloop();
+    for &phase in Phase::ALL.iter() {
+        stage.set_duty(phase, 0);
+    }
+    for &pulsed in Phase::ALL.iter() {
+        stage.set_duty(pulsed, DUTY);
+        let mut currents = [MilliAmperes(0); 3];
+        let mut v_now = v_in;
+        for &channel in channels(pulsed).iter().cycle().take(PERIODS) {
+            let v_adc = match stage.sample_on_time(channel) {
+                Some(v_adc) => v_adc,
+                None => {
+                    stop(stage);
+                    return report;
+                }
+            };
+            let value = convert(board, config, channel, v_adc);
+            if channel == Channel::VIn {
+                v_now = MilliVolts(value);
+                report.v_in_min.0 = report.v_in_min.0.min(value);
+            } else {
+                for (i, phase) in currents.iter_mut().zip(Phase::ALL.iter()) {
+                    if phase.current_channel() == channel {
+                        *i = MilliAmperes(value);
+                    }
+                }
+                report.i_peak.0 = report.i_peak.0.max(value.abs());
+            }
+            if let Some(short) = check(pulsed, currents, v_now, v_in) {
+                stop(stage);
+                report.short = Some(short);
+                report.complete = true;
+                return report;
+            }
+        }
+        stage.set_duty(pulsed, 0);
+    }
+    stop(stage);
+    report.complete = true;
+    report
+}
+
+fn stop<S: PowerStage + ?Sized>(stage: &mut S) {
+    stage.switch_to_manual();
+    for &phase in Phase::ALL.iter() {
+        stage.set_switch(phase, SwitchState::Off);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_checks() {
+        let idle = MilliVolts(24_000);
+        let quiet = [MilliAmperes(20), MilliAmperes(-30), MilliAmperes(10)];
+        assert_eq!(check(Phase::A, quiet, MilliVolts(23_900), idle), None);
+        assert_eq!(check(Phase::A, quiet, MilliVolts(12_000), idle), Some(Short::Ground(Phase::A)));
+        let b_to_c = [MilliAmperes(20), MilliAmperes(900), MilliAmperes(-880)];
+        assert_eq!(check(Phase::B, b_to_c, idle, idle), Some(Short::Phases(Phase::B, Phase::C)));
+        // Only the pulsed leg sees it, the larger of the other two is the best guess
+        let own = [MilliAmperes(1200), MilliAmperes(-100), MilliAmperes(-300)];
+        assert_eq!(check(Phase::A, own, idle, idle), Some(Short::Phases(Phase::A, Phase::C)));
+        assert_eq!(Short::Phases(Phase::B, Phase::C).to_string(), "b-c");
+        assert_eq!(Short::Ground(Phase::A).to_string(), "a-ground");
+        assert_eq!(Short::Driver.to_string(), "drv-fault");
+    }
+
+    #[test]
+    fn pulsed_leg_is_not_sampled() {
+        assert_eq!(channels(Phase::A), [Channel::IB, Channel::IC, Channel::VIn]);
+        assert_eq!(channels(Phase::B), [Channel::IA, Channel::IC, Channel::VIn]);
+        assert_eq!(channels(Phase::C), [Channel::IA, Channel::IB, Channel::VIn]);
+    }
+}
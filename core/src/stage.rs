@@ -0,0 +1,105 @@
+//! Power stage vocabulary shared by the firmware, remote protocols and the CLI.
+use crate::board::Board;
+use crate::config::Config;
+use crate::units::MilliVolts;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Phase {
+    A,
+    B,
+    C
+}
+
+impl Phase {
+    pub const ALL: [Phase; 3] = [Phase::A, Phase::B, Phase::C];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::A => "a",
+            Phase::B => "b",
+            Phase::C => "c",
+        }
+    }
+
+    /// The following phase, wrapping from C to A.
+    pub fn next(self) -> Phase {
+        match self {
+            Phase::A => Phase::B,
+            Phase::B => Phase::C,
+            Phase::C => Phase::A,
+        }
+    }
+
+    pub fn voltage_channel(self) -> Channel {
+        match self {
+            Phase::A => Channel::VA,
+            Phase::B => Channel::VB,
+            Phase::C => Channel::VC,
+        }
+    }
+
+    pub fn current_channel(self) -> Channel {
+        match self {
+            Phase::A => Channel::IA,
+            Phase::B => Channel::IB,
+            Phase::C => Channel::IC,
+        }
+    }
+}
+
+/// State of a half-bridge driven manually.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum SwitchState {
+    High,
+    Low,
+    Off,
+}
+
+/// Measurement channel, voltages are in mV and currents in mA.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Channel {
+    VIn,
+    VA,
+    VB,
+    VC,
+    IA,
+    IB,
+    IC,
+    VCan,
+}
+
+/// Gate outputs, ADC and hall inputs of a power stage, implemented by the board support
+/// in the firmware and by the simulator, so test routines can run on both.
+pub trait PowerStage {
+    /// Returns false if already in manual mode.
+    fn switch_to_manual(&mut self) -> bool;
+    /// Returns false if already in openloop mode.
+    fn switch_to_openloop(&mut self) -> bool;
+    /// Drives a half-bridge directly, returns false if not in manual mode.
+    fn set_switch(&mut self, phase: Phase, state: SwitchState) -> bool;
+    /// None if not in manual mode.
+    fn switch(&self, phase: Phase) -> Option<SwitchState>;
+    /// PWM duty in percent, returns false if not in openloop mode.
+    fn set_duty(&mut self, phase: Phase, duty: u8) -> bool;
+    /// Voltage at the ADC pin of the channel.
+    fn adc_voltage(&mut self, channel: Channel) -> MilliVolts;
+    /// Voltage at the ADC pin of the channel converted in the middle of the high side on-times,
+    /// triggered by the PWM timer in the next period. None if not in openloop mode or the
+    /// trigger didn't come.
+    fn sample_on_time(&mut self, channel: Channel) -> Option<MilliVolts>;
+    fn hall(&self) -> (bool, bool, bool);
+    fn delay_ms(&mut self, ms: u32);
+    fn delay_us(&mut self, us: u32);
+}
+
+/// Returns voltage in mV or current in mA depending on the channel,
+/// current amplifier offsets and gain are taken from `config`.
+pub fn measure<S: PowerStage + ?Sized>(stage: &mut S, board: &Board, config: &Config, channel: Channel) -> i32 {
+    let v_adc = stage.adc_voltage(channel);
+    convert(board, config, channel, v_adc)
+}
+
+/// `measure` for a voltage already taken at the ADC pin.
+pub fn convert(board: &Board, config: &Config, channel: Channel, v_adc: MilliVolts) -> i32 {
+    config.correct(channel, board.convert(channel, v_adc - config.offset(channel), config.current_sense_gain))
+}
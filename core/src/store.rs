@@ -0,0 +1,240 @@
+//! Configuration records kept in two flash sectors.
+//!
+//! Every save appends a record with an incremented sequence number to the active sector, so a sector
+//! is only erased after it filled up. Then the other sector is erased and takes the next record, the
+//! previous one stays intact until the sectors swap again. Loading picks the valid record with the
+//! highest sequence number.
+
+use crate::board::Board;
+use crate::config::{Config, RecordError, RECORD_LEN};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct FlashError;
+
+/// Two equally sized, erasable sectors reserved for the configuration.
+pub trait Flash {
+    fn sector_size(&self) -> u32;
+    fn read(&mut self, sector: usize, offset: u32, buf: &mut [u8]);
+    /// Erased flash reads 0xFF, programming can only clear bits.
+    fn program(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), FlashError>;
+    fn erase(&mut self, sector: usize) -> Result<(), FlashError>;
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StoreError {
+    Flash,
+    /// Read back differs from what was programmed.
+    Verify,
+}
+
+impl From<FlashError> for StoreError {
+    fn from(_: FlashError) -> Self {
+        StoreError::Flash
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Latest {
+    sector: usize,
+    sequence: u32,
+    config: Config,
+}
+
+/// Where the next record goes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+struct Scan {
+    latest: Option<Latest>,
+    /// First erased slot after the latest record in its sector.
+    next_free: Option<u32>,
+}
+
+fn slots<F: Flash>(flash: &F) -> u32 {
+    flash.sector_size() / RECORD_LEN as u32
+}
+
+fn scan<F: Flash>(flash: &mut F, defaults: Config) -> Scan {
+    let mut latest: Option<Latest> = None;
+    let mut free = [None, None];
+    let mut record = [0; RECORD_LEN];
+    for (sector, free) in free.iter_mut().enumerate() {
+        for slot in 0..slots(flash) {
+            let offset = slot * RECORD_LEN as u32;
+            flash.read(sector, offset, &mut record);
+            match Config::from_record(&record, defaults) {
+                Ok((config, sequence)) => {
+                    if latest.map_or(true, |l| sequence > l.sequence) {
+                        latest = Some(Latest { sector, sequence, config });
+                    }
+                }
+                Err(RecordError::Erased) => {
+                    *free = Some(offset);
+                    break;
+                }
+                // Interrupted write or a layout of another firmware, skip over it
+                Err(_) => {}
+            }
+        }
+    }
+    let next_free = latest.and_then(|l| free[l.sector]);
+    Scan { latest, next_free }
+}
+
+/// Latest saved settings and their sequence number, None if nothing is stored or the latest
+/// record holds values `board` doesn't accept, like a gain of another revision.
+pub fn load<F: Flash>(flash: &mut F, board: &Board) -> Option<(Config, u32)> {
+    let latest = scan(flash, Config::defaults(board)).latest?;
+    latest.config.check(board).ok()?;
+    Some((latest.config, latest.sequence))
+}
+
+/// Appends `config` as the newest record, returns its sequence number.
+pub fn save<F: Flash>(flash: &mut F, config: &Config) -> Result<u32, StoreError> {
+    let scan = scan(flash, *config);
+    let (sector, offset, sequence) = match (scan.latest, scan.next_free) {
+        (Some(latest), Some(offset)) => (latest.sector, offset, latest.sequence.wrapping_add(1)),
+        (latest, _) => {
+            // Active sector is full or nothing was stored yet, start over in the other one
+            let sector = latest.map_or(0, |l| 1 - l.sector);
+            flash.erase(sector)?;
+            (sector, 0, latest.map_or(1, |l| l.sequence.wrapping_add(1)))
+        }
+    };
+    let record = config.to_record(sequence);
+    flash.program(sector, offset, &record)?;
+    let mut check = [0; RECORD_LEN];
+    flash.read(sector, offset, &mut check);
+    if check != record {
+        return Err(StoreError::Verify);
+    }
+    Ok(sequence)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::{DRV8302_STAGE, REV1};
+
+    const SECTOR_SIZE: usize = RECORD_LEN * 4;
+
+    struct RamFlash {
+        sectors: [[u8; SECTOR_SIZE]; 2],
+        erase_count: [u32; 2],
+        /// Programming stops after this many bytes, simulates a power loss.
+        program_budget: Option<usize>,
+        /// Programmed bytes end up zero, simulates a worn out cell.
+        stuck_at_zero: bool,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            RamFlash { sectors: [[0xFF; SECTOR_SIZE]; 2], erase_count: [0; 2], program_budget: None, stuck_at_zero: false }
+        }
+    }
+
+    impl Flash for RamFlash {
+        fn sector_size(&self) -> u32 {
+            SECTOR_SIZE as u32
+        }
+
+        fn read(&mut self, sector: usize, offset: u32, buf: &mut [u8]) {
+            let offset = offset as usize;
+            buf.copy_from_slice(&self.sectors[sector][offset..offset + buf.len()]);
+        }
+
+        fn program(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+            for (i, byte) in data.iter().enumerate() {
+                if let Some(budget) = &mut self.program_budget {
+                    if *budget == 0 {
+                        return Err(FlashError);
+                    }
+                    *budget -= 1;
+                }
+                let byte = if self.stuck_at_zero { 0 } else { *byte };
+                self.sectors[sector][offset as usize + i] &= byte;
+            }
+            Ok(())
+        }
+
+        fn erase(&mut self, sector: usize) -> Result<(), FlashError> {
+            self.sectors[sector] = [0xFF; SECTOR_SIZE];
+            self.erase_count[sector] += 1;
+            Ok(())
+        }
+    }
+
+    fn config(dead_time_ns: u16) -> Config {
+        Config { dead_time_ns, ..Config::defaults(&REV1) }
+    }
+
+    #[test]
+    fn empty_flash() {
+        let mut flash = RamFlash::new();
+        assert_eq!(load(&mut flash, &REV1), None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut flash = RamFlash::new();
+        assert_eq!(save(&mut flash, &config(40)), Ok(1));
+        assert_eq!(load(&mut flash, &REV1), Some((config(40), 1)));
+        assert_eq!(save(&mut flash, &config(80)), Ok(2));
+        assert_eq!(load(&mut flash, &REV1), Some((config(80), 2)));
+    }
+
+    #[test]
+    fn sectors_are_erased_only_when_full() {
+        let mut flash = RamFlash::new();
+        for i in 0..4 {
+            save(&mut flash, &config(10 + i)).unwrap();
+        }
+        assert_eq!(flash.erase_count, [1, 0]);
+        // Sector 0 is full, the fifth record goes to sector 1 and sector 0 is kept as a backup
+        save(&mut flash, &config(20)).unwrap();
+        assert_eq!(flash.erase_count, [1, 1]);
+        assert_eq!(load(&mut flash, &REV1), Some((config(20), 5)));
+        assert_eq!(Config::from_record(&flash.sectors[0][3 * RECORD_LEN..], config(0)), Ok((config(13), 4)));
+
+        for i in 0..3 {
+            save(&mut flash, &config(30 + i)).unwrap();
+        }
+        assert_eq!(flash.erase_count, [1, 1]);
+        save(&mut flash, &config(40)).unwrap();
+        assert_eq!(flash.erase_count, [2, 1]);
+        assert_eq!(load(&mut flash, &REV1), Some((config(40), 9)));
+    }
+
+    #[test]
+    fn interrupted_save_keeps_previous() {
+        let mut flash = RamFlash::new();
+        save(&mut flash, &config(40)).unwrap();
+        flash.program_budget = Some(20);
+        assert_eq!(save(&mut flash, &config(80)), Err(StoreError::Flash));
+        flash.program_budget = None;
+        assert_eq!(load(&mut flash, &REV1), Some((config(40), 1)));
+        // Broken slot is skipped
+        assert_eq!(save(&mut flash, &config(10)), Ok(2));
+        assert_eq!(load(&mut flash, &REV1), Some((config(10), 2)));
+        assert_eq!(Config::from_record(&flash.sectors[0][2 * RECORD_LEN..], config(0)), Ok((config(10), 2)));
+    }
+
+    #[test]
+    fn verify_failure() {
+        let mut flash = RamFlash::new();
+        save(&mut flash, &config(40)).unwrap();
+        flash.stuck_at_zero = true;
+        assert_eq!(save(&mut flash, &config(80)), Err(StoreError::Verify));
+        assert_eq!(load(&mut flash, &REV1), Some((config(40), 1)));
+    }
+
+    #[test]
+    fn invalid_values_are_not_loaded() {
+        let mut flash = RamFlash::new();
+        // Gain 20 doesn't exist on the DRV8302
+        save(&mut flash, &config(500)).unwrap();
+        assert_eq!(load(&mut flash, &DRV8302_STAGE), None);
+        save(&mut flash, &Config { pwm_frequency_hz: 1, ..config(500) }).unwrap();
+        assert_eq!(load(&mut flash, &REV1), None);
+        save(&mut flash, &config(700)).unwrap();
+        assert_eq!(load(&mut flash, &REV1), Some((config(700), 3)));
+    }
+}
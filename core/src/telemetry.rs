@@ -0,0 +1,276 @@
+//! Binary telemetry stream, one sample per frame.
+//!
+//! Frame before encoding, little endian: `version: u8, kind: u8, payload, crc32: u32`, CRC covers
+//! everything before it. Frames are COBS encoded and each one is followed by a zero byte, so a
+//! decoder joining in the middle of the stream or losing bytes picks up at the next zero.
+
+use crate::cobs;
+use crate::crc::crc32;
+use core::fmt;
+
+pub const VERSION: u8 = 1;
+pub const KIND_SAMPLE: u8 = 1;
+const HEADER_LEN: usize = 2;
+const CRC_LEN: usize = 4;
+const SAMPLE_LEN: usize = 4 + 8 * 4 + 3 + 2;
+const FRAME_LEN: usize = HEADER_LEN + SAMPLE_LEN + CRC_LEN;
+/// Encoded frame with the delimiter.
+pub const MAX_ENCODED_LEN: usize = cobs::max_encoded_len(FRAME_LEN) + 1;
+
+/// Duty of a phase in manual mode with both switches off.
+pub const FLOATING: u8 = 0xFF;
+
+/// Bits of `Sample::flags`.
+pub mod flags {
+    pub const DRV_ENABLED: u8 = 1 << 0;
+    pub const DRV_FAULT: u8 = 1 << 1;
+    pub const OPENLOOP: u8 = 1 << 2;
+    pub const CAN_POWER: u8 = 1 << 3;
+    pub const CAN_UNDERVOLTAGE: u8 = 1 << 4;
+}
+
+/// Voltages in mV, currents in mA.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Sample {
+    pub sequence: u32,
+    pub v_in: i32,
+    pub v_phase: [i32; 3],
+    pub i_phase: [i32; 3],
+    pub v_can: i32,
+    /// Percent in openloop. In manual mode 100 - high side on, 0 - low side on, `FLOATING` - both off.
+    pub duties: [u8; 3],
+    /// Bits 0, 1, 2 - hall A, B, C.
+    pub halls: u8,
+    pub flags: u8,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum FrameError {
+    /// Bad byte stuffing or a frame longer than any known one.
+    Framing,
+    Length,
+    Crc,
+    Version(u8),
+    Kind(u8),
+}
+
+pub const CSV_HEADER: &str = "sequence,v_in,v_a,v_b,v_c,i_a,i_b,i_c,v_can,duty_a,duty_b,duty_c,\
+hall_a,hall_b,hall_c,drv_enabled,drv_fault,openloop,can_power,can_undervoltage";
+
+impl Sample {
+    /// Frame ready to be sent, delimiter included, returns its length.
+    pub fn encode(&self, encoded: &mut [u8; MAX_ENCODED_LEN]) -> usize {
+        let mut frame = [0u8; FRAME_LEN];
+        frame[0] = VERSION;
+        frame[1] = KIND_SAMPLE;
+        let mut at = HEADER_LEN;
+        let mut put = |bytes: &[u8]| {
+            frame[at..at + bytes.len()].copy_from_slice(bytes);
+            at += bytes.len();
+        };
+        put(&self.sequence.to_le_bytes());
+        put(&self.v_in.to_le_bytes());
+        for v in self.v_phase.iter().chain(self.i_phase.iter()) {
+            put(&v.to_le_bytes());
+        }
+        put(&self.v_can.to_le_bytes());
+        put(&self.duties);
+        put(&[self.halls, self.flags]);
+        let crc = crc32(&frame[..at]);
+        frame[at..].copy_from_slice(&crc.to_le_bytes());
+
+        // Always fits, MAX_ENCODED_LEN is the worst case
+        let len = cobs::encode(&frame, encoded).unwrap_or(0);
+        encoded[len] = 0;
+        len + 1
+    }
+
+    /// `encoded` is one frame without its delimiter.
+    pub fn decode(encoded: &[u8]) -> Result<Sample, FrameError> {
+        let mut frame = [0u8; FRAME_LEN];
+        let len = cobs::decode(encoded, &mut frame).ok_or(FrameError::Framing)?;
+        if len < HEADER_LEN + CRC_LEN {
+            return Err(FrameError::Length);
+        }
+        let crc_at = len - CRC_LEN;
+        let crc = u32::from_le_bytes([frame[crc_at], frame[crc_at + 1], frame[crc_at + 2], frame[crc_at + 3]]);
+        if crc32(&frame[..crc_at]) != crc {
+            return Err(FrameError::Crc);
+        }
+        if frame[0] != VERSION {
+            return Err(FrameError::Version(frame[0]));
+        }
+        if frame[1] != KIND_SAMPLE {
+            return Err(FrameError::Kind(frame[1]));
+        }
+        if crc_at != HEADER_LEN + SAMPLE_LEN {
+            return Err(FrameError::Length);
+        }
+        let payload = &frame[HEADER_LEN..crc_at];
+        let word = |i: usize| [payload[i], payload[i + 1], payload[i + 2], payload[i + 3]];
+        let int = |i: usize| i32::from_le_bytes(word(4 + i * 4));
+        Ok(Sample {
+            sequence: u32::from_le_bytes(word(0)),
+            v_in: int(0),
+            v_phase: [int(1), int(2), int(3)],
+            i_phase: [int(4), int(5), int(6)],
+            v_can: int(7),
+            duties: [payload[36], payload[37], payload[38]],
+            halls: payload[39],
+            flags: payload[40],
+        })
+    }
+
+    /// One row under `CSV_HEADER`, without the line end. Floating phases have an empty duty.
+    pub fn write_csv<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        write!(out, "{},{}", self.sequence, self.v_in)?;
+        for v in self.v_phase.iter().chain(self.i_phase.iter()) {
+            write!(out, ",{}", v)?;
+        }
+        write!(out, ",{}", self.v_can)?;
+        for duty in self.duties.iter() {
+            match *duty {
+                FLOATING => out.write_str(",")?,
+                duty => write!(out, ",{}", duty)?,
+            }
+        }
+        for bit in 0..3 {
+            write!(out, ",{}", (self.halls >> bit) & 1)?;
+        }
+        let all_flags = [flags::DRV_ENABLED, flags::DRV_FAULT, flags::OPENLOOP, flags::CAN_POWER, flags::CAN_UNDERVOLTAGE];
+        for flag in all_flags.iter() {
+            write!(out, ",{}", (self.flags & flag != 0) as u8)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a byte stream into frames at the zero delimiters.
+pub struct Decoder {
+    buf: [u8; MAX_ENCODED_LEN],
+    len: usize,
+    overflow: bool,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Decoder { buf: [0; MAX_ENCODED_LEN], len: 0, overflow: false }
+    }
+
+    /// Returns the sample or the error once a frame is complete, nothing for empty frames.
+    pub fn push(&mut self, byte: u8) -> Option<Result<Sample, FrameError>> {
+        if byte != 0 {
+            match self.buf.get_mut(self.len) {
+                Some(b) => {
+                    *b = byte;
+                    self.len += 1;
+                }
+                None => self.overflow = true,
+            }
+            return None;
+        }
+        let (len, overflow) = (self.len, self.overflow);
+        self.len = 0;
+        self.overflow = false;
+        match (len, overflow) {
+            (_, true) => Some(Err(FrameError::Framing)),
+            (0, false) => None,
+            (len, false) => Some(Sample::decode(&self.buf[..len])),
+        }
+    }
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Sample {
+        Sample {
+            sequence: 0x0102_0300,
+            v_in: 24_000,
+            v_phase: [12_000, 0, -150],
+            i_phase: [1500, -750, -750],
+            v_can: 11_800,
+            duties: [50, 0, FLOATING],
+            halls: 0b101,
+            flags: flags::DRV_ENABLED | flags::CAN_POWER,
+        }
+    }
+
+    fn encoded(sample: &Sample) -> Vec<u8> {
+        let mut frame = [0; MAX_ENCODED_LEN];
+        let len = sample.encode(&mut frame);
+        frame[..len].to_vec()
+    }
+
+    fn decode_stream(stream: &[u8]) -> Vec<Result<Sample, FrameError>> {
+        let mut decoder = Decoder::new();
+        stream.iter().filter_map(|b| decoder.push(*b)).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let frame = encoded(&sample());
+        assert_eq!(frame.last(), Some(&0));
+        assert!(!frame[..frame.len() - 1].contains(&0));
+        assert_eq!(Sample::decode(&frame[..frame.len() - 1]), Ok(sample()));
+        assert_eq!(decode_stream(&frame), vec![Ok(sample())]);
+        let zero = Sample::default();
+        assert_eq!(decode_stream(&encoded(&zero)), vec![Ok(zero)]);
+    }
+
+    #[test]
+    fn stream_resynchronises() {
+        let mut second = sample();
+        second.sequence += 1;
+        // Joined in the middle of a frame
+        let mut stream = vec![0x42, 0x17, 0];
+        stream.extend(encoded(&sample()));
+        // Lost the tail of a frame
+        let third = encoded(&second);
+        stream.extend(&third[..10]);
+        stream.push(0);
+        stream.extend(encoded(&second));
+        let decoded = decode_stream(&stream);
+        assert_eq!(decoded.len(), 4);
+        assert!(decoded[0].is_err());
+        assert_eq!(decoded[1], Ok(sample()));
+        assert!(decoded[2].is_err());
+        assert_eq!(decoded[3], Ok(second));
+        // Runaway garbage without delimiters
+        let mut stream = vec![0x55; 300];
+        stream.push(0);
+        stream.extend(encoded(&sample()));
+        assert_eq!(decode_stream(&stream), vec![Err(FrameError::Framing), Ok(sample())]);
+    }
+
+    #[test]
+    fn corrupted_frames() {
+        let mut frame = encoded(&sample());
+        frame[8] ^= 0x04;
+        assert_eq!(decode_stream(&frame), vec![Err(FrameError::Crc)]);
+
+        let mut raw = [0u8; FRAME_LEN];
+        let len = cobs::decode(&encoded(&sample())[..MAX_ENCODED_LEN - 1], &mut raw).unwrap();
+        raw[0] = 2;
+        let crc = crc32(&raw[..len - 4]);
+        raw[len - 4..len].copy_from_slice(&crc.to_le_bytes());
+        let mut reencoded = [0u8; MAX_ENCODED_LEN];
+        let n = cobs::encode(&raw[..len], &mut reencoded).unwrap();
+        assert_eq!(Sample::decode(&reencoded[..n]), Err(FrameError::Version(2)));
+    }
+
+    #[test]
+    fn csv() {
+        let mut row = String::new();
+        sample().write_csv(&mut row).unwrap();
+        assert_eq!(row, "16909056,24000,12000,0,-150,1500,-750,-750,11800,50,0,,1,0,1,1,0,0,1,0");
+        assert_eq!(row.split(',').count(), CSV_HEADER.split(',').count());
+    }
+}
@@ -0,0 +1,466 @@
+//! Physical quantities as integer newtypes and a fixed point scalar for gains and control math.
+//!
+//! Operators saturate instead of wrapping or panicking, a reading at the end of a range stays
+//! there. `checked_*` variants tell an overflow apart. Products and quotients of two quantities
+//! are computed in 64 bits, so intermediate values like mV · 10^6 don't overflow.
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::{Add, Div, Mul, Neg, Sub};
+
+/// Narrows a 64 bit intermediate to the i32 range.
+fn saturate(value: i64) -> i32 {
+    value.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+/// `num / den` saturated, division by zero goes to the end of the range `num` points at.
+fn saturating_quotient(num: i64, den: i64) -> i32 {
+    match den {
+        0 if num > 0 => i32::MAX,
+        0 if num < 0 => i32::MIN,
+        0 => 0,
+        _ => saturate(num / den),
+    }
+}
+
+/// mA through `micro_ohms` at `mv`, saturated.
+fn current(mv: i64, micro_ohms: i64) -> i32 {
+    saturating_quotient(mv * 1_000_000, micro_ohms)
+}
+
+macro_rules! quantity {
+    ($(#[$doc:meta])* $name:ident($t:ty)) => {
+        $(#[$doc])*
+        #[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+        pub struct $name(pub $t);
+
+        impl $name {
+            pub const ZERO: $name = $name(0);
+            pub const MIN: $name = $name(<$t>::MIN);
+            pub const MAX: $name = $name(<$t>::MAX);
+
+            pub fn checked_add(self, rhs: Self) -> Option<Self> {
+                self.0.checked_add(rhs.0).map($name)
+            }
+
+            pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+                self.0.checked_sub(rhs.0).map($name)
+            }
+
+            pub fn checked_mul(self, k: $t) -> Option<Self> {
+                self.0.checked_mul(k).map($name)
+            }
+
+            pub fn checked_div(self, k: $t) -> Option<Self> {
+                self.0.checked_div(k).map($name)
+            }
+
+            pub fn saturating_mul(self, k: $t) -> Self {
+                $name(self.0.saturating_mul(k))
+            }
+        }
+
+        impl Add for $name {
+            type Output = $name;
+
+            fn add(self, rhs: Self) -> Self::Output {
+                $name(self.0.saturating_add(rhs.0))
+            }
+        }
+
+        impl Sub for $name {
+            type Output = $name;
+
+            fn sub(self, rhs: Self) -> Self::Output {
+                $name(self.0.saturating_sub(rhs.0))
+            }
+        }
+    };
+}
+
+/// Value followed by the unit symbol.
+macro_rules! display {
+    ($($name:ident $suffix:literal),*) => {$(
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{}{}", self.0, $suffix)
+            }
+        }
+    )*};
+}
+
+macro_rules! signed {
+    ($($name:ident),*) => {$(
+        impl $name {
+            pub fn abs(self) -> Self {
+                $name(self.0.saturating_abs())
+            }
+        }
+
+        impl Neg for $name {
+            type Output = $name;
+
+            fn neg(self) -> Self::Output {
+                $name(self.0.saturating_neg())
+            }
+        }
+    )*};
+}
+
+quantity!(Ohms(u32));
+quantity!(MicroOhms(u32));
+quantity!(MilliVolts(i32));
+quantity!(MilliAmperes(i32));
+quantity!(MilliWatts(i32));
+quantity!(Hertz(u32));
+quantity!(Nanoseconds(u32));
+quantity!(
+    /// Temperature in 0.1 °C.
+    DeciCelsius(i32)
+);
+display!(Ohms "Ohm", MicroOhms "uOhm", MilliVolts "mV", MilliAmperes "mA", MilliWatts "mW", Hertz "Hz", Nanoseconds "ns");
+signed!(MilliVolts, MilliAmperes, MilliWatts, DeciCelsius);
+
+/// mV / Ω = mA, no resistance saturates.
+impl Div<Ohms> for MilliVolts {
+    type Output = MilliAmperes;
+
+    fn div(self, rhs: Ohms) -> Self::Output {
+        MilliAmperes(saturating_quotient(self.0 as i64, rhs.0 as i64))
+    }
+}
+
+/// mV / µΩ = kA, no resistance saturates.
+impl Div<MicroOhms> for MilliVolts {
+    type Output = MilliAmperes;
+
+    fn div(self, rhs: MicroOhms) -> Self::Output {
+        MilliAmperes(current(self.0 as i64, rhs.0 as i64))
+    }
+}
+
+impl Mul<Ohms> for MilliAmperes {
+    type Output = MilliVolts;
+
+    fn mul(self, rhs: Ohms) -> Self::Output {
+        MilliVolts(saturate(self.0 as i64 * rhs.0 as i64))
+    }
+}
+
+impl Mul<MicroOhms> for MilliAmperes {
+    type Output = MilliVolts;
+
+    fn mul(self, rhs: MicroOhms) -> Self::Output {
+        MilliVolts(saturate(self.0 as i64 * rhs.0 as i64 / 1_000_000))
+    }
+}
+
+/// mV · mA = µW, truncated to mW.
+impl Mul<MilliAmperes> for MilliVolts {
+    type Output = MilliWatts;
+
+    fn mul(self, rhs: MilliAmperes) -> Self::Output {
+        MilliWatts(saturate(self.0 as i64 * rhs.0 as i64 / 1_000))
+    }
+}
+
+impl Hertz {
+    /// Zero for 0 Hz.
+    pub fn period(self) -> Nanoseconds {
+        match self.0 {
+            0 => Nanoseconds(0),
+            hz => Nanoseconds(1_000_000_000 / hz),
+        }
+    }
+}
+
+impl Nanoseconds {
+    pub const fn from_micros(us: u32) -> Self {
+        Nanoseconds(us.saturating_mul(1_000))
+    }
+
+    /// Zero for 0 ns.
+    pub fn frequency(self) -> Hertz {
+        match self.0 {
+            0 => Hertz(0),
+            ns => Hertz(1_000_000_000 / ns),
+        }
+    }
+
+    /// Whole periods of `clock` in this time.
+    pub fn ticks(self, clock: Hertz) -> u64 {
+        self.0 as u64 * clock.0 as u64 / 1_000_000_000
+    }
+}
+
+impl fmt::Display for DeciCelsius {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        write!(f, "{}{}.{}°C", sign, abs / 10, abs % 10)
+    }
+}
+
+/// Signed Q16.16, 16 integer and 16 fraction bits. Operators saturate at `MIN` and `MAX`, a
+/// division by zero saturates toward the sign of the dividend.
+#[derive(Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Fixed(i32);
+
+impl Fixed {
+    pub const FRAC_BITS: u32 = 16;
+    pub const ZERO: Fixed = Fixed(0);
+    pub const ONE: Fixed = Fixed(1 << Self::FRAC_BITS);
+    pub const MIN: Fixed = Fixed(i32::MIN);
+    pub const MAX: Fixed = Fixed(i32::MAX);
+
+    pub const fn from_bits(bits: i32) -> Self {
+        Fixed(bits)
+    }
+
+    pub const fn to_bits(self) -> i32 {
+        self.0
+    }
+
+    pub fn from_int(n: i32) -> Self {
+        Fixed(saturate((n as i64) << Self::FRAC_BITS))
+    }
+
+    /// `num / den`, None for a zero denominator or out of range.
+    pub fn from_ratio(num: i32, den: i32) -> Option<Self> {
+        if den == 0 {
+            return None;
+        }
+        let bits = ((num as i64) << Self::FRAC_BITS) / den as i64;
+        i32::try_from(bits).ok().map(Fixed)
+    }
+
+    /// Rounded to the nearest integer, halves up.
+    pub fn round(self) -> i32 {
+        ((self.0 as i64 + (1 << (Self::FRAC_BITS - 1))) >> Self::FRAC_BITS) as i32
+    }
+
+    /// `value` times this, rounded and saturated.
+    pub fn scale(self, value: i32) -> i32 {
+        let product = value as i64 * self.0 as i64 + (1 << (Self::FRAC_BITS - 1));
+        saturate(product >> Self::FRAC_BITS)
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.0.checked_add(rhs.0).map(Fixed)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.0.checked_sub(rhs.0).map(Fixed)
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Option<Self> {
+        let bits = (self.0 as i64 * rhs.0 as i64) >> Self::FRAC_BITS;
+        i32::try_from(bits).ok().map(Fixed)
+    }
+
+    pub fn checked_div(self, rhs: Self) -> Option<Self> {
+        if rhs.0 == 0 {
+            return None;
+        }
+        let bits = ((self.0 as i64) << Self::FRAC_BITS) / rhs.0 as i64;
+        i32::try_from(bits).ok().map(Fixed)
+    }
+
+    pub fn saturating_div(self, rhs: Self) -> Self {
+        Fixed(saturating_quotient((self.0 as i64) << Self::FRAC_BITS, rhs.0 as i64))
+    }
+
+    pub fn abs(self) -> Self {
+        Fixed(self.0.saturating_abs())
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Fixed(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Fixed(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Fixed(saturate((self.0 as i64 * rhs.0 as i64) >> Self::FRAC_BITS))
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+
+    fn div(self, rhs: Self) -> Self::Output {
+        self.saturating_div(rhs)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+
+    fn neg(self) -> Self::Output {
+        Fixed(self.0.saturating_neg())
+    }
+}
+
+impl fmt::Display for Fixed {
+    /// Four decimals, truncated.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs() as u64;
+        let frac = ((abs & 0xFFFF) * 10_000) >> Self::FRAC_BITS;
+        write!(f, "{}{}.{:04}", sign, abs >> Self::FRAC_BITS, frac)
+    }
+}
+
+pub fn resistor_divider_inverse(rt: Ohms, rb: Ohms, vin: MilliVolts) -> MilliVolts {
+    MilliVolts(saturating_quotient(vin.0 as i64 * (rt.0 as i64 + rb.0 as i64), rb.0 as i64))
+}
+
+pub fn voltage_to_current(v_adc: MilliVolts, mid_point: MilliVolts, shunt: MicroOhms, gain: u8) -> MilliAmperes {
+    let mv_at_shunt = (v_adc - mid_point).0 as i64;
+    MilliAmperes(current(mv_at_shunt, shunt.0 as i64 * gain as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider_inverse() {
+        let v = resistor_divider_inverse(Ohms(34900), Ohms(4990), MilliVolts(1000));
+        assert_eq!(v, MilliVolts(7993));
+        let v = resistor_divider_inverse(Ohms(10_000), Ohms(10_000), MilliVolts(1650));
+        assert_eq!(v, MilliVolts(3300));
+        assert_eq!(resistor_divider_inverse(Ohms(34900), Ohms(4990), MilliVolts(0)), MilliVolts(0));
+        // 1 MOhm over 1 Ohm used to overflow in the product
+        assert_eq!(resistor_divider_inverse(Ohms(1_000_000), Ohms(1), MilliVolts(3300)), MilliVolts(i32::MAX));
+        assert_eq!(resistor_divider_inverse(Ohms(1_000), Ohms(0), MilliVolts(3300)), MilliVolts::MAX);
+    }
+
+    #[test]
+    fn current_at_midpoint_is_zero() {
+        let i = voltage_to_current(MilliVolts(1650), MilliVolts(1650), MicroOhms(10_000), 20);
+        assert_eq!(i, MilliAmperes(0));
+    }
+
+    #[test]
+    fn current_sign_follows_midpoint() {
+        // 200mV at the amplifier output, gain 20 -> 10mV at 10mOhm shunt -> 1A
+        let i = voltage_to_current(MilliVolts(1850), MilliVolts(1650), MicroOhms(10_000), 20);
+        assert_eq!(i, MilliAmperes(1000));
+        let i = voltage_to_current(MilliVolts(1450), MilliVolts(1650), MicroOhms(10_000), 20);
+        assert_eq!(i, MilliAmperes(-1000));
+    }
+
+    #[test]
+    fn current_full_adc_range() {
+        let i = voltage_to_current(MilliVolts(3300), MilliVolts(1650), MicroOhms(10_000), 20);
+        assert_eq!(i, MilliAmperes(8250));
+        let i = voltage_to_current(MilliVolts(0), MilliVolts(1650), MicroOhms(10_000), 20);
+        assert_eq!(i, MilliAmperes(-8250));
+    }
+
+    #[test]
+    fn current_above_two_volts() {
+        // 3.3 V at a 1 mOhm shunt, gain 10: mV * 10^6 is past i32
+        let i = voltage_to_current(MilliVolts(3300), MilliVolts(0), MicroOhms(1_000), 10);
+        assert_eq!(i, MilliAmperes(330_000));
+        let i = voltage_to_current(MilliVolts(-3300), MilliVolts(0), MicroOhms(1_000), 10);
+        assert_eq!(i, MilliAmperes(-330_000));
+        assert_eq!(voltage_to_current(MilliVolts(10), MilliVolts(0), MicroOhms(0), 10), MilliAmperes::MAX);
+        assert_eq!(voltage_to_current(MilliVolts(0), MilliVolts(0), MicroOhms(1_000), 0), MilliAmperes(0));
+    }
+
+    #[test]
+    fn saturation() {
+        assert_eq!(MilliVolts::MAX + MilliVolts(1), MilliVolts::MAX);
+        assert_eq!(MilliVolts::MIN - MilliVolts(1), MilliVolts::MIN);
+        assert_eq!(MilliVolts(5) - MilliVolts(7), MilliVolts(-2));
+        assert_eq!(Ohms(3) - Ohms(5), Ohms(0));
+        assert_eq!(-MilliAmperes::MIN, MilliAmperes::MAX);
+        assert_eq!(MilliAmperes::MIN.abs(), MilliAmperes::MAX);
+        assert_eq!(MilliVolts(1 << 30).saturating_mul(4), MilliVolts::MAX);
+        assert_eq!(MilliVolts::MAX.checked_add(MilliVolts(1)), None);
+        assert_eq!(MilliVolts(2).checked_sub(MilliVolts(3)), Some(MilliVolts(-1)));
+        assert_eq!(Hertz(u32::MAX).checked_mul(2), None);
+        assert_eq!(MilliAmperes(5).checked_div(0), None);
+    }
+
+    #[test]
+    fn cross_units() {
+        assert_eq!(MilliVolts(24_000) / Ohms(12), MilliAmperes(2_000));
+        assert_eq!(MilliVolts(-100) / Ohms(0), MilliAmperes::MIN);
+        assert_eq!(MilliVolts(10) / MicroOhms(10_000), MilliAmperes(1_000));
+        assert_eq!(MilliVolts::MAX / MicroOhms(1), MilliAmperes::MAX);
+        assert_eq!(MilliAmperes(2_000) * Ohms(12), MilliVolts(24_000));
+        assert_eq!(MilliAmperes(1_000) * MicroOhms(10_000), MilliVolts(10));
+        assert_eq!(MilliAmperes::MAX * Ohms(2), MilliVolts::MAX);
+        assert_eq!(MilliVolts(24_000) * MilliAmperes(-2_500), MilliWatts(-60_000));
+        assert_eq!(MilliVolts::MAX * MilliAmperes::MAX, MilliWatts::MAX);
+        assert_eq!(Hertz(20_000).period(), Nanoseconds(50_000));
+        assert_eq!(Nanoseconds(50_000).frequency(), Hertz(20_000));
+        assert_eq!(Hertz(0).period(), Nanoseconds(0));
+        assert_eq!(Nanoseconds::from_micros(10).ticks(Hertz(8_000_000)), 80);
+        assert_eq!(Nanoseconds::from_micros(u32::MAX), Nanoseconds::MAX);
+        assert_eq!(Nanoseconds::MAX.ticks(Hertz(u32::MAX)), 18_446_744_065);
+    }
+
+    #[test]
+    fn fixed_point() {
+        let half = Fixed::from_ratio(1, 2).unwrap();
+        let three_halves = Fixed::ONE + half;
+        assert_eq!(three_halves * Fixed::from_int(2), Fixed::from_int(3));
+        assert_eq!(Fixed::from_int(3) / Fixed::from_int(2), three_halves);
+        assert_eq!((Fixed::from_ratio(1, 3).unwrap() * Fixed::from_int(3)).round(), 1);
+        assert_eq!(half.round(), 1);
+        assert_eq!((-half).round(), 0);
+        assert_eq!(Fixed::from_ratio(-3, 2).unwrap().round(), -1);
+        assert_eq!(three_halves.scale(1_000), 1_500);
+        assert_eq!(Fixed::from_ratio(-1, 3).unwrap().scale(3_000), -1_000);
+        assert_eq!(Fixed::from_ratio(1, 0), None);
+        assert_eq!(Fixed::from_ratio(1 << 16, 1), None);
+
+        assert_eq!(Fixed::from_int(40_000), Fixed::MAX);
+        assert_eq!(Fixed::from_int(-40_000), Fixed::MIN);
+        assert_eq!(Fixed::MAX + Fixed::ONE, Fixed::MAX);
+        assert_eq!(Fixed::MIN - Fixed::ONE, Fixed::MIN);
+        assert_eq!(Fixed::MAX * Fixed::from_int(2), Fixed::MAX);
+        assert_eq!(Fixed::MIN * Fixed::from_int(2), Fixed::MIN);
+        assert_eq!(Fixed::ONE / Fixed::ZERO, Fixed::MAX);
+        assert_eq!(-Fixed::ONE / Fixed::ZERO, Fixed::MIN);
+        assert_eq!(Fixed::from_int(30_000) / half, Fixed::MAX);
+        assert_eq!(-Fixed::MIN, Fixed::MAX);
+        assert_eq!(Fixed::MIN.abs(), Fixed::MAX);
+        assert_eq!(Fixed::MAX.checked_add(Fixed::ONE), None);
+        assert_eq!(Fixed::MAX.checked_mul(Fixed::from_int(2)), None);
+        assert_eq!(Fixed::ONE.checked_div(Fixed::ZERO), None);
+        assert_eq!(half.checked_mul(half), Fixed::from_ratio(1, 4));
+        assert_eq!(Fixed::from_int(1_000).scale(i32::MAX), i32::MAX);
+        assert_eq!(Fixed::from_bits(Fixed::ONE.to_bits()), Fixed::ONE);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", MilliVolts(-12)), "-12mV");
+        assert_eq!(format!("{}", MilliAmperes(345)), "345mA");
+        assert_eq!(format!("{}", MilliWatts(60)), "60mW");
+        assert_eq!(format!("{}", Hertz(20_000)), "20000Hz");
+        assert_eq!(format!("{}", DeciCelsius(-55)), "-5.5°C");
+        assert_eq!(format!("{}", DeciCelsius(253)), "25.3°C");
+        assert_eq!(format!("{}", Fixed::from_ratio(-3, 2).unwrap()), "-1.5000");
+        assert_eq!(format!("{}", Fixed::from_ratio(1, 3).unwrap()), "0.3333");
+    }
+}
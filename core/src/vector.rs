@@ -0,0 +1,106 @@
+//! Static voltage vectors for rotor alignment.
+
+/// sin(0..=90 deg) in Q15
+const SIN_TABLE: [i16; 91] = [
+    0, 572, 1144, 1715, 2286, 2856, 3425, 3993, 4560, 5126,
+    5690, 6252, 6813, 7371, 7927, 8481, 9032, 9580, 10126, 10668,
+    11207, 11743, 12275, 12803, 13328, 13848, 14364, 14876, 15383, 15886,
+    16383, 16876, 17364, 17846, 18323, 18794, 19260, 19720, 20173, 20621,
+    21062, 21497, 21925, 22347, 22762, 23170, 23571, 23964, 24351, 24730,
+    25101, 25465, 25821, 26169, 26509, 26841, 27165, 27481, 27788, 28087,
+    28377, 28659, 28932, 29196, 29451, 29697, 29934, 30162, 30381, 30591,
+    30791, 30982, 31163, 31335, 31498, 31650, 31794, 31927, 32051, 32165,
+    32269, 32364, 32448, 32523, 32587, 32642, 32687, 32722, 32747, 32762,
+    32767,
+];
+const SIN_SCALE: i32 = 32767;
+
+pub fn sin_deg(deg: i32) -> i32 {
+    let deg = deg.rem_euclid(360) as usize;
+    match deg {
+        0..=90 => SIN_TABLE[deg] as i32,
+        91..=180 => SIN_TABLE[180 - deg] as i32,
+        181..=270 => -(SIN_TABLE[deg - 180] as i32),
+        _ => -(SIN_TABLE[360 - deg] as i32),
+    }
+}
+
+pub fn cos_deg(deg: i32) -> i32 {
+    sin_deg(deg.rem_euclid(360) + 90)
+}
+
+/// Compare values for phases A, B, C producing a voltage vector at electrical angle `angle_deg`
+/// (0 = phase A axis) with magnitude `duty` in percent of the half bus voltage.
+/// `period` is the compare value for 100% duty. Any angle is accepted, it's reduced to one turn.
+pub fn vector_duties(angle_deg: i32, duty: u8, period: u32) -> [u32; 3] {
+    let angle_deg = angle_deg.rem_euclid(360);
+    let duty = if duty > 100 {
+        100
+    } else {
+        duty
+    };
+    let half = period as i32 / 2;
+    let amplitude = half * duty as i32 / 100;
+    let leg = |shift: i32| {
+        (half + amplitude * cos_deg(angle_deg - shift) / SIN_SCALE) as u32
+    };
+    [leg(0), leg(120), leg(240)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_quadrants() {
+        assert_eq!(sin_deg(0), 0);
+        assert_eq!(sin_deg(90), SIN_SCALE);
+        assert_eq!(sin_deg(180), 0);
+        assert_eq!(sin_deg(270), -SIN_SCALE);
+        assert_eq!(sin_deg(30), 16383);
+        assert_eq!(sin_deg(150), 16383);
+        assert_eq!(sin_deg(210), -16383);
+        assert_eq!(sin_deg(330), -16383);
+        assert_eq!(sin_deg(-90), -SIN_SCALE);
+        assert_eq!(sin_deg(450), SIN_SCALE);
+        assert_eq!(cos_deg(0), SIN_SCALE);
+        assert_eq!(cos_deg(i32::MAX), cos_deg(i32::MAX % 360));
+        assert_eq!(sin_deg(i32::MIN), sin_deg(i32::MIN.rem_euclid(360)));
+    }
+
+    #[test]
+    fn zero_magnitude_is_midpoint() {
+        assert_eq!(vector_duties(77, 0, 400), [200, 200, 200]);
+    }
+
+    #[test]
+    fn vector_along_phase_a() {
+        assert_eq!(vector_duties(0, 100, 400), [400, 101, 101]);
+        assert_eq!(vector_duties(180, 100, 400), [0, 299, 299]);
+    }
+
+    #[test]
+    fn vector_along_phase_b() {
+        assert_eq!(vector_duties(120, 50, 400), [151, 300, 151]);
+    }
+
+    #[test]
+    fn any_angle_is_reduced() {
+        assert_eq!(vector_duties(120 + 360 * 5, 50, 400), vector_duties(120, 50, 400));
+        assert_eq!(vector_duties(i32::MAX, 50, 400), vector_duties(i32::MAX % 360, 50, 400));
+        assert_eq!(vector_duties(i32::MIN, 50, 400), vector_duties(i32::MIN.rem_euclid(360), 50, 400));
+    }
+
+    #[test]
+    fn duty_is_clamped() {
+        assert_eq!(vector_duties(0, 200, 400), vector_duties(0, 100, 400));
+    }
+
+    #[test]
+    fn phases_sum_to_three_halves() {
+        for angle in (0..360).step_by(7) {
+            let sum: u32 = vector_duties(angle, 100, 4000).iter().sum();
+            assert!((5990..=6010).contains(&sum), "angle {} sum {}", angle, sum);
+        }
+    }
+}
@@ -0,0 +1,14 @@
+#[allow(dead_code)]
+pub const RED: &str = "\x1b[1;31m";
+#[allow(dead_code)]
+pub const GREEN: &str = "\x1b[1;32m";
+#[allow(dead_code)]
+pub const CYAN: &str = "\x1b[96;36m";
+#[allow(dead_code)]
+pub const YELLOW: &str = "\x1b[93;33m";
+#[allow(dead_code)]
+pub const BG_CYAN: &str = "\x1b[4;46m";
+#[allow(dead_code)]
+pub const DEFAULT: &str = "\x1b[0m";
+#[allow(dead_code)]
+pub const CLEAR_SCREEN: &str = "\x1b[2J";
\ No newline at end of file
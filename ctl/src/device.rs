@@ -0,0 +1,155 @@
+//! Commands and replies over a link, the firmware is switched to JSON replies on connect.
+
+use crate::json::{self, Value};
+use crate::link::Link;
+use std::fmt::{self, Display};
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The link closed before the reply came.
+    Disconnected,
+    /// A reply the host couldn't make sense of.
+    Protocol(String),
+    /// The firmware refused the command or it failed, `reply` has the details.
+    Failed { error: String, message: String, reply: Value },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Disconnected => f.write_str("device disconnected"),
+            Error::Protocol(text) => write!(f, "unexpected reply: {}", text),
+            Error::Failed { error, message, reply } => {
+                write!(f, "{} ({})", message, error)?;
+                if let Some(token) = reply.get("token").and_then(Value::as_str) {
+                    write!(f, ": {}", token)?;
+                }
+                if let Some(expected) = reply.get("expected").and_then(Value::as_str) {
+                    write!(f, ", expected {}", expected)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Fields of a successful reply in the order the firmware wrote them.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Reply {
+    pub fields: Vec<(String, Value)>,
+}
+
+impl Reply {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+}
+
+/// Unknown command whose error reply marks the end of stale output.
+const SYNC_MARKER: &str = "sync-mark";
+/// Drops whatever was typed into the firmware line editor before.
+const CTRL_C: u8 = 0x03;
+
+pub struct Device {
+    link: Link,
+}
+
+impl Device {
+    /// Switches the firmware to JSON replies whatever mode it was left in.
+    pub fn connect(link: Link) -> Result<Device, Error> {
+        let mut device = Device { link };
+        device.link.send(&[CTRL_C])?;
+        device.link.send_line("mode json")?;
+        device.link.send_line(SYNC_MARKER)?;
+        // Before the marker there may be a human reply, an echo or a JSON reply
+        loop {
+            let reply = device.next_reply()?;
+            if reply.get("token").and_then(Value::as_str) == Some(SYNC_MARKER) {
+                return Ok(device);
+            }
+        }
+    }
+
+    /// Runs one firmware CLI command, e.g. `drv on` or `cfg set gain 20`.
+    pub fn command(&mut self, line: &str) -> Result<Reply, Error> {
+        self.link.send_line(line)?;
+        let reply = self.next_reply()?;
+        let text = |name| reply.get(name).and_then(Value::as_str).unwrap_or("").to_string();
+        match reply.get("status").and_then(Value::as_str) {
+            Some("ok") => match reply.get("fields") {
+                Some(Value::Object(fields)) => Ok(Reply { fields: fields.clone() }),
+                _ => Err(Error::Protocol(reply.to_string())),
+            },
+            Some("error") => Err(Error::Failed { error: text("error"), message: text("message"), reply }),
+            _ => Err(Error::Protocol(reply.to_string())),
+        }
+    }
+
+    /// Next JSON object with a status, other lines are echoes or prompts from human mode.
+    fn next_reply(&mut self) -> Result<Value, Error> {
+        loop {
+            let line = self.link.read_line()?.ok_or(Error::Disconnected)?;
+            let start = match line.find('{') {
+                Some(start) => start,
+                None => continue,
+            };
+            if let Ok(reply) = json::parse(&line[start..]) {
+                if reply.get("status").is_some() {
+                    return Ok(reply);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeDevice;
+
+    #[test]
+    fn connect_from_any_mode() {
+        for json in [false, true] {
+            let fake = FakeDevice::shared();
+            if json {
+                fake.borrow_mut().feed(b"mode json\n");
+            }
+            fake.borrow_mut().feed(b"drv o");
+            let mut device = Device::connect(FakeDevice::link(&fake)).unwrap();
+            let reply = device.command("status").unwrap();
+            assert_eq!(reply.get("drv_enabled"), Some(&Value::Bool(false)));
+            assert_eq!(reply.get("v_in"), Some(&Value::Number(24000.0)));
+        }
+    }
+
+    #[test]
+    fn commands_and_failures() {
+        let fake = FakeDevice::shared();
+        let mut device = Device::connect(FakeDevice::link(&fake)).unwrap();
+        assert_eq!(device.command("drv on").unwrap(), Reply { fields: vec![] });
+        assert!(fake.borrow().drv_enabled);
+
+        match device.command("cfg set gain 7") {
+            Err(Error::Failed { error, reply, .. }) => {
+                assert_eq!(error, "bad_argument");
+                assert_eq!(reply.get("token").and_then(Value::as_str), Some("7"));
+            }
+            other => panic!("{:?}", other),
+        }
+        let failure = device.command("frob").unwrap_err();
+        assert_eq!(failure.to_string(), "unknown command (unknown_command): frob");
+        assert_eq!(device.command("cfg set gain 20").unwrap().get("gain"), Some(&Value::Number(20.0)));
+
+        fake.borrow_mut().unplugged = true;
+        assert!(matches!(device.command("status"), Err(Error::Disconnected)));
+    }
+}
@@ -0,0 +1,185 @@
+//! Device stand-in for running the host side without hardware. Replies are made by the same
+//! line editor and reply writer as in the firmware, output carries rtt-target terminal switches.
+
+use crate::link::Link;
+use power_stage_core::cli::{dispatch, Arg, Command, DispatchError, Unit, Values};
+use power_stage_core::line::LineEditor;
+use power_stage_core::response::{ErrorKind, Failure, Mode, Outcome, Response};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+type Handler = fn(&mut FakeDevice, &Values, &mut Response<String>) -> Outcome;
+
+const COMMANDS: &[Command<Handler>] = &[
+    Command { name: "mode", help: "Reply mode", args: &[Arg::choice("mode", &["human", "json"])], handler: mode_command },
+    Command { name: "drv on", help: "Enable the gate driver", args: &[], handler: drv_on_command },
+    Command { name: "drv off", help: "Disable the gate driver", args: &[], handler: drv_off_command },
+    Command { name: "status", help: "Measurements", args: &[], handler: status_command },
+    Command {
+        name: "cfg set gain",
+        help: "Current sense gain",
+        args: &[Arg::choice("gain", &["5", "10", "20", "40"])],
+        handler: gain_command,
+    },
+    Command {
+        name: "vin",
+        help: "Set the measured input voltage",
+        args: &[Arg::int("voltage", 0, 60_000, Unit::MilliVolts)],
+        handler: vin_command,
+    },
+];
+
+const DRV_DISABLED: Failure = Failure::new(ErrorKind::WrongMode, "DRV disabled");
+
+pub struct FakeDevice {
+    mode: Mode,
+    line: LineEditor,
+    output: VecDeque<u8>,
+    terminal: u8,
+    pub drv_enabled: bool,
+    pub v_in: i32,
+    pub gain: i32,
+    /// Reads end as if the probe was disconnected.
+    pub unplugged: bool,
+}
+
+impl Default for FakeDevice {
+    fn default() -> Self {
+        FakeDevice {
+            mode: Mode::Human,
+            line: LineEditor::new("> "),
+            output: VecDeque::new(),
+            terminal: 0,
+            drv_enabled: false,
+            v_in: 24_000,
+            gain: 10,
+            unplugged: false,
+        }
+    }
+}
+
+impl FakeDevice {
+    pub fn shared() -> Rc<RefCell<FakeDevice>> {
+        Rc::new(RefCell::new(FakeDevice::default()))
+    }
+
+    /// Link as if the device was reached over RTT.
+    pub fn link(device: &Rc<RefCell<FakeDevice>>) -> Link {
+        Link::new(Output(device.clone()), Input(device.clone())).with_terminals()
+    }
+
+    /// Bytes as they come from the host.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let mut echo = String::new();
+            let entered = match self.mode {
+                Mode::Human => self.line.feed(byte, COMMANDS, &mut echo),
+                Mode::Json => self.line.feed(byte, COMMANDS, &mut String::new()),
+            };
+            let line = entered.map(String::from);
+            self.write(0, &echo);
+            if let Some(line) = line {
+                self.execute(&line);
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        let mut out = String::new();
+        let mut response = Response::new(&mut out, self.mode);
+        match dispatch(COMMANDS, line) {
+            Ok((command, args)) => {
+                let outcome = (command.handler)(self, &args, &mut response);
+                response.finish(outcome);
+            }
+            Err(DispatchError::Empty) if self.mode == Mode::Human => {}
+            Err(e) => response.finish_dispatch_error(COMMANDS, line, &e),
+        }
+        if self.mode == Mode::Human {
+            out.push_str(self.line.prompt());
+        }
+        self.write(0, &out);
+        // The status page keeps going meanwhile
+        let page = format!("V_IN: {}\n", self.v_in);
+        self.write(1, &page);
+    }
+
+    fn write(&mut self, terminal: u8, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if terminal != self.terminal {
+            self.output.extend(&[0xFF, b'0' + terminal]);
+            self.terminal = terminal;
+        }
+        self.output.extend(text.bytes());
+    }
+}
+
+fn mode_command(device: &mut FakeDevice, args: &Values, _response: &mut Response<String>) -> Outcome {
+    device.mode = if args.choice(0) == 0 { Mode::Human } else { Mode::Json };
+    Ok(())
+}
+
+fn drv_on_command(device: &mut FakeDevice, _args: &Values, _response: &mut Response<String>) -> Outcome {
+    device.drv_enabled = true;
+    Ok(())
+}
+
+fn drv_off_command(device: &mut FakeDevice, _args: &Values, _response: &mut Response<String>) -> Outcome {
+    device.drv_enabled = false;
+    Ok(())
+}
+
+fn status_command(device: &mut FakeDevice, _args: &Values, response: &mut Response<String>) -> Outcome {
+    response.field("drv_enabled", device.drv_enabled, "");
+    response.field("v_in", device.v_in, "mV");
+    Ok(())
+}
+
+fn gain_command(device: &mut FakeDevice, args: &Values, response: &mut Response<String>) -> Outcome {
+    if !device.drv_enabled {
+        return Err(DRV_DISABLED);
+    }
+    device.gain = args.text(0).parse().unwrap_or(device.gain);
+    response.field("gain", device.gain, "V/V");
+    Ok(())
+}
+
+fn vin_command(device: &mut FakeDevice, args: &Values, _response: &mut Response<String>) -> Outcome {
+    device.v_in = args.int(0);
+    Ok(())
+}
+
+/// Host to device half.
+struct Input(Rc<RefCell<FakeDevice>>);
+
+impl Write for Input {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().feed(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Device to host half, runs dry instead of blocking.
+struct Output(Rc<RefCell<FakeDevice>>);
+
+impl Read for Output {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut device = self.0.borrow_mut();
+        if device.unplugged {
+            return Ok(0);
+        }
+        let len = buf.len().min(device.output.len());
+        for (b, byte) in buf.iter_mut().zip(device.output.drain(..len)) {
+            *b = byte;
+        }
+        Ok(len)
+    }
+}
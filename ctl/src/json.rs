@@ -0,0 +1,310 @@
+//! Just enough JSON for the replies of the firmware and the result files.
+
+use std::fmt::{self, Display, Write};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// Fields in the order they were written.
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Field of an object, None for other values.
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(n, _)| n == name).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            Value::Bool(b) => Some(*b as u8 as f64),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+    /// Byte offset of the problem.
+    pub at: usize,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "bad JSON at byte {}", self.at)
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser { text: text.as_bytes(), at: 0 };
+    let value = parser.value()?;
+    parser.skip_space();
+    match parser.at == parser.text.len() {
+        true => Ok(value),
+        false => Err(parser.error()),
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self) -> ParseError {
+        ParseError { at: self.at }
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = self.text.get(self.at) {
+            self.at += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.text.get(self.at).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ParseError> {
+        match self.peek() == Some(byte) {
+            true => {
+                self.at += 1;
+                Ok(())
+            }
+            false => Err(self.error()),
+        }
+    }
+
+    fn keyword(&mut self, word: &str, value: Value) -> Result<Value, ParseError> {
+        match self.text[self.at..].starts_with(word.as_bytes()) {
+            true => {
+                self.at += word.len();
+                Ok(value)
+            }
+            false => Err(self.error()),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ParseError> {
+        match self.peek().ok_or_else(|| self.error())? {
+            b'{' => self.object(),
+            b'[' => self.array(),
+            b'"' => self.string().map(Value::String),
+            b't' => self.keyword("true", Value::Bool(true)),
+            b'f' => self.keyword("false", Value::Bool(false)),
+            b'n' => self.keyword("null", Value::Null),
+            _ => self.number(),
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, ParseError> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.at += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error());
+            }
+            let name = self.string()?;
+            self.expect(b':')?;
+            fields.push((name, self.value()?));
+            match self.peek() {
+                Some(b',') => self.at += 1,
+                Some(b'}') => {
+                    self.at += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, ParseError> {
+        self.expect(b'[')?;
+        let mut items = Vec::new();
+        if self.peek() == Some(b']') {
+            self.at += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.at += 1,
+                Some(b']') => {
+                    self.at += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error()),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"')?;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = *self.text.get(self.at).ok_or_else(|| self.error())?;
+            self.at += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self.text.get(self.at).ok_or_else(|| self.error())?;
+                    self.at += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let hex = self.text.get(self.at..self.at + 4).ok_or_else(|| self.error())?;
+                            let code = std::str::from_utf8(hex).ok().and_then(|h| u32::from_str_radix(h, 16).ok());
+                            self.at += 4;
+                            // Surrogate pairs never come from the firmware
+                            code.and_then(char::from_u32).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        _ => return Err(self.error()),
+                    };
+                    let mut utf8 = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error())
+    }
+
+    fn number(&mut self) -> Result<Value, ParseError> {
+        let start = self.at;
+        while let Some(b'0'..=b'9') | Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') = self.text.get(self.at) {
+            self.at += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.at])
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .map(Value::Number)
+            .ok_or(ParseError { at: start })
+    }
+}
+
+/// Writes `text` as a JSON string with the quotes.
+pub struct Quoted<'a>(pub &'a str);
+
+impl Display for Quoted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('"')?;
+        for c in self.0.chars() {
+            match c {
+                '"' => f.write_str("\\\"")?,
+                '\\' => f.write_str("\\\\")?,
+                '\n' => f.write_str("\\n")?,
+                '\r' => f.write_str("\\r")?,
+                '\t' => f.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+                c => f.write_char(c)?,
+            }
+        }
+        f.write_char('"')
+    }
+}
+
+/// Compact JSON, objects keep their field order.
+impl Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(n) if n.is_finite() => write!(f, "{}", n),
+            Value::Number(_) => f.write_str("null"),
+            Value::String(s) => write!(f, "{}", Quoted(s)),
+            Value::Array(items) => {
+                f.write_char('[')?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                f.write_char(']')
+            }
+            Value::Object(fields) => {
+                f.write_char('{')?;
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        f.write_char(',')?;
+                    }
+                    write!(f, "{}:{}", Quoted(name), value)?;
+                }
+                f.write_char('}')
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firmware_replies() {
+        let reply = parse("{\"fields\":{\"v_in\":24000,\"drv\":true,\"od\":\"2020:05\"},\"status\":\"ok\",\"code\":0}").unwrap();
+        let fields = reply.get("fields").unwrap();
+        assert_eq!(fields.get("v_in"), Some(&Value::Number(24000.0)));
+        assert_eq!(fields.get("drv").and_then(Value::as_f64), Some(1.0));
+        assert_eq!(fields.get("od").and_then(Value::as_str), Some("2020:05"));
+        assert_eq!(reply.get("status").and_then(Value::as_str), Some("ok"));
+        assert_eq!(reply.get("missing"), None);
+
+        let text = parse(" [ \"a\\\\b\\n\\u001b\" , -1.5e3, null, {} ] ").unwrap();
+        assert_eq!(
+            text,
+            Value::Array(vec![
+                Value::String("a\\b\n\u{1b}".to_string()),
+                Value::Number(-1500.0),
+                Value::Null,
+                Value::Object(vec![]),
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(parse(""), Err(ParseError { at: 0 }));
+        assert_eq!(parse("{\"a\":1"), Err(ParseError { at: 6 }));
+        assert_eq!(parse("{\"a\" 1}"), Err(ParseError { at: 5 }));
+        assert_eq!(parse("[1,]"), Err(ParseError { at: 3 }));
+        assert_eq!(parse("tru"), Err(ParseError { at: 0 }));
+        assert_eq!(parse("{} x"), Err(ParseError { at: 3 }));
+        assert_eq!(parse("\"open"), Err(ParseError { at: 5 }));
+    }
+
+    #[test]
+    fn written_back() {
+        let text = "{\"command\":\"cfg set gain 20\",\"ok\":true,\"fields\":{\"v\":-1.5,\"s\":\"\\\"q\\\"\\u0007\"},\"list\":[1,null]}";
+        let value = parse(text).unwrap();
+        assert_eq!(value.to_string(), text);
+        assert_eq!(parse(&value.to_string()), Ok(value));
+    }
+}
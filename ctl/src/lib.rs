@@ -0,0 +1,6 @@
+//! Host side control of the tester over the JSON reply mode of the firmware CLI.
+pub mod device;
+pub mod fake;
+pub mod json;
+pub mod link;
+pub mod script;
@@ -0,0 +1,228 @@
+//! Byte streams to the firmware terminal and the transports that carry them.
+
+use crate::fake::FakeDevice;
+use std::fmt::{self, Display};
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How long a TCP transport waits for the next byte before giving up.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const RTT_DEFAULT_ADDRESS: &str = "localhost:9090";
+
+/// rtt-target switches virtual terminals with this byte followed by `'0' + terminal`.
+const TERMINAL_SWITCH: u8 = 0xFF;
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Transport {
+    /// RTT channel 0 served over TCP by the debug probe software, e.g. OpenOCD
+    /// `rtt server start 9090 0`. Only terminal 0 is kept, the status page is on terminal 1.
+    Rtt(String),
+    /// USB or UART serial port, configure the line with stty beforehand.
+    Serial(String),
+    Tcp(String),
+    /// Child process talking on its stdin and stdout, a stand-in for the device.
+    Exec(String),
+    /// This process' stdin and stdout, for piping through other tools.
+    Stdio,
+    /// `fake::FakeDevice`, for trying things out without hardware.
+    Fake,
+}
+
+pub const TRANSPORT_USAGE: &str = "rtt[:host:port] serial:/dev/ttyACM0 tcp:host:port exec:command stdio fake";
+
+impl FromStr for Transport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, target) = match s.find(':') {
+            Some(at) => (&s[..at], Some(&s[at + 1..])),
+            None => (s, None),
+        };
+        match (kind, target) {
+            ("rtt", None) => Ok(Transport::Rtt(RTT_DEFAULT_ADDRESS.to_string())),
+            ("rtt", Some(address)) => Ok(Transport::Rtt(address.to_string())),
+            ("serial", Some(path)) => Ok(Transport::Serial(path.to_string())),
+            ("tcp", Some(address)) => Ok(Transport::Tcp(address.to_string())),
+            ("exec", Some(command)) => Ok(Transport::Exec(command.to_string())),
+            ("stdio", None) => Ok(Transport::Stdio),
+            ("fake", None) => Ok(Transport::Fake),
+            _ => Err(format!("Unknown transport: {}, expected one of {}", s, TRANSPORT_USAGE)),
+        }
+    }
+}
+
+impl Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transport::Rtt(address) => write!(f, "rtt:{}", address),
+            Transport::Serial(path) => write!(f, "serial:{}", path),
+            Transport::Tcp(address) => write!(f, "tcp:{}", address),
+            Transport::Exec(command) => write!(f, "exec:{}", command),
+            Transport::Stdio => f.write_str("stdio"),
+            Transport::Fake => f.write_str("fake"),
+        }
+    }
+}
+
+impl Transport {
+    pub fn open(&self) -> io::Result<Link> {
+        match self {
+            Transport::Rtt(address) | Transport::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_read_timeout(Some(READ_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                let link = Link::new(stream.try_clone()?, stream);
+                Ok(match self {
+                    Transport::Rtt(_) => link.with_terminals(),
+                    _ => link,
+                })
+            }
+            Transport::Serial(path) => {
+                let port = OpenOptions::new().read(true).write(true).open(path)?;
+                Ok(Link::new(port.try_clone()?, port))
+            }
+            Transport::Exec(command) => {
+                let mut words = command.split_ascii_whitespace();
+                let program = words.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+                let mut child = Command::new(program).args(words).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()?;
+                let stdin = child.stdin.take().expect("piped");
+                let stdout = child.stdout.take().expect("piped");
+                let mut link = Link::new(stdout, stdin);
+                link.child = Some(child);
+                Ok(link)
+            }
+            Transport::Stdio => Ok(Link::new(io::stdin(), io::stdout())),
+            Transport::Fake => Ok(FakeDevice::link(&FakeDevice::shared())),
+        }
+    }
+}
+
+/// Line oriented connection to the firmware terminal.
+pub struct Link {
+    input: BufReader<Box<dyn Read>>,
+    output: Box<dyn Write>,
+    /// Strip rtt-target virtual terminal switches and keep terminal 0.
+    terminals: bool,
+    terminal: u8,
+    child: Option<Child>,
+}
+
+impl Link {
+    pub fn new(input: impl Read + 'static, output: impl Write + 'static) -> Self {
+        Link {
+            input: BufReader::new(Box::new(input)),
+            output: Box::new(output),
+            terminals: false,
+            terminal: 0,
+            child: None,
+        }
+    }
+
+    pub fn with_terminals(mut self) -> Self {
+        self.terminals = true;
+        self
+    }
+
+    pub fn send_line(&mut self, line: &str) -> io::Result<()> {
+        self.output.write_all(line.as_bytes())?;
+        self.send(b"\n")
+    }
+
+    pub fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)?;
+        self.output.flush()
+    }
+
+    /// Next line without the line end, None once the device went away.
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        let mut switching = false;
+        loop {
+            let available = match self.input.fill_buf() {
+                Ok(buf) => buf,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no reply from the device"));
+                }
+                Err(e) => return Err(e),
+            };
+            if available.is_empty() {
+                return Ok(match line.is_empty() {
+                    true => None,
+                    false => Some(String::from_utf8_lossy(&line).into_owned()),
+                });
+            }
+            let mut used = 0;
+            let mut complete = false;
+            for &byte in available {
+                used += 1;
+                if switching {
+                    self.terminal = byte.wrapping_sub(b'0');
+                    switching = false;
+                    continue;
+                }
+                if self.terminals && byte == TERMINAL_SWITCH {
+                    switching = true;
+                    continue;
+                }
+                if self.terminal != 0 {
+                    continue;
+                }
+                match byte {
+                    b'\n' => {
+                        complete = true;
+                        break;
+                    }
+                    b'\r' => {}
+                    byte => line.push(byte),
+                }
+            }
+            self.input.consume(used);
+            if complete {
+                return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+            }
+        }
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            child.kill().ok();
+            child.wait().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transports() {
+        assert_eq!("rtt".parse(), Ok(Transport::Rtt("localhost:9090".to_string())));
+        assert_eq!("rtt:probe:19021".parse(), Ok(Transport::Rtt("probe:19021".to_string())));
+        assert_eq!("serial:/dev/ttyACM0".parse(), Ok(Transport::Serial("/dev/ttyACM0".to_string())));
+        assert_eq!("exec:sim --json".parse(), Ok(Transport::Exec("sim --json".to_string())));
+        assert_eq!("stdio".parse(), Ok(Transport::Stdio));
+        assert!("serial".parse::<Transport>().is_err());
+        assert!("usb:1".parse::<Transport>().is_err());
+        assert_eq!(Transport::Tcp("host:1".to_string()).to_string(), "tcp:host:1");
+    }
+
+    #[test]
+    fn lines_from_terminal_0() {
+        let stream = b"> \xFF1status\npage\n\xFF0{\"a\":1}\r\n\xFF1more\n\xFF0last".to_vec();
+        let mut link = Link::new(io::Cursor::new(stream), io::sink()).with_terminals();
+        assert_eq!(link.read_line().unwrap().as_deref(), Some("> {\"a\":1}"));
+        assert_eq!(link.read_line().unwrap().as_deref(), Some("last"));
+        assert_eq!(link.read_line().unwrap(), None);
+
+        let mut raw = Link::new(io::Cursor::new(b"a\xFF1\n".to_vec()), io::sink());
+        assert_eq!(raw.read_line().unwrap(), Some("a\u{FFFD}1".to_string()));
+    }
+}
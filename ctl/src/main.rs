@@ -0,0 +1,88 @@
+//! Controls the tester from the host over the JSON reply mode of the firmware CLI:
+//! `power-stage-ctl [-t transport] drv on` runs one firmware command,
+//! `power-stage-ctl [-t transport] run script.txt [-o results.jsonl]` runs a test script,
+//! `power-stage-ctl [-t transport] shell` takes commands from stdin, one per line.
+use power_stage_ctl::device::{Device, Reply};
+use power_stage_ctl::link::{Transport, TRANSPORT_USAGE};
+use power_stage_ctl::script;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+fn usage() -> ! {
+    fail(format!(
+        "Usage: power-stage-ctl [-t transport] <firmware command> | run script.txt [-o results.jsonl] | shell\n\
+         Transports: {}, default rtt",
+        TRANSPORT_USAGE
+    ))
+}
+
+fn print_reply(reply: &Reply) {
+    for (name, value) in reply.fields.iter() {
+        match value.as_str() {
+            Some(text) => println!("{}: {}", name, text),
+            None => println!("{}: {}", name, value),
+        }
+    }
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let mut transport = Transport::Rtt("localhost:9090".to_string());
+    if args.first().map(String::as_str) == Some("-t") {
+        if args.len() < 2 {
+            usage();
+        }
+        transport = args[1].parse().unwrap_or_else(|e| fail(e));
+        args.drain(..2);
+    }
+    if args.is_empty() {
+        usage();
+    }
+    let link = transport.open().unwrap_or_else(|e| fail(format!("{}: {}", transport, e)));
+    let mut device = Device::connect(link).unwrap_or_else(|e| fail(format!("{}: {}", transport, e)));
+
+    match args[0].as_str() {
+        "run" => {
+            let (path, output) = match &args[1..] {
+                [path] => (path, None),
+                [path, flag, output] if flag == "-o" => (path, Some(output)),
+                _ => usage(),
+            };
+            let text = fs::read_to_string(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            let steps = script::parse(&text).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            let mut results: Box<dyn Write> = match output {
+                Some(output) => Box::new(BufWriter::new(File::create(output).unwrap_or_else(|e| fail(format!("{}: {}", output, e))))),
+                None => Box::new(io::stdout()),
+            };
+            let passed = script::run(&mut device, &steps, &mut results).and_then(|p| results.flush().map(|_| p));
+            match passed {
+                Ok(true) => {}
+                Ok(false) => fail("Script failed"),
+                Err(e) => fail(e),
+            }
+        }
+        "shell" => {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                let line = line.unwrap_or_else(|e| fail(e));
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match device.command(line) {
+                    Ok(reply) => print_reply(&reply),
+                    Err(e) => eprintln!("{}", e),
+                }
+            }
+        }
+        _ => match device.command(&args.join(" ")) {
+            Ok(reply) => print_reply(&reply),
+            Err(e) => fail(e),
+        },
+    }
+}
@@ -0,0 +1,235 @@
+//! Test scripts: firmware commands one per line, with checks on the replies.
+//!
+//! ```text
+//! # Comments and empty lines are skipped
+//! drv on
+//! status
+//! expect v_in 22000..26000    # field of the last reply within a range, bounds included
+//! expect drv_enabled true     # or equal to a value
+//! sleep 500                   # ms
+//! ```
+//!
+//! The run stops at the first failed command or check. Every step is saved as one JSON object
+//! per line, the last line is the summary.
+
+use crate::device::{Device, Error, Reply};
+use crate::json::{Quoted, Value};
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Check {
+    Equals(Value),
+    Range(f64, f64),
+}
+
+impl Check {
+    fn parse(text: &str) -> Check {
+        if let Some(at) = text.find("..") {
+            if let (Ok(min), Ok(max)) = (text[..at].parse(), text[at + 2..].parse()) {
+                return Check::Range(min, max);
+            }
+        }
+        match text {
+            "true" => Check::Equals(Value::Bool(true)),
+            "false" => Check::Equals(Value::Bool(false)),
+            text => match text.parse() {
+                Ok(n) => Check::Equals(Value::Number(n)),
+                Err(_) => Check::Equals(Value::String(text.to_string())),
+            },
+        }
+    }
+
+    pub fn passes(&self, value: &Value) -> bool {
+        match self {
+            Check::Equals(expected) => value == expected,
+            Check::Range(min, max) => value.as_f64().is_some_and(|v| *min <= v && v <= *max),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Check::Equals(value) => write!(f, "{}", value),
+            Check::Range(min, max) => write!(f, "{}..{}", min, max),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Step {
+    Command(String),
+    Expect(String, Check),
+    Sleep(Duration),
+}
+
+/// Step with its line number, counting from 1.
+pub type Line = (usize, Step);
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: &'static str,
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Line>, ScriptError> {
+    let mut steps = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(at) => &line[..at],
+            None => line,
+        };
+        let mut words = line.split_ascii_whitespace();
+        let error = |message| ScriptError { line: i + 1, message };
+        let step = match words.next() {
+            None => continue,
+            Some("expect") => {
+                let field = words.next().ok_or_else(|| error("expected a field name"))?;
+                let check = words.next().ok_or_else(|| error("expected a value or a min..max range"))?;
+                Step::Expect(field.to_string(), Check::parse(check))
+            }
+            Some("sleep") => {
+                let ms = words.next().and_then(|ms| ms.parse().ok()).ok_or_else(|| error("expected time in ms"))?;
+                Step::Sleep(Duration::from_millis(ms))
+            }
+            Some(_) => Step::Command(line.split_ascii_whitespace().collect::<Vec<_>>().join(" ")),
+        };
+        if matches!(step, Step::Expect(..) | Step::Sleep(_)) && words.next().is_some() {
+            return Err(error("unexpected words after the step"));
+        }
+        steps.push((i + 1, step));
+    }
+    Ok(steps)
+}
+
+/// Runs the steps and writes the results, returns whether everything passed.
+/// Errors are only those of writing the results, device failures are results.
+pub fn run(device: &mut Device, steps: &[Line], results: &mut impl Write) -> io::Result<bool> {
+    let mut last = Reply { fields: Vec::new() };
+    let mut passed = true;
+    let mut done = 0;
+    for (line, step) in steps {
+        write!(results, "{{\"line\":{},", line)?;
+        match step {
+            Step::Command(command) => {
+                write!(results, "\"command\":{},", Quoted(command))?;
+                match device.command(command) {
+                    Ok(reply) => {
+                        writeln!(results, "\"status\":\"ok\",\"fields\":{}}}", Value::Object(reply.fields.clone()))?;
+                        last = reply;
+                    }
+                    Err(e) => {
+                        let error = match &e {
+                            Error::Failed { error, .. } => error.as_str(),
+                            _ => "link",
+                        };
+                        writeln!(results, "\"status\":\"error\",\"error\":{},\"message\":{}}}", Quoted(error), Quoted(&e.to_string()))?;
+                        passed = false;
+                    }
+                }
+            }
+            Step::Expect(field, check) => {
+                let value = last.get(field).cloned().unwrap_or(Value::Null);
+                let pass = check.passes(&value);
+                writeln!(
+                    results,
+                    "\"expect\":{},\"value\":{},\"limits\":{},\"status\":\"{}\"}}",
+                    Quoted(field),
+                    value,
+                    Quoted(&check.to_string()),
+                    if pass { "pass" } else { "fail" }
+                )?;
+                passed = pass;
+            }
+            Step::Sleep(time) => {
+                thread::sleep(*time);
+                writeln!(results, "\"sleep_ms\":{}}}", time.as_millis())?;
+            }
+        }
+        done += 1;
+        if !passed {
+            break;
+        }
+    }
+    writeln!(results, "{{\"summary\":{{\"steps\":{},\"done\":{},\"passed\":{}}}}}", steps.len(), done, passed)?;
+    Ok(passed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fake::FakeDevice;
+    use crate::json;
+
+    const SCRIPT: &str = "\
+# Gain needs the DRV
+drv on
+cfg set gain 20   # amplifier
+expect gain 20
+
+status
+expect v_in 23000..25000
+expect drv_enabled true
+sleep 1
+";
+
+    fn run_script(script: &str, prepare: impl FnOnce(&mut FakeDevice)) -> (bool, Vec<json::Value>) {
+        let fake = FakeDevice::shared();
+        prepare(&mut fake.borrow_mut());
+        let mut device = Device::connect(FakeDevice::link(&fake)).unwrap();
+        let steps = parse(script).unwrap();
+        let mut results = Vec::new();
+        let passed = run(&mut device, &steps, &mut results).unwrap();
+        let results = String::from_utf8(results).unwrap();
+        (passed, results.lines().map(|line| json::parse(line).unwrap()).collect())
+    }
+
+    #[test]
+    fn parses_steps() {
+        let steps = parse(SCRIPT).unwrap();
+        assert_eq!(steps.len(), 7);
+        assert_eq!(steps[1], (3, Step::Command("cfg set gain 20".to_string())));
+        assert_eq!(steps[4], (7, Step::Expect("v_in".to_string(), Check::Range(23000.0, 25000.0))));
+        assert_eq!(steps[6], (9, Step::Sleep(Duration::from_millis(1))));
+        assert_eq!(parse("expect\n"), Err(ScriptError { line: 1, message: "expected a field name" }));
+        assert_eq!(parse("drv on\nsleep soon"), Err(ScriptError { line: 2, message: "expected time in ms" }));
+        assert!(parse("expect a 1 2").is_err());
+
+        assert!(Check::parse("ok").passes(&Value::String("ok".to_string())));
+        assert!(Check::parse("-1..1").passes(&Value::Number(0.5)));
+        assert!(!Check::parse("-1..1").passes(&Value::Null));
+    }
+
+    #[test]
+    fn passing_run() {
+        let (passed, results) = run_script(SCRIPT, |_| {});
+        assert!(passed);
+        assert_eq!(results.len(), 8);
+        assert_eq!(results[1].get("fields").and_then(|f| f.get("gain")), Some(&Value::Number(20.0)));
+        assert_eq!(results[4].get("status").and_then(Value::as_str), Some("pass"));
+        assert_eq!(results[7].get("summary").and_then(|s| s.get("passed")), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn stops_at_failure() {
+        let (passed, results) = run_script(SCRIPT, |fake| fake.v_in = 12_000);
+        assert!(!passed);
+        assert_eq!(results.len(), 6);
+        assert_eq!(results[4].get("value"), Some(&Value::Number(12000.0)));
+        assert_eq!(results[4].get("status").and_then(Value::as_str), Some("fail"));
+
+        let (passed, results) = run_script("cfg set gain 20\nstatus\n", |_| {});
+        assert!(!passed);
+        assert_eq!(results[0].get("error").and_then(Value::as_str), Some("wrong_mode"));
+        assert_eq!(results[1].get("summary").and_then(|s| s.get("done")), Some(&Value::Number(1.0)));
+    }
+}
@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* STM32F405RG (flasher.conf): 1024K in sectors 0..11. Sectors 10 and 11 (2 x 128K) are
+     reserved for the configuration, see src/flash.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 768K
+  CONFIG : ORIGIN = 0x080C0000, LENGTH = 256K
+  RAM : ORIGIN = 0x20000000, LENGTH = 64K
+}
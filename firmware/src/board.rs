@@ -0,0 +1,40 @@
+//! Power stage revision selected at build time with a cargo feature, `rev1` is the default and
+//! the only one described so far, see `power_stage_core::board`.
+use power_stage_core::board::{Board, Pin, Port};
+
+#[cfg(feature = "rev1")]
+pub const BOARD: Board = power_stage_core::board::REV1;
+
+#[cfg(not(feature = "rev1"))]
+compile_error!("select a board revision: rev1");
+
+/// Gate outputs are TIM1 channels and complementary outputs, `init` wires these pins
+/// and `OpenLoop` switches them to AF1, other pins can't be driven by the PWM.
+const TIM1_PINS: [(Pin, Pin); 3] = [
+    (Pin::new(Port::A, 8), Pin::new(Port::B, 13)),
+    (Pin::new(Port::A, 9), Pin::new(Port::B, 14)),
+    (Pin::new(Port::A, 10), Pin::new(Port::B, 15)),
+];
+
+const fn legs_on_tim1(board: &Board) -> bool {
+    let mut i = 0;
+    while i < 3 {
+        if !board.legs[i].high.same_as(&TIM1_PINS[i].0) || !board.legs[i].low.same_as(&TIM1_PINS[i].1) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+const _: () = assert!(legs_on_tim1(&BOARD), "board gate outputs must be on TIM1 pins");
+
+/// PB12 is the only free TIM1_BKIN pin, `init` configures it when the board has a break input.
+const fn break_on_pb12(board: &Board) -> bool {
+    match board.fault_break {
+        Some(pin) => pin.same_as(&Pin::new(Port::B, 12)),
+        None => true,
+    }
+}
+
+const _: () = assert!(break_on_pb12(&BOARD), "board fault break must be on PB12");
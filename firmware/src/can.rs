@@ -0,0 +1,188 @@
+use stm32f4xx_hal as hal;
+use hal::{
+    pac::CAN1,
+    time::Hertz,
+    gpio::{gpiob::{PB8, PB9}, Alternate, AF9},
+};
+use bxcan::{filter::{ListEntry32, Mask32}, ExtendedId, Frame, Id, StandardId};
+use embedded_hal::blocking::delay::DelayMs;
+use power_stage_core::remote;
+use power_stage_core::canopen;
+use power_stage_core::can::{bit_timing, diagnose_bus, decode_esr, Bitrate, BusDiagnosis, ErrorStatus};
+
+/// CAN1 and its pins, in place of `hal::can::Can` so the error register stays readable through
+/// the owned peripheral, bxcan has no accessor for it.
+pub struct Can1Instance {
+    regs: CAN1,
+    _pins: (PB9<Alternate<AF9>>, PB8<Alternate<AF9>>),
+}
+
+impl Can1Instance {
+    fn new(regs: CAN1, pins: (PB9<Alternate<AF9>>, PB8<Alternate<AF9>>)) -> Self {
+        <CAN1 as hal::can::Enable>::enable();
+        Can1Instance { regs, _pins: pins }
+    }
+}
+
+// Owns CAN1, the same guarantees as hal::can::Can<CAN1>
+unsafe impl bxcan::Instance for Can1Instance {
+    const REGISTERS: *mut bxcan::RegisterBlock = CAN1::ptr() as *mut _;
+}
+
+unsafe impl bxcan::FilterOwner for Can1Instance {
+    const NUM_FILTER_BANKS: u8 = 28;
+}
+
+unsafe impl bxcan::MasterInstance for Can1Instance {}
+
+pub type Can1 = bxcan::Can<Can1Instance>;
+
+const DEFAULT_BITRATE: Bitrate = Bitrate::K500;
+const DEFAULT_NODE_ID: u8 = 1;
+
+/// Frames used in the loopback self test, covering both ID formats and ID bit patterns.
+const SELF_TEST_IDS: [u32; 6] = [0x000, 0x555, 0x7FF, 0x0000_0000, 0x1555_5555, 0x1FFF_FFFF];
+const SELF_TEST_TIMEOUT_MS: u32 = 10;
+
+fn self_test_frame(n: usize) -> Frame {
+    let data = [n as u8, 0x55, 0xAA, 0x00, 0xFF, 0x0F, 0xF0, !(n as u8)];
+    if n < 3 {
+        Frame::new_data(StandardId::new(SELF_TEST_IDS[n] as u16).unwrap(), data)
+    } else {
+        Frame::new_data(ExtendedId::new(SELF_TEST_IDS[n]).unwrap(), data)
+    }
+}
+
+pub struct CanNode {
+    can: Can1,
+    pclk: Hertz,
+    pub bitrate: Bitrate,
+    pub node_id: u8,
+    /// Send telemetry every n-th UI tick, 0 = off.
+    pub telemetry_divider: u8,
+    pub telemetry_counter: u8,
+    pub canopen: canopen::Node,
+}
+impl CanNode {
+    pub fn new(can1: CAN1, tx: PB9<Alternate<AF9>>, rx: PB8<Alternate<AF9>>, pclk: Hertz) -> Self {
+        let can = bxcan::Can::new(Can1Instance::new(can1, (tx, rx)));
+        let mut node = CanNode {
+            can,
+            pclk,
+            bitrate: DEFAULT_BITRATE,
+            node_id: DEFAULT_NODE_ID,
+            telemetry_divider: 0,
+            telemetry_counter: 0,
+            canopen: canopen::Node::new(DEFAULT_NODE_ID),
+        };
+        node.configure(DEFAULT_BITRATE, DEFAULT_NODE_ID);
+        node
+    }
+
+    /// Bus setup and a fresh CANopen node, the caller sends the boot-up frame. Returns false if
+    /// the bitrate is not reachable with the current clock, nothing changes then.
+    pub fn configure(&mut self, bitrate: Bitrate, node_id: u8) -> bool {
+        if !self.setup_bus(bitrate, node_id) {
+            return false;
+        }
+        self.canopen = canopen::Node::new(node_id);
+        true
+    }
+
+    /// Bit timing, filters and enable, the CANopen node keeps its state.
+    fn setup_bus(&mut self, bitrate: Bitrate, node_id: u8) -> bool {
+        let btr = match bit_timing(self.pclk.0, bitrate) {
+            Some(btr) => btr,
+            None => return false
+        };
+        self.can.modify_config().set_bit_timing(btr);
+        let mask = ExtendedId::MAX;
+        let command_id = ExtendedId::new(remote::command_id(node_id)).unwrap();
+        let sdo_rx = StandardId::new(canopen::SDO_RX_BASE + node_id as u16).unwrap();
+        let nmt = StandardId::new(canopen::NMT_ID).unwrap();
+        self.can.modify_filters()
+            .clear()
+            .enable_bank(0, Mask32::frames_with_ext_id(command_id, mask))
+            .enable_bank(1, [
+                ListEntry32::data_frames_with_id(sdo_rx),
+                ListEntry32::data_frames_with_id(nmt),
+            ]);
+        nb::block!(self.can.enable()).ok();
+        self.bitrate = bitrate;
+        self.node_id = node_id;
+        true
+    }
+
+    /// Sends test frames in silent loopback mode, bus is not affected.
+    /// Returns number of frames received back intact and total number of frames.
+    /// Configuration is restored afterwards.
+    pub fn loopback_test<D: DelayMs<u32>>(&mut self, bitrate: Bitrate, delay: &mut D) -> Option<(usize, usize)> {
+        let btr = bit_timing(self.pclk.0, bitrate)?;
+        self.can.modify_config()
+            .set_bit_timing(btr)
+            .set_loopback(true)
+            .set_silent(true);
+        self.can.modify_filters()
+            .clear()
+            .enable_bank(0, Mask32::accept_all());
+        nb::block!(self.can.enable()).ok();
+        while self.can.receive().is_ok() {}
+
+        let mut passed = 0;
+        for n in 0..SELF_TEST_IDS.len() {
+            let frame = self_test_frame(n);
+            if nb::block!(self.can.transmit(&frame)).is_err() {
+                continue;
+            }
+            for _ in 0..SELF_TEST_TIMEOUT_MS {
+                if let Ok(received) = self.can.receive() {
+                    if received == frame {
+                        passed += 1;
+                    }
+                    break;
+                }
+                delay.delay_ms(1);
+            }
+        }
+
+        self.restore();
+        Some((passed, SELF_TEST_IDS.len()))
+    }
+
+    /// Sends a single frame on the bus in normal mode and reports error counters.
+    pub fn bus_test<D: DelayMs<u32>>(&mut self, delay: &mut D) -> (BusDiagnosis, ErrorStatus) {
+        let id = ExtendedId::new(remote::frame_id(remote::FrameKind::Probe, self.node_id)).unwrap();
+        let frame = Frame::new_data(Id::Extended(id), [0u8; 0]);
+        let transmitted = match nb::block!(self.can.transmit_and_get_mailbox(&frame)) {
+            Ok((_, mailbox)) => {
+                delay.delay_ms(SELF_TEST_TIMEOUT_MS);
+                !self.can.abort(mailbox)
+            }
+            Err(_) => false
+        };
+        let status = self.error_status();
+        (diagnose_bus(transmitted, &status), status)
+    }
+
+    pub fn error_status(&mut self) -> ErrorStatus {
+        decode_esr(self.can.instance().regs.esr.read().bits())
+    }
+
+    fn restore(&mut self) {
+        self.can.modify_config()
+            .set_loopback(false)
+            .set_silent(false);
+        // Back on the bus as before, a master keeps its TPDO setup and NMT state
+        let (bitrate, node_id) = (self.bitrate, self.node_id);
+        self.setup_bus(bitrate, node_id);
+    }
+
+    pub fn receive(&mut self) -> Option<Frame> {
+        self.can.receive().ok()
+    }
+
+    /// Drops the frame if all mailboxes are busy.
+    pub fn transmit(&mut self, frame: &Frame) -> bool {
+        self.can.transmit(frame).is_ok()
+    }
+}
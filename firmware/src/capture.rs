@@ -0,0 +1,438 @@
+//! Waveform capture: TIM2 triggers ADC2 at `SAMPLE_RATE_HZ`, the ADC inputs of the selected
+//! sources are converted as an injected sequence and the end of sequence interrupt records a
+//! sample with `power_stage_core::capture`. ADC1 stays with the UI. TIM2 runs free of the PWM
+//! timer, samples land anywhere in the PWM period and the ripple aliases into the record.
+//!
+//! The binary telemetry stream shares the pacing: while it runs TIM2 triggers at twice
+//! `STREAM_RATE_HZ`, V_IN and the phase voltages are converted in one sequence, the phase currents
+//! and V_CAN in the next, and every second interrupt hands a finished frame to the stream task.
+//! Starting one stops the other.
+//!
+//! The `Recorder` with ADC2 and TIM2 is a local of the ADC task. The record and the mode changes
+//! the UI asks for are the `Link` resource: once per tick the UI hands its request over and copies
+//! out what it needs under a short lock, then pends the ADC task to apply the request.
+//!
+//! Finished records are dumped as CSV on their own RTT up channel, a few lines per UI tick. Each
+//! line goes out in one write and the channel blocks when full, so a dump is complete even with
+//! the status page busy on the terminal. The buffer holds a tick's lines, only a host that stops
+//! reading mid-dump holds the UI up.
+
+use stm32f4xx_hal as hal;
+use hal::pac::{Interrupt, ADC2, TIM2};
+use hal::rcc::Clocks;
+use hal::time::Hertz;
+use core::sync::atomic::{AtomicBool, Ordering};
+use rtt_target::UpChannel;
+use power_stage_core::capture::{Capture, CsvLine, Settings, SettingsError, Source, State, Trigger, CSV_LINE_LEN, MAX_SOURCES};
+use power_stage_core::config::Config;
+use power_stage_core::stage::{Channel, Phase};
+use power_stage_core::telemetry::Sample;
+use power_stage_core::units::MilliVolts;
+use crate::board::BOARD;
+use crate::observer::StreamInputs;
+use crate::{halls, observer, openloop, protection};
+
+pub const DEPTH: usize = 1024;
+pub const SAMPLE_RATE_HZ: u32 = 10_000;
+/// Telemetry frames per second. A frame takes about 0.45ms to encode at 8MHz and 51 bytes on RTT,
+/// about 11% of the CPU and 13kB/s for the probe.
+pub const STREAM_RATE_HZ: u32 = 250;
+/// Injected sequences of a telemetry frame, in turn. The frame looks its inputs up by channel.
+const STREAM_RANKS: [[Channel; MAX_SOURCES]; 2] = [
+    [Channel::VIn, Channel::VA, Channel::VB, Channel::VC],
+    [Channel::IA, Channel::IB, Channel::IC, Channel::VCan],
+];
+const PERIOD_NS: u32 = 1_000_000_000 / SAMPLE_RATE_HZ;
+const DUMP_LINES_PER_TICK: usize = 16;
+/// Dump channel buffer, `init` sizes it.
+pub const DUMP_BUFFER_LEN: usize = 2048;
+const _: () = assert!(DUMP_LINES_PER_TICK * CSV_LINE_LEN < DUMP_BUFFER_LEN, "a tick's dump lines must fit the channel");
+const ADC_MAX: u32 = 4095;
+/// RM0090 JEXTSEL, the PAC names these after another family.
+const JEXTSEL_TIM2_TRGO: u8 = 0b0011;
+/// 28 cycles for every input, same as the ADC1 conversions.
+const SMPR1_28_CYCLES: u32 = 0x0249_2492;
+const SMPR2_28_CYCLES: u32 = 0x1249_2492;
+
+/// What the interrupt needs to turn conversions into values, fixed while armed.
+struct Scaling {
+    /// ADC channel of each injected rank.
+    ranks: [Channel; MAX_SOURCES],
+    rank_count: usize,
+    vdda: u32,
+    config: Config,
+}
+
+impl Scaling {
+    fn convert(&self, channel: Channel, raw: u16) -> i32 {
+        let v_adc = MilliVolts((raw as u32 * self.vdda / ADC_MAX) as i32);
+        let value = BOARD.convert(channel, v_adc - self.config.offset(channel), self.config.current_sense_gain);
+        self.config.correct(channel, value)
+    }
+}
+
+/// Telemetry in progress.
+struct Stream {
+    scaling: Scaling,
+    /// Values of the first sequence while the second one converts.
+    first: Option<[i32; MAX_SOURCES]>,
+    sequence: u32,
+}
+
+enum Mode {
+    Capture(Scaling),
+    Stream(Stream),
+}
+
+/// Mode change asked for by the UI, applied by the ADC task.
+enum Request {
+    Capture(Scaling),
+    Stream(Scaling),
+    Stop,
+}
+
+/// Shared by the UI and the ADC task as an RTIC resource.
+pub struct Link {
+    capture: Capture<DEPTH>,
+    request: Option<Request>,
+}
+
+impl Link {
+    pub const fn new() -> Self {
+        Link { capture: Capture::new(), request: None }
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Set before a CLI command runs, fires the command trigger on the next sample.
+static COMMAND: AtomicBool = AtomicBool::new(false);
+
+/// ADC2 and TIM2, owned by the ADC task.
+pub struct Recorder {
+    adc: ADC2,
+    tim: TIM2,
+    /// Counts per second at the TIM2 input.
+    timer_clock: u32,
+    mode: Option<Mode>,
+}
+
+impl Recorder {
+    /// TIM2 and ADC2 clocks have to be enabled, `init` does it.
+    pub fn new(adc: ADC2, tim: TIM2, clocks: &Clocks) -> Self {
+        // APB1 timers run at twice the bus clock if the bus is prescaled
+        let timer_clock = if clocks.ppre1() == 1 {
+            clocks.pclk1()
+        } else {
+            Hertz(clocks.pclk1().0 * 2)
+        };
+        tim.psc.write(|w| w.psc().bits(0));
+        tim.cr2.modify(|_, w| w.mms().update());
+
+        // ADC clock prescaler is common and set up with ADC1
+        adc.smpr1.write(|w| unsafe { w.bits(SMPR1_28_CYCLES) });
+        adc.smpr2.write(|w| unsafe { w.bits(SMPR2_28_CYCLES) });
+        adc.cr1.modify(|_, w| w.scan().set_bit().jeocie().set_bit());
+        adc.cr2.modify(|_, w| unsafe { w.jextsel().bits(JEXTSEL_TIM2_TRGO) }.jexten().rising_edge().adon().set_bit());
+        Recorder { adc, tim, timer_clock: timer_clock.0, mode: None }
+    }
+
+    /// Starts converting `ranks` on every trigger at `rate_hz`.
+    fn start(&mut self, ranks: &[Channel], rate_hz: u32) {
+        self.adc.jsqr.write(|w| unsafe { w.bits(jsqr(ranks)) });
+        self.tim.arr.write(|w| unsafe { w.bits(self.timer_clock / rate_hz - 1) });
+        self.tim.cnt.reset();
+        self.tim.cr1.modify(|_, w| w.cen().enabled());
+    }
+
+    fn stop(&mut self) {
+        self.tim.cr1.modify(|_, w| w.cen().disabled());
+        self.mode = None;
+    }
+
+    fn apply(&mut self, request: Request) {
+        match request {
+            Request::Capture(scaling) => {
+                let (ranks, rank_count) = (scaling.ranks, scaling.rank_count);
+                self.mode = Some(Mode::Capture(scaling));
+                self.start(&ranks[..rank_count], SAMPLE_RATE_HZ);
+            }
+            Request::Stream(scaling) => {
+                self.mode = Some(Mode::Stream(Stream { scaling, first: None, sequence: 0 }));
+                self.start(&STREAM_RANKS[0], 2 * STREAM_RATE_HZ);
+            }
+            Request::Stop => self.stop(),
+        }
+        // A sequence still converting belongs to the previous mode
+        self.adc.sr.modify(|_, w| w.jeoc().clear_bit());
+    }
+
+    /// Applies a request of the UI or records a sample, called from the ADC task at the end of each
+    /// injected sequence and when `Sampler::sync` pends it. Returns a telemetry frame to send when
+    /// the stream has converted all of its channels.
+    pub fn record(&mut self, link: &mut Link) -> Option<Sample> {
+        if let Some(request) = link.request.take() {
+            self.apply(request);
+            return None;
+        }
+        let adc = &self.adc;
+        if adc.sr.read().jeoc().bit_is_clear() {
+            return None;
+        }
+        adc.sr.modify(|_, w| w.jeoc().clear_bit());
+        let raw = [
+            adc.jdr1.read().jdata().bits(),
+            adc.jdr2.read().jdata().bits(),
+            adc.jdr3.read().jdata().bits(),
+            adc.jdr4.read().jdata().bits(),
+        ];
+        let stream = match self.mode.as_mut()? {
+            Mode::Capture(scaling) => {
+                let capture = &mut link.capture;
+                if COMMAND.swap(false, Ordering::SeqCst) {
+                    capture.command_started();
+                }
+                capture.push(|source| match source {
+                    Source::Channel(channel) => {
+                        let rank = scaling.ranks[..scaling.rank_count].iter().position(|c| *c == channel).unwrap_or(0);
+                        scaling.convert(channel, raw[rank])
+                    }
+                    Source::Duty(phase) => openloop::current_duty(phase) as i32,
+                    Source::HallIndex => halls::read().3 as i32,
+                }, protection::is_fault());
+                if capture.state() == State::Done {
+                    self.stop();
+                }
+                return None;
+            }
+            Mode::Stream(stream) => stream,
+        };
+        let mut values = [0; MAX_SOURCES];
+        for ((value, &channel), &raw) in values.iter_mut().zip(stream.scaling.ranks.iter()).zip(raw.iter()) {
+            *value = stream.scaling.convert(channel, raw);
+        }
+        // The next trigger is half a frame away, plenty for the switch
+        let first = match stream.first.take() {
+            Some(first) => first,
+            None => {
+                stream.first = Some(values);
+                stream.scaling.ranks = STREAM_RANKS[1];
+                self.adc.jsqr.write(|w| unsafe { w.bits(jsqr(&STREAM_RANKS[1])) });
+                return None;
+            }
+        };
+        stream.scaling.ranks = STREAM_RANKS[0];
+        self.adc.jsqr.write(|w| unsafe { w.bits(jsqr(&STREAM_RANKS[0])) });
+        let sequences = [first, values];
+        let value = |channel: Channel| {
+            STREAM_RANKS.iter().zip(sequences.iter())
+                .find_map(|(ranks, values)| ranks.iter().position(|c| *c == channel).map(|rank| values[rank]))
+                .unwrap_or(0)
+        };
+        let inputs = StreamInputs {
+            v_in: value(Channel::VIn),
+            v_phase: Phase::ALL.map(|phase| value(phase.voltage_channel())),
+            i_phase: Phase::ALL.map(|phase| value(phase.current_channel())),
+            v_can: value(Channel::VCan),
+        };
+        let mut sample = observer::stream_sample(&stream.scaling.config, &inputs);
+        sample.sequence = stream.sequence;
+        stream.sequence = stream.sequence.wrapping_add(1);
+        Some(sample)
+    }
+}
+
+/// A sequence of n ranks occupies the last n JSQ fields, results land in JDR1.. in order.
+fn jsqr(ranks: &[Channel]) -> u32 {
+    let mut jsqr = (ranks.len() as u32 - 1) << 20;
+    for (i, channel) in ranks.iter().enumerate() {
+        let field = MAX_SOURCES - ranks.len() + i;
+        jsqr |= (BOARD.analog.get(*channel) as u32) << (5 * field);
+    }
+    jsqr
+}
+
+/// The UI side, the CLI handlers only get `BoardPeripherals`. Changes take effect at the next
+/// `sync`, `status` is as of the last one.
+pub struct Sampler {
+    /// Used by the next `arm`.
+    pub settings: Settings,
+    /// Settings of the last armed record, its dump is written with them.
+    record: Settings,
+    /// For the next `sync`.
+    request: Option<Request>,
+    streaming: bool,
+    status: (State, usize),
+    /// Next sample to dump.
+    dump: Option<usize>,
+    /// Rows `sync` copied out of the record for the next `poll`.
+    rows: [(i32, [i32; MAX_SOURCES]); DUMP_LINES_PER_TICK],
+    row_count: usize,
+    dump_channel: UpChannel,
+}
+
+impl Sampler {
+    /// `dump_channel` blocks when full.
+    pub fn new(dump_channel: UpChannel) -> Self {
+        let settings = Settings {
+            sources: [
+                Source::Channel(Channel::IA),
+                Source::Channel(Channel::IB),
+                Source::Channel(Channel::IC),
+                Source::HallIndex,
+            ],
+            source_count: 3,
+            decimation: 1,
+            trigger: Trigger::Now,
+            pretrigger: DEPTH / 4,
+        };
+        Sampler {
+            settings,
+            record: settings,
+            request: None,
+            streaming: false,
+            status: (State::Idle, 0),
+            dump: None,
+            rows: [(0, [0; MAX_SOURCES]); DUMP_LINES_PER_TICK],
+            row_count: 0,
+            dump_channel,
+        }
+    }
+
+    /// Starts recording with `settings`, `vdda` in mV scales the conversions. Stops the stream,
+    /// the caller checks `is_streaming` first to tell.
+    pub fn arm(&mut self, config: &Config, vdda: u32) -> Result<(), SettingsError> {
+        let (mut ranks, mut rank_count) = self.settings.adc_channels()?;
+        self.settings.check(DEPTH)?;
+        if rank_count == 0 {
+            // Conversions pace the samples even without analog sources
+            ranks[0] = Channel::VIn;
+            rank_count = 1;
+        }
+        // Commands before this one don't fire the trigger
+        COMMAND.store(false, Ordering::SeqCst);
+        self.record = self.settings;
+        self.request = Some(Request::Capture(Scaling { ranks, rank_count, vdda, config: *config }));
+        self.streaming = false;
+        self.status = (State::Armed, 0);
+        self.dump = None;
+        Ok(())
+    }
+
+    /// Stops recording, a finished record is kept.
+    pub fn stop(&mut self) {
+        if !self.streaming {
+            self.request = Some(Request::Stop);
+        }
+        self.keep_finished();
+        self.dump = None;
+    }
+
+    /// Starts the telemetry stream at `STREAM_RATE_HZ`, a capture in progress is stopped.
+    pub fn start_stream(&mut self, config: &Config, vdda: u32) {
+        let scaling = Scaling { ranks: STREAM_RANKS[0], rank_count: MAX_SOURCES, vdda, config: *config };
+        self.request = Some(Request::Stream(scaling));
+        self.streaming = true;
+        self.keep_finished();
+        self.dump = None;
+    }
+
+    pub fn stop_stream(&mut self) {
+        if self.streaming {
+            self.request = Some(Request::Stop);
+            self.streaming = false;
+        }
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// State and the number of samples recorded.
+    pub fn status(&self) -> (State, usize) {
+        self.status
+    }
+
+    /// Sample period after decimation.
+    pub fn period_ns(&self) -> u32 {
+        PERIOD_NS * self.settings.decimation.max(1) as u32
+    }
+
+    /// Starts dumping the finished record, false if there is none.
+    pub fn start_dump(&mut self) -> bool {
+        if self.status.0 != State::Done {
+            return false;
+        }
+        self.dump = Some(0);
+        self.row_count = 0;
+        true
+    }
+
+    /// A record in progress stops with the recorder, a finished one stays.
+    fn keep_finished(&mut self) {
+        if self.status.0 != State::Done {
+            self.status.0 = State::Idle;
+        }
+    }
+
+    /// Hands the request to the ADC task and copies out the status and the rows the next `poll`
+    /// dumps. Called every UI tick with `link` locked, which holds off the ADC task.
+    pub fn sync(&mut self, link: &mut Link) {
+        if let Some(request) = self.request.take() {
+            match request {
+                // Checked by `arm`
+                Request::Capture(_) => {
+                    link.capture.arm(self.record).ok();
+                }
+                _ if link.capture.state() != State::Done => link.capture.stop(),
+                _ => {}
+            }
+            link.request = Some(request);
+            rtic::pend(Interrupt::ADC);
+        }
+        self.status = (link.capture.state(), link.capture.recorded());
+        if let Some(next) = self.dump {
+            self.row_count = 0;
+            for (row, (n, values)) in self.rows.iter_mut().zip(link.capture.samples().skip(next).take(DUMP_LINES_PER_TICK)) {
+                row.0 = n;
+                row.1[..values.len()].copy_from_slice(values);
+                self.row_count += 1;
+            }
+        }
+    }
+
+    /// Writes the rows of a dump the last `sync` copied out, called every UI tick after it.
+    pub fn poll(&mut self) {
+        let next = match self.dump {
+            Some(next) => next,
+            None => return,
+        };
+        let mut line = CsvLine::new();
+        if next == 0 {
+            self.record.write_csv_header(&mut line).ok();
+            self.dump_channel.write(line.as_bytes());
+        }
+        for (n, values) in &self.rows[..self.row_count] {
+            line.clear();
+            self.record.write_csv_row(&mut line, PERIOD_NS, *n, &values[..self.record.source_count]).ok();
+            self.dump_channel.write(line.as_bytes());
+        }
+        let next = next + DUMP_LINES_PER_TICK;
+        self.dump = if next < DEPTH {
+            Some(next)
+        } else {
+            // Empty line ends the record
+            self.dump_channel.write(b"\r\n");
+            None
+        };
+    }
+}
+
+/// Fires the command trigger, called before a CLI command runs.
+pub fn command_started() {
+    COMMAND.store(true, Ordering::SeqCst);
+}
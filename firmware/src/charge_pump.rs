@@ -0,0 +1,76 @@
+use stm32f4xx_hal as hal;
+use hal::{
+    pac::TIM4,
+    rcc::Clocks,
+    time::Hertz,
+    gpio::{gpiob::PB7, Alternate, AF2},
+};
+
+pub const DEFAULT_FREQ: Hertz = Hertz(100_000);
+
+/// 50% duty square wave on PB7 (TIM4_CH2) driving the CAN power injection charge pump.
+pub struct ChargePump {
+    tim: TIM4,
+    _pin: PB7<Alternate<AF2>>,
+    timer_clock: Hertz,
+    freq: Hertz,
+    enabled: bool,
+}
+impl ChargePump {
+    /// TIM4 clock has to be enabled, `init` does it.
+    pub fn new(tim: TIM4, pin: PB7<Alternate<AF2>>, clocks: &Clocks) -> Self {
+        // APB1 timers run at twice the bus clock if the bus is prescaled
+        let timer_clock = if clocks.ppre1() == 1 {
+            clocks.pclk1()
+        } else {
+            Hertz(clocks.pclk1().0 * 2)
+        };
+        tim.ccmr1_output_mut().modify(|_, w| w
+            .oc2m().pwm_mode1()
+            .oc2pe().enabled()
+        );
+        tim.cr1.modify(|_, w| w.arpe().set_bit());
+
+        let mut charge_pump = ChargePump {
+            tim,
+            _pin: pin,
+            timer_clock,
+            freq: DEFAULT_FREQ,
+            enabled: false,
+        };
+        charge_pump.set_frequency(DEFAULT_FREQ);
+        charge_pump
+    }
+
+    pub fn set_frequency(&mut self, freq: Hertz) {
+        let ticks = self.timer_clock.0 / freq.0.max(1);
+        let psc = ticks.saturating_sub(1) / (1 << 16);
+        let period = (ticks / (psc + 1)).max(2);
+        self.tim.psc.write(|w| w.psc().bits(psc as u16));
+        self.tim.arr.write(|w| unsafe { w.bits(period - 1) });
+        self.tim.ccr2.write(|w| unsafe { w.bits(period / 2) });
+        self.tim.egr.write(|w| w.ug().set_bit());
+        self.freq = Hertz(self.timer_clock.0 / (psc + 1) / period);
+    }
+
+    /// Actual frequency after rounding to timer ticks.
+    pub fn frequency(&self) -> Hertz {
+        self.freq
+    }
+
+    pub fn enable(&mut self) {
+        self.tim.ccer.modify(|_, w| w.cc2e().set_bit());
+        self.tim.cr1.modify(|_, w| w.cen().enabled());
+        self.enabled = true;
+    }
+
+    pub fn disable(&mut self) {
+        self.tim.cr1.modify(|_, w| w.cen().disabled());
+        self.tim.ccer.modify(|_, w| w.cc2e().clear_bit());
+        self.enabled = false;
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+}
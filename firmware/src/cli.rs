@@ -0,0 +1,491 @@
+use crate::peripherals::BoardPeripherals;
+use no_std_compat::prelude::v1::*;
+use rtt_target::rprint;
+use embedded_hal::digital::v2::OutputPin;
+use stm32f4xx_hal::time::Hertz;
+use crate::od::TesterOd;
+use crate::board::BOARD;
+use crate::protection;
+use crate::capture;
+use crate::dpt::{self, Pulses};
+use crate::pwm;
+use power_stage_core::can::Bitrate;
+use power_stage_core::canopen::{DataType, Entry, ObjectDictionary};
+use power_stage_core::cli::{dispatch, fits, parse_on_off, parse_phase, Arg, Command, DispatchError, Help, Unit, Values};
+use power_stage_core::capture::{Edge, SettingsError, Source, State, Trigger, SOURCE_NAMES};
+use power_stage_core::line::LINE_LEN;
+use power_stage_core::pwm::{LegMode, LEG_MODE_NAMES};
+use power_stage_core::response::{ErrorKind, Failure, Mode, Outcome, Response};
+use power_stage_core::bench::{self, DRV_DISABLED, NOT_OPENLOOP, PHASES, SHORT_FOUND};
+
+type Handler = fn(&mut BoardPeripherals, &Values, &mut Response<Rtt>) -> Outcome;
+type Shared = bench::Commands<BoardPeripherals, Rtt>;
+
+const BAD_SOURCE: Failure = Failure::new(ErrorKind::BadArgument, "Unknown source");
+
+const ON_OFF: &[&str] = &["on", "off"];
+const SOURCE: Arg = Arg::choice("source", SOURCE_NAMES);
+
+const COMMANDS: &[Command<Handler>] = &[
+    Command { name: "help", help: "List commands or show the usage of a group", args: &[Arg::word("command").optional()], handler: help_command },
+    Command {
+        name: "mode",
+        help: "Human readable replies or one JSON object per line, also for telemetry",
+        args: &[Arg::choice("mode", &["human", "json"])],
+        handler: mode_command,
+    },
+    Command { name: "stream", help: "Binary telemetry on RTT channel 1, 250 samples/s", args: &[Arg::choice("state", ON_OFF)], handler: stream_command },
+    Command { name: "drv on", help: "Enable the gate driver, a fault turns it off", args: &[], handler: drv_on_command },
+    Command { name: "drv off", help: "Disable the gate driver", args: &[], handler: drv_off_command },
+    Command { name: "drv regs", help: "Dump gate driver registers", args: &[], handler: drv_regs_command },
+    Command {
+        name: "led",
+        help: "Status LEDs",
+        args: &[Arg::choice("led", &["red", "green", "blue"]), Arg::choice("state", ON_OFF)],
+        handler: led_command,
+    },
+    Shared::SWITCH,
+    Shared::SWITCH_MODE,
+    Shared::OL_MANUAL,
+    Command {
+        name: "ol leg",
+        help: "What a leg does: complementary or one side PWM, one side held on or both off",
+        args: &[Arg::choice("phase", PHASES), Arg::choice("mode", LEG_MODE_NAMES)],
+        handler: ol_leg_command,
+    },
+    Shared::OL_ALIGN,
+    Shared::OL_BRAKE,
+    Command { name: "can status", help: "Bitrate, node id and telemetry", args: &[], handler: can_status_command },
+    Command {
+        name: "can bitrate",
+        help: "Reconfigure the bus, kbps",
+        args: &[Arg::choice("kbps", &["125", "250", "500", "1000"])],
+        handler: can_bitrate_command,
+    },
+    Command { name: "can node", help: "CANopen node id", args: &[Arg::int("id", 1, 127, Unit::None)], handler: can_node_command },
+    Command {
+        name: "can telemetry",
+        help: "Send telemetry every n loops, 0 - off",
+        args: &[Arg::int("loops", 0, 255, Unit::None)],
+        handler: can_telemetry_command,
+    },
+    Command { name: "can test", help: "Loopback self test and bus health", args: &[], handler: can_test_command },
+    Command { name: "can od", help: "Dump the object dictionary", args: &[], handler: can_od_command },
+    Command { name: "can power", help: "Bus power output", args: &[Arg::choice("state", ON_OFF)], handler: can_power_command },
+    Command { name: "can standby", help: "Transceiver standby", args: &[Arg::choice("state", ON_OFF)], handler: can_standby_command },
+    Command {
+        name: "can pump",
+        help: "Charge pump frequency",
+        args: &[Arg::int("freq", 1, 1_000_000, Unit::Hertz)],
+        handler: can_pump_command,
+    },
+    Command {
+        name: "capture sources",
+        help: "Values to record",
+        args: &[SOURCE, SOURCE.optional(), SOURCE.optional(), SOURCE.optional()],
+        handler: capture_sources_command,
+    },
+    Command {
+        name: "capture trigger",
+        help: "Record right away, on a DRV fault or from the next command",
+        args: &[Arg::choice("trigger", &["now", "fault", "command"])],
+        handler: capture_trigger_command,
+    },
+    Command {
+        name: "capture level",
+        help: "Record when a value crosses the level, mV or mA",
+        args: &[SOURCE, Arg::choice("edge", &["rising", "falling"]), Arg::int("level", -100_000, 100_000, Unit::None)],
+        handler: capture_level_command,
+    },
+    Command {
+        name: "capture rate",
+        help: "Keep every n-th sample and how many samples before the trigger",
+        args: &[Arg::int("decimation", 1, 10_000, Unit::None), Arg::int("pretrigger", 0, capture::DEPTH as i32 - 1, Unit::None).optional()],
+        handler: capture_rate_command,
+    },
+    Command {
+        name: "capture arm",
+        help: "Start recording at 10000 samples/s from TIM2, not synced to the PWM, stops the telemetry stream",
+        args: &[],
+        handler: capture_arm_command,
+    },
+    Command { name: "capture stop", help: "Stop recording", args: &[], handler: capture_stop_command },
+    Command { name: "capture status", help: "Settings and progress", args: &[], handler: capture_status_command },
+    Command { name: "capture dump", help: "Finished record as CSV on RTT channel 2", args: &[], handler: capture_dump_command },
+    Shared::CFG_SHOW,
+    Shared::CFG_SET,
+    Shared::CFG_SAVE,
+    Shared::CFG_LOAD,
+    Shared::CFG_RESET,
+    Shared::CAL_VOLTAGE,
+    Shared::CAL_CURRENT,
+    Shared::TEST_BOOTSTRAP,
+    Shared::TEST_SHORTS,
+    Shared::TEST_LEGS,
+    Command {
+        name: "test dpt",
+        help: "Double pulse on the high side of a leg, inductive load to the return leg which holds its low side on, the next phase unless given, leaves manual mode with all off",
+        args: &[
+            Arg::choice("phase", PHASES),
+            Arg::int("pulse1_us", 1, dpt::MAX_US as i32, Unit::Microseconds),
+            Arg::int("gap_us", dpt::MIN_GAP_US as i32, dpt::MAX_US as i32, Unit::Microseconds),
+            Arg::int("pulse2_us", 1, dpt::MAX_US as i32, Unit::Microseconds),
+            Arg::choice("return", PHASES).optional(),
+        ],
+        handler: test_dpt_command,
+    },
+];
+const _: () = assert!(fits(COMMANDS));
+
+/// Writes to an RTT terminal, 0 is the CLI and 1 the status page.
+pub struct Rtt(pub u8);
+
+impl core::fmt::Write for Rtt {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        rprint!(=>self.0, s);
+        Ok(())
+    }
+}
+
+/// Swallows the echo in JSON mode.
+struct Discard;
+
+impl core::fmt::Write for Discard {
+    fn write_str(&mut self, _s: &str) -> core::fmt::Result {
+        Ok(())
+    }
+}
+
+pub fn print_prompt(bp: &BoardPeripherals) {
+    if bp.mode == Mode::Human {
+        rprint!("{}", bp.line.prompt());
+    }
+}
+
+pub fn process_input(bp: &mut BoardPeripherals) {
+    let mut input = [0u8; 64];
+    let input_len = bp.rtt_down_channel.read(&mut input);
+    for &byte in &input[0..input_len] {
+        let mut line = [0u8; LINE_LEN];
+        let entered = match bp.mode {
+            Mode::Human => bp.line.feed(byte, COMMANDS, &mut Rtt(0)),
+            Mode::Json => bp.line.feed(byte, COMMANDS, &mut Discard),
+        };
+        let line_len = match entered {
+            Some(entered) => {
+                line[..entered.len()].copy_from_slice(entered.as_bytes());
+                entered.len()
+            }
+            None => continue
+        };
+        // Editor only accepts printable ASCII
+        let line = core::str::from_utf8(&line[..line_len]).unwrap_or("");
+        let mut out = Rtt(0);
+        let mut response = Response::new(&mut out, bp.mode);
+        match dispatch(COMMANDS, line) {
+            Ok((command, args)) => {
+                if !command.name.starts_with("capture") {
+                    capture::command_started();
+                }
+                let outcome = (command.handler)(bp, &args, &mut response);
+                response.finish(outcome);
+            }
+            Err(DispatchError::Empty) if bp.mode == Mode::Human => {}
+            Err(e) => response.finish_dispatch_error(COMMANDS, line, &e),
+        }
+        print_prompt(bp);
+    }
+}
+
+fn help_command(_bp: &mut BoardPeripherals, args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    let help = Help(COMMANDS, args.text(0));
+    match response.mode() {
+        Mode::Human => rprint!("{}", help),
+        Mode::Json => response.text_field("help", help),
+    }
+    Ok(())
+}
+
+fn mode_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    // This reply is still written in the previous mode
+    bp.mode = if args.choice(0) == 0 { Mode::Human } else { Mode::Json };
+    Ok(())
+}
+
+fn stream_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    match args.choice(0) {
+        0 => {
+            let vdda = bp.adc.sample_to_millivolts(4095) as u32;
+            bp.sampler.start_stream(&bp.settings, vdda);
+        }
+        _ => bp.sampler.stop_stream(),
+    }
+    Ok(())
+}
+
+fn drv_on_command(_bp: &mut BoardPeripherals, _args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    protection::set_enabled(true);
+    Ok(())
+}
+
+fn drv_off_command(_bp: &mut BoardPeripherals, _args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    protection::set_enabled(false);
+    Ok(())
+}
+
+fn drv_regs_command(bp: &mut BoardPeripherals, _args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    if BOARD.drv.register_count() == 0 {
+        return Err(Failure::new(ErrorKind::NotSupported, "DRV has no SPI"));
+    }
+    for addr in 0..BOARD.drv.register_count() {
+        let value = bp.drv.read_register(addr);
+        response.field(format_args!("reg{}", addr), value, "");
+    }
+    Ok(())
+}
+
+fn ol_leg_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    let openloop = bp.openloop.as_mut().ok_or(NOT_OPENLOOP)?;
+    if let (Some(phase), Some(leg)) = (parse_phase(args.text(0)), LegMode::from_name(args.text(1))) {
+        openloop.set_leg(phase, leg);
+    }
+    Ok(())
+}
+
+fn led_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    let is_on = parse_on_off(args.text(1)).unwrap_or(false);
+    let led: &mut dyn OutputPin<Error = core::convert::Infallible> = match args.choice(0) {
+        0 => &mut bp.leds.red,
+        1 => &mut bp.leds.green,
+        _ => &mut bp.leds.blue,
+    };
+    if is_on {
+        led.set_high().ok();
+    } else {
+        led.set_low().ok();
+    }
+    Ok(())
+}
+
+fn capture_sources_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    let settings = &mut bp.sampler.settings;
+    settings.source_count = 0;
+    for i in (0..settings.sources.len()).filter(|i| args.is_present(*i)) {
+        settings.sources[settings.source_count] = Source::from_name(args.text(i)).ok_or(BAD_SOURCE)?;
+        settings.source_count += 1;
+    }
+    Ok(())
+}
+
+fn capture_trigger_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    bp.sampler.settings.trigger = match args.choice(0) {
+        0 => Trigger::Now,
+        1 => Trigger::Fault,
+        _ => Trigger::Command,
+    };
+    Ok(())
+}
+
+fn capture_level_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    let source = Source::from_name(args.text(0)).ok_or(BAD_SOURCE)?;
+    let edge = if args.choice(1) == 0 { Edge::Rising } else { Edge::Falling };
+    bp.sampler.settings.trigger = Trigger::Level { source, edge, level: args.int(2) };
+    Ok(())
+}
+
+fn capture_rate_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    bp.sampler.settings.decimation = args.int(0) as u16;
+    if args.is_present(1) {
+        bp.sampler.settings.pretrigger = args.int(1) as usize;
+    }
+    Ok(())
+}
+
+fn capture_arm_command(bp: &mut BoardPeripherals, _args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    let vdda = bp.adc.sample_to_millivolts(4095) as u32;
+    let was_streaming = bp.sampler.is_streaming();
+    bp.sampler.arm(&bp.settings, vdda).map_err(|e| match e {
+        SettingsError::NoSources => Failure::new(ErrorKind::BadArgument, "No sources, see capture sources"),
+        SettingsError::TooManyInputs => Failure::new(ErrorKind::BadArgument, "Sources and the trigger need more than 4 ADC inputs"),
+        SettingsError::Pretrigger => Failure::new(ErrorKind::BadArgument, "Pretrigger leaves no room for the record"),
+    })?;
+    if was_streaming {
+        response.text_field("stream", "stopped");
+    }
+    Ok(())
+}
+
+fn capture_stop_command(bp: &mut BoardPeripherals, _args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    bp.sampler.stop();
+    Ok(())
+}
+
+fn capture_status_command(bp: &mut BoardPeripherals, _args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    let (state, recorded) = bp.sampler.status();
+    let state = match state {
+        State::Idle => "idle",
+        State::Armed => "armed",
+        State::Triggered => "triggered",
+        State::Done => "done",
+    };
+    response.text_field("state", state);
+    response.field("recorded", recorded, "");
+    response.field("depth", capture::DEPTH, "");
+    let settings = bp.sampler.settings;
+    for (i, source) in settings.sources().iter().enumerate() {
+        response.text_field(format_args!("source_{}", i + 1), source.name());
+    }
+    response.field("period", bp.sampler.period_ns(), "ns");
+    response.field("pretrigger", settings.pretrigger, "");
+    match settings.trigger {
+        Trigger::Now => response.text_field("trigger", "now"),
+        Trigger::Fault => response.text_field("trigger", "fault"),
+        Trigger::Command => response.text_field("trigger", "command"),
+        Trigger::Level { source, edge, level } => {
+            let edge = if edge == Edge::Rising { "rising" } else { "falling" };
+            response.text_field("trigger", format_args!("{} {} {}", source.name(), edge, level));
+        }
+    }
+    Ok(())
+}
+
+fn capture_dump_command(bp: &mut BoardPeripherals, _args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    match bp.sampler.start_dump() {
+        true => Ok(()),
+        false => Err(Failure::new(ErrorKind::NotFound, "No finished record, see capture status")),
+    }
+}
+
+fn can_status_command(bp: &mut BoardPeripherals, _args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    response.field("bitrate", bp.can.bitrate.bps(), "bps");
+    response.field("node", bp.can.node_id, "");
+    response.field("telemetry_divider", bp.can.telemetry_divider, " loops");
+    Ok(())
+}
+
+fn can_bitrate_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    let bitrate = args.text(0).parse().ok().and_then(Bitrate::from_kbps)
+        .ok_or(Failure::new(ErrorKind::BadArgument, "Unknown bitrate"))?;
+    let node_id = bp.can.node_id;
+    if !bp.can.configure(bitrate, node_id) {
+        return Err(Failure::new(ErrorKind::NotSupported, "Bitrate not reachable with current clock"));
+    }
+    crate::od::send_boot_up(bp);
+    Ok(())
+}
+
+fn can_node_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    let bitrate = bp.can.bitrate;
+    if !bp.can.configure(bitrate, args.int(0) as u8) {
+        return Err(Failure::new(ErrorKind::NotSupported, "Bitrate not reachable with current clock"));
+    }
+    crate::od::send_boot_up(bp);
+    Ok(())
+}
+
+fn can_telemetry_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    bp.can.telemetry_divider = args.int(0) as u8;
+    bp.can.telemetry_counter = 0;
+    Ok(())
+}
+
+fn can_test_command(bp: &mut BoardPeripherals, _args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    let bitrates = [("loopback_125k", Bitrate::K125), ("loopback_250k", Bitrate::K250), ("loopback_500k", Bitrate::K500), ("loopback_1m", Bitrate::M1)];
+    let mut all_passed = true;
+    for &(name, bitrate) in &bitrates {
+        match bp.can.loopback_test(bitrate, &mut bp.delay) {
+            Some((passed, total)) => {
+                all_passed &= passed == total;
+                response.text_field(name, format_args!("{}/{}", passed, total));
+            }
+            None => response.text_field(name, "unreachable"),
+        }
+    }
+    let (diagnosis, status) = bp.can.bus_test(&mut bp.delay);
+    response.text_field("bus", format_args!("{:?}", diagnosis));
+    response.field("tec", status.tec, "");
+    response.field("rec", status.rec, "");
+    response.field("warning", status.warning, "");
+    response.field("passive", status.passive, "");
+    response.field("bus_off", status.bus_off, "");
+    response.text_field("lec", format_args!("{:?}", status.last_error));
+    if !all_passed {
+        return Err(Failure::new(ErrorKind::Failed, "Loopback test failed"));
+    }
+    Ok(())
+}
+
+/// `2020:05 V_A, mV` for humans, `2020:05` as a JSON key.
+struct OdKey<'a>(&'a Entry, bool);
+
+impl core::fmt::Display for OdKey<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:04x}:{:02x}", self.0.index, self.0.sub)?;
+        if self.1 {
+            write!(f, " {}", self.0.name)?;
+        }
+        Ok(())
+    }
+}
+
+fn can_od_command(bp: &mut BoardPeripherals, _args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    let with_name = response.mode() == Mode::Human;
+    let mut od = TesterOd(bp);
+    for entry in od.entries() {
+        let key = OdKey(entry, with_name);
+        match od.read(entry.index, entry.sub) {
+            Ok(value) if entry.data_type == DataType::I32 => response.field(key, value as i32, ""),
+            Ok(value) => response.field(key, value, ""),
+            Err(_) => response.field(key, "null", ""),
+        }
+    }
+    Ok(())
+}
+
+fn can_power_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    bp.canbus.set_power(args.choice(0) == 0);
+    Ok(())
+}
+
+fn can_standby_command(bp: &mut BoardPeripherals, args: &Values, _response: &mut Response<Rtt>) -> Outcome {
+    bp.canbus.set_standby(args.choice(0) == 0);
+    Ok(())
+}
+
+fn can_pump_command(bp: &mut BoardPeripherals, args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    bp.canbus.charge_pump.set_frequency(Hertz(args.int(0) as u32));
+    response.field("frequency", bp.canbus.charge_pump.frequency().0, "Hz");
+    Ok(())
+}
+
+fn test_dpt_command(bp: &mut BoardPeripherals, args: &Values, response: &mut Response<Rtt>) -> Outcome {
+    if !protection::is_enabled() {
+        return Err(DRV_DISABLED);
+    }
+    if bp.short.is_some() {
+        return Err(SHORT_FOUND);
+    }
+    let phase = parse_phase(args.text(0)).ok_or(Failure::new(ErrorKind::BadArgument, "Unknown phase"))?;
+    let return_phase = if args.is_present(4) {
+        parse_phase(args.text(4)).ok_or(Failure::new(ErrorKind::BadArgument, "Unknown phase"))?
+    } else {
+        phase.next()
+    };
+    if return_phase == phase {
+        return Err(Failure::new(ErrorKind::BadArgument, "The return leg has to differ from the pulsed one"));
+    }
+    let pulses = Pulses { first_us: args.int(1) as u32, gap_us: args.int(2) as u32, second_us: args.int(3) as u32 };
+    let timing = dpt::timing(pwm::timer_clock(&bp.clocks), pulses)
+        .ok_or(Failure::new(ErrorKind::BadArgument, "Pulses too short to sample or too long for the timer"))?;
+    let report = dpt::run(bp, phase, return_phase, &timing)
+        .ok_or(Failure::new(ErrorKind::Failed, "Current samples missing"))?;
+    response.field("v_in", report.v_in.0, "mV");
+    response.field("i_off", report.i_off.0, "mA");
+    response.field("i_on", report.i_on.0, "mA");
+    match report.inductance_uh(pulses.first_us) {
+        Some(l) => response.field("inductance", l, "uH"),
+        None => response.field("inductance", "null", ""),
+    }
+    Ok(())
+}
+
@@ -0,0 +1,139 @@
+//! Double pulse test on the hardware, timing and results are in `power_stage_core::dpt`.
+//!
+//! ADC3 converts the return leg's current as two injected ranks in discontinuous mode so each
+//! TIM1_CC4 event converts the next rank, the first just before turn-off and the second just
+//! after turn-on. ADC1 stays with the UI and ADC2 with the waveform capture.
+//!
+//! `test shorts` uses the same ADC for single conversions triggered by TIM1 TRGO in the middle of
+//! the high side on-times, see `ComplementaryPwm::new`.
+
+use stm32f4xx_hal as hal;
+use hal::pac::ADC3;
+use embedded_hal::blocking::delay::DelayMs;
+use power_stage_core::bootstrap::CHARGE_MS;
+pub use power_stage_core::dpt::{pulsed_current, sampled_channel, timing, Pulses, Report, Timing, MAX_US, MIN_GAP_US};
+use power_stage_core::pwm::LegMode;
+use power_stage_core::stage::{measure, Channel, Phase, SwitchState};
+use power_stage_core::units::{MilliAmperes, MilliVolts};
+use crate::board::BOARD;
+use crate::peripherals::BoardPeripherals;
+use crate::pwm::{self, ComplementaryPwm, Pins};
+
+/// RM0090 JEXTSEL, the PAC names these after another family.
+const JEXTSEL_TIM1_CC4: u8 = 0b0000;
+const JEXTSEL_TIM1_TRGO: u8 = 0b0001;
+/// A conversion takes 3 + 12 ADC clocks, the trigger comes within a PWM period, a few ms covers
+/// periods down to 100Hz.
+const CONVERSION_POLLS: u32 = 10_000;
+/// The return low side stays on this long after the pulses so the load current decays.
+const FREEWHEEL_MS: u32 = 10;
+
+/// ADC3 only reaches inputs 0..3 and 10..13.
+const fn on_adc3(input: u8) -> bool {
+    input <= 3 || (input >= 10 && input <= 13)
+}
+
+const _: () = assert!(
+    on_adc3(BOARD.analog.i_a) && on_adc3(BOARD.analog.i_b) && on_adc3(BOARD.analog.i_c) && on_adc3(BOARD.analog.v_in),
+    "phase currents and V_IN must be ADC3 inputs"
+);
+
+pub struct EdgeSampler {
+    adc: ADC3,
+}
+
+impl EdgeSampler {
+    /// ADC3 clock has to be enabled, `init` does it.
+    pub fn new(adc: ADC3) -> Self {
+        // Shortest sample time (0) for every input, the edges are close together
+        adc.smpr1.reset();
+        adc.smpr2.reset();
+        adc.cr2.modify(|_, w| w.jexten().rising_edge().adon().set_bit());
+        EdgeSampler { adc }
+    }
+
+    /// Next two TIM1_CC4 events convert `input`, one each.
+    fn arm(&mut self, input: u8) {
+        self.adc.cr1.modify(|_, w| w.jdiscen().set_bit());
+        self.adc.cr2.modify(|_, w| unsafe { w.jextsel().bits(JEXTSEL_TIM1_CC4) });
+        // Two ranks in JSQ3 and JSQ4, results in JDR1 and JDR2
+        let jsqr = 1 << 20 | (input as u32) << 15 | (input as u32) << 10;
+        self.adc.jsqr.write(|w| unsafe { w.bits(jsqr) });
+        self.adc.sr.modify(|_, w| w.jeoc().clear_bit().jstrt().clear_bit());
+    }
+
+    /// Both samples, None if the triggers didn't come.
+    fn read(&mut self) -> Option<[u16; 2]> {
+        if !self.wait() {
+            return None;
+        }
+        Some([self.adc.jdr1.read().jdata().bits(), self.adc.jdr2.read().jdata().bits()])
+    }
+
+    /// Converts `input` at the next TIM1 TRGO, None if it didn't come.
+    pub fn convert_on_time(&mut self, input: u8) -> Option<u16> {
+        self.adc.cr1.modify(|_, w| w.jdiscen().clear_bit());
+        self.adc.cr2.modify(|_, w| unsafe { w.jextsel().bits(JEXTSEL_TIM1_TRGO) });
+        // One rank in JSQ4, result in JDR1
+        self.adc.jsqr.write(|w| unsafe { w.bits((input as u32) << 15) });
+        self.adc.sr.modify(|_, w| w.jeoc().clear_bit().jstrt().clear_bit());
+        if !self.wait() {
+            return None;
+        }
+        Some(self.adc.jdr1.read().jdata().bits())
+    }
+
+    /// Waits for the end of the injected sequence, false if the triggers didn't come.
+    fn wait(&mut self) -> bool {
+        for _ in 0..CONVERSION_POLLS {
+            if self.adc.sr.read().jeoc().bit_is_set() {
+                self.adc.sr.modify(|_, w| w.jeoc().clear_bit());
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Pulses the high side of `phase` with the low side of `return_phase` on and the third leg
+/// floating, the stage is left in manual mode with all switches off. None if the current wasn't
+/// sampled. `return_phase` has to differ from `phase`, see `sampled_channel`.
+pub fn run(bp: &mut BoardPeripherals, phase: Phase, return_phase: Phase, timing: &Timing) -> Option<Report> {
+    let channel = sampled_channel(phase, return_phase)?;
+    bp.switch_to_manual();
+    let config = bp.settings;
+    let v_in = MilliVolts(measure(bp, &BOARD, &config, Channel::VIn));
+    let switches = bp.switches.take().expect("manual mode has the switches");
+    let tim1 = bp.tim1.take().expect("TIM1 is parked with the switches");
+    let pins = Pins::from_switches(switches);
+    let mut pwm = ComplementaryPwm::new(tim1, pins, pwm::timer_clock(&bp.clocks), config.pwm_frequency(), config.dead_time());
+
+    // Low side of the tested leg on first, a bootstrap supplied high side needs the charge
+    for &leg in Phase::ALL.iter() {
+        pwm.set_leg(leg, LegMode::Low);
+    }
+    bp.delay.delay_ms(CHARGE_MS);
+    // A connected motor would split the current into the third leg's shunt
+    for &leg in Phase::ALL.iter().filter(|&&leg| leg != phase && leg != return_phase) {
+        pwm.set_leg(leg, LegMode::Float);
+    }
+    bp.edge_sampler.arm(BOARD.analog.get(channel));
+    pwm.double_pulse(phase, timing);
+    let samples = bp.edge_sampler.read();
+    bp.delay.delay_ms(FREEWHEEL_MS);
+
+    let (tim1, pins) = pwm.free();
+    let mut switches = pins.into_switches();
+    for &leg in Phase::ALL.iter() {
+        switches.set(leg, SwitchState::Off);
+    }
+    bp.switches = Some(switches);
+    bp.tim1 = Some(tim1);
+
+    let [off, on] = samples?;
+    let current = |raw: u16| {
+        let v_adc = MilliVolts(bp.adc.sample_to_millivolts(raw) as i32) - config.offset(channel);
+        pulsed_current(MilliAmperes(config.correct(channel, BOARD.convert(channel, v_adc, config.current_sense_gain))))
+    };
+    Some(Report { v_in, i_off: current(off), i_on: current(on) })
+}
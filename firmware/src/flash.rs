@@ -0,0 +1,95 @@
+//! Configuration sectors in the internal flash, reserved in `memory.x`.
+use stm32f4xx_hal as hal;
+use power_stage_core::store::{Flash, FlashError};
+
+/// Sector number and start address, both 128K.
+const SECTORS: [(u8, u32); 2] = [(10, 0x080C_0000), (11, 0x080E_0000)];
+const SECTOR_SIZE: u32 = 128 * 1024;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+/// EOP, OPERR, WRPERR, PGAERR, PGPERR, PGSERR
+const SR_FLAGS: u32 = 0xF3;
+const SR_ERRORS: u32 = 0xF2;
+/// 32 bit parallelism, needs 2.7-3.6V supply.
+const PSIZE_X32: u8 = 0b10;
+
+pub struct InternalFlash {
+    flash: hal::pac::FLASH,
+}
+
+impl InternalFlash {
+    pub fn new(flash: hal::pac::FLASH) -> Self {
+        InternalFlash { flash }
+    }
+
+    fn unlock(flash: &hal::pac::FLASH) {
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY1) });
+            flash.keyr.write(|w| unsafe { w.key().bits(KEY2) });
+        }
+        flash.sr.write(|w| unsafe { w.bits(SR_FLAGS) });
+    }
+
+    fn finish(flash: &hal::pac::FLASH) -> Result<(), FlashError> {
+        while flash.sr.read().bsy().bit_is_set() {}
+        let errors = flash.sr.read().bits() & SR_ERRORS;
+        flash.cr.write(|w| w.lock().set_bit());
+        if errors != 0 {
+            Err(FlashError)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Data cache may still hold the old content of an erased sector.
+    fn reset_data_cache(flash: &hal::pac::FLASH) {
+        flash.acr.modify(|_, w| w.dcen().clear_bit());
+        flash.acr.modify(|_, w| w.dcrst().set_bit());
+        flash.acr.modify(|_, w| w.dcrst().clear_bit());
+        flash.acr.modify(|_, w| w.dcen().set_bit());
+    }
+}
+
+impl Flash for InternalFlash {
+    fn sector_size(&self) -> u32 {
+        SECTOR_SIZE
+    }
+
+    fn read(&mut self, sector: usize, offset: u32, buf: &mut [u8]) {
+        let start = SECTORS[sector].1 + offset;
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = unsafe { core::ptr::read_volatile((start as usize + i) as *const u8) };
+        }
+    }
+
+    /// Offset and length have to be word aligned, records always are.
+    fn program(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        if offset % 4 != 0 || data.len() % 4 != 0 || offset + data.len() as u32 > SECTOR_SIZE {
+            return Err(FlashError);
+        }
+        let flash = &self.flash;
+        Self::unlock(flash);
+        flash.cr.write(|w| unsafe { w.psize().bits(PSIZE_X32).pg().set_bit() });
+        let start = SECTORS[sector].1 + offset;
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            unsafe { core::ptr::write_volatile((start as usize + i * 4) as *mut u32, word) };
+            while flash.sr.read().bsy().bit_is_set() {}
+        }
+        let result = Self::finish(flash);
+        Self::reset_data_cache(flash);
+        result
+    }
+
+    /// Blocks for up to a couple of seconds, code fetch stalls while the sector is erased.
+    fn erase(&mut self, sector: usize) -> Result<(), FlashError> {
+        let flash = &self.flash;
+        Self::unlock(flash);
+        flash.cr.write(|w| unsafe { w.psize().bits(PSIZE_X32).ser().set_bit().snb().bits(SECTORS[sector].0) });
+        flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = Self::finish(flash);
+        Self::reset_data_cache(flash);
+        result
+    }
+}
@@ -0,0 +1,56 @@
+//! Hall sensor inputs, read by the EXTI15_10 task on every edge so the capture interrupt and
+//! the UI get the last state without owning the pins.
+use stm32f4xx_hal as hal;
+use hal::{
+    gpio::{gpioc::{PC13, PC14, PC15}, Edge, ExtiPin, Floating, Input},
+    pac::EXTI,
+    syscfg::SysCfg,
+};
+use embedded_hal::digital::v2::InputPin;
+use power_stage_core::hall::hall_index;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// Bit 0 is sensor A, 1 B and 2 C.
+static STATE: AtomicU8 = AtomicU8::new(0);
+
+pub struct HallSensors {
+    a: PC13<Input<Floating>>,
+    b: PC14<Input<Floating>>,
+    c: PC15<Input<Floating>>,
+}
+
+impl HallSensors {
+    pub fn new(a: PC13<Input<Floating>>, b: PC14<Input<Floating>>, c: PC15<Input<Floating>>, syscfg: &mut SysCfg, exti: &mut EXTI) -> Self {
+        let mut halls = HallSensors { a, b, c };
+        macro_rules! listen {
+            ($($pin: ident),*) => {
+                $(
+                    halls.$pin.make_interrupt_source(syscfg);
+                    halls.$pin.trigger_on_edge(exti, Edge::RISING_FALLING);
+                    halls.$pin.enable_interrupt(exti);
+                )*
+            }
+        }
+        listen!(a, b, c);
+        halls.update();
+        halls
+    }
+
+    /// Called from the EXTI15_10 task.
+    pub fn update(&mut self) {
+        self.a.clear_interrupt_pending_bit();
+        self.b.clear_interrupt_pending_bit();
+        self.c.clear_interrupt_pending_bit();
+        let state = self.a.is_high().unwrap() as u8
+            | (self.b.is_high().unwrap() as u8) << 1
+            | (self.c.is_high().unwrap() as u8) << 2;
+        STATE.store(state, Ordering::SeqCst);
+    }
+}
+
+/// Returns (a, b, c, index) as of the last edge.
+pub fn read() -> (bool, bool, bool, u8) {
+    let state = STATE.load(Ordering::SeqCst);
+    let (a, b, c) = (state & 1 != 0, state & 2 != 0, state & 4 != 0);
+    (a, b, c, hall_index(a, b, c))
+}
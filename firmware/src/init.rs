@@ -0,0 +1,195 @@
+use stm32f4xx_hal as hal;
+use crate::peripherals::*;
+use crate::can::CanNode;
+use crate::charge_pump::ChargePump;
+use crate::board::BOARD;
+use crate::flash::InternalFlash;
+use power_stage_core::config::Config;
+use power_stage_core::store;
+use power_stage_core::line::LineEditor;
+use crate::observer::TelemetryStream;
+use crate::capture::{Recorder, Sampler};
+use crate::dpt::EdgeSampler;
+use crate::protection::Guard;
+use crate::halls::HallSensors;
+use crate::TICK_PERIOD_MS;
+use power_stage_core::response::Mode;
+use embedded_hal::digital::v2::OutputPin;
+use hal::{
+    prelude::*,
+    stm32::{Peripherals, TIM7},
+    delay::Delay,
+    adc::config::AdcConfig,
+    timer::{Event, Timer},
+};
+use rtt_target::rprintln;
+
+/// Peripherals owned by the interrupt driven tasks, see `main.rs`.
+pub struct Tasks {
+    /// Paces the UI task every `TICK_PERIOD_MS`.
+    pub tick: Timer<TIM7>,
+    pub guard: Guard,
+    pub halls: HallSensors,
+    /// Written by the stream task with the frames the ADC task finishes.
+    pub stream: TelemetryStream,
+    pub recorder: Recorder,
+}
+
+pub fn init_all(cp: cortex_m::Peripherals, dp: Peripherals) -> (BoardPeripherals, Tasks) {
+    let channels = rtt_target::rtt_init! {
+        up: {
+            0: { // channel number
+                size: 1024 // buffer size in bytes
+                mode: NoBlockSkip // mode (optional, default: NoBlockSkip, see enum ChannelMode)
+                name: "Terminal" // name (optional, default: no name)
+            }
+            1: {
+                size: 1024
+                mode: NoBlockSkip
+                name: "Telemetry"
+            }
+            2: {
+                size: 2048 // capture::DUMP_BUFFER_LEN
+                mode: BlockIfFull
+                name: "Capture"
+            }
+        }
+        down: {
+            0: {
+                size: 16
+                name: "Terminal"
+            }
+        }
+    };
+    rtt_target::set_print_channel(channels.up.0);
+    rprintln!("{} v{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    rprintln!("Board: {}, {}", BOARD.name, BOARD.drv.name());
+
+    // Debug probe keeps RTT access while the idle task sleeps
+    dp.DBGMCU.cr.modify(|_, w| w.dbg_sleep().set_bit());
+    // Peripherals driven through their registers, the HAL enables its own
+    dp.RCC.apb1enr.modify(|_, w| w.tim2en().enabled().tim4en().enabled());
+    dp.RCC.apb2enr.modify(|_, w| w.tim1en().enabled().adc2en().enabled().adc3en().enabled());
+
+    let rcc = dp.RCC.constrain();
+    let clocks = rcc.cfgr.sysclk(8.mhz()).use_hse(8.mhz()).pclk1(8.mhz()).freeze();
+    let delay = Delay::new(cp.SYST, clocks);
+
+    let gpioa = dp.GPIOA.split();
+    let gpiob = dp.GPIOB.split();
+    let gpioc = dp.GPIOC.split();
+    let gpiod = dp.GPIOD.split();
+    let mut syscfg = dp.SYSCFG.constrain();
+    let mut exti = dp.EXTI;
+
+    let adc = hal::adc::Adc::adc1(dp.ADC1, true, AdcConfig::default());
+
+    let drv_sck = gpioc.pc10.into_push_pull_output();
+    let drv_miso = gpioc.pc11.into_floating_input();
+    let drv_mosi = gpioc.pc12.into_push_pull_output();
+    let spi_timer = hal::timer::Timer::tim6(dp.TIM6, 200.khz(), clocks);
+    let spi = bitbang_hal::spi::SPI::new(
+        bitbang_hal::spi::MODE_0,
+        drv_miso,
+        drv_mosi,
+        drv_sck,
+        spi_timer
+    );
+
+    let mut drv_cs = gpiod.pd2.into_push_pull_output();
+    drv_cs.set_high().ok();
+
+    let can1_rx = gpiob.pb8.into_alternate_af9();
+    let can1_tx = gpiob.pb9.into_alternate_af9();
+    let can = CanNode::new(dp.CAN1, can1_tx, can1_rx, clocks.pclk1());
+
+    let mut tick = Timer::tim7(dp.TIM7, (1000 / TICK_PERIOD_MS).hz(), clocks);
+    tick.listen(Event::TimeOut);
+    let tasks = Tasks {
+        stream: TelemetryStream::new(channels.up.1),
+        tick,
+        guard: Guard::new(gpiob.pb5.into_push_pull_output(), gpiob.pb4.into_floating_input(), &mut syscfg, &mut exti),
+        halls: HallSensors::new(
+            gpioc.pc13.into_floating_input(),
+            gpioc.pc14.into_floating_input(),
+            gpioc.pc15.into_floating_input(),
+            &mut syscfg,
+            &mut exti
+        ),
+        recorder: Recorder::new(dp.ADC2, dp.TIM2, &clocks),
+    };
+    let mut flash = InternalFlash::new(dp.FLASH);
+    let settings = load_settings(&mut flash);
+
+    let bp = BoardPeripherals {
+        // rcc,
+        clocks,
+        delay,
+        adc,
+        rtt_down_channel: channels.down.0,
+        line: LineEditor::new("> "),
+        mode: Mode::Human,
+        drv: Drv {
+            offset_cal: gpiob.pb1.into_push_pull_output(),
+            fault_break: match BOARD.fault_break {
+                Some(_) => Some(gpiob.pb12.into_alternate_af1().internal_pull_up(true)),
+                None => None,
+            },
+            spi,
+            cs: drv_cs
+        },
+        switches: Some(Switches {
+            ah: gpioa.pa8.into_push_pull_output(),
+            al: gpiob.pb13.into_push_pull_output(),
+            bh: gpioa.pa9.into_push_pull_output(),
+            bl: gpiob.pb14.into_push_pull_output(),
+            ch: gpioa.pa10.into_push_pull_output(),
+            cl: gpiob.pb15.into_push_pull_output()
+        }),
+        tim1: Some(dp.TIM1),
+        openloop: None,
+        feedback: Feedback {
+            pa0: gpioa.pa0.into_analog(),
+            pa1: gpioa.pa1.into_analog(),
+            pa2: gpioa.pa2.into_analog(),
+            pa3: gpioa.pa3.into_analog(),
+            pc0: gpioc.pc0.into_analog(),
+            pc1: gpioc.pc1.into_analog(),
+            pc2: gpioc.pc2.into_analog(),
+            pc3: gpioc.pc3.into_analog(),
+            pc4: gpioc.pc4.into_analog(),
+            pc5: gpioc.pc5.into_analog()
+        },
+        canbus: CanBus {
+            power_inject_enable: gpioa.pa15.into_push_pull_output(),
+            charge_pump: ChargePump::new(dp.TIM4, gpiob.pb7.into_alternate_af2(), &clocks),
+            standby_enable: gpiob.pb3.into_push_pull_output()
+        },
+        can,
+        leds: Leds {
+            red: gpiob.pb2.into_push_pull_output(),
+            green: gpiob.pb0.into_push_pull_output(),
+            blue: gpioc.pc6.into_push_pull_output()
+        },
+        sampler: Sampler::new(channels.up.2),
+        edge_sampler: EdgeSampler::new(dp.ADC3),
+        flash,
+        settings,
+        short: None,
+        cal_low: None
+    };
+    (bp, tasks)
+}
+
+fn load_settings(flash: &mut InternalFlash) -> Config {
+    match store::load(flash, &BOARD) {
+        Some((config, sequence)) => {
+            rprintln!("Config #{} loaded", sequence);
+            config
+        }
+        None => {
+            rprintln!("No valid config stored, using defaults");
+            Config::defaults(&BOARD)
+        }
+    }
+}
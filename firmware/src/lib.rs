@@ -0,0 +1,23 @@
+//! STM32F405 board support and command handling, the RTIC app with the tasks is in `main.rs`.
+#![no_std]
+
+pub mod board;
+pub mod peripherals;
+pub mod init;
+pub mod cli;
+pub mod observer;
+pub use power_stage_core::vt100;
+pub mod openloop;
+pub mod pwm;
+pub mod can;
+pub mod remote;
+pub mod charge_pump;
+pub mod od;
+pub mod flash;
+pub mod capture;
+pub mod dpt;
+pub mod protection;
+pub mod halls;
+
+/// Period of the UI task, see `main.rs`.
+pub const TICK_PERIOD_MS: u32 = 50;
@@ -0,0 +1,126 @@
+#![no_main]
+#![no_std]
+
+use panic_rtt_target as _;
+
+// Higher priority preempts lower: the capture recorder on every ADC2 sequence, the DRV
+// fault, hall edges and telemetry frames, the UI every `TICK_PERIOD_MS` and the status page last.
+#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [EXTI0, EXTI1])]
+mod app {
+    use stm32f4xx_hal::{pac::TIM7, timer::{Event, Timer}};
+    use power_stage_tester::peripherals::BoardPeripherals;
+    use power_stage_tester::protection::Guard;
+    use power_stage_tester::halls::HallSensors;
+    use power_stage_tester::init::init_all;
+    use power_stage_tester::observer::TelemetryStream;
+    use power_stage_core::telemetry::Sample;
+    use power_stage_tester::capture::{Link, Recorder};
+    use power_stage_tester::{cli, observer, od, remote, TICK_PERIOD_MS};
+
+    /// VDDA drifts with load and temperature, the voltage readings follow it from VREFINT.
+    const VDDA_PERIOD_MS: u32 = 1000;
+
+    #[shared]
+    struct Shared {
+        bp: BoardPeripherals,
+        /// Capture record and requests, the UI locks it briefly once per tick.
+        capture: &'static mut Link,
+    }
+
+    #[local]
+    struct Local {
+        tick: Timer<TIM7>,
+        guard: Guard,
+        halls: HallSensors,
+        stream: TelemetryStream,
+        recorder: Recorder,
+        /// UI ticks since VDDA was last taken from VREFINT.
+        vdda_ticks: u32,
+    }
+
+    #[init(local = [link: Link = Link::new()])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        let (mut bp, tasks) = init_all(cx.core, cx.device);
+        od::send_boot_up(&mut bp);
+        cli::print_prompt(&bp);
+        (
+            Shared { bp, capture: cx.local.link },
+            Local {
+                tick: tasks.tick,
+                guard: tasks.guard,
+                halls: tasks.halls,
+                stream: tasks.stream,
+                recorder: tasks.recorder,
+                vdda_ticks: 0,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+
+    #[task(binds = ADC, priority = 4, shared = [capture], local = [recorder])]
+    fn adc(mut cx: adc::Context) {
+        let recorder = cx.local.recorder;
+        // Highest priority, the lock masks nothing
+        if let Some(sample) = cx.shared.capture.lock(|link| recorder.record(link)) {
+            // Dropped while the previous frame is still being written, the sequence shows the gap
+            stream::spawn(sample).ok();
+        }
+    }
+
+    #[task(binds = EXTI4, priority = 3, local = [guard])]
+    fn drv_fault(cx: drv_fault::Context) {
+        cx.local.guard.update();
+    }
+
+    #[task(binds = EXTI15_10, priority = 3, local = [halls])]
+    fn hall_edge(cx: hall_edge::Context) {
+        cx.local.halls.update();
+    }
+
+    /// Above the UI, so long commands don't hold the frames back.
+    #[task(priority = 3, local = [stream])]
+    fn stream(cx: stream::Context, sample: Sample) {
+        cx.local.stream.send(&sample);
+    }
+
+    #[task(binds = TIM7, priority = 2, shared = [bp, capture], local = [tick, vdda_ticks])]
+    fn ui(mut cx: ui::Context) {
+        cx.local.tick.clear_interrupt(Event::TimeOut);
+        *cx.local.vdda_ticks += 1;
+        let refresh_vdda = *cx.local.vdda_ticks >= VDDA_PERIOD_MS / TICK_PERIOD_MS;
+        if refresh_vdda {
+            *cx.local.vdda_ticks = 0;
+        }
+        let mut capture = cx.shared.capture;
+        cx.shared.bp.lock(|bp| {
+            if refresh_vdda {
+                bp.adc.calibrate();
+            }
+            cli::process_input(bp);
+            remote::poll(bp);
+            capture.lock(|link| bp.sampler.sync(link));
+            bp.sampler.poll();
+            observer::publish(bp);
+        });
+        // Skipped while the previous page is still being written
+        status::spawn().ok();
+    }
+
+    #[task(priority = 1, shared = [bp])]
+    fn status(mut cx: status::Context) {
+        cx.shared.bp.lock(observer::print_system_status);
+    }
+}
+
+use cortex_m_rt::exception;
+#[exception]
+fn HardFault(ef: &cortex_m_rt::ExceptionFrame) -> ! {
+    panic!("HF: {:#?}", ef);
+}
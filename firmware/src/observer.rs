@@ -0,0 +1,231 @@
+use crate::peripherals::BoardPeripherals;
+use rtt_target::{rprintln, rprint};
+use crate::vt100;
+use crate::board::BOARD;
+use crate::{halls, openloop, protection};
+use embedded_hal::digital::v2::InputPin;
+use power_stage_core::units::{MilliAmperes, MilliVolts};
+use power_stage_core::stage::{self, Channel, Phase, PowerStage, SwitchState};
+use power_stage_core::telemetry::{self, flags, Sample};
+use rtt_target::UpChannel;
+use power_stage_core::config::Config;
+use core::sync::atomic::{AtomicU8, Ordering};
+use power_stage_core::response::{JsonObject, Mode};
+use crate::cli::Rtt;
+use core::fmt::Write;
+
+/// Returns (raw sample, voltage at the ADC pin).
+fn sample(bp: &mut BoardPeripherals, channel: Channel) -> (u16, MilliVolts) {
+    let sample = bp.adc_sample(channel);
+    let v_adc = bp.adc.sample_to_millivolts(sample);
+    (sample, MilliVolts(v_adc as i32))
+}
+
+fn print_channel(bp: &mut BoardPeripherals, channel: Channel) {
+    let (raw, v_adc) = sample(bp, channel);
+    let value = BOARD.convert(channel, v_adc - bp.settings.offset(channel), bp.settings.current_sense_gain);
+    let value = bp.settings.correct(channel, value);
+    match channel {
+        Channel::IA | Channel::IB | Channel::IC => {
+            rprint!(=>1, "Raw={}\tVadc={}\tI={}", raw, v_adc, MilliAmperes(value));
+        }
+        _ => {
+            rprint!(=>1, "Raw={}\tVadc={}\tV={}", raw, v_adc, MilliVolts(value));
+        }
+    }
+}
+
+pub fn print_system_status(bp: &mut BoardPeripherals) {
+    if bp.sampler.is_streaming() {
+        return;
+    }
+    if bp.mode == Mode::Json {
+        print_telemetry(bp);
+        return;
+    }
+    rprintln!(=>1, "{}", vt100::CLEAR_SCREEN);
+    rprintln!(=>1, "Board: {}, {}", BOARD.name, BOARD.drv.name());
+
+    let is_drv_on = protection::is_enabled();
+    rprintln!(=>1, "DRV enabled?: {}", is_drv_on);
+
+    let is_fault = protection::is_fault();
+    match is_fault {
+        true => {
+            rprintln!(=>1, "{}DRV FAULT{}", vt100::RED, vt100::DEFAULT);
+        }
+        false => {
+            rprintln!(=>1, "{}DRV OK{}", vt100::GREEN, vt100::DEFAULT);
+        }
+    }
+    if protection::is_tripped() {
+        rprintln!(=>1, "{}DRV turned off by a fault{}", vt100::RED, vt100::DEFAULT);
+    }
+    if let Some(short) = bp.short {
+        rprintln!(=>1, "{}Short {} found, openloop refused{}", vt100::RED, short, vt100::DEFAULT);
+    }
+
+    rprint!(=>1, "V_IN: ");
+    print_channel(bp, Channel::VIn);
+    rprintln!(=>1, "\n");
+
+    rprintln!(=>1, "A: ");
+    print_channel(bp, Channel::VA);
+    rprintln!(=>1, "");
+    print_channel(bp, Channel::IA);
+    rprintln!(=>1, "\n");
+
+    rprintln!(=>1, "B: ");
+    print_channel(bp, Channel::VB);
+    rprintln!(=>1, "");
+    print_channel(bp, Channel::IB);
+    rprintln!(=>1, "\n");
+
+    rprintln!(=>1, "C: ");
+    print_channel(bp, Channel::VC);
+    rprintln!(=>1, "");
+    print_channel(bp, Channel::IC);
+    rprintln!(=>1, "\n");
+
+    let can_power = bp.canbus.power_inject_enable.is_high().unwrap();
+    let can_standby = bp.canbus.standby_enable.is_high().unwrap();
+    let v_can = MilliVolts(measure(bp, Channel::VCan));
+    rprint!(=>1, "CAN: power={} standby={} pump={} {}Hz V={}",
+            can_power, can_standby,
+            bp.canbus.charge_pump.is_enabled(), bp.canbus.charge_pump.frequency().0, v_can);
+    if can_power && v_can < bp.settings.can_undervoltage {
+        rprint!(=>1, "\t{}UNDERVOLTAGE{}", vt100::RED, vt100::DEFAULT);
+    }
+    rprintln!(=>1, "\n");
+
+    let halls = halls::read();
+    rprintln!(=>1, "Halls: {:?}", halls);
+}
+
+/// Same as the status page as one JSON record per line, voltages in mV and currents in mA.
+fn print_telemetry(bp: &mut BoardPeripherals) {
+    let mut out = Rtt(1);
+    let mut record = JsonObject::begin(&mut out);
+    record.text_field("type", "telemetry");
+    record.field("drv_enabled", protection::is_enabled());
+    record.field("drv_fault", protection::is_fault());
+    let channels = [
+        ("v_in", Channel::VIn),
+        ("v_a", Channel::VA),
+        ("i_a", Channel::IA),
+        ("v_b", Channel::VB),
+        ("i_b", Channel::IB),
+        ("v_c", Channel::VC),
+        ("i_c", Channel::IC),
+    ];
+    for &(name, channel) in channels.iter() {
+        record.field(name, measure(bp, channel));
+    }
+    let can_power = bp.canbus.power_inject_enable.is_high().unwrap();
+    let v_can = MilliVolts(measure(bp, Channel::VCan));
+    record.field("can_power", can_power);
+    record.field("can_standby", bp.canbus.standby_enable.is_high().unwrap());
+    record.field("v_can", v_can.0);
+    record.field("can_undervoltage", can_power && v_can < bp.settings.can_undervoltage);
+    let (a, b, c, index) = halls::read();
+    record.field("hall_a", a);
+    record.field("hall_b", b);
+    record.field("hall_c", c);
+    record.field("hall_index", index);
+    writeln!(record.end()).ok();
+}
+
+/// Frames written to RTT channel 1 in the format of `power_stage_core::telemetry`, at
+/// `capture::STREAM_RATE_HZ` while `Sampler::start_stream` runs.
+pub struct TelemetryStream {
+    channel: UpChannel,
+}
+
+impl TelemetryStream {
+    pub fn new(channel: UpChannel) -> Self {
+        TelemetryStream { channel }
+    }
+
+    /// A frame that doesn't fit the channel buffer is skipped, the sequence gap shows it on the host.
+    pub fn send(&mut self, sample: &Sample) {
+        let mut frame = [0u8; telemetry::MAX_ENCODED_LEN];
+        let len = sample.encode(&mut frame);
+        self.channel.write(&frame[..len]);
+    }
+}
+
+/// Leg states as `Sample::duties`, openloop legs that aren't floating are read live.
+static LEGS: [AtomicU8; 3] = [AtomicU8::new(telemetry::FLOATING), AtomicU8::new(telemetry::FLOATING), AtomicU8::new(telemetry::FLOATING)];
+/// `flags::OPENLOOP` and `flags::CAN_POWER`.
+static STATE_FLAGS: AtomicU8 = AtomicU8::new(0);
+
+/// Publishes the stream fields only the UI task can read, called after every UI tick. They lag the
+/// commands by up to `TICK_PERIOD_MS`.
+pub fn publish(bp: &mut BoardPeripherals) {
+    for (leg, &phase) in LEGS.iter().zip(Phase::ALL.iter()) {
+        let state = match (&bp.openloop, bp.switch(phase)) {
+            (Some(openloop), _) => openloop.leg_duty(phase).unwrap_or(telemetry::FLOATING),
+            (None, Some(SwitchState::High)) => 100,
+            (None, Some(SwitchState::Low)) => 0,
+            (None, _) => telemetry::FLOATING,
+        };
+        leg.store(state, Ordering::Relaxed);
+    }
+    let mut state_flags = 0;
+    if bp.openloop.is_some() {
+        state_flags |= flags::OPENLOOP;
+    }
+    if bp.canbus.power_inject_enable.is_high().unwrap() {
+        state_flags |= flags::CAN_POWER;
+    }
+    STATE_FLAGS.store(state_flags, Ordering::Relaxed);
+}
+
+/// Converted ADC inputs of a telemetry frame, mV or mA.
+pub struct StreamInputs {
+    pub v_in: i32,
+    pub v_phase: [i32; 3],
+    pub i_phase: [i32; 3],
+    pub v_can: i32,
+}
+
+/// Everything the binary stream carries except the sequence number, from the ADC interrupt.
+pub fn stream_sample(config: &Config, inputs: &StreamInputs) -> Sample {
+    let state_flags = STATE_FLAGS.load(Ordering::Relaxed);
+    let mut duties = [0u8; 3];
+    for ((duty, leg), &phase) in duties.iter_mut().zip(LEGS.iter()).zip(Phase::ALL.iter()) {
+        *duty = match leg.load(Ordering::Relaxed) {
+            telemetry::FLOATING => telemetry::FLOATING,
+            _ if state_flags & flags::OPENLOOP != 0 => openloop::current_duty(phase),
+            state => state,
+        };
+    }
+    let (a, b, c, _) = halls::read();
+    let mut flags = state_flags;
+    let states = [
+        (protection::is_enabled(), flags::DRV_ENABLED),
+        (protection::is_fault(), flags::DRV_FAULT),
+        (state_flags & flags::CAN_POWER != 0 && MilliVolts(inputs.v_can) < config.can_undervoltage, flags::CAN_UNDERVOLTAGE),
+    ];
+    for &(state, flag) in states.iter() {
+        if state {
+            flags |= flag;
+        }
+    }
+    Sample {
+        sequence: 0,
+        v_in: inputs.v_in,
+        v_phase: inputs.v_phase,
+        i_phase: inputs.i_phase,
+        v_can: inputs.v_can,
+        duties,
+        halls: a as u8 | (b as u8) << 1 | (c as u8) << 2,
+        flags,
+    }
+}
+
+/// Returns voltage in mV or current in mA depending on the channel.
+pub fn measure(bp: &mut BoardPeripherals, channel: Channel) -> i32 {
+    let config = bp.settings;
+    stage::measure(bp, &BOARD, &config, channel)
+}
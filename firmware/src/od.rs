@@ -0,0 +1,213 @@
+//! Tester state exposed as a CANopen object dictionary.
+use crate::peripherals::BoardPeripherals;
+use crate::observer;
+use crate::board::BOARD;
+use crate::{halls, protection};
+use power_stage_core::canopen::{self, Abort, Access, DataType, Entry, Node, ObjectDictionary};
+use power_stage_core::stage::{Channel, Phase, SwitchState};
+use bxcan::{Frame, StandardId};
+
+const DEVICE_NAME: &[u8] = b"power-stage-tester";
+
+macro_rules! entry {
+    ($index: expr, $sub: expr, $data_type: ident, $access: ident, $name: expr) => {
+        Entry { index: $index, sub: $sub, data_type: DataType::$data_type, access: Access::$access, name: $name }
+    }
+}
+
+const ENTRIES: &[Entry] = &[
+    entry!(0x1000, 0, U32, Ro, "Device type"),
+    entry!(0x1008, 0, VisibleString, Ro, "Device name"),
+    entry!(0x2000, 1, U8, Rw, "DRV enable"),
+    entry!(0x2000, 2, U8, Ro, "DRV fault"),
+    entry!(0x2001, 0, U8, Rw, "Mode: 0 - manual, 1 - openloop"),
+    entry!(0x2002, 1, U16, Rw, "DRV register 0"),
+    entry!(0x2002, 2, U16, Rw, "DRV register 1"),
+    entry!(0x2002, 3, U16, Rw, "DRV register 2"),
+    entry!(0x2002, 4, U16, Rw, "DRV register 3"),
+    entry!(0x2002, 5, U16, Rw, "DRV register 4"),
+    entry!(0x2002, 6, U16, Rw, "DRV register 5"),
+    entry!(0x2002, 7, U16, Rw, "DRV register 6"),
+    entry!(0x2010, 1, U8, Rw, "Duty A, %"),
+    entry!(0x2010, 2, U8, Rw, "Duty B, %"),
+    entry!(0x2010, 3, U8, Rw, "Duty C, %"),
+    entry!(0x2011, 1, U8, Rw, "Switch A: 0 - off, 1 - high, 2 - low"),
+    entry!(0x2011, 2, U8, Rw, "Switch B: 0 - off, 1 - high, 2 - low"),
+    entry!(0x2011, 3, U8, Rw, "Switch C: 0 - off, 1 - high, 2 - low"),
+    entry!(0x2020, 1, I32, Ro, "V_IN, mV"),
+    entry!(0x2020, 2, I32, Ro, "V_A, mV"),
+    entry!(0x2020, 3, I32, Ro, "V_B, mV"),
+    entry!(0x2020, 4, I32, Ro, "V_C, mV"),
+    entry!(0x2020, 5, I32, Ro, "I_A, mA"),
+    entry!(0x2020, 6, I32, Ro, "I_B, mA"),
+    entry!(0x2020, 7, I32, Ro, "I_C, mA"),
+    entry!(0x2020, 8, I32, Ro, "V_CAN, mV"),
+    entry!(0x2021, 0, U8, Ro, "Hall index"),
+    entry!(0x2030, 1, U8, Rw, "Current sense gain"),
+    entry!(0x2030, 2, U32, Rw, "CAN undervoltage threshold, mV"),
+    entry!(0x2030, 3, U16, Rw, "Dead time, ns"),
+    entry!(0x2030, 4, U32, Rw, "PWM frequency, Hz"),
+];
+
+pub struct TesterOd<'a>(pub &'a mut BoardPeripherals);
+
+fn phase(sub: u8) -> Phase {
+    match sub {
+        1 => Phase::A,
+        2 => Phase::B,
+        _ => Phase::C,
+    }
+}
+
+/// `cfg` name of the 0x2030 sub-index, sub-indexes were checked against the entries.
+fn setting(sub: u8) -> &'static str {
+    match sub {
+        1 => "gain",
+        2 => "can_uv",
+        3 => "dead_time",
+        _ => "pwm_freq",
+    }
+}
+
+impl ObjectDictionary for TesterOd<'_> {
+    fn entries(&self) -> &'static [Entry] {
+        ENTRIES
+    }
+
+    fn read(&mut self, index: u16, sub: u8) -> Result<u32, Abort> {
+        let bp = &mut *self.0;
+        let value = match (index, sub) {
+            (0x1000, _) => 0,
+            (0x2000, 1) => protection::is_enabled() as u32,
+            (0x2000, _) => protection::is_fault() as u32,
+            (0x2001, _) => bp.openloop.is_some() as u32,
+            (0x2002, _) if sub > BOARD.drv.register_count() => return Err(Abort::SubindexDoesNotExist),
+            (0x2002, _) => bp.drv.read_register(sub - 1) as u32,
+            (0x2010, _) => bp.openloop.as_ref().ok_or(Abort::DeviceState)?.duty(phase(sub)) as u32,
+            (0x2011, _) => {
+                match bp.switches.as_ref().ok_or(Abort::DeviceState)?.get(phase(sub)) {
+                    SwitchState::Off => 0,
+                    SwitchState::High => 1,
+                    SwitchState::Low => 2,
+                }
+            }
+            (0x2020, _) => {
+                let channel = match sub {
+                    1 => Channel::VIn,
+                    2 => Channel::VA,
+                    3 => Channel::VB,
+                    4 => Channel::VC,
+                    5 => Channel::IA,
+                    6 => Channel::IB,
+                    7 => Channel::IC,
+                    _ => Channel::VCan,
+                };
+                observer::measure(bp, channel) as u32
+            }
+            (0x2021, _) => halls::read().3 as u32,
+            (0x2030, _) => bp.settings.get(setting(sub)).unwrap_or(0) as u32,
+            _ => return Err(Abort::ObjectDoesNotExist)
+        };
+        Ok(value)
+    }
+
+    fn write(&mut self, index: u16, sub: u8, value: u32) -> Result<(), Abort> {
+        let bp = &mut *self.0;
+        match (index, sub) {
+            (0x2000, 1) => {
+                match value {
+                    0 => protection::set_enabled(false),
+                    1 => protection::set_enabled(true),
+                    _ => return Err(Abort::ValueRange)
+                };
+            }
+            (0x2001, _) => {
+                match value {
+                    0 => bp.switch_to_manual(),
+                    1 if bp.short.is_some() => return Err(Abort::DeviceState),
+                    1 => bp.switch_to_openloop(),
+                    _ => return Err(Abort::ValueRange)
+                };
+            }
+            (0x2002, _) if sub > BOARD.drv.register_count() => return Err(Abort::SubindexDoesNotExist),
+            (0x2002, _) => bp.drv.write_register(sub - 1, value as u16),
+            (0x2010, _) => {
+                if value > 100 {
+                    return Err(Abort::ValueRange);
+                }
+                bp.openloop.as_mut().ok_or(Abort::DeviceState)?.update_duty(phase(sub), value as u8);
+            }
+            (0x2011, _) => {
+                let state = match value {
+                    0 => SwitchState::Off,
+                    1 => SwitchState::High,
+                    2 => SwitchState::Low,
+                    _ => return Err(Abort::ValueRange)
+                };
+                bp.switches.as_mut().ok_or(Abort::DeviceState)?.set(phase(sub), state);
+            }
+            (0x2030, _) => {
+                if value > i32::MAX as u32 {
+                    return Err(Abort::ValueRange);
+                }
+                bp.settings.set(&BOARD, setting(sub), value as i32).map_err(|_| Abort::ValueRange)?;
+            }
+            _ => return Err(Abort::ObjectDoesNotExist)
+        }
+        Ok(())
+    }
+
+    fn read_string(&mut self, index: u16, _sub: u8) -> Result<&'static [u8], Abort> {
+        match index {
+            0x1008 => Ok(DEVICE_NAME),
+            _ => Err(Abort::ObjectDoesNotExist)
+        }
+    }
+}
+
+/// Node is moved out of `bp` while the dictionary borrows it.
+fn with_node<R>(bp: &mut BoardPeripherals, f: impl FnOnce(&mut Node, &mut TesterOd) -> R) -> R {
+    let placeholder = Node::new(bp.can.node_id);
+    let mut node = core::mem::replace(&mut bp.can.canopen, placeholder);
+    let result = f(&mut node, &mut TesterOd(bp));
+    bp.can.canopen = node;
+    result
+}
+
+fn std_frame(id: u16, data: &[u8]) -> Frame {
+    Frame::new_data(StandardId::new(id).unwrap(), bxcan::Data::new(data).unwrap())
+}
+
+pub fn send_boot_up(bp: &mut BoardPeripherals) {
+    let frame = std_frame(bp.can.canopen.boot_up_id(), &[0]);
+    bp.can.transmit(&frame);
+}
+
+/// Handle NMT or SDO request frame.
+pub fn handle_frame(bp: &mut BoardPeripherals, id: u16, data: &[u8]) {
+    if id == canopen::NMT_ID {
+        if bp.can.canopen.handle_nmt(data) {
+            send_boot_up(bp);
+        }
+    } else if id == bp.can.canopen.sdo_rx_id() {
+        let response = with_node(bp, |node, od| node.handle_sdo(od, data));
+        if let Some(response) = response {
+            let frame = std_frame(bp.can.canopen.sdo_tx_id(), &response);
+            bp.can.transmit(&frame);
+        }
+    }
+}
+
+pub fn poll_tpdos(bp: &mut BoardPeripherals, elapsed_ms: u16) {
+    let mut frames: [Option<Frame>; canopen::TPDO_COUNT] = Default::default();
+    let mut count = 0;
+    with_node(bp, |node, od| {
+        node.poll_tpdos(od, elapsed_ms, |id, data| {
+            frames[count] = Some(std_frame(id, data));
+            count += 1;
+        })
+    });
+    for frame in frames.iter().flatten() {
+        bp.can.transmit(frame);
+    }
+}
@@ -0,0 +1,96 @@
+use crate::peripherals::{Switches};
+use crate::pwm::{ComplementaryPwm, Pins};
+use stm32f4xx_hal as hal;
+use hal::pac::TIM1;
+use core::sync::atomic::{AtomicU8, Ordering};
+use rtt_target::rprintln;
+use power_stage_core::{pwm::{self, LegMode}, vector};
+use power_stage_core::units::{Hertz, Nanoseconds};
+pub use power_stage_core::stage::Phase;
+
+/// Duties in percent as last written, for the capture interrupt.
+static DUTIES: [AtomicU8; 3] = [AtomicU8::new(0), AtomicU8::new(0), AtomicU8::new(0)];
+
+pub struct OpenLoop {
+    pwm: ComplementaryPwm,
+}
+impl OpenLoop {
+    pub fn init(tim: TIM1, timer_clock: Hertz, switches: Switches, pwm_freq: Hertz, dead_time: Nanoseconds) -> Self {
+        let mut pwm = ComplementaryPwm::new(tim, Pins::from_switches(switches), timer_clock, pwm_freq, dead_time);
+        for &phase in Phase::ALL.iter() {
+            pwm.set_leg(phase, LegMode::Pwm);
+        }
+        let openloop = OpenLoop { pwm };
+        openloop.publish_duties();
+        openloop
+    }
+
+    /// Stops the timer, the pins go back to manual outputs.
+    pub fn deinit(self) -> (Switches, TIM1) {
+        rprintln!("OpenLoop:deinit");
+        for duty in DUTIES.iter() {
+            duty.store(0, Ordering::Relaxed);
+        }
+        let (tim, pins) = self.pwm.free();
+        (pins.into_switches(), tim)
+    }
+
+    pub fn update_duty(&mut self, phase: Phase, duty: u8) {
+        self.pwm.set_duty(phase, duty);
+        self.publish_duties();
+    }
+
+    /// Duty in percent.
+    pub fn duty(&self, phase: Phase) -> u8 {
+        self.pwm.duty(phase)
+    }
+
+    /// Duty the leg applies with its mode, None while floating.
+    pub fn leg_duty(&self, phase: Phase) -> Option<u8> {
+        self.pwm.leg(phase).duty(self.duty(phase))
+    }
+
+    pub fn set_leg(&mut self, phase: Phase, leg: LegMode) {
+        self.pwm.set_leg(phase, leg);
+        self.publish_duties();
+    }
+
+    pub fn leg(&self, phase: Phase) -> LegMode {
+        self.pwm.leg(phase)
+    }
+
+    /// Apply a static voltage vector at electrical angle `angle_deg` (0 = phase A axis)
+    /// with magnitude `duty` in percent of the half bus voltage.
+    pub fn apply_vector(&mut self, angle_deg: i32, duty: u8) {
+        let duties = vector::vector_duties(angle_deg, duty, self.pwm.period() as u32);
+        self.set_compares(duties.map(|duty| duty as u16));
+    }
+
+    /// Drive `phase` at `duty` percent while keeping low-side switches of other phases on,
+    /// so DC current flows from `phase` into the two other windings.
+    pub fn dc_injection(&mut self, phase: Phase, duty: u8) {
+        self.set_compares([0; 3]);
+        self.update_duty(phase, duty);
+    }
+
+    /// Set all phases to 50% duty, zero voltage across the windings.
+    pub fn zero_vector(&mut self) {
+        self.set_compares([self.pwm.period() / 2; 3]);
+    }
+
+    fn set_compares(&mut self, compares: [u16; 3]) {
+        self.pwm.set_compares(compares);
+        self.publish_duties();
+    }
+
+    fn publish_duties(&self) {
+        for (duty, &phase) in DUTIES.iter().zip([Phase::A, Phase::B, Phase::C].iter()) {
+            duty.store(self.leg_duty(phase).unwrap_or(0), Ordering::Relaxed);
+        }
+    }
+}
+
+/// Duty in percent as last written, 0 in manual mode and for a floating leg. Readable from any priority.
+pub fn current_duty(phase: Phase) -> u8 {
+    DUTIES[pwm::channel(phase)].load(Ordering::Relaxed)
+}
@@ -0,0 +1,358 @@
+use stm32f4xx_hal as hal;
+use hal::{
+    gpio::{
+        gpioa::*, gpiob::*, gpioc::*, gpiod::PD2,
+        Output, Input, Analog, Floating, PushPull, Alternate, AF1,
+    }
+};
+use embedded_hal::digital::v2::{OutputPin, StatefulOutputPin};
+use embedded_hal::blocking::spi::Transfer;
+use power_stage_core::units::MilliVolts;
+use power_stage_core::config::Config;
+use power_stage_core::line::LineEditor;
+use power_stage_core::response::Mode;
+use power_stage_core::shorts::Short;
+use power_stage_core::calibration::Point;
+use power_stage_core::bench::Bench;
+use power_stage_core::board::Board;
+use crate::openloop::OpenLoop;
+use crate::pwm;
+use crate::capture::Sampler;
+use crate::dpt::EdgeSampler;
+use power_stage_core::stage::{Channel, Phase, PowerStage, SwitchState};
+use crate::halls;
+use crate::flash::InternalFlash;
+use crate::can::CanNode;
+use crate::charge_pump::ChargePump;
+use crate::board::BOARD;
+use crate::protection;
+use hal::adc::{config::SampleTime, Adc};
+use hal::pac::{ADC1, TIM1};
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+
+#[allow(clippy::upper_case_acronyms)]
+type OPP = Output<PushPull>;
+
+const ADC_SAMPLE_TIME: SampleTime = SampleTime::Cycles_28;
+
+pub struct BoardPeripherals {
+    // pub rcc: hal::rcc::Rcc,
+    pub clocks: hal::rcc::Clocks,
+    pub delay: hal::delay::Delay,
+    pub rtt_down_channel: rtt_target::DownChannel,
+    pub line: LineEditor,
+    /// Replies and telemetry for humans or as JSON records, `mode` command switches.
+    pub mode: Mode,
+    pub adc: hal::adc::Adc<hal::pac::ADC1>,
+
+    pub drv: Drv,
+    pub switches: Option<Switches>,
+    /// PWM timer while in manual mode, `openloop` owns it otherwise.
+    pub tim1: Option<TIM1>,
+    pub openloop: Option<OpenLoop>,
+    pub feedback: Feedback,
+    pub canbus: CanBus,
+    pub can: CanNode,
+    pub leds: Leds,
+    /// Waveform capture, records from the ADC2 interrupt.
+    pub sampler: Sampler,
+    pub edge_sampler: EdgeSampler,
+    pub flash: InternalFlash,
+    /// Runtime adjustable parameters, `cfg save` stores them in flash.
+    pub settings: Config,
+    /// Found by the last `test shorts`, openloop and the other tests refuse to run until it passes.
+    pub short: Option<Short>,
+    /// Low point of a `cal voltage` in progress, the high point completes it.
+    pub cal_low: Option<Point>,
+}
+impl BoardPeripherals {
+    /// Returns false if already in manual mode.
+    pub fn switch_to_manual(&mut self) -> bool {
+        match self.openloop.take() {
+            Some(openloop) => {
+                let (switches, tim1) = openloop.deinit();
+                self.switches = Some(switches);
+                self.tim1 = Some(tim1);
+                true
+            }
+            None => false
+        }
+    }
+
+    /// Returns false if already in openloop mode.
+    pub fn switch_to_openloop(&mut self) -> bool {
+        match self.switches.take() {
+            Some(switches) => {
+                let tim1 = self.tim1.take().expect("TIM1 is parked with the switches");
+                let timer_clock = pwm::timer_clock(&self.clocks);
+                self.openloop = Some(OpenLoop::init(tim1, timer_clock, switches, self.settings.pwm_frequency(), self.settings.dead_time()));
+                true
+            }
+            None => false
+        }
+    }
+}
+
+impl BoardPeripherals {
+    /// Raw ADC sample of the channel, input is taken from the board description.
+    pub fn adc_sample(&mut self, channel: Channel) -> u16 {
+        convert_input(&mut self.adc, BOARD.analog.get(channel))
+    }
+}
+
+/// ADC input selected by number, HAL conversions need the input as a type.
+struct AdcInput<const N: u8>;
+impl<const N: u8> embedded_hal::adc::Channel<ADC1> for AdcInput<N> {
+    type ID = u8;
+
+    fn channel() -> u8 {
+        N
+    }
+}
+
+fn convert_input(adc: &mut Adc<ADC1>, input: u8) -> u16 {
+    macro_rules! convert {
+        ($($n: literal),*) => {
+            match input {
+                $($n => adc.convert(&AdcInput::<$n>, ADC_SAMPLE_TIME),)*
+                // Board descriptions only use external inputs, checked by the core tests
+                _ => 0
+            }
+        }
+    }
+    convert!(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15)
+}
+
+impl PowerStage for BoardPeripherals {
+    fn switch_to_manual(&mut self) -> bool {
+        BoardPeripherals::switch_to_manual(self)
+    }
+
+    fn switch_to_openloop(&mut self) -> bool {
+        BoardPeripherals::switch_to_openloop(self)
+    }
+
+    fn set_switch(&mut self, phase: Phase, state: SwitchState) -> bool {
+        match &mut self.switches {
+            Some(switches) => {
+                switches.set(phase, state);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn switch(&self, phase: Phase) -> Option<SwitchState> {
+        self.switches.as_ref().map(|s| s.get(phase))
+    }
+
+    fn set_duty(&mut self, phase: Phase, duty: u8) -> bool {
+        match &mut self.openloop {
+            Some(openloop) => {
+                openloop.update_duty(phase, duty);
+                true
+            }
+            None => false
+        }
+    }
+
+    fn adc_voltage(&mut self, channel: Channel) -> MilliVolts {
+        let sample = self.adc_sample(channel);
+        MilliVolts(self.adc.sample_to_millivolts(sample) as i32)
+    }
+
+    fn sample_on_time(&mut self, channel: Channel) -> Option<MilliVolts> {
+        self.openloop.as_ref()?;
+        let sample = self.edge_sampler.convert_on_time(BOARD.analog.get(channel))?;
+        Some(MilliVolts(self.adc.sample_to_millivolts(sample) as i32))
+    }
+
+    fn hall(&self) -> (bool, bool, bool) {
+        let (a, b, c, _) = halls::read();
+        (a, b, c)
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.delay.delay_ms(ms);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.delay.delay_us(us);
+    }
+}
+
+impl Bench for BoardPeripherals {
+    type Flash = InternalFlash;
+
+    fn board(&self) -> Board {
+        BOARD
+    }
+
+    fn config(&mut self) -> &mut Config {
+        &mut self.settings
+    }
+
+    fn short(&mut self) -> &mut Option<Short> {
+        &mut self.short
+    }
+
+    fn cal_low(&mut self) -> &mut Option<Point> {
+        &mut self.cal_low
+    }
+
+    fn flash(&mut self) -> &mut InternalFlash {
+        &mut self.flash
+    }
+
+    fn driver_enabled(&self) -> bool {
+        protection::is_enabled()
+    }
+
+    fn calibrate_adc(&mut self) -> MilliVolts {
+        self.adc.calibrate();
+        MilliVolts(self.adc.sample_to_millivolts(4095) as i32)
+    }
+
+    fn apply_vector(&mut self, angle_deg: i32, duty: u8) -> bool {
+        self.openloop.as_mut().map(|openloop| openloop.apply_vector(angle_deg, duty)).is_some()
+    }
+
+    fn dc_injection(&mut self, phase: Phase, duty: u8) -> bool {
+        self.openloop.as_mut().map(|openloop| openloop.dc_injection(phase, duty)).is_some()
+    }
+
+    fn zero_vector(&mut self) -> bool {
+        self.openloop.as_mut().map(|openloop| openloop.zero_vector()).is_some()
+    }
+}
+
+pub struct Drv {
+    /// Enable and nFAULT are with `protection`.
+    pub offset_cal: PB1<OPP>,
+    /// TIM1 break input on nFAULT, pulled up. Only on a board with `Board::fault_break`, rev1
+    /// has nFAULT on PB4 alone and leaves PB12 unconfigured.
+    pub fault_break: Option<PB12<Alternate<AF1>>>,
+    pub spi: bitbang_hal::spi::SPI<
+        PC11<Input<Floating>>,
+        PC12<OPP>,
+        PC10<OPP>,
+        hal::timer::Timer<hal::pac::TIM6>
+    >,
+    pub cs: PD2<OPP>,
+}
+impl Drv {
+    fn transfer_word(&mut self, word: u16) -> u16 {
+        let mut buf = word.to_be_bytes();
+        self.cs.set_low().ok();
+        self.spi.transfer(&mut buf).ok();
+        self.cs.set_high().ok();
+        u16::from_be_bytes(buf)
+    }
+
+    /// DRV83xx SPI frame: R/W bit, 4 bit address, 11 bit data.
+    /// Command is sent twice, since some parts answer in the following frame.
+    pub fn read_register(&mut self, addr: u8) -> u16 {
+        let cmd = (1 << 15) | ((addr as u16 & 0xF) << 11);
+        self.transfer_word(cmd);
+        self.transfer_word(cmd) & 0x7FF
+    }
+
+    pub fn write_register(&mut self, addr: u8, data: u16) {
+        self.transfer_word(((addr as u16 & 0xF) << 11) | (data & 0x7FF));
+    }
+}
+
+pub struct Switches {
+    pub ah: PA8<OPP>,
+    pub al: PB13<OPP>,
+    pub bh: PA9<OPP>,
+    pub bl: PB14<OPP>,
+    pub ch: PA10<OPP>,
+    pub cl: PB15<OPP>,
+}
+impl Switches {
+    pub fn set(&mut self, phase: Phase, state: SwitchState) {
+        match phase {
+            Phase::A => set_leg(&mut self.ah, &mut self.al, state),
+            Phase::B => set_leg(&mut self.bh, &mut self.bl, state),
+            Phase::C => set_leg(&mut self.ch, &mut self.cl, state),
+        }
+    }
+
+    pub fn get(&self, phase: Phase) -> SwitchState {
+        let (high, low) = match phase {
+            Phase::A => (self.ah.is_set_high(), self.al.is_set_high()),
+            Phase::B => (self.bh.is_set_high(), self.bl.is_set_high()),
+            Phase::C => (self.ch.is_set_high(), self.cl.is_set_high()),
+        };
+        match (high.unwrap(), low.unwrap()) {
+            (true, _) => SwitchState::High,
+            (false, true) => SwitchState::Low,
+            (false, false) => SwitchState::Off,
+        }
+    }
+}
+
+/// Break before make: both switches are turned off before the requested one is turned on.
+fn set_leg<H: OutputPin, L: OutputPin>(high: &mut H, low: &mut L, state: SwitchState) {
+    high.set_low().ok();
+    low.set_low().ok();
+    match state {
+        SwitchState::High => {
+            high.set_high().ok();
+        }
+        SwitchState::Low => {
+            low.set_high().ok();
+        }
+        SwitchState::Off => {}
+    }
+}
+
+
+/// All external ADC inputs are kept in analog mode, which signal is on which input
+/// depends on the board revision, see `board::BOARD.analog`.
+pub struct Feedback {
+    pub pa0: PA0<Analog>,
+    pub pa1: PA1<Analog>,
+    pub pa2: PA2<Analog>,
+    pub pa3: PA3<Analog>,
+    pub pc0: PC0<Analog>,
+    pub pc1: PC1<Analog>,
+    pub pc2: PC2<Analog>,
+    pub pc3: PC3<Analog>,
+    pub pc4: PC4<Analog>,
+    pub pc5: PC5<Analog>,
+}
+
+pub struct CanBus {
+    pub power_inject_enable: PA15<OPP>,
+    pub charge_pump: ChargePump,
+    pub standby_enable: PB3<OPP>,
+}
+
+impl CanBus {
+    /// Charge pump has to run for the injection switch to turn on.
+    pub fn set_power(&mut self, on: bool) {
+        if on {
+            self.charge_pump.enable();
+            self.power_inject_enable.set_high().ok();
+        } else {
+            self.power_inject_enable.set_low().ok();
+            self.charge_pump.disable();
+        }
+    }
+
+    pub fn set_standby(&mut self, on: bool) {
+        if on {
+            self.standby_enable.set_high().ok();
+        } else {
+            self.standby_enable.set_low().ok();
+        }
+    }
+}
+
+pub struct Leds {
+    pub red: PB2<OPP>,
+    pub green: PB0<OPP>,
+    pub blue: PC6<OPP>,
+}
\ No newline at end of file
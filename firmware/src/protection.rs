@@ -0,0 +1,76 @@
+//! DRV gate driver enable and nFAULT, owned by the EXTI4 task so a fault turns the driver off
+//! without waiting for the UI. Everything else requests changes and reads the state through
+//! the atomics below.
+use stm32f4xx_hal as hal;
+use hal::{
+    gpio::{gpiob::{PB4, PB5}, Edge, ExtiPin, Floating, Input, Output, PushPull},
+    pac::{Interrupt, EXTI},
+    syscfg::SysCfg,
+};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The UI wants the driver enabled.
+static REQUEST: AtomicBool = AtomicBool::new(false);
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// nFAULT is asserted.
+static FAULT: AtomicBool = AtomicBool::new(false);
+/// The driver was turned off by a fault and stays off until enabled again.
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+
+pub struct Guard {
+    enable: PB5<Output<PushPull>>,
+    fault: PB4<Input<Floating>>,
+}
+
+impl Guard {
+    pub fn new(mut enable: PB5<Output<PushPull>>, mut fault: PB4<Input<Floating>>, syscfg: &mut SysCfg, exti: &mut EXTI) -> Self {
+        enable.set_low().ok();
+        fault.make_interrupt_source(syscfg);
+        fault.trigger_on_edge(exti, Edge::RISING_FALLING);
+        fault.enable_interrupt(exti);
+        let mut guard = Guard { enable, fault };
+        guard.update();
+        guard
+    }
+
+    /// Called from the EXTI4 task on nFAULT edges and on requests from `set_enabled`.
+    pub fn update(&mut self) {
+        self.fault.clear_interrupt_pending_bit();
+        let fault = self.fault.is_low().unwrap();
+        let asserted = fault && !FAULT.swap(fault, Ordering::SeqCst);
+        if asserted && ENABLED.load(Ordering::SeqCst) {
+            REQUEST.store(false, Ordering::SeqCst);
+            TRIPPED.store(true, Ordering::SeqCst);
+        }
+        let on = REQUEST.load(Ordering::SeqCst);
+        if on {
+            self.enable.set_high().ok();
+        } else {
+            self.enable.set_low().ok();
+        }
+        ENABLED.store(on, Ordering::SeqCst);
+    }
+}
+
+/// Turns the driver on or off, done by the time this returns since the EXTI4 task preempts
+/// every caller. Enabling clears a trip.
+pub fn set_enabled(on: bool) {
+    if on {
+        TRIPPED.store(false, Ordering::SeqCst);
+    }
+    REQUEST.store(on, Ordering::SeqCst);
+    rtic::pend(Interrupt::EXTI4);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn is_fault() -> bool {
+    FAULT.load(Ordering::SeqCst)
+}
+
+pub fn is_tripped() -> bool {
+    TRIPPED.load(Ordering::SeqCst)
+}
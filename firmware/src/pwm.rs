@@ -0,0 +1,300 @@
+//! TIM1 center aligned complementary PWM, high sides on CH1..3 and low sides on CH1N..3N.
+//! Register values come from `power_stage_core::pwm`, this only writes them.
+//!
+//! `double_pulse` borrows the timer for the double pulse test, see `power_stage_core::dpt`.
+//!
+//! TRGO follows the channel 4 reference, which drives no pin and rises once per period at the
+//! bottom of the count, the middle of every high side on-time. ADC3 samples the short check there.
+//! The break input is enabled only on a board with `Board::fault_break`, active low on nFAULT, and
+//! a DRV fault then stops the outputs in hardware. Rev1 has none, its nFAULT is a GPIO.
+use stm32f4xx_hal as hal;
+use hal::{
+    gpio::{gpioa::*, gpiob::*, Alternate, AF1},
+    pac::TIM1,
+    rcc::Clocks,
+};
+use cortex_m::interrupt;
+use crate::board::BOARD;
+use crate::peripherals::Switches;
+use power_stage_core::dpt::Timing;
+use power_stage_core::pwm::{
+    ccer, center_aligned_arr, channel, compare_duty, dead_time_dtg, duty_compare, rescale_compare, LegMode, OutputMode,
+};
+use power_stage_core::stage::Phase;
+use power_stage_core::units::{Hertz, Nanoseconds};
+
+/// TIM1 runs from the system clock here, the register math takes it in the core units.
+pub fn timer_clock(clocks: &Clocks) -> Hertz {
+    Hertz(clocks.sysclk().0)
+}
+
+/// Channel 4 compare value, the reference is active while counting through 0 and 1.
+const ON_TIME_TRIGGER: u16 = 1;
+
+pub struct Pins {
+    pub ah: PA8<Alternate<AF1>>,
+    pub al: PB13<Alternate<AF1>>,
+    pub bh: PA9<Alternate<AF1>>,
+    pub bl: PB14<Alternate<AF1>>,
+    pub ch: PA10<Alternate<AF1>>,
+    pub cl: PB15<Alternate<AF1>>,
+}
+
+impl Pins {
+    pub fn from_switches(switches: Switches) -> Self {
+        Pins {
+            ah: switches.ah.into_alternate_af1(),
+            al: switches.al.into_alternate_af1(),
+            bh: switches.bh.into_alternate_af1(),
+            bl: switches.bl.into_alternate_af1(),
+            ch: switches.ch.into_alternate_af1(),
+            cl: switches.cl.into_alternate_af1(),
+        }
+    }
+
+    /// Back to manual outputs.
+    pub fn into_switches(self) -> Switches {
+        Switches {
+            ah: self.ah.into_push_pull_output(),
+            al: self.al.into_push_pull_output(),
+            bh: self.bh.into_push_pull_output(),
+            bl: self.bl.into_push_pull_output(),
+            ch: self.ch.into_push_pull_output(),
+            cl: self.cl.into_push_pull_output(),
+        }
+    }
+}
+
+pub struct ComplementaryPwm {
+    tim: TIM1,
+    pins: Pins,
+    timer_clock: Hertz,
+    /// (high, low) output enables per channel.
+    outputs: [(bool, bool); 3],
+    legs: [LegMode; 3],
+}
+
+impl ComplementaryPwm {
+    /// Starts the timer at 50% duty with every leg floating, `set_leg` turns them on. Out of
+    /// range settings fall back to the slowest PWM and the longest dead time, `Config` checks
+    /// them beforehand.
+    pub fn new(tim: TIM1, pins: Pins, timer_clock: Hertz, frequency: Hertz, dead_time: Nanoseconds) -> Self {
+        let arr = center_aligned_arr(timer_clock, frequency).unwrap_or(u16::MAX);
+        let dtg = dead_time_dtg(timer_clock, dead_time).unwrap_or(0xFF);
+
+        // Every register used is written, the timer may have run before
+        tim.cr1.write(|w| w
+            .cms().center_aligned1()
+            .ckd().div1()
+        );
+        tim.arr.write(|w| w.arr().bits(arr));
+        tim.psc.write(|w| w.psc().bits(0));
+        tim.rcr.write(|w| unsafe { w.rep().bits(0) });
+        tim.egr.write(|w| w.ug().update());
+        tim.cnt.reset();
+        tim.ccer.reset();
+        tim.ccmr1_output_mut().reset();
+        tim.ccmr2_output_mut().reset();
+        // Output idle and idle_n state, TRGO from the channel 4 reference
+        tim.cr2.write(|w| w
+            .ois1().set_bit().ois1n().set_bit()
+            .ois2().set_bit().ois2n().set_bit()
+            .ois3().set_bit().ois3n().set_bit()
+            .mms().compare_oc4()
+        );
+        let mut pwm = ComplementaryPwm { tim, pins, timer_clock, outputs: [(false, false); 3], legs: [LegMode::Float; 3] };
+        for &phase in [Phase::A, Phase::B, Phase::C].iter() {
+            pwm.write_mode(phase, OutputMode::Pwm);
+        }
+        pwm.tim.ccmr2_output_mut().modify(|_, w| w.oc4m().pwm_mode1());
+        pwm.tim.ccr4.write(|w| w.ccr().bits(ON_TIME_TRIGGER));
+        pwm.set_compares([arr / 2; 3]);
+        pwm.write_outputs();
+        // Enable preload
+        pwm.tim.ccmr1_output_mut().modify(|_, w| w.oc1pe().enabled().oc2pe().enabled());
+        pwm.tim.ccmr2_output_mut().modify(|_, w| w.oc3pe().set_bit());
+        // Dead time, break on a low nFAULT if wired, then MOE stays cleared until the next `new`
+        pwm.tim.bdtr.write(|w| unsafe { w
+            .ossr().idle_level()
+            .ossi().idle_level()
+            .lock().bits(0)
+            .dtg().bits(dtg)
+            .aoe().clear_bit()
+            .bke().bit(BOARD.fault_break.is_some())
+            .bkp().clear_bit()
+        });
+        pwm.tim.cr2.modify(|_, w| w.ccpc().set_bit());
+        pwm.tim.cr1.modify(|_, w| w.arpe().set_bit());
+        // Enable
+        pwm.tim.cr1.modify(|_, w| w.cen().enabled());
+        pwm.tim.bdtr.modify(|_, w| w.moe().enabled());
+        pwm
+    }
+
+    /// Stops the timer with the outputs in their idle state.
+    pub fn free(self) -> (TIM1, Pins) {
+        self.tim.bdtr.modify(|_, w| w.moe().disabled_idle());
+        self.tim.cr1.modify(|_, w| w.cen().disabled());
+        (self.tim, self.pins)
+    }
+
+    /// Reload value, compare values run from 0 to this.
+    pub fn period(&self) -> u16 {
+        self.tim.arr.read().arr().bits()
+    }
+
+    pub fn compare(&self, phase: Phase) -> u16 {
+        match phase {
+            Phase::A => self.tim.ccr1.read().ccr().bits(),
+            Phase::B => self.tim.ccr2.read().ccr().bits(),
+            Phase::C => self.tim.ccr3.read().ccr().bits(),
+        }
+    }
+
+    /// Writes all three compare values, they take effect together at the next update.
+    pub fn set_compares(&mut self, compares: [u16; 3]) {
+        self.tim.cr1.modify(|_, w| w.udis().disabled());
+        self.tim.ccr1.write(|w| w.ccr().bits(compares[0]));
+        self.tim.ccr2.write(|w| w.ccr().bits(compares[1]));
+        self.tim.ccr3.write(|w| w.ccr().bits(compares[2]));
+        self.tim.cr1.modify(|_, w| w.udis().enabled());
+    }
+
+    /// Duty in percent.
+    pub fn duty(&self, phase: Phase) -> u8 {
+        compare_duty(self.compare(phase), self.period())
+    }
+
+    pub fn set_duty(&mut self, phase: Phase, duty: u8) {
+        let mut compares = [self.compare(Phase::A), self.compare(Phase::B), self.compare(Phase::C)];
+        compares[channel(phase)] = duty_compare(duty, self.period());
+        self.set_compares(compares);
+    }
+
+    /// Enables the high (CHx) and low (CHxN) side outputs of a leg, a disabled output is not
+    /// driven by the timer.
+    pub fn set_outputs(&mut self, phase: Phase, high: bool, low: bool) {
+        self.outputs[channel(phase)] = (high, low);
+        self.write_outputs();
+        self.commit();
+    }
+
+    pub fn outputs(&self, phase: Phase) -> (bool, bool) {
+        self.outputs[channel(phase)]
+    }
+
+    fn write_outputs(&mut self) {
+        let bits = ccer(self.outputs);
+        self.tim.ccer.write(|w| unsafe { w.bits(bits as u32) });
+    }
+
+    /// With CCPC set, output enables and modes are preloaded until a COM event.
+    fn commit(&mut self) {
+        self.tim.egr.write(|w| w.comg().set_bit());
+    }
+
+    /// PWM or a leg held as if at 100% or 0% duty, dead time still applies. Takes effect
+    /// immediately.
+    pub fn set_mode(&mut self, phase: Phase, mode: OutputMode) {
+        self.write_mode(phase, mode);
+        self.commit();
+    }
+
+    fn write_mode(&mut self, phase: Phase, mode: OutputMode) {
+        let ocm = mode.ocm();
+        match phase {
+            Phase::A => self.tim.ccmr1_output_mut().modify(|_, w| w.oc1m().bits(ocm)),
+            Phase::B => self.tim.ccmr1_output_mut().modify(|_, w| w.oc2m().bits(ocm)),
+            Phase::C => self.tim.ccmr2_output_mut().modify(|_, w| w.oc3m().bits(ocm)),
+        }
+    }
+
+    /// Output mode and enables of a leg together, see `LegMode`.
+    pub fn set_leg(&mut self, phase: Phase, leg: LegMode) {
+        let (mode, high, low) = leg.outputs();
+        self.write_mode(phase, mode);
+        self.outputs[channel(phase)] = (high, low);
+        self.write_outputs();
+        self.commit();
+        self.legs[channel(phase)] = leg;
+    }
+
+    pub fn leg(&self, phase: Phase) -> LegMode {
+        self.legs[channel(phase)]
+    }
+
+    /// False if the dead time generator can't produce it, the dead time is unchanged then.
+    pub fn set_dead_time(&mut self, dead_time: Nanoseconds) -> bool {
+        match dead_time_dtg(self.timer_clock, dead_time) {
+            Some(dtg) => {
+                self.tim.bdtr.modify(|_, w| unsafe { w.dtg().bits(dtg) });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Keeps the duties, false if the timer can't produce the frequency.
+    pub fn set_frequency(&mut self, frequency: Hertz) -> bool {
+        let arr = match center_aligned_arr(self.timer_clock, frequency) {
+            Some(arr) => arr,
+            None => return false,
+        };
+        let old_arr = self.period();
+        let compares = [Phase::A, Phase::B, Phase::C].map(|phase| rescale_compare(self.compare(phase), old_arr, arr));
+        self.tim.arr.write(|w| w.arr().bits(arr));
+        self.set_compares(compares);
+        true
+    }
+
+    fn write_compare(&mut self, phase: Phase, compare: u16) {
+        match phase {
+            Phase::A => self.tim.ccr1.write(|w| w.ccr().bits(compare)),
+            Phase::B => self.tim.ccr2.write(|w| w.ccr().bits(compare)),
+            Phase::C => self.tim.ccr3.write(|w| w.ccr().bits(compare)),
+        }
+    }
+
+    /// Switches the high side of `phase` on for the two pulses of `timing`, the channel 4
+    /// compare event marks the sample points. The other legs keep their modes, `phase` is left
+    /// floating and the PWM runs again afterwards. Blocks with interrupts disabled until the
+    /// counter stopped, a few ms at most.
+    pub fn double_pulse(&mut self, phase: Phase, timing: &Timing) {
+        let arr = self.period();
+        let compares = [Phase::A, Phase::B, Phase::C].map(|phase| self.compare(phase));
+        self.set_leg(phase, LegMode::Float);
+
+        // Edge aligned up counting, only the counter sets UIF
+        self.tim.cr1.write(|w| w.arpe().set_bit().urs().counter_only());
+        self.tim.ccmr2_output_mut().modify(|_, w| w.oc4pe().set_bit());
+        // First period in the shadow registers, the second one preloaded
+        self.tim.arr.write(|w| w.arr().bits(timing.first.arr));
+        self.write_compare(phase, timing.first.compare);
+        self.tim.ccr4.write(|w| w.ccr().bits(timing.first.sample));
+        self.tim.egr.write(|w| w.ug().update());
+        self.tim.arr.write(|w| w.arr().bits(timing.second.arr));
+        self.write_compare(phase, timing.second.compare);
+        self.tim.ccr4.write(|w| w.ccr().bits(timing.second.sample));
+        self.write_mode(phase, OutputMode::Pwm2);
+        self.outputs[channel(phase)] = (true, false);
+        self.write_outputs();
+        self.commit();
+
+        interrupt::free(|_| {
+            self.tim.sr.modify(|_, w| w.uif().clear());
+            self.tim.cr1.modify(|_, w| w.cen().enabled());
+            while self.tim.sr.read().uif().bit_is_clear() {}
+            // Stops at the end of the second period, the counter is back at 0 below the compare value
+            self.tim.cr1.modify(|_, w| w.opm().enabled());
+            while self.tim.cr1.read().cen().is_enabled() {}
+        });
+
+        self.set_leg(phase, LegMode::Float);
+        self.tim.cr1.write(|w| w.cms().center_aligned1().ckd().div1().arpe().set_bit());
+        self.tim.arr.write(|w| w.arr().bits(arr));
+        self.set_compares(compares);
+        self.tim.ccr4.write(|w| w.ccr().bits(ON_TIME_TRIGGER));
+        self.tim.egr.write(|w| w.ug().update());
+        self.tim.cr1.modify(|_, w| w.cen().enabled());
+    }
+}
@@ -0,0 +1,117 @@
+//! Executes remote commands received over CAN, see `power_stage_core::remote` for the protocol.
+use crate::peripherals::BoardPeripherals;
+use crate::observer;
+use crate::od;
+use crate::{halls, protection};
+use power_stage_core::remote::{command_id, decode, encode_reply, frame_id, pack_i16, Command, FrameKind, Status};
+use power_stage_core::stage::Channel;
+use bxcan::{ExtendedId, Frame, Id};
+
+/// Returns optional reply payload.
+fn execute(bp: &mut BoardPeripherals, command: Command) -> Result<Option<i32>, Status> {
+    match command {
+        Command::SwitchMode(openloop) => {
+            if openloop {
+                if bp.short.is_some() {
+                    return Err(Status::WrongMode);
+                }
+                bp.switch_to_openloop();
+            } else {
+                bp.switch_to_manual();
+            }
+        }
+        Command::Switch(phase, state) => {
+            let switches = bp.switches.as_mut().ok_or(Status::WrongMode)?;
+            switches.set(phase, state);
+        }
+        Command::Duty(phase, duty) => {
+            let openloop = bp.openloop.as_mut().ok_or(Status::WrongMode)?;
+            openloop.update_duty(phase, duty);
+        }
+        Command::Drv(on) => protection::set_enabled(on),
+        Command::Measure(channel) => {
+            return Ok(Some(observer::measure(bp, channel)));
+        }
+        Command::Telemetry(divider) => {
+            bp.can.telemetry_divider = divider;
+            bp.can.telemetry_counter = 0;
+        }
+    }
+    Ok(None)
+}
+
+fn ext_frame(kind: FrameKind, node_id: u8, data: &[u8]) -> Frame {
+    let id = ExtendedId::new(frame_id(kind, node_id)).unwrap();
+    Frame::new_data(id, bxcan::Data::new(data).unwrap())
+}
+
+/// Handle all pending command and CANopen frames, send telemetry and PDOs if due.
+/// Called from the UI task every `TICK_PERIOD_MS`.
+pub fn poll(bp: &mut BoardPeripherals) {
+    while let Some(frame) = bp.can.receive() {
+        let data = match frame.data() {
+            Some(data) => data,
+            None => continue
+        };
+        let id = match frame.id() {
+            Id::Standard(id) => {
+                od::handle_frame(bp, id.as_raw(), data);
+                continue;
+            }
+            Id::Extended(id) => id,
+        };
+        if id.as_raw() != command_id(bp.can.node_id) {
+            continue;
+        }
+        let opcode = data.first().copied().unwrap_or(0);
+        let result = decode(data).and_then(|command| execute(bp, command));
+        let mut reply = [0u8; 6];
+        let reply_len = encode_reply(opcode, result, &mut reply);
+        let reply = ext_frame(FrameKind::Reply, bp.can.node_id, &reply[..reply_len]);
+        bp.can.transmit(&reply);
+    }
+
+    od::poll_tpdos(bp, crate::TICK_PERIOD_MS as u16);
+
+    if bp.can.telemetry_divider == 0 {
+        return;
+    }
+    bp.can.telemetry_counter += 1;
+    if bp.can.telemetry_counter < bp.can.telemetry_divider {
+        return;
+    }
+    bp.can.telemetry_counter = 0;
+    send_telemetry(bp);
+}
+
+fn send_telemetry(bp: &mut BoardPeripherals) {
+    let node_id = bp.can.node_id;
+    let status = [
+        protection::is_enabled() as u8,
+        protection::is_fault() as u8,
+        bp.openloop.is_some() as u8,
+        halls::read().3,
+    ];
+    let frame = ext_frame(FrameKind::Status, node_id, &status);
+    bp.can.transmit(&frame);
+
+    let mut buf = [0u8; 8];
+    let voltages = [
+        observer::measure(bp, Channel::VIn),
+        observer::measure(bp, Channel::VA),
+        observer::measure(bp, Channel::VB),
+        observer::measure(bp, Channel::VC),
+    ];
+    let len = pack_i16(&voltages, 10, &mut buf);
+    let frame = ext_frame(FrameKind::Voltages, node_id, &buf[..len]);
+    bp.can.transmit(&frame);
+
+    let currents = [
+        observer::measure(bp, Channel::IA),
+        observer::measure(bp, Channel::IB),
+        observer::measure(bp, Channel::IC),
+    ];
+    let len = pack_i16(&currents, 10, &mut buf);
+    let frame = ext_frame(FrameKind::Currents, node_id, &buf[..len]);
+    bp.can.transmit(&frame);
+}
@@ -0,0 +1,4 @@
+//! Simulated power stage for running test sequences on the host.
+pub mod model;
+pub mod session;
+pub mod shell;
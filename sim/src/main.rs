@@ -0,0 +1,36 @@
+//! Runs the simulated power stage with commands from stdin, one per line:
+//! `power-stage-sim [rev1] < sequence.txt`, the board names are in `power_stage_core::board::BOARDS`
+use power_stage_core::board::BOARDS;
+use power_stage_sim::session::Session;
+use power_stage_sim::shell;
+use std::io::BufRead;
+
+fn main() {
+    let board = match std::env::args().nth(1) {
+        Some(name) => match BOARDS.iter().find(|b| b.name == name) {
+            Some(board) => *board,
+            None => {
+                eprintln!("Unknown board: {}, expected rev1", name);
+                std::process::exit(1);
+            }
+        },
+        None => BOARDS[0],
+    };
+    let mut session = Session::new(board);
+    let stdin = std::io::stdin();
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("> {}", line);
+        print!("{}", shell::execute(&mut session, line));
+    }
+}
@@ -0,0 +1,603 @@
+//! Electrical model of a power stage driving a star connected three-phase RL load with back-EMF.
+//!
+//! Switches are ideal, in openloop mode the PWM is averaged over one period and dead time is modelled
+//! as a duty error depending on the current direction. Current is positive when flowing from the leg
+//! into the winding. The shunts sit in the low sides, like on the DRV830x boards.
+
+use power_stage_core::board::{Board, Divider};
+use power_stage_core::stage::{Channel, Phase, PowerStage, SwitchState};
+use power_stage_core::units::MilliVolts;
+
+/// Integration step, s.
+const DT: f64 = 1e-6;
+pub(crate) const ADC_FULL_SCALE_MV: f64 = 3300.0;
+const ADC_MAX_SAMPLE: f64 = 4095.0;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Params {
+    /// DC bus voltage, V.
+    pub v_bus: f64,
+    /// Winding resistance, Ohm.
+    pub r: f64,
+    /// Winding inductance, H.
+    pub l: f64,
+    /// Back-EMF amplitude per electrical rad/s, V.
+    pub ke: f64,
+    /// Electrical speed, rad/s. The load is driven externally, speed does not depend on the current.
+    pub speed: f64,
+    /// Dead time, s.
+    pub dead_time: f64,
+    /// PWM period, s.
+    pub pwm_period: f64,
+    /// Body diode forward voltage, V.
+    pub diode_drop: f64,
+    /// CAN bus voltage, V.
+    pub v_can: f64,
+    /// How long a bootstrap supplied high side stays on after its low side was on, s.
+    /// Infinite for a charge pump.
+    pub bootstrap_hold: f64,
+    /// Deviation of the shunts from the board description, relative. The conversions keep
+    /// using the nominal value, like a board before `cal current`.
+    pub shunt_error: f64,
+    /// Phase current at which the DRV's overcurrent protection turns all gates off, A.
+    pub overcurrent: f64,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            v_bus: 24.0,
+            r: 0.5,
+            l: 200e-6,
+            ke: 0.01,
+            speed: 0.0,
+            dead_time: 1e-6,
+            pwm_period: 50e-6,
+            diode_drop: 0.7,
+            v_can: 0.0,
+            bootstrap_hold: f64::INFINITY,
+            shunt_error: 0.0,
+            overcurrent: f64::INFINITY,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Drive {
+    Manual([SwitchState; 3]),
+    /// Duty as a fraction of the period.
+    Pwm([f64; 3]),
+}
+
+pub struct Simulator {
+    pub board: Board,
+    pub params: Params,
+    /// Gain the shunt amplifiers are configured for.
+    pub current_gain: u8,
+    drive: Drive,
+    currents: [f64; 3],
+    /// Time each high side has been on since its low side was, s.
+    high_times: [f64; 3],
+    /// Electrical angle, rad.
+    angle: f64,
+    time: f64,
+    /// The overcurrent protection turned the gates off, until `enable_driver`.
+    tripped: bool,
+}
+
+fn index(phase: Phase) -> usize {
+    match phase {
+        Phase::A => 0,
+        Phase::B => 1,
+        Phase::C => 2,
+    }
+}
+
+fn divide(divider: Divider, v: f64) -> f64 {
+    v * divider.rb.0 as f64 / (divider.rt.0 as f64 + divider.rb.0 as f64)
+}
+
+impl Simulator {
+    /// Starts in manual mode with all switches off, like the board after reset.
+    pub fn new(board: Board) -> Self {
+        Simulator {
+            board,
+            params: Params::default(),
+            current_gain: board.default_current_gain,
+            drive: Drive::Manual([SwitchState::Off; 3]),
+            currents: [0.0; 3],
+            high_times: [0.0; 3],
+            angle: 0.0,
+            time: 0.0,
+            tripped: false,
+        }
+    }
+
+    /// Simulated time, s.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    pub fn current(&self, phase: Phase) -> f64 {
+        self.currents[index(phase)]
+    }
+
+    /// Gate driver on, false after an overcurrent trip.
+    pub fn driver_enabled(&self) -> bool {
+        !self.tripped
+    }
+
+    /// Clears an overcurrent trip, like `drv on`.
+    pub fn enable_driver(&mut self) {
+        self.tripped = false;
+    }
+
+    /// What the gates see, all off while tripped.
+    fn gates(&self) -> Drive {
+        if self.tripped {
+            Drive::Manual([SwitchState::Off; 3])
+        } else {
+            self.drive
+        }
+    }
+
+    pub fn set_angle(&mut self, angle: f64) {
+        self.angle = angle.rem_euclid(2.0 * core::f64::consts::PI);
+    }
+
+    pub fn angle(&self) -> f64 {
+        self.angle
+    }
+
+    /// Duty between 0 and 1 with more resolution than `PowerStage::set_duty`, false if not in openloop mode.
+    pub fn set_duty_fraction(&mut self, phase: Phase, duty: f64) -> bool {
+        match &mut self.drive {
+            Drive::Pwm(duties) => {
+                duties[index(phase)] = duty.clamp(0.0, 1.0);
+                true
+            }
+            Drive::Manual(_) => false,
+        }
+    }
+
+    fn emf(&self) -> [f64; 3] {
+        let amplitude = self.params.ke * self.params.speed;
+        let third = 2.0 * core::f64::consts::PI / 3.0;
+        [
+            amplitude * self.angle.sin(),
+            amplitude * (self.angle - third).sin(),
+            amplitude * (self.angle + third).sin(),
+        ]
+    }
+
+    /// Leg output voltage if it is forced by a switch or a conducting diode, None if floating.
+    fn forced_voltage(&self, k: usize) -> Option<f64> {
+        let v_bus = self.params.v_bus;
+        let i = self.currents[k];
+        match self.gates() {
+            Drive::Manual(switches) => match switches[k] {
+                SwitchState::High if self.high_times[k] < self.params.bootstrap_hold => Some(v_bus),
+                SwitchState::Low => Some(0.0),
+                // Off or a high side without gate voltage
+                _ if i > 0.0 => Some(-self.params.diode_drop),
+                _ if i < 0.0 => Some(v_bus + self.params.diode_drop),
+                _ => None,
+            },
+            Drive::Pwm(duties) => {
+                let duty = match duties[k] {
+                    d if d <= 0.0 => 0.0,
+                    d if d >= 1.0 => 1.0,
+                    d => {
+                        // Turn on is delayed by the dead time, meanwhile the diode picked by the current conducts
+                        let error = self.params.dead_time / self.params.pwm_period;
+                        let d = if i > 0.0 {
+                            d - error
+                        } else if i < 0.0 {
+                            d + error
+                        } else {
+                            d
+                        };
+                        d.clamp(0.0, 1.0)
+                    }
+                };
+                Some(duty * v_bus)
+            }
+        }
+    }
+
+    /// Leg voltages and the star point voltage, floating legs follow the star point and their back-EMF
+    /// until a body diode clamps them to the bus.
+    fn voltages(&self) -> ([Option<f64>; 3], f64) {
+        let emf = self.emf();
+        let mut forced = [self.forced_voltage(0), self.forced_voltage(1), self.forced_voltage(2)];
+        let star = |forced: &[Option<f64>; 3]| {
+            let (sum, n) = forced.iter().zip(emf.iter())
+                .filter_map(|(v, e)| v.map(|v| v - e))
+                .fold((0.0, 0), |(sum, n), v| (sum + v, n + 1));
+            if n == 0 { 0.0 } else { sum / n as f64 }
+        };
+        let mut v_star = star(&forced);
+        let high_diode = self.params.v_bus + self.params.diode_drop;
+        let low_diode = -self.params.diode_drop;
+        for k in 0..3 {
+            if forced[k].is_none() {
+                let v = v_star + emf[k];
+                if v > high_diode {
+                    forced[k] = Some(high_diode);
+                    v_star = star(&forced);
+                } else if v < low_diode {
+                    forced[k] = Some(low_diode);
+                    v_star = star(&forced);
+                }
+            }
+        }
+        (forced, v_star)
+    }
+
+    /// Current through the low side shunt of a leg averaged over a PWM period, A. The shunt only
+    /// carries the phase current while the low side switch or its diode conducts.
+    fn shunt_current(&self, phase: Phase) -> f64 {
+        let k = index(phase);
+        let i = self.currents[k];
+        let low_share = match self.gates() {
+            Drive::Manual(switches) => match switches[k] {
+                SwitchState::Low => 1.0,
+                SwitchState::High if self.high_times[k] < self.params.bootstrap_hold => 0.0,
+                // Off or a high side without gate voltage, the low side diode takes positive current
+                _ if i > 0.0 => 1.0,
+                _ => 0.0,
+            },
+            Drive::Pwm(_) => match self.forced_voltage(k) {
+                Some(v) if self.params.v_bus > 0.0 => 1.0 - (v / self.params.v_bus).clamp(0.0, 1.0),
+                _ => 0.0,
+            },
+        };
+        i * low_share
+    }
+
+    /// Output voltage of a leg clamped to the bus rails, V.
+    pub fn leg_voltage(&self, phase: Phase) -> f64 {
+        let (forced, v_star) = self.voltages();
+        let k = index(phase);
+        forced[k].unwrap_or(v_star + self.emf()[k]).max(0.0).min(self.params.v_bus)
+    }
+
+    fn step(&mut self) {
+        let (forced, v_star) = self.voltages();
+        let emf = self.emf();
+        let conducting = forced.iter().filter(|v| v.is_some()).count();
+        for k in 0..3 {
+            match forced[k] {
+                Some(v) if conducting >= 2 => {
+                    let di = (v - v_star - emf[k] - self.params.r * self.currents[k]) / self.params.l;
+                    let i = self.currents[k] + di * DT;
+                    // A diode stops conducting when its current reaches zero
+                    let diode_only = match self.gates() {
+                        Drive::Manual(switches) => match switches[k] {
+                            SwitchState::High => self.high_times[k] >= self.params.bootstrap_hold,
+                            state => state == SwitchState::Off,
+                        },
+                        Drive::Pwm(_) => false,
+                    };
+                    let blocked = diode_only && ((v < 0.0 && i < 0.0) || (v > 0.0 && i > 0.0));
+                    self.currents[k] = if blocked { 0.0 } else { i };
+                }
+                _ => self.currents[k] = 0.0,
+            }
+        }
+        for k in 0..3 {
+            self.high_times[k] = match self.gates() {
+                Drive::Manual(switches) if switches[k] == SwitchState::High => self.high_times[k] + DT,
+                Drive::Manual(switches) if switches[k] == SwitchState::Off => self.high_times[k],
+                // The low side charges the bootstrap, also during PWM
+                _ => 0.0,
+            };
+        }
+        if self.currents.iter().any(|i| i.abs() > self.params.overcurrent) {
+            self.tripped = true;
+        }
+        self.set_angle(self.angle + self.params.speed * DT);
+        self.time += DT;
+    }
+
+    pub fn advance(&mut self, seconds: f64) {
+        let steps = (seconds / DT).round() as u64;
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// 12 bit conversion of a voltage at the ADC pin.
+    fn adc(v_pin_mv: f64) -> MilliVolts {
+        let sample = (v_pin_mv / ADC_FULL_SCALE_MV * ADC_MAX_SAMPLE).round().clamp(0.0, ADC_MAX_SAMPLE);
+        MilliVolts((sample * ADC_FULL_SCALE_MV / ADC_MAX_SAMPLE) as i32)
+    }
+}
+
+impl PowerStage for Simulator {
+    fn switch_to_manual(&mut self) -> bool {
+        match self.drive {
+            Drive::Manual(_) => false,
+            Drive::Pwm(_) => {
+                self.drive = Drive::Manual([SwitchState::Off; 3]);
+                true
+            }
+        }
+    }
+
+    /// Openloop starts with all legs at 50%, like `OpenLoop::init`.
+    fn switch_to_openloop(&mut self) -> bool {
+        match self.drive {
+            Drive::Pwm(_) => false,
+            Drive::Manual(_) => {
+                self.drive = Drive::Pwm([0.5; 3]);
+                true
+            }
+        }
+    }
+
+    fn set_switch(&mut self, phase: Phase, state: SwitchState) -> bool {
+        match &mut self.drive {
+            Drive::Manual(switches) => {
+                switches[index(phase)] = state;
+                true
+            }
+            Drive::Pwm(_) => false,
+        }
+    }
+
+    fn switch(&self, phase: Phase) -> Option<SwitchState> {
+        match self.drive {
+            Drive::Manual(switches) => Some(switches[index(phase)]),
+            Drive::Pwm(_) => None,
+        }
+    }
+
+    fn set_duty(&mut self, phase: Phase, duty: u8) -> bool {
+        match &mut self.drive {
+            Drive::Pwm(duties) => {
+                duties[index(phase)] = duty.min(100) as f64 / 100.0;
+                true
+            }
+            Drive::Manual(_) => false,
+        }
+    }
+
+    fn adc_voltage(&mut self, channel: Channel) -> MilliVolts {
+        let board = self.board;
+        let leg = |phase| divide(board.phase_divider, self.leg_voltage(phase));
+        let shunt = board.shunt.0 as f64 * 1e-6 * (1.0 + self.params.shunt_error);
+        let amplifier = |phase| {
+            let v_shunt = self.shunt_current(phase) * shunt;
+            board.current_midpoint.0 as f64 + v_shunt * self.current_gain as f64 * 1000.0
+        };
+        let v_pin_mv = match channel {
+            Channel::VIn => divide(board.v_in_divider, self.params.v_bus) * 1000.0,
+            Channel::VA => leg(Phase::A) * 1000.0,
+            Channel::VB => leg(Phase::B) * 1000.0,
+            Channel::VC => leg(Phase::C) * 1000.0,
+            Channel::IA => amplifier(Phase::A),
+            Channel::IB => amplifier(Phase::B),
+            Channel::IC => amplifier(Phase::C),
+            Channel::VCan => divide(board.v_can_divider, self.params.v_can) * 1000.0,
+        };
+        Self::adc(v_pin_mv)
+    }
+
+    /// The PWM is averaged, the reading is the one after the next period.
+    fn sample_on_time(&mut self, channel: Channel) -> Option<MilliVolts> {
+        if let Drive::Manual(_) = self.drive {
+            return None;
+        }
+        self.advance(self.params.pwm_period);
+        Some(self.adc_voltage(channel))
+    }
+
+    /// 120° sensors aligned with the phase A back-EMF.
+    fn hall(&self) -> (bool, bool, bool) {
+        let deg = self.angle.to_degrees();
+        let a = deg < 180.0;
+        let b = (120.0..300.0).contains(&deg);
+        let c = !(60.0..240.0).contains(&deg);
+        (a, b, c)
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.advance(ms as f64 * 1e-3);
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.advance(us as f64 * 1e-6);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use power_stage_core::board::REV1;
+    use power_stage_core::hall::hall_index;
+    use power_stage_core::stage::measure;
+    use power_stage_core::config::Config;
+    use power_stage_core::bootstrap;
+    use power_stage_core::dpt;
+    use power_stage_core::units::MilliAmperes;
+
+    fn close(a: f64, b: f64, tolerance: f64) -> bool {
+        (a - b).abs() <= tolerance
+    }
+
+    #[test]
+    fn dc_current_through_two_windings() {
+        let mut sim = Simulator::new(REV1);
+        sim.set_switch(Phase::A, SwitchState::High);
+        sim.set_switch(Phase::B, SwitchState::Low);
+        sim.delay_ms(10);
+        // 24V across two 0.5 Ohm windings
+        assert!(close(sim.current(Phase::A), 24.0, 0.01), "{}", sim.current(Phase::A));
+        assert!(close(sim.current(Phase::B), -24.0, 0.01));
+        assert_eq!(sim.current(Phase::C), 0.0);
+        assert!(close(sim.leg_voltage(Phase::C), 12.0, 0.01));
+    }
+
+    #[test]
+    fn current_rises_with_time_constant() {
+        let mut sim = Simulator::new(REV1);
+        sim.set_switch(Phase::A, SwitchState::High);
+        sim.set_switch(Phase::B, SwitchState::Low);
+        // tau = 2L / 2R = 400us
+        sim.advance(400e-6);
+        assert!(close(sim.current(Phase::A), 24.0 * (1.0 - (-1.0f64).exp()), 0.1));
+    }
+
+    #[test]
+    fn freewheeling_through_diodes() {
+        let mut sim = Simulator::new(REV1);
+        sim.set_switch(Phase::A, SwitchState::High);
+        sim.set_switch(Phase::B, SwitchState::Low);
+        sim.delay_ms(5);
+        sim.set_switch(Phase::A, SwitchState::Off);
+        sim.advance(1e-6);
+        // Low side diode of A takes the current, B is still on
+        assert!(sim.current(Phase::A) > 0.0);
+        assert_eq!(sim.leg_voltage(Phase::A), 0.0);
+        sim.delay_ms(10);
+        assert_eq!(sim.current(Phase::A), 0.0);
+        assert_eq!(sim.current(Phase::B), 0.0);
+    }
+
+    #[test]
+    fn measurements_follow_board_description() {
+        let mut sim = Simulator::new(REV1);
+        let config = Config::defaults(&REV1);
+        sim.params.v_can = 12.0;
+        let v_in = measure(&mut sim, &REV1, &config, Channel::VIn);
+        assert!(close(v_in as f64, 24_000.0, 20.0), "{}", v_in);
+        let v_can = measure(&mut sim, &REV1, &config, Channel::VCan);
+        assert!(close(v_can as f64, 12_000.0, 20.0), "{}", v_can);
+
+        sim.set_switch(Phase::A, SwitchState::High);
+        sim.set_switch(Phase::C, SwitchState::Low);
+        sim.params.v_bus = 3.0;
+        sim.delay_ms(10);
+        let v_a = measure(&mut sim, &REV1, &config, Channel::VA);
+        assert!(close(v_a as f64, 3000.0, 20.0), "{}", v_a);
+        // 3A * 10mOhm * 20 = 600mV at the amplifier, only the low side shunt sees it
+        let i_c = measure(&mut sim, &REV1, &config, Channel::IC);
+        assert!(close(i_c as f64, -3000.0, 20.0), "{}", i_c);
+        let i_a = measure(&mut sim, &REV1, &config, Channel::IA);
+        assert!(close(i_a as f64, 0.0, 20.0), "{}", i_a);
+        // Calibrated amplifier offset of 10mV is 50mA
+        let offset = Config { current_offsets: [MilliVolts(0), MilliVolts(0), MilliVolts(10)], ..config };
+        let i_c_offset = measure(&mut sim, &REV1, &offset, Channel::IC);
+        assert_eq!(i_c - i_c_offset, 50);
+    }
+
+    #[test]
+    fn double_pulse_current_on_return_shunt() {
+        let mut sim = Simulator::new(REV1);
+        let config = Config::defaults(&REV1);
+        let channel = dpt::sampled_channel(Phase::A, Phase::B).unwrap();
+        // An inductor with little resistance, the current ramps linearly
+        sim.params.r = 0.01;
+        sim.set_switch(Phase::B, SwitchState::Low);
+        // First pulse, 24V across both windings build up 24V * 100us / 400uH = 6A
+        sim.set_switch(Phase::A, SwitchState::High);
+        sim.advance(100e-6);
+        let i_off = dpt::pulsed_current(MilliAmperes(measure(&mut sim, &REV1, &config, channel)));
+        assert!(close(i_off.0 as f64, 6000.0, 100.0), "{:?}", i_off);
+        // The pulsed leg's own shunt carries nothing while its high side is on
+        assert!(close(measure(&mut sim, &REV1, &config, Channel::IA) as f64, 0.0, 20.0));
+        // Gap, the low side diode of A freewheels, then the second pulse
+        sim.set_switch(Phase::A, SwitchState::Off);
+        sim.advance(10e-6);
+        sim.set_switch(Phase::A, SwitchState::High);
+        sim.advance(1e-6);
+        let i_on = dpt::pulsed_current(MilliAmperes(measure(&mut sim, &REV1, &config, channel)));
+        assert!(close(i_on.0 as f64, i_off.0 as f64, 100.0), "{:?} {:?}", i_on, i_off);
+
+        let report = dpt::Report { v_in: MilliVolts(24_000), i_off, i_on };
+        let l = report.inductance_uh(100).unwrap();
+        assert!(close(l as f64, 400.0, 10.0), "{}", l);
+    }
+
+    #[test]
+    fn amplifier_saturates() {
+        let mut sim = Simulator::new(REV1);
+        sim.set_switch(Phase::A, SwitchState::High);
+        sim.set_switch(Phase::B, SwitchState::Low);
+        sim.delay_ms(10);
+        assert_eq!(sim.adc_voltage(Channel::IB), MilliVolts(0));
+        // Positive current shows in the low side diode while freewheeling
+        sim.set_switch(Phase::A, SwitchState::Off);
+        sim.advance(1e-6);
+        assert_eq!(sim.adc_voltage(Channel::IA), MilliVolts(3300));
+    }
+
+    #[test]
+    fn pwm_average_and_dead_time() {
+        let mut sim = Simulator::new(REV1);
+        assert!(!sim.set_duty(Phase::A, 60));
+        assert!(sim.switch_to_openloop());
+        assert!(!sim.switch_to_openloop());
+        assert_eq!(sim.switch(Phase::A), None);
+        sim.set_duty(Phase::A, 60);
+        sim.set_duty(Phase::B, 40);
+        sim.delay_ms(10);
+        // 20% of 24V minus the dead time loss on both legs
+        let expected = (0.2 - 2.0 * 1e-6 / 50e-6) * 24.0 / 1.0;
+        assert!(close(sim.current(Phase::A), expected, 0.05), "{}", sim.current(Phase::A));
+        assert!(sim.switch_to_manual());
+        assert_eq!(sim.switch(Phase::A), Some(SwitchState::Off));
+    }
+
+    #[test]
+    fn back_emf_on_floating_legs() {
+        let mut sim = Simulator::new(REV1);
+        sim.params.speed = 1000.0;
+        sim.set_switch(Phase::B, SwitchState::Low);
+        sim.set_angle(core::f64::consts::FRAC_PI_2);
+        // No current path, A sits at star point + e_a, star point is e_b below ground
+        let e = 10.0;
+        let expected = e - e * (core::f64::consts::FRAC_PI_2 - 2.0 * core::f64::consts::PI / 3.0).sin();
+        assert!(close(sim.leg_voltage(Phase::A), expected, 1e-9), "{}", sim.leg_voltage(Phase::A));
+        assert_eq!(sim.current(Phase::A), 0.0);
+    }
+
+    #[test]
+    fn bootstrap_hold_time() {
+        let config = Config::defaults(&REV1);
+        let report = bootstrap::run(&mut Simulator::new(REV1), &REV1, &config);
+        assert!(report.passed(1000), "{:?}", report);
+        assert_eq!(report.legs[2].sag_at_ms, None);
+
+        let mut sim = Simulator::new(REV1);
+        sim.params.bootstrap_hold = 0.03;
+        let report = bootstrap::run(&mut sim, &REV1, &config);
+        assert!(close(report.v_in.0 as f64, 24_000.0, 20.0));
+        for leg in report.legs.iter() {
+            assert!(leg.v_low.0 < 100, "{:?}", leg);
+            assert_eq!(leg.sustained_ms, 20);
+            // Sagged in the 50ms hold
+            assert_eq!(leg.sag_at_ms, Some(30));
+        }
+        assert!(report.passed(20));
+        assert!(!report.passed(50));
+        assert_eq!(sim.switch(Phase::A), Some(SwitchState::Off));
+    }
+
+    #[test]
+    fn hall_sequence() {
+        let mut sim = Simulator::new(REV1);
+        let mut sequence = Vec::new();
+        for sector in 0..6 {
+            sim.set_angle((sector as f64 * 60.0 + 30.0).to_radians());
+            let (a, b, c) = sim.hall();
+            sequence.push(hall_index(a, b, c));
+        }
+        let mut sorted = sequence.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), 6, "{:?}", sequence);
+        assert!(!sequence.contains(&0) && !sequence.contains(&7));
+    }
+}
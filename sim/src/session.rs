@@ -0,0 +1,168 @@
+//! Simulator with the state the tester keeps between commands, so the shared CLI commands run on it.
+
+use crate::model::{Simulator, ADC_FULL_SCALE_MV};
+use power_stage_core::bench::Bench;
+use power_stage_core::board::Board;
+use power_stage_core::calibration::Point;
+use power_stage_core::config::Config;
+use power_stage_core::shorts::Short;
+use power_stage_core::stage::{Channel, Phase, PowerStage, SwitchState};
+use power_stage_core::store::{Flash, FlashError};
+use power_stage_core::units::MilliVolts;
+use power_stage_core::vector::vector_duties;
+
+const SECTOR_SIZE: usize = 2048;
+
+/// Two configuration sectors kept in memory for the duration of a run.
+pub struct RamFlash {
+    sectors: [Vec<u8>; 2],
+}
+
+impl RamFlash {
+    pub fn new() -> Self {
+        RamFlash { sectors: [vec![0xFF; SECTOR_SIZE], vec![0xFF; SECTOR_SIZE]] }
+    }
+}
+
+impl Default for RamFlash {
+    fn default() -> Self {
+        RamFlash::new()
+    }
+}
+
+impl Flash for RamFlash {
+    fn sector_size(&self) -> u32 {
+        SECTOR_SIZE as u32
+    }
+
+    fn read(&mut self, sector: usize, offset: u32, buf: &mut [u8]) {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.sectors[sector][offset..offset + buf.len()]);
+    }
+
+    fn program(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        for (i, byte) in data.iter().enumerate() {
+            self.sectors[sector][offset as usize + i] &= byte;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), FlashError> {
+        self.sectors[sector].fill(0xFF);
+        Ok(())
+    }
+}
+
+pub struct Session {
+    pub sim: Simulator,
+    pub config: Config,
+    pub short: Option<Short>,
+    pub cal_low: Option<Point>,
+    pub flash: RamFlash,
+}
+
+impl Session {
+    /// Default settings and nothing stored, like a new board.
+    pub fn new(board: Board) -> Self {
+        Session { sim: Simulator::new(board), config: Config::defaults(&board), short: None, cal_low: None, flash: RamFlash::new() }
+    }
+
+    fn set_duties(&mut self, duties: [f64; 3]) -> bool {
+        Phase::ALL.iter().zip(duties.iter()).all(|(&phase, &duty)| self.sim.set_duty_fraction(phase, duty))
+    }
+}
+
+impl PowerStage for Session {
+    fn switch_to_manual(&mut self) -> bool {
+        self.sim.switch_to_manual()
+    }
+
+    /// PWM frequency and dead time are taken from the settings, like the firmware does.
+    fn switch_to_openloop(&mut self) -> bool {
+        if self.sim.switch(Phase::A).is_none() {
+            return false;
+        }
+        self.sim.params.pwm_period = 1.0 / self.config.pwm_frequency_hz as f64;
+        self.sim.params.dead_time = self.config.dead_time_ns as f64 * 1e-9;
+        self.sim.switch_to_openloop()
+    }
+
+    fn set_switch(&mut self, phase: Phase, state: SwitchState) -> bool {
+        self.sim.set_switch(phase, state)
+    }
+
+    fn switch(&self, phase: Phase) -> Option<SwitchState> {
+        self.sim.switch(phase)
+    }
+
+    fn set_duty(&mut self, phase: Phase, duty: u8) -> bool {
+        self.sim.set_duty(phase, duty)
+    }
+
+    fn adc_voltage(&mut self, channel: Channel) -> MilliVolts {
+        self.sim.adc_voltage(channel)
+    }
+
+    fn sample_on_time(&mut self, channel: Channel) -> Option<MilliVolts> {
+        self.sim.sample_on_time(channel)
+    }
+
+    fn hall(&self) -> (bool, bool, bool) {
+        self.sim.hall()
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.sim.delay_ms(ms)
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.sim.delay_us(us)
+    }
+}
+
+impl Bench for Session {
+    type Flash = RamFlash;
+
+    fn board(&self) -> Board {
+        self.sim.board
+    }
+
+    fn config(&mut self) -> &mut Config {
+        &mut self.config
+    }
+
+    fn short(&mut self) -> &mut Option<Short> {
+        &mut self.short
+    }
+
+    fn cal_low(&mut self) -> &mut Option<Point> {
+        &mut self.cal_low
+    }
+
+    fn flash(&mut self) -> &mut RamFlash {
+        &mut self.flash
+    }
+
+    fn driver_enabled(&self) -> bool {
+        self.sim.driver_enabled()
+    }
+
+    fn calibrate_adc(&mut self) -> MilliVolts {
+        MilliVolts(ADC_FULL_SCALE_MV as i32)
+    }
+
+    /// Compares in ns of the PWM period, finer than any timer the firmware runs on.
+    fn apply_vector(&mut self, angle_deg: i32, duty: u8) -> bool {
+        let period = (self.sim.params.pwm_period * 1e9).round() as u32;
+        let compares = vector_duties(angle_deg, duty, period);
+        self.set_duties(compares.map(|c| c as f64 / period as f64))
+    }
+
+    fn dc_injection(&mut self, phase: Phase, duty: u8) -> bool {
+        self.set_duties([0.0; 3]) && self.sim.set_duty(phase, duty)
+    }
+
+    fn zero_vector(&mut self) -> bool {
+        self.set_duties([0.5; 3])
+    }
+}
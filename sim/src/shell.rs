@@ -0,0 +1,284 @@
+//! Text commands for the simulator. The power stage commands are the firmware's own from
+//! `power_stage_core::bench`, the rest controls the model.
+
+use crate::session::Session;
+use power_stage_core::bench::{self, Handler};
+use power_stage_core::cli::{dispatch, fits, Arg, Command, Help, Unit, Values};
+use power_stage_core::hall::hall_index;
+use power_stage_core::response::{ErrorKind, Failure, Mode, Outcome, Response};
+use power_stage_core::stage::{measure, Channel, Phase, PowerStage};
+
+const PARAMS: &[&str] = &["vbus", "r", "l", "ke", "speed", "vcan", "bootstrap", "shunt", "trip"];
+
+type Shared = bench::Commands<Session, String>;
+
+const COMMANDS: &[Command<Handler<Session, String>>] = &[
+    Command { name: "help", help: "List commands or show the usage of a group", args: &[Arg::word("command").optional()], handler: help_command },
+    Shared::SWITCH,
+    Shared::SWITCH_MODE,
+    Shared::OL_MANUAL,
+    Shared::OL_ALIGN,
+    Shared::OL_BRAKE,
+    Shared::CFG_SHOW,
+    Shared::CFG_SET,
+    Shared::CFG_SAVE,
+    Shared::CFG_LOAD,
+    Shared::CFG_RESET,
+    Shared::CAL_VOLTAGE,
+    Shared::CAL_CURRENT,
+    Shared::TEST_BOOTSTRAP,
+    Shared::TEST_SHORTS,
+    Shared::TEST_LEGS,
+    Command { name: "drv on", help: "Enable the gate driver after an overcurrent trip", args: &[], handler: drv_on_command },
+    Command { name: "run", help: "Let simulated time pass", args: &[bench::TIME], handler: run_command },
+    Command { name: "status", help: "Same measurements as the firmware status page", args: &[], handler: status_command },
+    Command {
+        name: "set",
+        help: "Model parameter in SI units, speed in electrical rad/s, shunt error relative, trip is the DRV overcurrent",
+        args: &[Arg::choice("param", PARAMS), Arg::word("value")],
+        handler: set_command,
+    },
+    Command {
+        name: "gain",
+        help: "Shunt amplifier gain of the simulated DRV, the settings follow",
+        args: &[Arg::int("gain", 1, 255, Unit::None)],
+        handler: gain_command,
+    },
+];
+const _: () = assert!(fits(COMMANDS));
+
+/// Runs one line, returns the reply as the firmware would print it.
+pub fn execute(session: &mut Session, line: &str) -> String {
+    let mut out = String::new();
+    let mut response = Response::new(&mut out, Mode::Human);
+    match dispatch(COMMANDS, line) {
+        Ok((command, args)) => {
+            let outcome = (command.handler)(session, &args, &mut response);
+            response.finish(outcome);
+        }
+        Err(e) => response.finish_dispatch_error(COMMANDS, line, &e),
+    }
+    out
+}
+
+fn help_command(_session: &mut Session, args: &Values, response: &mut Response<String>) -> Outcome {
+    response.message(Help(COMMANDS, args.text(0)).to_string().trim_end());
+    Ok(())
+}
+
+fn drv_on_command(session: &mut Session, _args: &Values, _response: &mut Response<String>) -> Outcome {
+    session.sim.enable_driver();
+    Ok(())
+}
+
+fn run_command(session: &mut Session, args: &Values, _response: &mut Response<String>) -> Outcome {
+    session.delay_ms(args.int(0) as u32);
+    Ok(())
+}
+
+fn set_command(session: &mut Session, args: &Values, _response: &mut Response<String>) -> Outcome {
+    let value: f64 = args.text(1).parse().map_err(|_| Failure::new(ErrorKind::BadArgument, "Expected a number"))?;
+    let params = &mut session.sim.params;
+    let positive = Failure::new(ErrorKind::BadArgument, "Expected a positive value");
+    match PARAMS[args.choice(0)] {
+        "vbus" => params.v_bus = value,
+        "r" | "l" | "bootstrap" | "trip" if value <= 0.0 => return Err(positive),
+        "r" => params.r = value,
+        "l" => params.l = value,
+        "bootstrap" => params.bootstrap_hold = value,
+        "ke" => params.ke = value,
+        "speed" => params.speed = value,
+        "vcan" => params.v_can = value,
+        "trip" => params.overcurrent = value,
+        _ => params.shunt_error = value,
+    }
+    Ok(())
+}
+
+fn gain_command(session: &mut Session, args: &Values, _response: &mut Response<String>) -> Outcome {
+    let gain = args.int(0) as u8;
+    if !session.sim.board.drv.supports_gain(gain) {
+        return Err(Failure::new(ErrorKind::NotSupported, "Gain not supported by the DRV"));
+    }
+    session.sim.current_gain = gain;
+    session.config.current_sense_gain = gain;
+    Ok(())
+}
+
+fn status_command(session: &mut Session, _args: &Values, response: &mut Response<String>) -> Outcome {
+    let board = session.sim.board;
+    let config = session.config;
+    response.text_field("board", board.name);
+    response.text_field("drv", board.drv.name());
+    response.field("time", (session.sim.time() * 1000.0).round(), "ms");
+    response.field("v_in", measure(session, &board, &config, Channel::VIn), "mV");
+    for &phase in Phase::ALL.iter() {
+        let name = phase.name();
+        match session.switch(phase) {
+            Some(state) => response.text_field(format_args!("{}_switch", name), format_args!("{:?}", state)),
+            None => response.text_field(format_args!("{}_switch", name), "PWM"),
+        }
+        response.field(format_args!("{}_v", name), measure(session, &board, &config, phase.voltage_channel()), "mV");
+        response.field(format_args!("{}_i", name), measure(session, &board, &config, phase.current_channel()), "mA");
+    }
+    response.field("v_can", measure(session, &board, &config, Channel::VCan), "mV");
+    let (a, b, c) = session.hall();
+    response.text_field("halls", format_args!("({}, {}, {}, {})", a, b, c, hall_index(a, b, c)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use power_stage_core::board::{Board, DrvVariant, REV1};
+    use power_stage_core::stage::SwitchState;
+    use power_stage_core::vt100;
+
+    fn ok(reply: &str) -> bool {
+        reply.ends_with(&format!("{}Ok{}\n", vt100::GREEN, vt100::DEFAULT))
+    }
+
+    fn run(session: &mut Session, lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| execute(session, line)).collect()
+    }
+
+    #[test]
+    fn manual_sequence() {
+        let mut session = Session::new(REV1);
+        let replies = run(&mut session, &["set vbus 2", "sw ah", "sw bl", "run 10"]);
+        assert!(replies.iter().all(|r| ok(r)), "{:?}", replies);
+        assert!((session.sim.current(Phase::A) - 2.0).abs() < 0.01);
+        let text = execute(&mut session, "status");
+        assert!(text.contains("a_switch: High\n"), "{}", text);
+        assert!(text.contains("b_switch: Low\n"), "{}", text);
+    }
+
+    #[test]
+    fn mode_is_checked() {
+        let mut session = Session::new(REV1);
+        assert!(execute(&mut session, "ol manual a 10").contains("Not in openloop mode, swmode openloop first"));
+        assert!(ok(&execute(&mut session, "swmode openloop")));
+        assert!(execute(&mut session, "sw ah").contains("Manual control disabled"));
+        assert!(ok(&execute(&mut session, "ol manual a 10")));
+    }
+
+    #[test]
+    fn align_returns_to_zero_vector() {
+        let mut session = Session::new(REV1);
+        session.sim.params.v_bus = 12.0;
+        run(&mut session, &["swmode openloop", "ol align 0 50 20"]);
+        // Current builds up during alignment and decays through the zero vector afterwards
+        assert!(session.sim.current(Phase::A) > 1.0, "{}", session.sim.current(Phase::A));
+        execute(&mut session, "run 20");
+        assert!(session.sim.current(Phase::A).abs() < 0.01);
+    }
+
+    #[test]
+    fn align_keeps_pwm_resolution() {
+        // At 90° phase A sits at the star point, whole percent duties would leave it a third of a percent off
+        let mut session = Session::new(REV1);
+        session.sim.params.v_bus = 12.0;
+        let replies = run(&mut session, &["cfg set dead_time 0", "swmode openloop", "ol align 90 1 20"]);
+        assert!(replies.iter().all(|r| ok(r)), "{:?}", replies);
+        assert!(session.sim.current(Phase::A).abs() < 0.01, "{}", session.sim.current(Phase::A));
+        assert!(session.sim.current(Phase::B) > 0.08, "{}", session.sim.current(Phase::B));
+    }
+
+    #[test]
+    fn run_is_bounded() {
+        let mut session = Session::new(REV1);
+        assert!(execute(&mut session, "run 100000").contains("out of range <time 1..10000ms>"));
+        assert_eq!(session.sim.time(), 0.0);
+    }
+
+    #[test]
+    fn bootstrap_test() {
+        let mut session = Session::new(REV1);
+        assert!(ok(&execute(&mut session, "set bootstrap 0.03")));
+        let text = execute(&mut session, "test bootstrap 50");
+        assert!(text.contains("a_sustained: 20ms\na_sag_at: 30ms\na_sag_v: 0mV\na_passed: false\n"), "{}", text);
+        assert!(text.contains("A leg didn't pull low or sagged early"), "{}", text);
+        assert!(execute(&mut session, "test frob").contains("unknown command"));
+    }
+
+    #[test]
+    fn shorts_test() {
+        // A connected motor fails the test, its windings connect the phases
+        let mut session = Session::new(REV1);
+        let text = execute(&mut session, "test shorts");
+        // The pulsed current splits between the other two windings, half of it stays below the limit
+        assert!(text.contains("short: b-") && text.contains("Short found"), "{}", text);
+        assert!(text.starts_with("abort: software\n"), "{}", text);
+        assert_eq!(session.switch(Phase::A), Some(SwitchState::Off));
+        assert!(execute(&mut session, "swmode openloop").contains("Short found"));
+        // Motor disconnected, a huge inductance passes no current
+        let mut session = Session::new(REV1);
+        assert!(ok(&execute(&mut session, "set l 1000")));
+        let text = execute(&mut session, "test shorts");
+        assert!(ok(&text), "{}", text);
+    }
+
+    #[test]
+    fn shorts_test_driver_trip() {
+        // The DRV trips below the check's own limit, the trip is kept until a clean pass
+        let mut session = Session::new(REV1);
+        assert!(ok(&execute(&mut session, "set trip 0.3")));
+        let text = execute(&mut session, "test shorts");
+        assert!(text.contains("short: drv-fault\n") && text.contains("DRV shut down"), "{}", text);
+        assert!(execute(&mut session, "test shorts").contains("DRV disabled"));
+        assert!(ok(&execute(&mut session, "drv on")));
+        assert!(execute(&mut session, "swmode openloop").contains("Short found"));
+        assert!(execute(&mut session, "test bootstrap").contains("Short found"));
+        let replies = run(&mut session, &["set l 1000", "test shorts", "swmode openloop"]);
+        assert!(replies.iter().all(|r| ok(r)), "{:?}", replies);
+    }
+
+    #[test]
+    fn current_calibration() {
+        // Shunts 4% above nominal read 4% high. The windings are the reference load and B's low side
+        // shunt carries the current returning from A and C, its own leg never drives it
+        let mut session = Session::new(REV1);
+        let replies = run(&mut session, &["set shunt 0.04", "cfg set ref_load 750"]);
+        assert!(replies.iter().all(|r| ok(r)), "{:?}", replies);
+        let text = execute(&mut session, "cal current b 8000");
+        assert!(text.starts_with("duty: 27%\n"), "{}", text);
+        let gain: u16 = text.lines().find_map(|l| l.strip_prefix("gain: ")).unwrap().parse().unwrap();
+        assert!((9_580..=9_700).contains(&gain), "{}", text);
+        assert_eq!(session.config.current_gains[1], gain);
+        assert_eq!(session.switch(Phase::B), Some(SwitchState::Off));
+        assert!(text.contains("sequence: 1\n"), "{}", text);
+        assert!(execute(&mut session, "cal current b 500").contains("Current out of reach"));
+    }
+
+    #[test]
+    fn settings_are_stored() {
+        let mut session = Session::new(REV1);
+        let replies = run(&mut session, &["cfg set dead_time 500", "cfg save", "cfg reset"]);
+        assert!(replies.iter().all(|r| ok(r)), "{:?}", replies);
+        assert_eq!(session.config.dead_time_ns, 1000);
+        assert!(ok(&execute(&mut session, "cfg load")));
+        assert_eq!(session.config.dead_time_ns, 500);
+        execute(&mut session, "swmode openloop");
+        assert!((session.sim.params.dead_time - 500e-9).abs() < 1e-12);
+        // Erasing flash stalls the CPU, nothing may switch meanwhile
+        assert!(execute(&mut session, "cfg save").contains("Saving stalls the CPU"));
+        run(&mut session, &["swmode manual", "sw ah"]);
+        assert!(execute(&mut session, "cfg save").contains("Saving stalls the CPU"));
+        assert!(ok(&execute(&mut session, "sw az")));
+        assert!(execute(&mut session, "cfg save").contains("sequence: 2\n"));
+    }
+
+    #[test]
+    fn errors() {
+        // DRV8323S amplifiers have gain 5 but not 80
+        let mut session = Session::new(Board { drv: DrvVariant::Drv8323S, ..REV1 });
+        assert!(execute(&mut session, "sw").contains("missing ah/al/az/bh/bl/bz/ch/cl/cz"));
+        assert!(execute(&mut session, "sw ax").contains("expected ah/al/az"));
+        assert!(execute(&mut session, "run soon").contains("expected <time 1..10000ms>"));
+        assert!(execute(&mut session, "set r -1").contains("Expected a positive value"));
+        assert!(execute(&mut session, "set r x").contains("Expected a number"));
+        assert!(execute(&mut session, "gain 80").contains("Gain not supported by the DRV"));
+        assert!(ok(&execute(&mut session, "gain 5")));
+        assert!(execute(&mut session, "fly").contains("unknown command"));
+    }
+}
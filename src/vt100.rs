@@ -1,14 +0,0 @@
-#[allow(dead_code)]
-pub const RED: &'static str = "\x1b[1;31m";
-#[allow(dead_code)]
-pub const GREEN: &'static str = "\x1b[1;32m";
-#[allow(dead_code)]
-pub const CYAN: &'static str = "\x1b[96;36m";
-#[allow(dead_code)]
-pub const YELLOW: &'static str = "\x1b[93;33m";
-#[allow(dead_code)]
-pub const BG_CYAN: &'static str = "\x1b[4;46m";
-#[allow(dead_code)]
-pub const DEFAULT: &'static str = "\x1b[0m";
-#[allow(dead_code)]
-pub const CLEAR_SCREEN: &'static str = "\x1b[2J";
\ No newline at end of file
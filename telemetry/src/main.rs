@@ -0,0 +1,60 @@
+//! Decodes the binary telemetry stream from RTT channel 1 into CSV on stdout:
+//! `power-stage-telemetry [capture.bin] > samples.csv`, reads stdin without an argument.
+//! Frames that fail to decode are skipped and counted on stderr.
+//! The firmware sends 250 samples/s, paced by TIM2 with ADC2, see `capture::STREAM_RATE_HZ` there.
+use power_stage_core::telemetry::{Decoder, CSV_HEADER};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+fn main() {
+    let input: Box<dyn Read> = match std::env::args().nth(1) {
+        Some(path) => match File::open(&path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => Box::new(io::stdin()),
+    };
+    if let Err(e) = convert(input, io::stdout().lock()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn convert(mut input: impl Read, output: impl Write) -> io::Result<()> {
+    let mut output = BufWriter::new(output);
+    writeln!(output, "{}", CSV_HEADER)?;
+    let mut decoder = Decoder::new();
+    let mut row = String::new();
+    let mut bad_frames = 0u32;
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        for byte in &buf[..len] {
+            match decoder.push(*byte) {
+                Some(Ok(sample)) => {
+                    row.clear();
+                    sample.write_csv(&mut row).ok();
+                    writeln!(output, "{}", row)?;
+                }
+                Some(Err(e)) => {
+                    bad_frames += 1;
+                    eprintln!("Bad frame: {:?}", e);
+                }
+                None => {}
+            }
+        }
+        output.flush()?;
+    }
+    if bad_frames != 0 {
+        eprintln!("{} bad frames skipped", bad_frames);
+    }
+    Ok(())
+}